@@ -48,7 +48,7 @@ impl From<Status> for u32 {
 
         word |= (sr.irq_disable as u32) << 7;
         word |= (sr.fiq_disable as u32) << 6;
-        word |= (sr.state as u32) << 4;
+        word |= (sr.state as u32) << 5;
         word |= sr.mode as u32;
         word // return the condition word
     }
@@ -71,6 +71,31 @@ impl Default for Status {
     }
 }
 
+impl Status {
+    pub fn mode(&self) -> u8 {
+        self.mode
+    }
+
+    #[allow(dead_code)]
+    pub fn irq_disabled(&self) -> bool {
+        self.irq_disable
+    }
+}
+
+/*
+Processor modes, as encoded in the bottom 5 bits of the CPSR.
+
+A new core starts in Supervisor mode with IRQs and FIQs disabled (a CPSR of 0xD3), the same as after a reset. A mode of 0 isn't a
+real mode, a CPSR that's been cleared to it is treated like System.
+*/
+pub const MODE_USR: u8 = 0x10;
+pub const MODE_FIQ: u8 = 0x11;
+pub const MODE_IRQ: u8 = 0x12;
+pub const MODE_SVC: u8 = 0x13;
+pub const MODE_ABT: u8 = 0x17;
+pub const MODE_UND: u8 = 0x1B;
+pub const MODE_SYS: u8 = 0x1F;
+
 /* Registers */
 #[derive(Clone, Copy, Debug)]
 pub struct Reg {
@@ -92,7 +117,9 @@ pub struct Reg {
     pub data:       u32,
     pub shift:      u8,
 
+    /* pipeline[0] was fetched last and pipeline[1] is the next to execute, prefetched is its address (None to refill from R15) */
     pub pipeline:   [u32; 3],
+    pub prefetched: Option<u32>,
 }
 
 /* The same as Status, defining the default values. */
@@ -118,135 +145,115 @@ impl Default for Reg {
             shift:      0,
 
             pipeline:   [0; 3],
+            prefetched: None,
         }
     }
 }
 
 /* The different functions of the Reg object to facilitiate different operations */
 impl Reg {
+    /* The register index refers to in a given mode, switching mode is all it takes to switch banks */
+    pub fn banked(&mut self, mode: u8, index: usize) -> &mut u32 {
+        match (mode, index) {
+            (MODE_FIQ, 8 ..= 14)    => &mut self.fiq[index-8],
+            (MODE_SVC, 13 ..= 14)   => &mut self.svc[index-13],
+            (MODE_ABT, 13 ..= 14)   => &mut self.abt[index-13],
+            (MODE_IRQ, 13 ..= 14)   => &mut self.irq[index-13],
+            (MODE_UND, 13 ..= 14)   => &mut self.und[index-13],
+            _                       => &mut self.gp[index],
+        }
+    }
+
     #[allow(dead_code)]
     pub fn write(&mut self, index: usize, data: u32) { /* Write to a register */
-        match self.cpsr.mode {
-            0   =>  {
-                self.gp[index] = data;
-            },
-            1   =>  {
-                match index {
-                    8 ..= 14    => self.fiq[index-8] = data,
-                    _           => self.gp[index] = data,
-                };
-            },
-            2   =>  {
-                match index {
-                    13 ..= 14   => self.svc[index-13] = data,
-                    _           => self.gp[index] = data,
-                };
-            },
-            3   =>  {
-                match index {
-                    13 ..= 14   => self.abt[index-13] = data,
-                    _           => self.gp[index] = data,
-                };
-            }
-            4   =>  {
-                match index {
-                    13 ..= 14   => self.irq[index-13] = data,
-                    _           => self.gp[index] = data,
-                };
-            }
-            5   =>  {
-                match index {
-                    13 ..= 14   => self.und[index-13] = data,
-                    _           => self.gp[index] = data,
-                };
-            }
-            _   =>  unreachable!()
-        }
+        *self.banked(self.cpsr.mode, index) = data;
     }
 
     pub fn read(&mut self, index: usize) -> u32 {   /* Read from a register */
+        *self.banked(self.cpsr.mode, index)
+    }
+
+    /* All 16 registers as the current mode sees them */
+    #[allow(dead_code)]
+    pub fn current(&self) -> [u32; 16] {
+        let mut reg = *self;
+        std::array::from_fn(|index| reg.read(index))
+    }
+
+    /* The SPSR of the current mode, User and System don't have one */
+    pub fn spsr(&mut self) -> Option<&mut Status> {
         match self.cpsr.mode {
-            0   =>  {
-                self.gp[index]
-            },
-            1   =>  {
-                match index {
-                    8 ..= 14    => self.fiq[index-8],
-                    _           => self.gp[index],
-                }
-            },
-            2   =>  {
-                match index {
-                    13 ..= 14   => self.svc[index-13],
-                    _           => self.gp[index],
-                }
-            },
-            3   =>  {
-                match index {
-                    13 ..= 14   => self.abt[index-13],
-                    _           => self.gp[index],
-                }
-            }
-            4   =>  {
-                match index {
-                    13 ..= 14   => self.irq[index-13],
-                    _           => self.gp[index],
-                }
-            }
-            5   =>  {
-                match index {
-                    13 ..= 14   => self.und[index-13],
-                    _           => self.gp[index],
-                }
-            }
-            _   =>  unreachable!()
+            MODE_FIQ => Some(&mut self.spsr_fiq),
+            MODE_SVC => Some(&mut self.spsr_svc),
+            MODE_ABT => Some(&mut self.spsr_abt),
+            MODE_IRQ => Some(&mut self.spsr_irq),
+            MODE_UND => Some(&mut self.spsr_und),
+            _        => None,
+        }
+    }
+
+    /* Everything but User mode can change the control bits */
+    pub fn privileged(&self) -> bool {
+        self.cpsr.mode != MODE_USR
+    }
+
+    /*
+    Take an exception: the CPSR is saved to the new mode's SPSR, the processor goes to ARM state in that mode with IRQs
+    (and FIQs as well for a FIQ) disabled and link is put in the new mode's r14. The caller deals with the PC.
+    */
+    #[allow(dead_code)]
+    pub fn exception(&mut self, mode: u8, link: u32) {
+        let cpsr = self.cpsr;
+        self.cpsr.mode = mode;
+        self.cpsr.state = false;
+        self.cpsr.irq_disable = true;
+        if mode == MODE_FIQ {
+            self.cpsr.fiq_disable = true;
         }
+        if let Some(spsr) = self.spsr() {
+            *spsr = cpsr;
+        }
+        self.write(14, link);
     }
 
     #[allow(dead_code)]
     pub fn transfer_spsr(&mut self) { /* Transfer the status register */
-        self.cpsr = match self.cpsr.mode {
-            0 => self.cpsr,
-            1 => self.spsr_fiq,
-            2 => self.spsr_svc,
-            3 => self.spsr_abt,
-            4 => self.spsr_irq,
-            5 => self.spsr_und,
-            _ => panic!("Mode {} does not exist", self.cpsr.mode)
+        if let Some(spsr) = self.spsr() {
+            let spsr = *spsr;
+            self.cpsr = spsr;
         }
     }
 
+    /* Read the CPSR (a==0) or SPSR, reading a SPSR that doesn't exist gives the CPSR */
+    #[allow(dead_code)]
     pub fn read_psr(&mut self, a: u32) -> u32 {
-        return if a==0 {
-            self.cpsr
+        let cpsr = self.cpsr;
+        if a == 0 {
+            cpsr.into()
         } else {
-            match self.cpsr.mode {
-                0 => self.cpsr,
-                1 => self.spsr_fiq,
-                2 => self.spsr_svc,
-                3 => self.spsr_abt,
-                4 => self.spsr_irq,
-                5 => self.spsr_und,
-                _ => panic!("Mode {} does not exist", self.cpsr.mode)
-            }
-        }.into();
+            self.spsr().map_or(cpsr, |spsr| *spsr).into()
+        }
     }
 
+    /*
+    Write the CPSR (a==0) or SPSR, only the fields set in mask are changed (bit 0: control, 1: extension, 2: status, 3: flags).
+    User mode can only change the flags, the T bit can't be changed this way and a mode that doesn't exist is ignored.
+    */
     #[allow(dead_code)]
-    pub fn write_psr(&mut self, a: u32, data: u32) {
-        let data: Status = data.into();
-        if a==0 {
-            self.cpsr = data;
-        } else {
-            match self.cpsr.mode {
-                0 => self.cpsr = data,
-                1 => self.spsr_fiq = data,
-                2 => self.spsr_svc = data,
-                3 => self.spsr_abt = data,
-                4 => self.spsr_irq = data,
-                5 => self.spsr_und = data,
-                _ => panic!("Mode {} does not exist", self.cpsr.mode)
-            };
+    pub fn write_psr(&mut self, a: u32, mask: u32, data: u32) {
+        let mut bits: u32 = (0..4).filter(|field| (mask >> field) & 0b1 == 0b1).map(|field| 0xFF << (8*field)).sum();
+
+        if a == 0 {
+            if !self.privileged() {
+                bits &= 0xFF000000;
+            }
+            bits &= !(1 << 5);
+            if !matches!((data & 0x1F) as u8, MODE_USR | MODE_FIQ | MODE_IRQ | MODE_SVC | MODE_ABT | MODE_UND | MODE_SYS) {
+                bits &= !0x1F;
+            }
+            self.cpsr = ((u32::from(self.cpsr) & !bits) | (data & bits)).into();
+        } else if let Some(spsr) = self.spsr() {
+            *spsr = ((u32::from(*spsr) & !bits) | (data & bits)).into();
         }
     }
 }
@@ -276,6 +283,67 @@ impl fmt::Display for Reg {
     }
 }
 
+/*
+The state of the multiplier between cycles
+
+The ARM7TDMI has an 8x32 multiplier, each cycle it takes 8 bits of the multiplier (Rs) as four radix-4 booth digits and adds the
+matching multiples of the multiplicand (Rm) into a carry-save accumulator, stopping early once the rest of Rs is all 0s (or all 1s
+for a signed multiply). The accumulator starts with the value being accumulated, and the sum and carry words are only added together
+at the end.
+*/
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Booth {
+    pub multiplicand: u64,  //Rm, extended to 64 bits
+    pub multiplier: u64,    //Rs, extended to 64 bits
+    pub sum: u64,           //carry-save partial sum
+    pub carry: u64,         //carry-save partial carries
+    pub bits: u32,          //how many bits of the multiplier have been used
+    pub cycles: u32,        //how many cycles the multiply takes (m)
+}
+
+/*
+Which NZCV values pass each condition, bit n of an entry is set if the condition holds when NZCV == n.
+
+0xF (NV) is "never" on the ARM7TDMI, ARMv4 says not to use it and later architectures reuse the space for
+unconditional instructions, but this core never executes anything with it.
+*/
+pub const COND_TABLE: [u16; 16] = cond_table();
+
+const fn cond_table() -> [u16; 16] {
+    let mut table = [0u16; 16];
+    let mut nzcv = 0;
+    while nzcv < 16 {
+        let (n, z, c, v) = (nzcv & 0b1000 != 0, nzcv & 0b0100 != 0, nzcv & 0b0010 != 0, nzcv & 0b0001 != 0);
+        let pass = [
+            z,                  //EQ
+            !z,                 //NE
+            c,                  //CS
+            !c,                 //CC
+            n,                  //MI
+            !n,                 //PL
+            v,                  //VS
+            !v,                 //VC
+            c && !z,            //HI
+            !c || z,            //LS
+            n == v,             //GE
+            n != v,             //LT
+            !z && (n == v),     //GT
+            z || (n != v),      //LE
+            true,               //AL
+            false,              //NV
+        ];
+        let mut code = 0;
+        while code < 16 {
+            if pass[code] {
+                table[code] |= 1 << nzcv;
+            }
+            code += 1;
+        }
+        nzcv += 1;
+    }
+    table
+}
+
 /* The processor core */
 #[derive(Clone, Copy, Debug)]
 pub struct Core {
@@ -298,7 +366,9 @@ pub struct Core {
 
     pub barrelfunc: u8,
     pub shiftamnt: u32,
+    pub shiftreg: bool, //The shift amount came from a register rather than the instruction
     pub barrelbus: u32,
+    pub shiftcarry: bool, //Carry out of the barrel shifter
 
     pub databus: u32,
     pub datareg: u32,
@@ -306,8 +376,17 @@ pub struct Core {
 
     pub transferblock: [u32; 16],
     pub multicycle: u8,
+    pub booth: Booth,
 
     pub cycle: u8, //The current instruction cycle (is reset after each instruction)
+    pub waitstates: u32, //Extra cycles spent waiting on the bus during the current instruction
+
+    #[allow(dead_code)]
+    pub hle_bios: bool, //SWIs are run natively by bios.rs instead of going to the BIOS
+    #[allow(dead_code)]
+    pub halted: bool, //Waiting for an interrupt, see bios::halted
+    #[allow(dead_code)]
+    pub intr_wait: u16, //The interrupts IntrWait is waiting for, 0 to wake on any
 }
 
 /* Print-formatting so that I can print the contents of the structure */
@@ -357,8 +436,9 @@ impl fmt::Display for Core {
 
 /* The processor 'Core' itself */
 impl Core {
+    /* A core in the state reset leaves it in */
     pub fn new() -> Self {
-        Core {
+        let mut core = Core {
             reg: Default::default(), //We need some registers
 
             /* There are all internal busses used when executing instructions */
@@ -379,7 +459,9 @@ impl Core {
 
             barrelfunc: 0,
             shiftamnt: 0,
+            shiftreg: false,
             barrelbus: 0,
+            shiftcarry: false,
 
             databus: 0,
             datareg: 0,
@@ -387,9 +469,24 @@ impl Core {
 
             transferblock: [0; 16],
             multicycle: 0,
+            booth: Default::default(),
             
             cycle: 0,
-        }
+            waitstates: 0,
+
+            hle_bios: false,
+            halted: false,
+            intr_wait: 0,
+        };
+        core.reset();
+        core
+    }
+
+    /* The state the processor comes out of reset in: Supervisor mode, ARM state with interrupts off, about to run the reset vector */
+    #[allow(dead_code)]
+    pub fn reset(&mut self) {
+        self.reg = Default::default();
+        self.reg.cpsr = Status::from(0xC0 | MODE_SVC as u32);
     }
 
     #[allow(dead_code)]
@@ -402,37 +499,21 @@ impl Core {
         self.reg.cpsr.n = (result as i32) < 0;
     }
 
-    fn set_vc_add(&mut self, op1: u32, op2: u32) { // Set the half and full carry flags for an add operation
-        self.reg.cpsr.v = (op1 as i32).overflowing_add(op2 as i32).1;
-        self.reg.cpsr.c = op1.overflowing_add(op2).1;
+    /*
+    The adder all the arithmetic operations go through, returns the result, carry out and overflow.
+    Subtraction is done as op1 + !op2 + 1, so the carry out is NOT borrow like on the real thing.
+    */
+    pub fn add_with_carry(op1: u32, op2: u32, carry: bool) -> (u32, bool, bool) {
+        let sum = op1 as u64 + op2 as u64 + carry as u64;
+        let result = sum as u32;
+        let overflow = ((op1 ^ result) & (op2 ^ result)) >> 31 == 1;    //both operands have a different sign to the result
+        (result, sum > 0xFFFFFFFF, overflow)
     }
 
-    fn set_vc_sub(&mut self, op1: u32, op2: u32) { // As above but for a subtraction operation
-        self.reg.cpsr.v = (op1 as i32).overflowing_sub(op2 as i32).1;
-        self.reg.cpsr.c = op1.overflowing_sub(op2).1;
-    }
-
-    /* Instruction condition codes */
-    #[allow(dead_code)]
-    pub fn cond_codes(&mut self, code: u32) -> bool {
-        match code { //match condition code
-            0x0 =>  self.reg.cpsr.z == true,                                            //If the zero flag is set
-            0x1 =>  self.reg.cpsr.z == false,                                           //If the zero flag is cleared
-            0x2 =>  self.reg.cpsr.c == true,                                            //If the carry flag is set
-            0x3 =>  self.reg.cpsr.c == false,                                           //If the carry flag is cleared
-            0x4 =>  self.reg.cpsr.n == true,                                            //If the negative flag is set
-            0x5 =>  self.reg.cpsr.n == false,                                           //If the negative flag is cleared
-            0x6 =>  self.reg.cpsr.v == true,                                            //If the half-carry flag is set
-            0x7 =>  self.reg.cpsr.v == false,                                           //If the half-carry flag is cleared
-            0x8 =>  self.reg.cpsr.c == true && self.reg.cpsr.z == false,                //If carry is set and zero is cleared
-            0x9 =>  self.reg.cpsr.c == false && self.reg.cpsr.z == true,                //If carry is cleared and zero is set
-            0xA =>  self.reg.cpsr.n == self.reg.cpsr.v,                                 //If negative equals half-carry
-            0xB =>  self.reg.cpsr.n != self.reg.cpsr.v,                                 //If negative does not equal half-carry
-            0xC =>  self.reg.cpsr.z == false && (self.reg.cpsr.n == self.reg.cpsr.v),   //If zero is cleared and negative equals half-carry
-            0xD =>  self.reg.cpsr.z == true || (self.reg.cpsr.n != self.reg.cpsr.v),    //If zero is set or negative does not equal half-carry
-            0xE =>  true,                                                               //Always
-            _   =>  false                                                               //No more condition codes! (never)
-        } //return true if condition is met
+    /* Instruction condition codes, looked up by the condition and the current NZCV */
+    pub fn cond_codes(&self, code: u32) -> bool {
+        let nzcv = u32::from(self.reg.cpsr) >> 28;
+        (COND_TABLE[(code & 0xF) as usize] >> nzcv) & 0b1 == 0b1
     }
 
     #[allow(dead_code)]
@@ -440,39 +521,39 @@ impl Core {
         /* Shift instructions down in the pipeline */
         self.reg.pipeline[2] = self.reg.pipeline[1];
         self.reg.pipeline[1] = self.reg.pipeline[0];
-        self.reg.pipeline[0] = self.datareg;       //get new instruction from the data bus
+        self.reg.pipeline[0] = self.instbus;       //get new instruction from the instruction bus
     }
 
     pub fn alu(&mut self) { /* The alu fuctions */
-        let tmp = match self.aluop { /* match the alu operation */
-            0   =>  self.abus & self.barrelbus,                                                     //AND
-            1   =>  self.abus ^ self.barrelbus,                                                     //EOR
-            2   =>  self.abus.wrapping_sub(self.barrelbus),                                         //SUB
-            3   =>  self.barrelbus.wrapping_sub(self.abus),                                         //RSB
-            4   =>  self.abus.wrapping_add(self.barrelbus),                                         //ADD
-            5   =>  self.abus.wrapping_add(self.barrelbus.wrapping_add(self.reg.cpsr.c as u32)),    //ADC
-            6   =>  self.abus.wrapping_sub(self.barrelbus.wrapping_add(!self.reg.cpsr.c as u32)),   //SBC
-            7   =>  self.barrelbus.wrapping_sub(self.abus.wrapping_add(!self.reg.cpsr.c as u32)),   //RSC
-            8   =>  self.abus & self.barrelbus,                                                     //TST
-            9   =>  self.abus ^ self.barrelbus,                                                     //TEQ
-            10  =>  self.abus.wrapping_sub(self.barrelbus),                                         //CMP
-            11  =>  self.abus.wrapping_add(self.barrelbus),                                         //CMN
-            12  =>  self.abus | self.barrelbus,                                                     //ORR
-            13  =>  self.barrelbus,                                                                 //MOV
-            14  =>  self.abus & !self.barrelbus,                                                    //BIC
-            15  =>  !self.barrelbus,                                                                //MVN
+        let (a, b, c) = (self.abus, self.barrelbus, self.reg.cpsr.c);
+        /* Logical operations take carry from the barrel shifter and leave overflow alone */
+        let logical = |result: u32| (result, self.shiftcarry, self.reg.cpsr.v);
+
+        let (tmp, carry, overflow) = match self.aluop { /* match the alu operation */
+            0   =>  logical(a & b),                             //AND
+            1   =>  logical(a ^ b),                             //EOR
+            2   =>  Self::add_with_carry(a, !b, true),          //SUB
+            3   =>  Self::add_with_carry(b, !a, true),          //RSB
+            4   =>  Self::add_with_carry(a, b, false),          //ADD
+            5   =>  Self::add_with_carry(a, b, c),              //ADC
+            6   =>  Self::add_with_carry(a, !b, c),             //SBC
+            7   =>  Self::add_with_carry(b, !a, c),             //RSC
+            8   =>  logical(a & b),                             //TST
+            9   =>  logical(a ^ b),                             //TEQ
+            10  =>  Self::add_with_carry(a, !b, true),          //CMP
+            11  =>  Self::add_with_carry(a, b, false),          //CMN
+            12  =>  logical(a | b),                             //ORR
+            13  =>  logical(b),                                 //MOV
+            14  =>  logical(a & !b),                            //BIC
+            15  =>  logical(!b),                                //MVN
             _   =>  unreachable!()
         };
 
         /* Condition codes */
         if self.setcond {
-            match self.aluop {
-                0 | 1 | 8 | 9 | 12 | 13 | 14 | 15 => self.set_zn(tmp),                              //AND, EOR, TST, TEQ, ORR, MOV, BIC, MVN
-                4 | 5 | 11 => { self.set_zn(tmp); self.set_vc_add(self.abus, self.barrelbus); },    //ADD, ADC
-                3 | 7 => { self.set_zn(tmp); self.set_vc_sub(self.barrelbus, self.abus); },         //SUB, RSC
-                2 | 6 | 10 => {self.set_zn(tmp); self.set_vc_sub(self.abus, self.barrelbus); },     //EOR, SBC, CMP
-                _   =>  unreachable!()
-            }
+            self.set_zn(tmp);
+            self.reg.cpsr.c = carry;
+            self.reg.cpsr.v = overflow;
         }
         /* Register write-back */
         if self.aluop < 8 || self.aluop > 0xB {                                                     //not TST, TEQ, CMP, CMN
@@ -480,17 +561,80 @@ impl Core {
         }
     }
 
-    #[allow(dead_code)]
+    /* Load the multiplier, signed is for MUL, MLA, SMULL and SMLAL */
+    pub fn mul_setup(&mut self, rm: u32, rs: u32, accumulate: u64, signed: bool) {
+        let extend = |x: u32| if signed {x as i32 as i64 as u64} else {x as u64};
+        self.booth = Booth {
+            multiplicand: extend(rm),
+            multiplier: extend(rs),
+            sum: accumulate,
+            carry: 0,
+            bits: 0,
+            cycles: Core::mul_cycles(rs, signed),
+        };
+    }
+
+    /* The number of multiplier cycles (m), depending on how many of the top bytes of Rs can be skipped */
+    pub fn mul_cycles(rs: u32, signed: bool) -> u32 {
+        for (m, shift) in [(1, 8), (2, 16), (3, 24)] {
+            if rs >> shift == 0 || (signed && (rs as i32) >> shift == -1) {
+                return m
+            }
+        }
+        4
+    }
+
+    pub fn mul_done(&self) -> bool {
+        self.booth.bits >= 8*self.booth.cycles
+    }
+
+    /* A single cycle of the multiplier */
     pub fn mul(&mut self) {
-        /* The multiply instruction */
+        for _ in 0..4 {
+            let k = self.booth.bits;
+            let digit = ((self.booth.multiplier << 1) >> k) & 0b111; //bits k+1, k and k-1
+
+            /* The multiple of the multiplicand for this digit, negative ones are inverted with the +1 carried in to bit 0 */
+            let (addend, inject) = match digit {
+                0 | 7   => (0, 0),
+                1 | 2   => (self.booth.multiplicand << k, 0),
+                3       => (self.booth.multiplicand << (k+1), 0),
+                4       => (!(self.booth.multiplicand << (k+1)), 1),
+                5 | 6   => (!(self.booth.multiplicand << k), 1),
+                _       => unreachable!()
+            };
 
-        /*
-        This is not done because there is little to no information about how the multiply hardware actually works in the ARM7TDMI.
-        The only real information that is a available is that it is an 8x32 booth multiplier, and it takes some form of
-        data input from the a and b busses (of which both seem to be bidirectional)
+            /* Carry-save adder */
+            let sum = self.booth.sum ^ self.booth.carry ^ addend;
+            let carry = (self.booth.sum & self.booth.carry) | (self.booth.sum & addend) | (self.booth.carry & addend);
+            self.booth.sum = sum;
+            self.booth.carry = (carry << 1) | inject;
 
-        Even the carry output of this function is deemed 'undefined'. If only ARM actually documented their secrets.
-        */
+            self.booth.bits += 2;
+        }
+    }
+
+    /* The final addition, setting the flags if needed. The C flag is left with whatever the carry-save adder ended on */
+    pub fn mul_result(&mut self, long: bool) -> u64 {
+        /* Digits above where the multiplier stopped that aren't zero, only ever the bit below making the next digit -1 */
+        let used = self.booth.bits;
+        let mask = if used >= 64 {u64::MAX} else {(1 << used) - 1};
+        let low = self.booth.multiplier & mask;
+        let taken = if (low >> (used - 1)) & 1 == 1 {low | !mask} else {low};   //what the booth digits so far add up to
+        let remaining = self.booth.multiplier.wrapping_sub(taken);
+
+        let result = self.booth.sum
+            .wrapping_add(self.booth.carry)
+            .wrapping_add(remaining.wrapping_mul(self.booth.multiplicand));
+
+        if self.setcond {
+            let top = if long {63} else {31};
+            self.reg.cpsr.n = (result >> top) & 1 == 1;
+            self.reg.cpsr.z = if long {result == 0} else {result as u32 == 0};
+            self.reg.cpsr.c = (self.booth.carry >> top) & 1 == 1;
+        }
+
+        result
     }
 
     /* decode shift opcode */
@@ -498,38 +642,65 @@ impl Core {
         let shifttype: u8 = ((shift & 0x6) >> 1) as u8;
         self.barrelfunc = shifttype;
         if bitpat!( _ _ _ _ 0 _ _ 1 )(shift) {
-            self.shiftamnt = self.reg.read((shift >> 4) as usize) & 0x1F;
+            /* Only the bottom byte of Rs is used */
+            self.shiftamnt = self.reg.read((shift >> 4) as usize) & 0xFF;
+            self.shiftreg = true;
         } else if bitpat!( _ _ _ _ _ _ _ 0 )(shift) {
             self.shiftamnt = shift >> 3;
+            self.shiftreg = false;
         } else { panic!("shift mode does not exist") }
     }
 
-    /* Decode immediate shift opcode */
-    pub fn decode_shift_imm(&mut self, mut shift: u32) {
-        shift = (shift >> 8) & 0xF;
-        self.barrelfunc = 3;
-        self.shiftamnt = shift.rotate_right(2*shift);
+    /* Decode a rotated immediate, operand is the bottom 12 bits of the instruction */
+    pub fn decode_shift_imm(&mut self, operand: u32) {
+        self.shiftamnt = ((operand >> 8) & 0xF) * 2;
+        self.shiftreg = false;
+        /* No rotation is a plain LSL #0 rather than an RRX, so the carry is left alone */
+        self.barrelfunc = if self.shiftamnt == 0 {0} else {3};
     }
 
-    /* the barrel shifter */
+    /*
+    the barrel shifter
+
+    Immediate shifts by 0 are special encodings: LSL #0 is no shift, LSR #0 and ASR #0 mean #32 and ROR #0 is RRX.
+    Register shifts use the whole bottom byte of Rs, a shift by 0 leaves the value and carry alone and anything
+    from 32 upwards shifts everything out (ROR just wraps around).
+    */
     pub fn barrel_shift(&mut self) {
-        self.barrelbus = match self.barrelfunc { /* Barrel shifter function */
-            0   =>  self.bbus << self.shiftamnt,                                                                                                    //LSL 
-            1   =>  self.bbus >> self.shiftamnt,                                                                                                    //LSR 
-            2   =>  ((self.bbus as i32) >> self.shiftamnt) as u32,                                                                                  //ASR 
-            3   =>  {if self.shiftamnt == 0 {(self.bbus >> 1) | ((self.reg.cpsr.c as u32) << 31)} else {self.bbus.rotate_right(self.shiftamnt)}},   //ROR, RRX 
-            _   =>  unreachable!()
-        };
+        let value = self.bbus;
+        let carry = self.reg.cpsr.c;
+        let bit = |n: u32| (value >> n) & 0b1 == 0b1;
 
-        if self.setcond { /* set carry flag */
-            self.reg.cpsr.c = match self.barrelfunc { /* match function */
-                0   =>  (self.bbus >> 32-self.shiftamnt) & 0b1,
-                1   =>  (self.bbus >> self.shiftamnt-1) & 0b1,
-                2   =>  ((self.bbus as i32) >> (self.shiftamnt - 1)) as u32 & 0b1,
-                3   =>  if self.shiftamnt == 0 {self.bbus & 0b1} else {(self.bbus >> ((self.shiftamnt - 1) & 0x1F)) & 0b1}
+        let (result, carry) = if self.shiftreg && self.shiftamnt == 0 {
+            (value, carry)
+        } else {
+            match self.barrelfunc { /* Barrel shifter function */
+                0   =>  match self.shiftamnt {                                                          //LSL
+                    0       =>  (value, carry),
+                    1..=31  =>  (value << self.shiftamnt, bit(32 - self.shiftamnt)),
+                    32      =>  (0, bit(0)),
+                    _       =>  (0, false),
+                },
+                1   =>  match self.shiftamnt {                                                          //LSR
+                    1..=31  =>  (value >> self.shiftamnt, bit(self.shiftamnt - 1)),
+                    0 | 32  =>  (0, bit(31)),
+                    _       =>  (0, false),
+                },
+                2   =>  match self.shiftamnt {                                                          //ASR
+                    1..=31  =>  (((value as i32) >> self.shiftamnt) as u32, bit(self.shiftamnt - 1)),
+                    _       =>  (((value as i32) >> 31) as u32, bit(31)),
+                },
+                3   =>  match self.shiftamnt & 0x1F {                                                   //ROR, RRX
+                    0 if self.shiftamnt == 0    =>  ((value >> 1) | ((carry as u32) << 31), bit(0)),
+                    0                           =>  (value, bit(31)),
+                    amount                      =>  (value.rotate_right(amount), bit(amount - 1)),
+                },
                 _   =>  unreachable!()
-            } != 0;
-        }
+            }
+        };
+
+        self.barrelbus = result;
+        self.shiftcarry = carry;
     }
 
     /* Update the register bank */
@@ -549,9 +720,10 @@ impl Core {
         self.multicycle = 0;
         self.transferblock = [0; 16];
 
+        /* Lowest register first, that's the order they go to memory in */
         for x in 0..16 {
-            if (rlist>>(15-x)) & 0b1 == 0b1 {
-                self.transferblock[self.multicycle as usize] = (15-x) as u32;
+            if (rlist>>x) & 0b1 == 0b1 {
+                self.transferblock[self.multicycle as usize] = x as u32;
                 self.multicycle += 1;
             }
         }
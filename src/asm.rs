@@ -0,0 +1,950 @@
+use std::collections::HashMap;
+use std::fmt;
+
+use crate::decode;
+
+/*
+A small ARMv4T assembler, so that tests can be written as assembly instead of instruction words.
+
+It reads what the disassembler prints (in any of its formats) as well as the usual GNU spellings, so anything that was
+disassembled can be assembled back. It works in two passes, the first lays out where every label and literal goes and the
+second encodes everything now that they are all known.
+
+Directives are .arm, .thumb, .word, .hword, .byte, .align, .pool (or .ltorg) and .equ. The values loaded by `ldr rd,=value`
+go in the next pool, or after the end of the program if there isn't one. Comments start with ; @ or //.
+*/
+
+/* What went wrong and on which line, counting from 1 */
+#[allow(dead_code)]
+#[derive(Debug, PartialEq, Clone)]
+pub struct AsmError {
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for AsmError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+/* The assembled bytes, which start at origin, and the address of each label */
+#[allow(dead_code)]
+#[derive(Debug, Clone)]
+pub struct Program {
+    pub origin: u32,
+    pub bytes: Vec<u8>,
+    pub symbols: HashMap<String, u32>,
+}
+
+#[allow(dead_code)]
+impl Program {
+    pub fn words(&self) -> Vec<u32> {
+        self.bytes.chunks(4).map(|chunk| chunk.iter().rev().fold(0, |word, byte| (word << 8) | *byte as u32)).collect()
+    }
+
+    pub fn halfwords(&self) -> Vec<u16> {
+        self.bytes.chunks(2).map(|chunk| chunk.iter().rev().fold(0, |half, byte| (half << 8) | *byte as u16)).collect()
+    }
+
+    /* The labels the other way round, which is what DisasmFormat::symbols wants */
+    pub fn names(&self) -> HashMap<u32, String> {
+        self.symbols.iter().map(|(name, addr)| (*addr, name.clone())).collect()
+    }
+}
+
+#[allow(dead_code)]
+pub fn assemble(source: &str, origin: u32) -> Result<Program, AsmError> {
+    assemble_in(source, origin, false)
+}
+
+/* A single ARM instruction at address 0, panicking if it doesn't assemble. Meant for tests */
+#[allow(dead_code)]
+pub fn arm(source: &str) -> u32 {
+    assemble_in(source, 0, false).unwrap_or_else(|error| panic!("{} in {:?}", error, source)).words()[0]
+}
+
+/* The same for a Thumb instruction */
+#[allow(dead_code)]
+pub fn thumb(source: &str) -> u16 {
+    assemble_in(source, 0, true).unwrap_or_else(|error| panic!("{} in {:?}", error, source)).halfwords()[0]
+}
+
+enum Item {
+    Inst { mnemonic: String, operands: Vec<String>, literal: Option<u32> },
+    Data { size: u32, values: Vec<String> },
+    Space(u32),
+    Pool(Vec<String>),
+}
+
+struct Stmt {
+    line: usize,
+    addr: u32,
+    thumb: bool,
+    item: Item,
+}
+
+fn assemble_in(source: &str, origin: u32, thumb: bool) -> Result<Program, AsmError> {
+    let mut symbols = HashMap::new();
+    let mut stmts: Vec<Stmt> = Vec::new();
+    let mut pending: Vec<(String, Vec<usize>)> = Vec::new();    //literals waiting for a pool, and the loads that use them
+    let mut addr = origin;
+    let mut thumb = thumb;
+    let mut last = 0;
+
+    /* First pass, where everything goes */
+    for (number, text) in source.lines().enumerate() {
+        let line = number + 1;
+        let error = |message: String| AsmError { line, message };
+        let mut text = strip_comment(text).trim();
+        last = line;
+
+        while let Some((label, rest)) = text.split_once(':') {
+            let label = label.trim();
+            if !is_identifier(label) {
+                break
+            }
+            if symbols.insert(label.to_string(), addr).is_some() {
+                return Err(error(format!("{} is defined twice", label)))
+            }
+            text = rest.trim();
+        }
+        if text.is_empty() {
+            continue
+        }
+
+        let (head, rest) = text.split_once(char::is_whitespace).unwrap_or((text, ""));
+        let head = head.to_lowercase();
+        let operands = split_operands(rest);
+
+        match head.as_str() {
+            ".arm" | ".code32" => thumb = false,
+            ".thumb" | ".code16" => thumb = true,
+            ".equ" | ".set" => {
+                if operands.len() != 2 || !is_identifier(&operands[0]) {
+                    return Err(error(format!("{} needs a name and a value", head)))
+                }
+                let value = evaluate(&operands[1], &symbols).map_err(error)?;
+                symbols.insert(operands[0].clone(), value);
+            },
+            ".word" | ".hword" | ".half" | ".short" | ".byte" => {
+                let size = match head.as_str() {".word" => 4, ".byte" => 1, _ => 2};
+                addr = addr.wrapping_add(size * operands.len() as u32);
+                stmts.push(Stmt { line, addr: addr.wrapping_sub(size * operands.len() as u32), thumb, item: Item::Data { size, values: operands } });
+            },
+            ".align" => {
+                let power = if operands.is_empty() {2} else {evaluate(&operands[0], &symbols).map_err(error)?};
+                if power > 12 {
+                    return Err(error(format!("can't align to 2^{}", power)))
+                }
+                let pad = addr.wrapping_neg() & ((1 << power) - 1);
+                stmts.push(Stmt { line, addr, thumb, item: Item::Space(pad) });
+                addr = addr.wrapping_add(pad);
+            },
+            ".pool" | ".ltorg" => {
+                addr = flush_pool(&mut stmts, &mut pending, line, addr, thumb);
+            },
+            _ if head.starts_with('.') => {
+                return Err(error(format!("unknown directive {}", head)))
+            },
+            _ => {
+                /* A literal is only needed when the value can't be made with a MOV or MVN, which in Thumb is always */
+                let literal = head.starts_with("ldr") && operands.len() == 2 && operands[1].starts_with('=') && (thumb || {
+                    let value = evaluate(&operands[1][1..], &symbols).ok();
+                    value.and_then(|v| encode_imm(v).or(encode_imm(!v))).is_none()
+                });
+                if literal {
+                    let value = operands[1][1..].trim().to_string();
+                    match pending.iter_mut().find(|(pooled, _)| *pooled == value) {
+                        Some((_, users)) => users.push(stmts.len()),
+                        None => pending.push((value, vec![stmts.len()])),
+                    }
+                }
+
+                /* A BL in Thumb is two instructions, unless it's just the second half */
+                let pair = head == "bl" && !operands.first().is_some_and(|op| op.starts_with("lr+"));
+                let size = if thumb && !pair {2} else {4};
+                stmts.push(Stmt { line, addr, thumb, item: Item::Inst { mnemonic: head, operands, literal: None } });
+                addr = addr.wrapping_add(size);
+            },
+        }
+    }
+    flush_pool(&mut stmts, &mut pending, last, addr, thumb);
+
+    /* Second pass, encoding it all */
+    let mut bytes = Vec::new();
+    for stmt in &stmts {
+        let error = |message: String| AsmError { line: stmt.line, message };
+        match &stmt.item {
+            Item::Inst { mnemonic, operands, literal } => {
+                let encoder = Encoder { symbols: &symbols, pc: stmt.addr, literal: *literal };
+                if stmt.thumb {
+                    if stmt.addr & 0b1 != 0 {
+                        return Err(error("Thumb instructions have to be halfword aligned".to_string()))
+                    }
+                    for half in encoder.thumb(mnemonic, operands).map_err(error)? {
+                        bytes.extend_from_slice(&half.to_le_bytes());
+                    }
+                } else {
+                    if stmt.addr & 0b11 != 0 {
+                        return Err(error("ARM instructions have to be word aligned".to_string()))
+                    }
+                    bytes.extend_from_slice(&encoder.arm(mnemonic, operands).map_err(error)?.to_le_bytes());
+                }
+            },
+            Item::Data { size, values } => {
+                for value in values {
+                    let value = evaluate(value, &symbols).map_err(error)?;
+                    let bits = size * 8;
+                    /* Either signed or unsigned has to fit */
+                    if bits < 32 && value >> bits != 0 && (value as i32) < -(1 << (bits - 1)) {
+                        return Err(error(format!("0x{:X} doesn't fit in {} bytes", value, size)))
+                    }
+                    bytes.extend_from_slice(&value.to_le_bytes()[..*size as usize]);
+                }
+            },
+            Item::Space(pad) => bytes.resize(bytes.len() + *pad as usize, 0),
+            Item::Pool(values) => {
+                for value in values {
+                    bytes.extend_from_slice(&evaluate(value, &symbols).map_err(error)?.to_le_bytes());
+                }
+            },
+        }
+    }
+
+    Ok(Program { origin, bytes, symbols })
+}
+
+/* Put the waiting literals down at the next word, and tell the loads where they ended up */
+fn flush_pool(stmts: &mut Vec<Stmt>, pending: &mut Vec<(String, Vec<usize>)>, line: usize, addr: u32, thumb: bool) -> u32 {
+    if pending.is_empty() {
+        return addr
+    }
+    let pad = addr.wrapping_neg() & 0b11;
+    stmts.push(Stmt { line, addr, thumb, item: Item::Space(pad) });
+    let start = addr.wrapping_add(pad);
+
+    for (i, (_, users)) in pending.iter().enumerate() {
+        for user in users {
+            if let Item::Inst { literal, .. } = &mut stmts[*user].item {
+                *literal = Some(start + 4 * i as u32);
+            }
+        }
+    }
+    let values: Vec<String> = pending.drain(..).map(|(value, _)| value).collect();
+    let end = start.wrapping_add(4 * values.len() as u32);
+    stmts.push(Stmt { line, addr: start, thumb, item: Item::Pool(values) });
+    end
+}
+
+fn strip_comment(text: &str) -> &str {
+    let end = [text.find(';'), text.find('@'), text.find("//")].into_iter().flatten().min().unwrap_or(text.len());
+    &text[..end]
+}
+
+fn is_identifier(text: &str) -> bool {
+    text.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_' || c == '.') && text.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.')
+}
+
+/* Split on the commas that aren't inside [] or {} */
+fn split_operands(text: &str) -> Vec<String> {
+    let mut operands = Vec::new();
+    let mut current = String::new();
+    let mut depth = 0;
+
+    for c in text.chars() {
+        match c {
+            '[' | '{' => depth += 1,
+            ']' | '}' => depth -= 1,
+            ',' if depth == 0 => {
+                operands.push(current.trim().to_string());
+                current.clear();
+                continue
+            },
+            _ => {},
+        }
+        current.push(c);
+    }
+    if !current.trim().is_empty() {
+        operands.push(current.trim().to_string());
+    }
+    operands
+}
+
+/* Hex as 0x1A or 1Ah, binary as 0b101, otherwise decimal */
+fn number(text: &str) -> Option<u32> {
+    let text = text.to_lowercase();
+    if text.starts_with(|c: char| c.is_ascii_digit()) && text.ends_with('h') {
+        u32::from_str_radix(&text[..text.len() - 1], 16).ok()
+    } else if let Some(hex) = text.strip_prefix("0x") {
+        u32::from_str_radix(hex, 16).ok()
+    } else if let Some(binary) = text.strip_prefix("0b") {
+        u32::from_str_radix(binary, 2).ok()
+    } else {
+        text.parse().ok()
+    }
+}
+
+/* Numbers and labels added and subtracted, with an optional # in front */
+fn evaluate(text: &str, symbols: &HashMap<String, u32>) -> Result<u32, String> {
+    let text = text.trim();
+    let mut rest = text.strip_prefix('#').unwrap_or(text).trim();
+    let mut negative = false;
+    let mut total: u32 = 0;
+
+    if let Some(stripped) = rest.strip_prefix('-') {
+        negative = true;
+        rest = stripped;
+    } else if let Some(stripped) = rest.strip_prefix('+') {
+        rest = stripped;
+    }
+
+    loop {
+        let end = rest.find(['+', '-']).unwrap_or(rest.len());
+        let term = rest[..end].trim();
+        if term.is_empty() {
+            return Err(format!("missing value in {:?}", text))
+        }
+        let value = number(term).or_else(|| symbols.get(term).copied()).ok_or_else(|| format!("unknown symbol {}", term))?;
+        total = if negative {total.wrapping_sub(value)} else {total.wrapping_add(value)};
+
+        if end == rest.len() {
+            return Ok(total)
+        }
+        negative = rest[end..].starts_with('-');
+        rest = &rest[end + 1..];
+    }
+}
+
+fn register(text: &str) -> Result<u32, String> {
+    let name = text.trim().to_lowercase();
+    match name.as_str() {
+        "sp" => Ok(13),
+        "lr" => Ok(14),
+        "pc" => Ok(15),
+        "ip" => Ok(12),
+        "fp" => Ok(11),
+        _ => name.strip_prefix('r').and_then(|n| n.parse().ok()).filter(|r| *r < 16).ok_or_else(|| format!("{} isn't a register", text.trim())),
+    }
+}
+
+fn is_register(text: &str) -> bool {
+    register(text).is_ok()
+}
+
+fn low_register(text: &str) -> Result<u32, String> {
+    register(text).and_then(|r| if r < 8 {Ok(r)} else {Err(format!("{} isn't one of r0-r7", text.trim()))})
+}
+
+/* {r0,r2-r4,lr} */
+fn register_list(text: &str) -> Result<u32, String> {
+    let inner = text.trim().strip_prefix('{').and_then(|t| t.strip_suffix('}')).ok_or_else(|| format!("{} isn't a register list", text))?;
+    let mut list = 0;
+    for item in inner.split(',').map(str::trim).filter(|item| !item.is_empty()) {
+        match item.split_once('-') {
+            Some((first, last)) => {
+                for r in register(first)?..=register(last)? {
+                    list |= 1 << r;
+                }
+            },
+            None => list |= 1 << register(item)?,
+        }
+    }
+    Ok(list)
+}
+
+/* The 8 bit value and even rotation that make up an ARM immediate, if there is one */
+fn encode_imm(value: u32) -> Option<u32> {
+    (0..16).find(|rotate| value.rotate_left(rotate * 2) <= 0xFF).map(|rotate| (rotate << 8) | value.rotate_left(rotate * 2))
+}
+
+const CONDS: [(&str, u32); 18] = [
+    ("eq", 0x0), ("ne", 0x1), ("cs", 0x2), ("hs", 0x2), ("cc", 0x3), ("lo", 0x3), ("mi", 0x4), ("pl", 0x5), ("vs", 0x6),
+    ("vc", 0x7), ("hi", 0x8), ("ls", 0x9), ("ge", 0xA), ("lt", 0xB), ("gt", 0xC), ("le", 0xD), ("al", 0xE), ("nv", 0xF),
+];
+
+const DATAOPS: [&str; 16] = ["and", "eor", "sub", "rsb", "add", "adc", "sbc", "rsc", "tst", "teq", "cmp", "cmn", "orr", "mov", "bic", "mvn"];
+
+const S: &[&str] = &["", "s"];
+const NONE: &[&str] = &[""];
+const MODES: &[&str] = &["", "ia", "ib", "da", "db", "fd", "ed", "fa", "ea"];
+
+/* Each mnemonic and the suffixes it can have, apart from the condition */
+const ARM: &[(&str, &[&str])] = &[
+    ("and", S), ("eor", S), ("sub", S), ("rsb", S), ("add", S), ("adc", S), ("sbc", S), ("rsc", S),
+    ("tst", S), ("teq", S), ("cmp", S), ("cmn", S), ("orr", S), ("mov", S), ("bic", S), ("mvn", S),
+    ("mul", S), ("mla", S), ("umull", S), ("umlal", S), ("smull", S), ("smlal", S),
+    ("ldr", &["", "b", "t", "bt", "h", "sb", "sh"]), ("str", &["", "b", "t", "bt", "h", "sb", "sh"]),
+    ("ldm", MODES), ("stm", MODES), ("push", NONE), ("pop", NONE), ("swp", &["", "b"]),
+    ("b", NONE), ("bl", NONE), ("bx", NONE), ("swi", NONE), ("mrs", NONE), ("msr", NONE),
+];
+
+const THUMB: &[(&str, &[&str])] = &[
+    ("lsl", S), ("lsr", S), ("asr", S), ("ror", S), ("add", S), ("sub", S), ("mov", S), ("cmp", NONE),
+    ("and", S), ("eor", S), ("adc", S), ("sbc", S), ("tst", NONE), ("neg", S), ("cmn", NONE), ("orr", S),
+    ("mul", S), ("bic", S), ("mvn", S),
+    ("ldr", &["", "b", "h", "sb", "sh"]), ("str", &["", "b", "h"]), ("ldsb", NONE), ("ldsh", NONE),
+    ("ldm", &["", "ia"]), ("stm", &["", "ia"]), ("push", NONE), ("pop", NONE),
+    ("b", NONE), ("bl", NONE), ("bx", NONE), ("swi", NONE),
+];
+
+/* Split a mnemonic into its base, suffix and condition, taking the condition either before (pre-UAL) or after (UAL) the suffix */
+fn mnemonic(text: &str, table: &[(&'static str, &'static [&'static str])]) -> Result<(&'static str, &'static str, u32), String> {
+    let cond = |text: &str| if text.is_empty() {Some(0xE)} else {CONDS.iter().find(|(name, _)| *name == text).map(|(_, cond)| *cond)};
+
+    let mut bases: Vec<_> = table.iter().filter(|(base, _)| text.starts_with(base)).collect();
+    bases.sort_by_key(|(base, _)| std::cmp::Reverse(base.len()));
+
+    for &(base, suffixes) in bases {
+        let rest = &text[base.len()..];
+        for &suffix in suffixes {
+            if let Some(cond) = rest.strip_prefix(suffix).and_then(cond).or_else(|| rest.strip_suffix(suffix).and_then(cond)) {
+                return Ok((base, suffix, cond))
+            }
+        }
+    }
+    Err(format!("unknown instruction {}", text))
+}
+
+fn swi_number(text: &str) -> Option<u32> {
+    (0..0x100).find(|number| decode::swi_name(*number).is_some_and(|name| name.eq_ignore_ascii_case(text.trim())))
+}
+
+/* The offset part of an address */
+enum Offset {
+    Imm(u32),
+    Reg(u32, Option<String>),
+}
+
+/* An address operand, either [rn,offset] in some form or just where to load from */
+enum Address {
+    Absolute(u32),
+    Indexed { pre: bool, up: bool, writeback: bool, rn: u32, offset: Offset },
+}
+
+struct Encoder<'a> {
+    symbols: &'a HashMap<String, u32>,
+    pc: u32,
+    literal: Option<u32>,
+}
+
+impl Encoder<'_> {
+    fn eval(&self, text: &str) -> Result<u32, String> {
+        evaluate(text, self.symbols)
+    }
+
+    fn operands(&self, operands: &[String], count: usize) -> Result<(), String> {
+        if operands.len() == count {Ok(())} else {Err(format!("expected {} operands, not {}", count, operands.len()))}
+    }
+
+    /* Bits 11-4 of a register operand */
+    fn shift(&self, shift: Option<&String>, by_register: bool) -> Result<u32, String> {
+        let Some(shift) = shift else {
+            return Ok(0)
+        };
+        let shift = shift.trim().to_lowercase();
+        if shift == "rrx" {
+            return Ok(3 << 5)
+        }
+
+        let (name, amount) = shift.split_once(char::is_whitespace).ok_or_else(|| format!("{} isn't a shift", shift))?;
+        let kind = match name {
+            "lsl" | "asl" => 0,
+            "lsr" => 1,
+            "asr" => 2,
+            "ror" => 3,
+            _ => return Err(format!("{} isn't a shift", name)),
+        };
+
+        if is_register(amount) {
+            if !by_register {
+                return Err("can't shift by a register here".to_string())
+            }
+            return Ok((register(amount)? << 8) | (kind << 5) | (1 << 4))
+        }
+        match (kind, self.eval(amount)?) {
+            (0, amount @ 0..=31) | (3, amount @ 1..=31) => Ok((amount << 7) | (kind << 5)),
+            (1 | 2, amount @ 1..=32) => Ok(((amount & 0x1F) << 7) | (kind << 5)),
+            (_, amount) => Err(format!("can't {} by {}", name, amount)),
+        }
+    }
+
+    /* The second operand of a data processing instruction, an immediate or a shifted register */
+    fn operand2(&self, operands: &[String]) -> Result<u32, String> {
+        match operands {
+            [imm] if !is_register(imm) => {
+                let value = self.eval(imm)?;
+                encode_imm(value).map(|imm| imm | (1 << 25)).ok_or_else(|| format!("0x{:X} can't be made with an 8 bit rotated immediate", value))
+            },
+            [rm] => register(rm),
+            [rm, shift] => Ok(register(rm)? | self.shift(Some(shift), true)?),
+            _ => Err("expected a register or an immediate".to_string()),
+        }
+    }
+
+    fn address(&self, operands: &[String]) -> Result<Address, String> {
+        let first = operands.first().ok_or("missing address")?.trim();
+        if !first.starts_with('[') {
+            return Ok(Address::Absolute(self.eval(first)?))
+        }
+
+        let close = first.find(']').ok_or("missing ]")?;
+        let parts = split_operands(&first[1..close]);
+        let writeback = match first[close + 1..].trim() {
+            "" => false,
+            "!" => true,
+            other => return Err(format!("unexpected {} after the address", other)),
+        };
+
+        match parts.first() {
+            Some(base) if is_register(base) => {},
+            Some(addr) if parts.len() == 1 => return Ok(Address::Absolute(self.eval(addr)?)),
+            _ => return Err(format!("{} isn't an address", first)),
+        }
+        let rn = register(&parts[0])?;
+
+        /* Anything after the brackets is a post-indexed offset */
+        let (pre, offset) = if operands.len() > 1 {
+            if writeback || parts.len() > 1 {
+                return Err("post-indexed addresses have their offset after the brackets".to_string())
+            }
+            (false, &operands[1..])
+        } else {
+            (true, &parts[1..])
+        };
+
+        let (up, offset) = match offset {
+            [] => (true, Offset::Imm(0)),
+            [offset, rest @ ..] => {
+                let offset = offset.strip_prefix('#').unwrap_or(offset).trim();
+                let (up, offset) = match offset.strip_prefix('-') {
+                    Some(offset) => (false, offset),
+                    None => (true, offset.strip_prefix('+').unwrap_or(offset)),
+                };
+                if is_register(offset) {
+                    (up, Offset::Reg(register(offset)?, rest.first().cloned()))
+                } else if rest.is_empty() {
+                    (up, Offset::Imm(self.eval(offset)?))
+                } else {
+                    return Err("only a register offset can be shifted".to_string())
+                }
+            },
+        };
+
+        Ok(Address::Indexed { pre, up, writeback, rn, offset })
+    }
+
+    /* How far an absolute address is from the PC, as an up bit and size */
+    fn relative(&self, target: u32, pc: u32) -> (bool, u32) {
+        let distance = target.wrapping_sub(pc) as i32;
+        (distance >= 0, distance.unsigned_abs())
+    }
+
+    fn arm(&self, text: &str, operands: &[String]) -> Result<u32, String> {
+        let (base, suffix, cond) = mnemonic(text, ARM)?;
+        let cond = cond << 28;
+        let s = if suffix == "s" {1 << 20} else {0};
+
+        if let Some(opcode) = DATAOPS.iter().position(|op| *op == base) {
+            let opcode = opcode as u32;
+            return match opcode {
+                0x8..=0xB => {
+                    Ok(cond | (opcode << 21) | (1 << 20) | (register(operands.first().ok_or("missing register")?)? << 16) | self.operand2(&operands[1..])?)
+                },
+                0xD | 0xF => {
+                    Ok(cond | (opcode << 21) | s | (register(operands.first().ok_or("missing register")?)? << 12) | self.operand2(&operands[1..])?)
+                },
+                /* add rd,=address is an ADD or SUB from the PC */
+                0x2 | 0x4 if operands.len() == 2 && operands[1].starts_with('=') => {
+                    let distance = self.eval(&operands[1][1..])?.wrapping_sub(self.pc.wrapping_add(8));
+                    /* Some distances can be reached either way, then it's whichever was written */
+                    let (add, sub) = (encode_imm(distance).map(|imm| (0x4, imm)), encode_imm(distance.wrapping_neg()).map(|imm| (0x2, imm)));
+                    let (opcode, imm) = if opcode == 0x4 {add.or(sub)} else {sub.or(add)}
+                        .ok_or_else(|| format!("{} is too far to reach with an immediate", operands[1]))?;
+                    Ok(cond | (1 << 25) | (opcode << 21) | s | (15 << 16) | (register(&operands[0])? << 12) | imm)
+                },
+                _ => {
+                    if operands.len() < 3 {
+                        return Err("expected a destination, a register and an operand".to_string())
+                    }
+                    Ok(cond | (opcode << 21) | s | (register(&operands[1])? << 16) | (register(&operands[0])? << 12) | self.operand2(&operands[2..])?)
+                },
+            }
+        }
+
+        match base {
+            "mul" | "mla" => {
+                let regs = operands.iter().map(|op| register(op)).collect::<Result<Vec<_>, _>>()?;
+                match (base, regs.as_slice()) {
+                    ("mul", [rd, rm, rs]) => Ok(cond | s | (rd << 16) | (rs << 8) | 0x90 | rm),
+                    ("mla", [rd, rm, rs, rn]) => Ok(cond | (1 << 21) | s | (rd << 16) | (rn << 12) | (rs << 8) | 0x90 | rm),
+                    _ => Err(format!("wrong number of registers for {}", base)),
+                }
+            },
+            "umull" | "umlal" | "smull" | "smlal" => {
+                self.operands(operands, 4)?;
+                let signed = if base.starts_with('s') {1 << 22} else {0};
+                let accumulate = if base.ends_with("lal") {1 << 21} else {0};
+                let (rdlo, rdhi, rm, rs) = (register(&operands[0])?, register(&operands[1])?, register(&operands[2])?, register(&operands[3])?);
+                Ok(cond | 0x00800090 | signed | accumulate | s | (rdhi << 16) | (rdlo << 12) | (rs << 8) | rm)
+            },
+            "swp" => {
+                self.operands(operands, 3)?;
+                let rn = operands[2].strip_prefix('[').and_then(|op| op.strip_suffix(']')).ok_or("the address of a swap is [rn]")?;
+                let b = if suffix == "b" {1 << 22} else {0};
+                Ok(cond | 0x01000090 | b | (register(rn)? << 16) | (register(&operands[0])? << 12) | register(&operands[1])?)
+            },
+            "bx" => {
+                self.operands(operands, 1)?;
+                Ok(cond | 0x012FFF10 | register(&operands[0])?)
+            },
+            "b" | "bl" => {
+                self.operands(operands, 1)?;
+                let offset = self.eval(&operands[0])?.wrapping_sub(self.pc.wrapping_add(8)) as i32;
+                if offset & 0b11 != 0 || !(-0x2000000..0x2000000).contains(&offset) {
+                    return Err(format!("can't branch to {}", operands[0]))
+                }
+                let link = if base == "bl" {1 << 24} else {0};
+                Ok(cond | 0x0A000000 | link | ((offset >> 2) as u32 & 0xFFFFFF))
+            },
+            "swi" => {
+                self.operands(operands, 1)?;
+                /* The BIOS takes the function from bits 16-23 */
+                let comment = match swi_number(&operands[0]) {
+                    Some(number) => number << 16,
+                    None => self.eval(&operands[0])?,
+                };
+                if comment > 0xFFFFFF {
+                    return Err(format!("0x{:X} doesn't fit in a SWI", comment))
+                }
+                Ok(cond | 0x0F000000 | comment)
+            },
+            "mrs" => {
+                self.operands(operands, 2)?;
+                let spsr = match operands[1].to_lowercase().as_str() {
+                    "cpsr" => 0,
+                    "spsr" => 1 << 22,
+                    other => return Err(format!("{} isn't cpsr or spsr", other)),
+                };
+                Ok(cond | 0x010F0000 | spsr | (register(&operands[0])? << 12))
+            },
+            "msr" => {
+                self.operands(operands, 2)?;
+                let psr = operands[0].to_lowercase();
+                let (name, fields) = psr.split_once('_').unwrap_or((&psr, "fc"));
+                let spsr = match name {
+                    "cpsr" => 0,
+                    "spsr" => 1 << 22,
+                    other => return Err(format!("{} isn't cpsr or spsr", other)),
+                };
+                let mask = match fields {
+                    "all" => 0b1001,
+                    "flg" => 0b1000,
+                    "ctl" => 0b0001,
+                    _ => fields.chars().try_fold(0, |mask, field| match field {
+                        'f' => Ok(mask | 0b1000),
+                        's' => Ok(mask | 0b0100),
+                        'x' => Ok(mask | 0b0010),
+                        'c' => Ok(mask | 0b0001),
+                        _ => Err(format!("{} isn't a PSR field", field)),
+                    })?,
+                };
+                Ok(cond | 0x0120F000 | spsr | (mask << 16) | self.operand2(&operands[1..])?)
+            },
+            "ldr" | "str" => self.arm_transfer(base == "ldr", suffix, cond, operands),
+            "ldm" | "stm" | "push" | "pop" => {
+                let load = base == "ldm" || base == "pop";
+                let (rn, list) = match base {
+                    "push" | "pop" => ("sp!", operands.first().ok_or("missing register list")?),
+                    _ => {
+                        self.operands(operands, 2)?;
+                        (operands[0].as_str(), &operands[1])
+                    },
+                };
+                let mode = match base {
+                    "push" | "pop" => "fd",
+                    _ if suffix.is_empty() => "ia",
+                    _ => suffix,
+                };
+                let (p, u) = match (load, mode) {
+                    (_, "ia") | (true, "fd") | (false, "ea") => (0, 1),
+                    (_, "ib") | (true, "ed") | (false, "fa") => (1, 1),
+                    (_, "da") | (true, "fa") | (false, "ed") => (0, 0),
+                    _ => (1, 0),
+                };
+                let (rn, writeback) = match rn.trim().strip_suffix('!') {
+                    Some(rn) => (register(rn)?, 1),
+                    None => (register(rn)?, 0),
+                };
+                let (list, user) = match list.trim().strip_suffix('^') {
+                    Some(list) => (register_list(list)?, 1),
+                    None => (register_list(list)?, 0),
+                };
+                Ok(cond | 0x08000000 | (p << 24) | (u << 23) | (user << 22) | (writeback << 21) | ((load as u32) << 20) | (rn << 16) | list)
+            },
+            _ => Err(format!("unknown instruction {}", text)),
+        }
+    }
+
+    fn arm_transfer(&self, load: bool, suffix: &str, cond: u32, operands: &[String]) -> Result<u32, String> {
+        let rd = register(operands.first().ok_or("missing register")?)?;
+        let l = if load {1 << 20} else {0};
+
+        /* ldr rd,=value uses a MOV or MVN when it can, and the literal pool when it can't */
+        let address = match operands.get(1) {
+            Some(value) if value.starts_with('=') => {
+                if !load || !suffix.is_empty() {
+                    return Err("only ldr can load a literal".to_string())
+                }
+                match self.literal {
+                    Some(literal) => Address::Absolute(literal),
+                    None => {
+                        let value = self.eval(&value[1..])?;
+                        return match (encode_imm(value), encode_imm(!value)) {
+                            (Some(imm), _) => Ok(cond | 0x03A00000 | (rd << 12) | imm),
+                            (_, Some(imm)) => Ok(cond | 0x03E00000 | (rd << 12) | imm),
+                            _ => Err("the literal was never put in a pool".to_string()),
+                        }
+                    },
+                }
+            },
+            _ => self.address(&operands[1..])?,
+        };
+        let (pre, up, writeback, rn, offset) = match address {
+            Address::Absolute(target) => {
+                let (up, distance) = self.relative(target, self.pc.wrapping_add(8));
+                (true, up, false, 15, Offset::Imm(distance))
+            },
+            Address::Indexed { pre, up, writeback, rn, offset } => (pre, up, writeback, rn, offset),
+        };
+        let indexing = ((pre as u32) << 24) | ((up as u32) << 23) | ((writeback as u32) << 21) | l | (rn << 16) | (rd << 12);
+
+        match suffix {
+            "" | "b" | "t" | "bt" => {
+                let b = if suffix.starts_with('b') {1 << 22} else {0};
+                /* The user mode transfers are post-indexed with the writeback bit set */
+                let t = if suffix.ends_with('t') {
+                    if pre {
+                        return Err("ldrt and strt have to be post-indexed".to_string())
+                    }
+                    1 << 21
+                } else {
+                    0
+                };
+                let offset = match offset {
+                    Offset::Imm(imm) if imm < 0x1000 => imm,
+                    Offset::Imm(imm) => return Err(format!("offset 0x{:X} is too big", imm)),
+                    Offset::Reg(rm, shift) => (1 << 25) | rm | self.shift(shift.as_ref(), false)?,
+                };
+                Ok(cond | 0x04000000 | indexing | b | t | offset)
+            },
+            _ => {
+                let sh = match suffix {"h" => 0b01, "sb" => 0b10, _ => 0b11};
+                let offset = match offset {
+                    Offset::Imm(imm) if imm < 0x100 => (1 << 22) | ((imm & 0xF0) << 4) | (imm & 0xF),
+                    Offset::Imm(imm) => return Err(format!("offset 0x{:X} is too big", imm)),
+                    Offset::Reg(rm, None) => rm,
+                    Offset::Reg(_, Some(_)) => return Err("halfword transfers can't shift their offset".to_string()),
+                };
+                Ok(cond | indexing | 0x90 | (sh << 5) | offset)
+            },
+        }
+    }
+
+    fn thumb(&self, text: &str, operands: &[String]) -> Result<Vec<u16>, String> {
+        let (base, suffix, cond) = mnemonic(text, THUMB)?;
+        if cond != 0xE && base != "b" {
+            return Err("only branches can be conditional in Thumb".to_string())
+        }
+        let reg = |i: usize| operands.get(i).ok_or_else(|| "missing register".to_string()).and_then(|op| register(op));
+        let low = |i: usize| operands.get(i).ok_or_else(|| "missing register".to_string()).and_then(|op| low_register(op));
+        let imm = |i: usize, max: u32, scale: u32| -> Result<u32, String> {
+            let value = self.eval(operands.get(i).ok_or("missing value")?)?;
+            if value % scale != 0 || value > max {
+                return Err(format!("0x{:X} is out of range", value))
+            }
+            Ok(value / scale)
+        };
+        /* Hi register operations, where either register can be r8-r15 */
+        let hi = |op: u32| -> Result<Vec<u16>, String> {
+            let (rd, rs) = (reg(0)?, reg(1)?);
+            Ok(vec![(0x4400 | (op << 8) | ((rd & 0x8) << 4) | (rs << 3) | (rd & 0x7)) as u16])
+        };
+        const ALUOPS: [&str; 16] = ["and", "eor", "lsl", "lsr", "asr", "adc", "sbc", "ror", "tst", "neg", "cmp", "cmn", "orr", "mul", "bic", "mvn"];
+
+        let inst = match (base, operands.len()) {
+            ("lsl" | "lsr" | "asr", 3) => {
+                let op = ["lsl", "lsr", "asr"].iter().position(|name| *name == base).unwrap() as u32;
+                let amount = match (op, self.eval(&operands[2])?) {
+                    (0, amount @ 0..=31) => amount,
+                    (_, amount @ 1..=32) if op != 0 => amount & 0x1F,
+                    (_, amount) => return Err(format!("can't {} by {}", base, amount)),
+                };
+                (op << 11) | (amount << 6) | (low(1)? << 3) | low(0)?
+            },
+            ("add" | "sub", 3) => {
+                let sub = (base == "sub") as u32;
+                match operands[1].to_lowercase().as_str() {
+                    "sp" if base == "add" => 0xA800 | (low(0)? << 8) | imm(2, 1020, 4)?,
+                    "pc" if base == "add" => 0xA000 | (low(0)? << 8) | imm(2, 1020, 4)?,
+                    _ if is_register(&operands[2]) => 0x1800 | (sub << 9) | (low(2)? << 6) | (low(1)? << 3) | low(0)?,
+                    _ => 0x1C00 | (sub << 9) | (imm(2, 7, 1)? << 6) | (low(1)? << 3) | low(0)?,
+                }
+            },
+            ("add" | "sub", 2) => {
+                let sub = (base == "sub") as u32;
+                if operands[0].eq_ignore_ascii_case("sp") && !is_register(&operands[1]) {
+                    0xB000 | (sub << 7) | imm(1, 508, 4)?
+                } else if let (Some(target), false) = (operands[1].strip_prefix('='), sub == 1) {
+                    let target = self.eval(target)?;
+                    if reg(0)? == 14 {
+                        /* The first half of a BL on its own */
+                        let offset = target.wrapping_sub(self.pc.wrapping_add(4)) as i32;
+                        if offset & 0xFFF != 0 || !(-0x400000..0x400000).contains(&offset) {
+                            return Err(format!("can't reach {} from the first half of a BL", operands[1]))
+                        }
+                        0xF000 | ((offset >> 12) as u32 & 0x7FF)
+                    } else {
+                        let distance = target.wrapping_sub(self.pc.wrapping_add(4) & !0b11);
+                        if distance & 0b11 != 0 || distance > 1020 {
+                            return Err(format!("can't reach {} from here", operands[1]))
+                        }
+                        0xA000 | (low(0)? << 8) | (distance >> 2)
+                    }
+                } else if is_register(&operands[1]) {
+                    match (base, suffix) {
+                        ("add", "") => return hi(0),
+                        _ => 0x1800 | (sub << 9) | (low(1)? << 6) | (low(0)? << 3) | low(0)?,
+                    }
+                } else {
+                    0x3000 | (sub << 11) | (low(0)? << 8) | imm(1, 0xFF, 1)?
+                }
+            },
+            ("mov", 2) if is_register(&operands[1]) => {
+                /* movs between low registers is a shift by 0, mov is the hi register one */
+                match suffix {
+                    "s" => (low(1)? << 3) | low(0)?,
+                    _ => return hi(2),
+                }
+            },
+            ("mov", 2) => 0x2000 | (low(0)? << 8) | imm(1, 0xFF, 1)?,
+            ("cmp", 2) if !is_register(&operands[1]) => 0x2800 | (low(0)? << 8) | imm(1, 0xFF, 1)?,
+            ("cmp", 2) if reg(0)? > 7 || reg(1)? > 7 => return hi(1),
+            ("bx", 1) => 0x4700 | (reg(0)? << 3),
+            (_, 2) if ALUOPS.contains(&base) => {
+                let op = ALUOPS.iter().position(|name| *name == base).unwrap() as u32;
+                0x4000 | (op << 6) | (low(1)? << 3) | low(0)?
+            },
+            ("ldr" | "str" | "ldsb" | "ldsh", 2) => return self.thumb_transfer(base, suffix, operands).map(|inst| vec![inst]),
+            ("push" | "pop", 1) => {
+                let list = register_list(&operands[0])?;
+                let (inst, extra) = if base == "push" {(0xB400, 14)} else {(0xBC00, 15)};
+                if list & !(0xFF | (1 << extra)) != 0 {
+                    return Err(format!("{} can only use r0-r7 and {}", base, if extra == 14 {"lr"} else {"pc"}))
+                }
+                inst | (((list >> extra) & 0b1) << 8) | (list & 0xFF)
+            },
+            ("ldm" | "stm", 2) => {
+                let rb = operands[0].trim();
+                let rb = low_register(rb.strip_suffix('!').unwrap_or(rb))?;
+                let list = register_list(&operands[1])?;
+                if list > 0xFF {
+                    return Err(format!("{} can only use r0-r7", base))
+                }
+                (if base == "ldm" {0xC800} else {0xC000}) | (rb << 8) | list
+            },
+            ("b", 1) => {
+                let offset = self.eval(&operands[0])?.wrapping_sub(self.pc.wrapping_add(4)) as i32;
+                match cond {
+                    0xE if offset & 0b1 == 0 && (-0x800..0x800).contains(&offset) => 0xE000 | ((offset >> 1) as u32 & 0x7FF),
+                    0xE | 0xF => return Err(format!("can't branch to {}", operands[0])),
+                    _ if offset & 0b1 == 0 && (-0x100..0x100).contains(&offset) => 0xD000 | (cond << 8) | ((offset >> 1) as u32 & 0xFF),
+                    _ => return Err(format!("can't branch to {}", operands[0])),
+                }
+            },
+            ("bl", 1) => {
+                if let Some(offset) = operands[0].strip_prefix("lr+") {
+                    /* The second half on its own */
+                    let offset = self.eval(offset)?;
+                    if offset & 0b1 != 0 || offset > 0xFFE {
+                        return Err(format!("can't reach {} from the second half of a BL", operands[0]))
+                    }
+                    0xF800 | (offset >> 1)
+                } else {
+                    let offset = self.eval(&operands[0])?.wrapping_sub(self.pc.wrapping_add(4)) as i32;
+                    if offset & 0b1 != 0 || !(-0x400000..0x400000).contains(&offset) {
+                        return Err(format!("can't branch to {}", operands[0]))
+                    }
+                    return Ok(vec![0xF000 | ((offset >> 12) as u32 & 0x7FF) as u16, 0xF800 | ((offset >> 1) as u32 & 0x7FF) as u16])
+                }
+            },
+            ("swi", 1) => {
+                let number = match swi_number(&operands[0]) {
+                    Some(number) => number,
+                    None => imm(0, 0xFF, 1)?,
+                };
+                0xDF00 | number
+            },
+            _ => return Err(format!("wrong operands for {}", text)),
+        };
+        Ok(vec![inst as u16])
+    }
+
+    fn thumb_transfer(&self, base: &str, suffix: &str, operands: &[String]) -> Result<u16, String> {
+        let rd = low_register(&operands[0])?;
+        let load = base.starts_with("ld");
+
+        let address = match operands[1].strip_prefix('=') {
+            Some(_) if base == "ldr" && suffix.is_empty() => Address::Absolute(self.literal.ok_or("the literal was never put in a pool")?),
+            Some(_) => return Err("only ldr can load a literal".to_string()),
+            None => self.address(&operands[1..])?,
+        };
+        let (rb, offset) = match address {
+            Address::Absolute(target) if base == "ldr" && suffix.is_empty() => {
+                let distance = target.wrapping_sub(self.pc.wrapping_add(4) & !0b11);
+                if distance & 0b11 != 0 || distance > 1020 {
+                    return Err(format!("can't load from 0x{:X} here", target))
+                }
+                return Ok((0x4800 | (rd << 8) | (distance >> 2)) as u16)
+            },
+            Address::Indexed { pre: true, up: true, writeback: false, rn, offset } => (rn, offset),
+            _ => return Err("Thumb loads and stores only take [rb,offset]".to_string()),
+        };
+
+        let inst = match (base, suffix, offset) {
+            (_, _, Offset::Reg(_, Some(_))) => return Err("Thumb can't shift an offset".to_string()),
+            (_, _, Offset::Reg(ro, None)) => {
+                let op = match (base, suffix) {
+                    ("str", "") => 0b000,
+                    ("str", "h") => 0b001,
+                    ("str", _) => 0b010,
+                    ("ldsb", _) | ("ldr", "sb") => 0b011,
+                    ("ldr", "") => 0b100,
+                    ("ldr", "h") => 0b101,
+                    ("ldr", "b") => 0b110,
+                    _ => 0b111,
+                };
+                0x5000 | (op << 9) | (low_register(&format!("r{}", ro))? << 6) | (low_register(&format!("r{}", rb))? << 3) | rd
+            },
+            (_, _, Offset::Imm(imm)) => {
+                let (inst, max, scale) = match (base, suffix, rb) {
+                    ("ldr" | "str", "", 13) => (0x9000 | (rd << 8), 1020, 4),
+                    ("ldr" | "str", "", 15) if load => (0x4800 | (rd << 8), 1020, 4),
+                    ("ldr" | "str", "", _) => (0x6000, 124, 4),
+                    ("ldr" | "str", "b", _) => (0x7000, 31, 1),
+                    ("ldr" | "str", "h", _) => (0x8000, 62, 2),
+                    _ => return Err(format!("{}{} needs a register offset", base, suffix)),
+                };
+                if imm % scale != 0 || imm > max {
+                    return Err(format!("offset 0x{:X} is out of range", imm))
+                }
+                let load = (load as u32) << 11;
+                match rb {
+                    13 | 15 if max == 1020 => inst | load | (imm / scale),
+                    _ => inst | load | ((imm / scale) << 6) | (low_register(&format!("r{}", rb))? << 3) | rd,
+                }
+            },
+        };
+        Ok(inst as u16)
+    }
+}
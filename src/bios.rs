@@ -0,0 +1,553 @@
+use crate::arm7tdmi::{ self, Core, Status };
+use crate::memory::{ Memory, Width };
+
+/*
+High level emulation of the BIOS, so games can run without a dump of the real one.
+
+With Core::hle_bios set the SWI instruction doesn't take the exception, the call is done natively here instead and execution
+carries on after it (still taking the 3 cycles of the SWI itself, not however long the real BIOS would have taken).
+All memory accesses go through load/store, so they aren't timed either.
+
+The only code that has to be in the BIOS region is the IRQ trampoline at 0x18, which install() writes there.
+*/
+
+/* I/O registers the calls touch */
+const DISPCNT: u32      = 0x04000000;
+const SOUNDBIAS: u32    = 0x04000088;
+const IE: u32           = 0x04000200;
+const IF: u32           = 0x04000202;
+const IME: u32          = 0x04000208;
+const HALTCNT: u32      = 0x04000301;
+const POSTFLG: u32      = 0x04000300;
+
+/* I/O registers the BIOS leaves set when it starts the game, everything else is zero */
+const BOOT_IO: [(u32, u32); 7] = [
+    (DISPCNT, 0x80),        //forced blank
+    (0x04000020, 0x100),    //BG2PA, the affine backgrounds start unscaled
+    (0x04000026, 0x100),    //BG2PD
+    (0x04000030, 0x100),    //BG3PA
+    (0x04000036, 0x100),    //BG3PD
+    (SOUNDBIAS, 0x200),
+    (0x04000130, 0x3FF),    //KEYINPUT, nothing pressed
+];
+
+/* The interrupt handler and the flags it acknowledges to IntrWait live at the top of on-chip work RAM */
+const BIOS_IF: u32      = 0x03007FF8;
+const RESET_FLAG: u32   = 0x03007FFA;
+
+/* Stacks the BIOS sets up for each mode */
+pub const SP_SVC: u32   = 0x03007FE0;
+pub const SP_IRQ: u32   = 0x03007FA0;
+pub const SP_USR: u32   = 0x03007F00;
+
+/*
+The IRQ vector branches to a handler which saves the registers the caller can use and calls the game's handler with r0 at the
+I/O registers, from the pointer at 0x03007FFC (0x03FFFFFC on the real thing, which is a mirror of the same place).
+*/
+const TRAMPOLINE: [(u32, u32); 9] = [
+    (0x018, 0xEA000042), //B 0x128
+    (0x128, 0xE92D500F), //STMFD sp!,{r0-r3,r12,lr}
+    (0x12C, 0xE3A00301), //MOV r0,#0x04000000
+    (0x130, 0xE59F100C), //LDR r1,[pc,#12]
+    (0x134, 0xE28FE000), //ADD lr,pc,#0
+    (0x138, 0xE591F000), //LDR pc,[r1]
+    (0x13C, 0xE8BD500F), //LDMFD sp!,{r0-r3,r12,lr}
+    (0x140, 0xE25EF004), //SUBS pc,lr,#4
+    (0x144, 0x03007FFC), //where the handler pointer is
+];
+
+/* Write the IRQ trampoline into the BIOS region */
+#[allow(dead_code)]
+pub fn install<M: Memory>(bus: &mut M) {
+    for (addr, word) in TRAMPOLINE {
+        bus.mem_write_32(addr as usize, word);
+    }
+}
+
+/* The registers as the BIOS leaves them when it starts the game at pc: in System mode with everything else cleared */
+#[allow(dead_code)]
+pub fn boot_state(core: &mut Core, pc: u32) {
+    core.reg.gp = [0; 16];
+    core.reg.svc = [SP_SVC, 0];
+    core.reg.irq = [SP_IRQ, 0];
+    core.reg.spsr_svc = Status::from(0);
+    core.reg.spsr_irq = Status::from(0);
+    core.reg.cpsr = Status::from(arm7tdmi::MODE_SYS as u32);
+    core.reg.gp[13] = SP_USR;
+    core.reg.gp[15] = pc;
+    core.reg.prefetched = None;
+}
+
+/*
+Skip the BIOS intro and start the game as if the BIOS had just finished with it, with or without a BIOS image loaded.
+POSTFLG is set, as the BIOS does after the first boot.
+*/
+#[allow(dead_code)]
+pub fn direct_boot<M: Memory>(core: &mut Core, bus: &mut M) {
+    boot_state(core, 0x08000000);
+    for (addr, value) in BOOT_IO {
+        bus.store(addr as usize, Width::Half, value);
+    }
+    bus.store(POSTFLG as usize, Width::Byte, 1);
+}
+
+/*
+Whether the core is still halted, after Halt, IntrWait or VBlankIntrWait. Halt waits for any enabled interrupt to be requested,
+the other two wait for the game's interrupt handler to acknowledge one they're waiting for at BIOS_IF.
+A halted core idles for a cycle at a time.
+
+Interrupts are still taken while it waits (see interrupt), which is what ends a Halt.
+*/
+#[allow(dead_code)]
+pub fn halted<M: Memory>(core: &mut Core, bus: &mut M) -> bool {
+    /* IntrWait keeps waiting while the interrupt handler runs, the handler is what tells it to stop */
+    if !core.halted || core.reg.cpsr.mode() == arm7tdmi::MODE_IRQ {
+        return false;
+    }
+
+    let woken = if core.intr_wait == 0 {
+        bus.load(IE as usize, Width::Half) & bus.load(IF as usize, Width::Half) != 0
+    } else {
+        let flags = bus.load(BIOS_IF as usize, Width::Half);
+        if flags & core.intr_wait as u32 != 0 {
+            bus.store(BIOS_IF as usize, Width::Half, flags & !(core.intr_wait as u32));
+            true
+        } else {
+            false
+        }
+    };
+
+    if woken {
+        core.halted = false;
+        core.intr_wait = 0;
+    } else {
+        bus.idle(1);
+    }
+    !woken
+}
+
+/*
+Whether an interrupt will be taken before the next instruction: IME is on, an enabled interrupt has been requested and IRQs aren't
+disabled in the CPSR. Memories without the I/O registers never have one.
+*/
+#[allow(dead_code)]
+pub fn irq_pending<M: Memory>(core: &Core, bus: &mut M) -> bool {
+    !core.reg.cpsr.irq_disabled() && bus.mapped(IME as usize) && bus.load(IME as usize, Width::Half) & 0b1 != 0 &&
+        bus.load(IE as usize, Width::Half) & bus.load(IF as usize, Width::Half) != 0
+}
+
+/*
+Take the IRQ exception if one is pending, between instructions. LR is left 4 past the instruction that would have run next, which is
+where SUBS pc,lr,#4 at the end of the trampoline goes back to, and R15 at the vector so the engine carries on from there.
+*/
+#[allow(dead_code)]
+pub fn interrupt<M: Memory>(core: &mut Core, bus: &mut M) -> bool {
+    if !irq_pending(core, bus) {
+        return false;
+    }
+    if core.intr_wait == 0 {
+        core.halted = false;
+    }
+    let link = core.reg.gp[15].wrapping_add(4);
+    core.reg.exception(arm7tdmi::MODE_IRQ, link);
+    core.reg.gp[15] = 0x18;
+    core.reg.prefetched = None;
+    true
+}
+
+/* The call number is the bottom byte of the comment field in Thumb state, which ends up in the top half of it in ARM state */
+#[allow(dead_code)]
+pub fn number(inst: u32, thumb: bool) -> u8 {
+    if thumb {inst as u8} else {(inst >> 16) as u8}
+}
+
+/*
+Run a BIOS call, returning where execution should carry on if it isn't after the SWI (only SoftReset goes somewhere else).
+Calls that aren't implemented do nothing.
+*/
+#[allow(dead_code)]
+pub fn call<M: Memory>(core: &mut Core, bus: &mut M, number: u8) -> Option<u32> {
+    let r = core.reg.gp;
+    match number {
+        0x00 => return Some(soft_reset(core, bus)),
+        0x01 => register_ram_reset(bus, r[0]),
+        0x02 => {
+            bus.store(HALTCNT as usize, Width::Byte, 0);
+            core.halted = true;
+        },
+        0x04 => intr_wait(core, bus, r[0] != 0, r[1] as u16),
+        0x05 => intr_wait(core, bus, true, 1),
+        0x06 => div(core, r[0] as i32, r[1] as i32),
+        0x07 => div(core, r[1] as i32, r[0] as i32),
+        0x08 => core.reg.gp[0] = sqrt(r[0]),
+        0x09 => core.reg.gp[0] = arctan(r[0] as i16 as i32) as u32,
+        0x0A => core.reg.gp[0] = arctan2(r[0] as i16 as i32, r[1] as i16 as i32),
+        0x0B => cpu_set(bus, r[0], r[1], r[2]),
+        0x0C => cpu_fast_set(bus, r[0], r[1], r[2]),
+        0x0E => bg_affine_set(bus, r[0], r[1], r[2]),
+        0x0F => obj_affine_set(bus, r[0], r[1], r[2], r[3]),
+        0x10 => bit_unpack(bus, r[0], r[1], r[2]),
+        0x11 | 0x12 => {
+            let data = lz77(bus, r[0]);
+            write_out(bus, r[1], &data, number == 0x12);
+        },
+        0x13 => huffman(bus, r[0], r[1]),
+        0x14 | 0x15 => {
+            let data = run_length(bus, r[0]);
+            write_out(bus, r[1], &data, number == 0x15);
+        },
+        0x16 | 0x17 => {
+            let data = unfilter(bus, r[0], 1);
+            write_out(bus, r[1], &data, number == 0x17);
+        },
+        0x18 => {
+            let data = unfilter(bus, r[0], 2);
+            write_out(bus, r[1], &data, true);
+        },
+        0x19 => {
+            let bias = bus.load(SOUNDBIAS as usize, Width::Half);
+            bus.store(SOUNDBIAS as usize, Width::Half, (bias & !0x3FF) | if r[0] != 0 {0x200} else {0});
+        },
+        0x1F => core.reg.gp[0] = midi_key_to_freq(bus, r[0], r[1] as u8, r[2] as u8),
+        _ => {}
+    }
+    None
+}
+
+/* Clears the top of on-chip work RAM and starts again from ROM, or work RAM if the flag at RESET_FLAG is set */
+fn soft_reset<M: Memory>(core: &mut Core, bus: &mut M) -> u32 {
+    let pc = if bus.load(RESET_FLAG as usize, Width::Byte) == 0 {0x08000000} else {0x02000000};
+    for addr in (0x03007E00..0x03008000).step_by(4) {
+        bus.store(addr, Width::Word, 0);
+    }
+    boot_state(core, pc);
+    pc
+}
+
+/* Each bit of flags clears a part of memory, or a group of I/O registers */
+fn register_ram_reset<M: Memory>(bus: &mut M, flags: u32) {
+    let clear = |bus: &mut M, start: u32, end: u32| {
+        for addr in (start..end).step_by(4) {
+            bus.store(addr as usize, Width::Word, 0);
+        }
+    };
+
+    bus.store(DISPCNT as usize, Width::Half, 0x80); //forced blank
+    if flags & 0x01 != 0 { clear(bus, 0x02000000, 0x02040000); }
+    if flags & 0x02 != 0 { clear(bus, 0x03000000, 0x03007E00); } //the top is left alone, the stacks are there
+    if flags & 0x04 != 0 { clear(bus, 0x05000000, 0x05000400); }
+    if flags & 0x08 != 0 { clear(bus, 0x06000000, 0x06018000); }
+    if flags & 0x10 != 0 { clear(bus, 0x07000000, 0x07000400); }
+    if flags & 0x20 != 0 { clear(bus, 0x04000120, 0x04000160); } //serial
+    if flags & 0x40 != 0 { clear(bus, 0x04000060, 0x040000B0); } //sound
+    if flags & 0x80 != 0 {                                       //everything else
+        clear(bus, 0x04000004, 0x04000060);
+        clear(bus, 0x040000B0, 0x04000120);
+        clear(bus, 0x04000200, 0x04000204);
+    }
+}
+
+/* Turns interrupts on and halts until the handler acknowledges one in mask, discard clears the ones already acknowledged first */
+fn intr_wait<M: Memory>(core: &mut Core, bus: &mut M, discard: bool, mask: u16) {
+    bus.store(IME as usize, Width::Half, 1);
+    if discard {
+        let flags = bus.load(BIOS_IF as usize, Width::Half);
+        bus.store(BIOS_IF as usize, Width::Half, flags & !(mask as u32));
+    }
+    core.halted = true;
+    core.intr_wait = mask;
+    halted(core, bus);
+}
+
+/* r0 = number / denom, r1 = number % denom, r3 = |r0|. The real BIOS never returns from a division by zero */
+fn div(core: &mut Core, number: i32, denom: i32) {
+    let (quotient, remainder) = if denom == 0 {
+        (if number < 0 {-1} else {1}, number)
+    } else {
+        (number.wrapping_div(denom), number.wrapping_rem(denom))
+    };
+    core.reg.gp[0] = quotient as u32;
+    core.reg.gp[1] = remainder as u32;
+    core.reg.gp[3] = quotient.unsigned_abs();
+}
+
+fn sqrt(value: u32) -> u32 {
+    let mut root = (value as f64).sqrt() as u32;
+    while root * root > value {
+        root -= 1;
+    }
+    root
+}
+
+/* The same polynomial the BIOS uses, tan is 1.14 fixed point and the result goes from -0x4000 to 0x4000 for -pi/2 to pi/2 */
+fn arctan(tan: i32) -> i32 {
+    let a = -(tan.wrapping_mul(tan) >> 14);
+    let mut b = ((0xA9 * a) >> 14) + 0x390;
+    for term in [0x91C, 0xFB6, 0x16AA, 0x2081, 0x3651, 0xA2F9] {
+        b = (b.wrapping_mul(a) >> 14) + term;
+    }
+    tan.wrapping_mul(b) >> 16
+}
+
+/* The angle of (x, y) from 0 to 0xFFFF for the whole circle */
+fn arctan2(x: i32, y: i32) -> u32 {
+    let angle = match (x, y) {
+        (x, 0) => if x >= 0 {0} else {0x8000},
+        (0, y) => if y >= 0 {0x4000} else {0xC000},
+        (x, y) if y >= 0 && x >= 0 && x >= y => arctan((y << 14) / x),
+        (x, y) if y >= 0 && x < 0 && -x >= y => arctan((y << 14) / x) + 0x8000,
+        (x, y) if y >= 0 => 0x4000 - arctan((x << 14) / y),
+        (x, y) if x <= 0 && -x > -y => arctan((y << 14) / x) + 0x8000,
+        (x, y) if x > 0 && x >= -y => arctan((y << 14) / x) + 0x10000,
+        (x, y) => 0xC000 - arctan((x << 14) / y),
+    };
+    angle as u32 & 0xFFFF
+}
+
+/* Copy or fill (bit 24) halfwords, or words with bit 26 set, the count is in the bottom 21 bits */
+fn cpu_set<M: Memory>(bus: &mut M, src: u32, dst: u32, control: u32) {
+    let count = control & 0x1FFFFF;
+    let fill = control & (1 << 24) != 0;
+    let (width, size) = if control & (1 << 26) != 0 {(Width::Word, 4)} else {(Width::Half, 2)};
+    let (src, dst) = (src & !(size - 1), dst & !(size - 1));
+
+    let value = bus.load(src as usize, width);
+    for i in 0..count {
+        let data = if fill {value} else {bus.load((src + i*size) as usize, width)};
+        bus.store((dst + i*size) as usize, width, data);
+    }
+}
+
+/* CpuSet for words only, the count is rounded up to a multiple of 8 */
+fn cpu_fast_set<M: Memory>(bus: &mut M, src: u32, dst: u32, control: u32) {
+    let count = ((control & 0x1FFFFF) + 7) & !7;
+    cpu_set(bus, src, dst, count | (control & (1 << 24)) | (1 << 26));
+}
+
+/* Scale and rotation matrix, the angle is in the top byte of a halfword */
+fn rotation(sx: f64, sy: f64, angle: u32) -> [f64; 4] {
+    let theta = (angle >> 8) as f64 / 128.0 * std::f64::consts::PI;
+    let (sin, cos) = theta.sin_cos();
+    [cos * sx, -sin * sx, sin * sy, cos * sy]
+}
+
+/* 20 byte entries of origin, display centre, scale and angle, into 16 byte BG affine parameters with the start position */
+fn bg_affine_set<M: Memory>(bus: &mut M, src: u32, dst: u32, count: u32) {
+    for i in 0..count {
+        let (src, dst) = ((src + i*20) as usize, (dst + i*16) as usize);
+        let ox = bus.load(src, Width::Word) as i32 as f64 / 256.0;
+        let oy = bus.load(src + 4, Width::Word) as i32 as f64 / 256.0;
+        let cx = bus.load(src + 8, Width::Half) as i16 as f64;
+        let cy = bus.load(src + 10, Width::Half) as i16 as f64;
+        let sx = bus.load(src + 12, Width::Half) as i16 as f64 / 256.0;
+        let sy = bus.load(src + 14, Width::Half) as i16 as f64 / 256.0;
+        let [pa, pb, pc, pd] = rotation(sx, sy, bus.load(src + 16, Width::Half));
+
+        for (j, p) in [pa, pb, pc, pd].iter().enumerate() {
+            bus.store(dst + 2*j, Width::Half, (p * 256.0) as i32 as u32);
+        }
+        let x = ox - (pa * cx + pb * cy);
+        let y = oy - (pc * cx + pd * cy);
+        bus.store(dst + 8, Width::Word, (x * 256.0) as i32 as u32);
+        bus.store(dst + 12, Width::Word, (y * 256.0) as i32 as u32);
+    }
+}
+
+/* 8 byte entries of scale and angle, the parameters are written diff bytes apart (2 for BG, 8 straight into OAM) */
+fn obj_affine_set<M: Memory>(bus: &mut M, src: u32, dst: u32, count: u32, diff: u32) {
+    for i in 0..count {
+        let (src, dst) = (src + i*8, dst + i*diff*4);
+        let sx = bus.load(src as usize, Width::Half) as i16 as f64 / 256.0;
+        let sy = bus.load(src as usize + 2, Width::Half) as i16 as f64 / 256.0;
+        let params = rotation(sx, sy, bus.load(src as usize + 4, Width::Half));
+
+        for (j, p) in params.iter().enumerate() {
+            bus.store((dst + j as u32 * diff) as usize, Width::Half, (p * 256.0) as i32 as u32);
+        }
+    }
+}
+
+/*
+Widen each unit of the source to the destination width, written a word at a time. The info at r2 is the source length in bytes,
+the source and destination widths in bits and an offset added to each unit (bit 31 adds it to zero units as well).
+*/
+fn bit_unpack<M: Memory>(bus: &mut M, src: u32, dst: u32, info: u32) {
+    let mut length = bus.load(info as usize, Width::Half);
+    let src_width = bus.load(info as usize + 2, Width::Byte);
+    let dst_width = bus.load(info as usize + 3, Width::Byte);
+    let offset = bus.load(info as usize + 4, Width::Word);
+    if !matches!(src_width, 1 | 2 | 4 | 8) || !matches!(dst_width, 1 | 2 | 4 | 8 | 16 | 32) {
+        return;
+    }
+
+    let (mut src, mut dst) = (src as usize, dst as usize);
+    let (mut byte, mut bits) = (0, 0);
+    let (mut out, mut filled) = (0u32, 0);
+    while length > 0 || bits > 0 {
+        if bits == 0 {
+            byte = bus.load(src, Width::Byte);
+            src += 1;
+            bits = 8;
+            length -= 1;
+        }
+        let mut unit = byte & ((1 << src_width) - 1);
+        byte >>= src_width;
+        bits -= src_width;
+        if unit != 0 || offset & 0x80000000 != 0 {
+            unit = unit.wrapping_add(offset & 0x7FFFFFFF);
+        }
+
+        out |= unit << filled;
+        filled += dst_width;
+        if filled == 32 {
+            bus.store(dst, Width::Word, out);
+            dst += 4;
+            out = 0;
+            filled = 0;
+        }
+    }
+}
+
+/* The decompressors read a header with the size of the output in the top 24 bits */
+fn header<M: Memory>(bus: &mut M, src: u32) -> usize {
+    (bus.load(src as usize, Width::Word) >> 8) as usize
+}
+
+/* Flag bytes say whether each of the next 8 blocks is a byte as it is, or 3-18 bytes copied from 1-4096 bytes back */
+fn lz77<M: Memory>(bus: &mut M, src: u32) -> Vec<u8> {
+    let size = header(bus, src);
+    let mut src = src as usize + 4;
+    let mut byte = |bus: &mut M| {
+        src += 1;
+        bus.load(src - 1, Width::Byte) as u8
+    };
+
+    let mut data = Vec::with_capacity(size);
+    while data.len() < size {
+        let flags = byte(bus);
+        for bit in (0..8).rev() {
+            if data.len() >= size {
+                break;
+            }
+            if flags & (1 << bit) == 0 {
+                let value = byte(bus);
+                data.push(value);
+            } else {
+                let (hi, lo) = (byte(bus) as usize, byte(bus) as usize);
+                let length = (hi >> 4) + 3;
+                let disp = (((hi & 0xF) << 8) | lo) + 1;
+                for _ in 0..length {
+                    let value = data.get(data.len().wrapping_sub(disp)).copied().unwrap_or(0);
+                    data.push(value);
+                }
+            }
+        }
+    }
+    data.truncate(size);
+    data
+}
+
+/* Flag bytes are followed by 1-128 bytes as they are, or with bit 7 set one byte repeated 3-130 times */
+fn run_length<M: Memory>(bus: &mut M, src: u32) -> Vec<u8> {
+    let size = header(bus, src);
+    let mut src = src as usize + 4;
+
+    let mut data = Vec::with_capacity(size);
+    while data.len() < size {
+        let flag = bus.load(src, Width::Byte) as usize;
+        src += 1;
+        if flag & 0x80 != 0 {
+            let value = bus.load(src, Width::Byte) as u8;
+            src += 1;
+            data.extend(std::iter::repeat_n(value, (flag & 0x7F) + 3));
+        } else {
+            for _ in 0..(flag & 0x7F) + 1 {
+                data.push(bus.load(src, Width::Byte) as u8);
+                src += 1;
+            }
+        }
+    }
+    data.truncate(size);
+    data
+}
+
+/* Each unit of width bytes is the difference from the one before it */
+fn unfilter<M: Memory>(bus: &mut M, src: u32, width: usize) -> Vec<u8> {
+    let size = header(bus, src);
+    let (src, mask) = (src as usize + 4, if width == 1 {0xFF} else {0xFFFF});
+
+    let mut data = Vec::with_capacity(size);
+    let mut value = 0u32;
+    for i in (0..size).step_by(width) {
+        let diff = bus.load(src + i, if width == 1 {Width::Byte} else {Width::Half});
+        value = value.wrapping_add(diff) & mask;
+        data.extend_from_slice(&value.to_le_bytes()[..width]);
+    }
+    data
+}
+
+/*
+The tree follows the header, a byte with its size in halfwords minus one and then the nodes. Each node has the offset to its
+pair of children in bits 0-5, and whether the child for a 0 (bit 7) or 1 (bit 6) is a data unit rather than another node.
+The bitstream after it is read a word at a time from the top bit, the units are 4 or 8 bits and written out a word at a time.
+*/
+fn huffman<M: Memory>(bus: &mut M, src: u32, dst: u32) {
+    let size = header(bus, src);
+    let unit = bus.load(src as usize, Width::Byte) & 0xF;
+    if unit != 4 && unit != 8 {
+        return;
+    }
+    let root = src as usize + 5;
+    let mut stream = src as usize + 4 + (bus.load(src as usize + 4, Width::Byte) as usize + 1) * 2;
+
+    let (mut dst, end) = (dst as usize, dst as usize + size);
+    let (mut node_addr, mut node) = (root, bus.load(root, Width::Byte));
+    let (mut out, mut filled) = (0u32, 0);
+    while dst < end {
+        let word = bus.load(stream, Width::Word);
+        stream += 4;
+        for bit in (0..32).rev() {
+            let right = (word >> bit) & 1;
+            let child = (node_addr & !1) + (node as usize & 0x3F) * 2 + 2 + right as usize;
+            if node & (0x80 >> right) == 0 {
+                node_addr = child;
+                node = bus.load(child, Width::Byte);
+                continue;
+            }
+
+            out |= (bus.load(child, Width::Byte) & ((1 << unit) - 1)) << filled;
+            filled += unit;
+            (node_addr, node) = (root, bus.load(root, Width::Byte));
+            if filled == 32 {
+                bus.store(dst, Width::Word, out);
+                dst += 4;
+                out = 0;
+                filled = 0;
+                if dst >= end {
+                    break;
+                }
+            }
+        }
+    }
+}
+
+/* The Vram versions of the decompressors only write halfwords, as video memory doesn't take bytes */
+fn write_out<M: Memory>(bus: &mut M, dst: u32, data: &[u8], halfwords: bool) {
+    if halfwords {
+        for (i, pair) in data.chunks(2).enumerate() {
+            let value = pair[0] as u32 | (*pair.get(1).unwrap_or(&0) as u32) << 8;
+            bus.store(dst as usize + 2*i, Width::Half, value);
+        }
+    } else {
+        for (i, byte) in data.iter().enumerate() {
+            bus.store(dst as usize + i, Width::Byte, *byte as u32);
+        }
+    }
+}
+
+/* The frequency to play a sample at for a MIDI key and fine adjustment, from the sample's own frequency at offset 4 of its header */
+fn midi_key_to_freq<M: Memory>(bus: &mut M, wave: u32, key: u8, fine: u8) -> u32 {
+    let freq = bus.load(wave as usize + 4, Width::Word) as f64;
+    let exponent = (180.0 - key as f64 - fine as f64 / 256.0) / 12.0;
+    (freq / 2f64.powf(exponent)) as u32
+}
@@ -4,14 +4,18 @@ use std::io::BufReader;
 use std::fs::File;
 use std::vec::*;
 
+use crate::memory::{ Memory, Cycle, Width };
+use crate::cache::InstCache;
+use crate::decode::{ ArmInst, ThumbInst };
+
 /* Memory Map */
 /* 
   00000000-00003FFF   BIOS - System ROM         (16 KBytes)
   00004000-01FFFFFF   Not used
   02000000-0203FFFF   WRAM - On-board Work RAM  (256 KBytes) 2 Wait
-  02040000-02FFFFFF   Not used
+  02040000-02FFFFFF   Mirrors of it
   03000000-03007FFF   WRAM - On-chip Work RAM   (32 KBytes)
-  03008000-03FFFFFF   Not used
+  03008000-03FFFFFF   Mirrors of it
   04000000-040003FE   I/O Registers
   04000400-04FFFFFF   Not used
   05000000-050003FF   BG/OBJ Palette RAM        (1 Kbyte)
@@ -40,22 +44,63 @@ const OBJ_START: usize    = 0x05000000;
 const OBJ_END: usize      = 0x050003FF;
 const VRAM_START: usize   = 0x06000000;
 const VRAM_END: usize     = 0x06017FFF;
+const OBJ_VRAM_START: usize         = 0x06010000;   //tile modes (0-2)
+const OBJ_VRAM_BITMAP_START: usize  = 0x06014000;   //bitmap modes (3-5)
 const OAM_START: usize    = 0x07000000;
 const OAM_END: usize      = 0x070003FF;
 const GPK0_START: usize   = 0x08000000;
-/* Need to include code to handle wait-states */
-#[allow(dead_code)] //for now
 const GPK0_END: usize     = 0x09FFFFFF;
-#[allow(dead_code)]
 const GPK1_START: usize   = 0x0A000000;
-#[allow(dead_code)]
 const GPK1_END: usize     = 0x0BFFFFFF;
-#[allow(dead_code)]
 const GPK2_START: usize   = 0x0C000000;
 const GPK2_END: usize     = 0x0DFFFFFF;
 const GPKSRAM_START: usize= 0x0E000000;
 const GPKSRAM_END: usize  = 0x0E00FFFF;
 
+/* I/O registers the bus itself cares about */
+const IF: usize           = 0x04000202;
+const WAITCNT: usize      = 0x04000204;
+const WAITCNT_PREFETCH: u16 = 1 << 14;
+
+const PREFETCH_SIZE: u32  = 8; //halfwords
+
+/* Wait states selectable in WAITCNT, indexed by the 2-bit field */
+const FIRST_ACCESS: [u32; 4] = [4, 3, 2, 8];
+/* Second (sequential) access wait states for WS0, WS1 and WS2 when the control bit is clear */
+const SECOND_ACCESS: [u32; 3] = [2, 4, 8];
+
+/*
+Access timing, in cycles, with the default WAITCNT (each access also includes the 1 cycle it always takes)
+
+  Region        Bus   8   16  32
+  BIOS ROM      32    1   1   1
+  IWRAM 32K     32    1   1   1
+  I/O           32    1   1   1
+  OAM           32    1   1   1
+  EWRAM 256K    16    3   3   6
+  Palette RAM   16    1   1   2
+  VRAM          16    1   1   2
+  GamePak ROM   16    5   5   8     (N cycle, WS0)
+  GamePak SRAM  8     5   -   -
+*/
+
+/*
+The Game Pak prefetch buffer
+
+While the CPU is busy with internal cycles or with memory other than the cartridge, the cartridge bus would otherwise sit idle, so
+the prefetcher keeps reading sequential halfwords after the last opcode fetched from ROM. An opcode fetch that finds its data in
+the buffer takes 1 cycle instead of a full ROM access. Any fetch from somewhere else (a branch) starts it again from scratch, and a
+data access to ROM takes over the cartridge bus, throwing the buffer away.
+*/
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Prefetch {
+    active: bool,
+    head: usize,    //address of the oldest halfword in the buffer, which is what the CPU wants next
+    next: usize,    //address of the halfword being fetched
+    count: u32,     //number of halfwords in the buffer
+    progress: u32,  //cycles spent on the halfword being fetched
+}
+
 #[derive(Clone,Debug)]
 pub struct Bus {
 	bios: 	Vec<u8>,
@@ -67,134 +112,399 @@ pub struct Bus {
 	oam:	Vec<u8>,
 	gpk:	Vec<u8>,
 	//gsrm:	[u8; GPKSRAM_END-GPKSRAM_START],
+	prefetch: Prefetch,
+	icache: InstCache,
 }
 
 #[allow(dead_code)]
 impl Bus {
     pub fn new() -> Self {
         Bus {
-            bios: 	vec![0; BIOS_END-MEM_START],
-            wram0:	vec![0; WRAM0_END-WRAM0_START],
-            wram1:	vec![0; WRAM1_END-WRAM1_START],
-            io:	    vec![0; IO_END-IO_START],
-            obj:	vec![0; OBJ_END-OBJ_START],
-            vram:	vec![0; VRAM_END-VRAM_START],
-            oam:	vec![0; OAM_END-OAM_START],
-            gpk:	vec![0; GPK2_END-GPK0_START],
+            bios: 	vec![0; BIOS_END-MEM_START+1],
+            wram0:	vec![0; WRAM0_END-WRAM0_START+1],
+            wram1:	vec![0; WRAM1_END-WRAM1_START+1],
+            io:	    vec![0; IO_END-IO_START+1],
+            obj:	vec![0; OBJ_END-OBJ_START+1],
+            vram:	vec![0; VRAM_END-VRAM_START+1],
+            oam:	vec![0; OAM_END-OAM_START+1],
+            gpk:	vec![0; GPK2_END-GPK0_START+1],
             //gsrm:	[0; GPKSRAM_END-GPKSRAM_START],
+            prefetch: Default::default(),
+            icache: InstCache::new(),
+        }
+    }
+
+    /* The work RAMs repeat all the way through their 16MB areas, the BIOS clears the top of on-chip RAM through 0x03FFFFxx */
+    fn mirror(addr: usize) -> usize {
+        match addr {
+            0x02000000 ..= 0x02FFFFFF => WRAM0_START | (addr & (WRAM0_END - WRAM0_START)),
+            0x03000000 ..= 0x03FFFFFF => WRAM1_START | (addr & (WRAM1_END - WRAM1_START)),
+            _ => addr,
         }
     }
 
-    pub fn mem_read(&self, addr: usize) -> u8 {
+    /* Store a single byte, used directly by the 16 and 32-bit writes */
+    fn write_byte(&mut self, addr: usize, data: u8) {
+        let addr = Self::mirror(addr);
+        if Self::cacheable(addr) {
+            self.icache.invalidate(addr);
+        }
         match addr {
             MEM_START ..= BIOS_END => {
-                self.bios[addr]
+                self.bios[addr] = data;
             },
             WRAM0_START ..= WRAM0_END => {
-                self.wram0[(addr - WRAM0_START)]
+                self.wram0[addr - WRAM0_START] = data;
             },
             WRAM1_START ..= WRAM1_END => {
-                self.wram1[(addr - WRAM1_START)]
+                self.wram1[addr - WRAM1_START] = data;
             },
             IO_START ..= IO_END => {
-                self.io[(addr - IO_START)]
+                self.io[addr - IO_START] = data;
             },
             OBJ_START ..= OBJ_END => {
-                self.obj[(addr - OBJ_START)]
+                self.obj[addr - OBJ_START] = data;
             },
             VRAM_START ..= VRAM_END => {
-                self.vram[(addr - VRAM_START)]
+                self.vram[addr - VRAM_START] = data;
             },
             OAM_START ..= OAM_END => {
-                self.oam[(addr - OAM_START)]
+                self.oam[addr - OAM_START] = data;
             },
             GPK0_START ..= GPK2_END => {
-                self.gpk[(addr - GPK0_START)]
+                self.gpk[addr - GPK0_START] = data;
             },
             GPKSRAM_START ..= GPKSRAM_END => {
-                //self.gsrm[(addr - GPKSRAM_START)]
-                0
+                //self.gsrm[addr - GPKSRAM_START] = data;
             },
-            _   => panic!("Illegal memory read at {}", addr)
+            _   => panic!("Illegal memory write at {}", addr)
+        }
+    }
+
+    /* Write a byte into both halves of the (aligned) halfword containing addr */
+    fn write_byte_dup(&mut self, addr: usize, data: u8) {
+        self.write_byte(addr & !1, data);
+        self.write_byte(addr | 1, data);
+    }
+
+    /* Code is only expected to run from ROM and work RAM, so those are the only places decoded instructions are cached */
+    fn cacheable(addr: usize) -> bool {
+        matches!(addr, WRAM0_START ..= WRAM0_END | WRAM1_START ..= WRAM1_END | GPK0_START ..= GPK2_END)
+    }
+
+    /* Whether any decoded instructions are cached in the page containing addr */
+    pub fn cached(&self, addr: usize) -> bool {
+        self.icache.contains(addr)
+    }
+
+    /* The current background mode from DISPCNT */
+    pub fn bg_mode(&self) -> u8 {
+        self.io[0] & 0b111
+    }
+
+    /* Where OBJ VRAM begins, the bitmap modes (3-5) use more of VRAM for the background */
+    pub fn obj_vram_start(&self) -> usize {
+        if self.bg_mode() >= 3 {
+            OBJ_VRAM_BITMAP_START
+        } else {
+            OBJ_VRAM_START
+        }
+    }
+
+    /* A data access either takes the cartridge bus away from the prefetcher or lets it run alongside */
+    fn data_access(&mut self, addr: usize, cycles: u32) {
+        if (GPK0_START ..= GPK2_END).contains(&addr) {
+            self.prefetch.active = false;
+        } else {
+            self.prefetch_step(cycles);
+        }
+    }
+
+    pub fn prefetch_enabled(&self) -> bool {
+        self.waitcnt() & WAITCNT_PREFETCH != 0
+    }
+
+    /* Run the prefetcher for some number of cycles */
+    fn prefetch_step(&mut self, cycles: u32) {
+        if !self.prefetch_enabled() || !self.prefetch.active {
+            return
+        }
+
+        self.prefetch.progress += cycles;
+        while self.prefetch.count < PREFETCH_SIZE {
+            let needed = self.access_cycles(self.prefetch.next, Width::Half, Cycle::S);
+            if self.prefetch.progress < needed {
+                return
+            }
+            self.prefetch.progress -= needed;
+            self.prefetch.count += 1;
+            self.prefetch.next += 2;
+        }
+        self.prefetch.progress = 0; //the buffer is full, so it stops
+    }
+
+    /* The wait state control register */
+    pub fn waitcnt(&self) -> u16 {
+        let lo = self.io[WAITCNT - IO_START] as u16;
+        let hi = self.io[WAITCNT - IO_START + 1] as u16;
+        lo | (hi << 8)
+    }
+
+    /* Game Pak ROM timing for wait state region ws (0-2) */
+    fn rom_cycles(&self, ws: usize, addr: usize, width: Width, cycle: Cycle) -> u32 {
+        let waitcnt = self.waitcnt() as usize;
+        let first = FIRST_ACCESS[(waitcnt >> (2 + 3*ws)) & 0b11];
+        let second = if (waitcnt >> (4 + 3*ws)) & 0b1 == 1 {1} else {SECOND_ACCESS[ws]};
+
+        /* The cartridge address counter can't cross a 128K block, so the first access in one is always non-sequential */
+        let cycle = if addr & 0x1FFFF == 0 {Cycle::N} else {cycle};
+
+        let half = 1 + match cycle {
+            Cycle::N => first,
+            Cycle::S => second,
+        };
+
+        if width == Width::Word {
+            half + 1 + second   //split into two 16-bit accesses, the second is always sequential
+        } else {
+            half
         }
     }
 
-    pub fn mem_write(&mut self, addr: usize, data: u8) {
+    pub fn load_mem(&mut self) -> io::Result<()> {
+        let f = File::open("./gba_bios.gba")?;
+        let mut reader = BufReader::new(f);
+        let mut buffer = Vec::new();
+
+        reader.read_to_end(&mut buffer)?;
+
+        for (i, byte) in buffer.iter().enumerate() {
+            self.write_byte(i, *byte);
+        }
+        Ok(())
+    }
+}
+
+
+impl Memory for Bus {
+    fn mem_read(&mut self, addr: usize) -> u8 {
+        let addr = Self::mirror(addr);
         match addr {
             MEM_START ..= BIOS_END => {
-                self.bios[addr] = data;
+                self.bios[addr]
             },
             WRAM0_START ..= WRAM0_END => {
-                self.wram0[(addr - WRAM0_START)] = data;
+                self.wram0[addr - WRAM0_START]
             },
             WRAM1_START ..= WRAM1_END => {
-                self.wram1[(addr - WRAM1_START)] = data;
+                self.wram1[addr - WRAM1_START]
             },
             IO_START ..= IO_END => {
-                //self.io[(addr - IO_START)] = data;
+                self.io[addr - IO_START]
             },
             OBJ_START ..= OBJ_END => {
-                //self.obj[(addr - OBJ_START)] = data;
+                self.obj[addr - OBJ_START]
             },
             VRAM_START ..= VRAM_END => {
-                //self.vram[(addr - VRAM_START)] = data;
+                self.vram[addr - VRAM_START]
             },
             OAM_START ..= OAM_END => {
-                //self.oam[(addr - OAM_START)] = data;
+                self.oam[addr - OAM_START]
             },
             GPK0_START ..= GPK2_END => {
-                self.gpk[(addr - GPK0_START)] = data;
+                self.gpk[addr - GPK0_START]
             },
             GPKSRAM_START ..= GPKSRAM_END => {
-                //self.gsrm[(addr - GPKSRAM_START)] = data;
+                //self.gsrm[addr - GPKSRAM_START]
+                0
             },
-            _   => panic!("Illegal memory write at {}", addr)
+            _   => panic!("Illegal memory read at {}", addr)
         }
     }
 
-    pub fn mem_read_16(&mut self, addr: usize) -> u16 {
-        let lo = self.mem_read(addr) as u16;
-        let hi = self.mem_read(addr+1) as u16;
-        lo | (hi << 8)
-    }
-
-    pub fn mem_read_32(&mut self, addr: usize) -> u32 {
-        let lo = self.mem_read(addr) as u32;
-        let lo1 = self.mem_read(addr+1) as u32;
-        let hi = self.mem_read(addr+2) as u32;
-        let hi1 = self.mem_read(addr+3) as u32;
-        lo | (lo1 << 8) | (hi << 16) | (hi1 << 24)
+    /* 8-bit writes from the CPU, video memory is on a 16-bit bus and does not take byte writes as-is */
+    fn mem_write(&mut self, addr: usize, data: u8) {
+        match addr {
+            OBJ_START ..= OBJ_END => {
+                self.write_byte_dup(addr, data);        //byte is written to both halves of the halfword
+            },
+            VRAM_START ..= VRAM_END => {
+                if addr < self.obj_vram_start() {
+                    self.write_byte_dup(addr, data);    //BG VRAM behaves like palette RAM
+                }                                       //OBJ VRAM ignores byte writes
+            },
+            OAM_START ..= OAM_END => {
+                //OAM ignores byte writes
+            },
+            _   => self.write_byte(addr, data)
+        }
     }
 
-    pub fn mem_write_16(&mut self, addr: usize, data: u16) {
+    fn mem_write_16(&mut self, addr: usize, data: u16) {
         let lo = (data & 0xFF) as u8;
         let hi = (data >> 8) as u8;
-        self.mem_write(addr, lo);
-        self.mem_write(addr+1, hi);
+        self.write_byte(addr, lo);
+        self.write_byte(addr+1, hi);
     }
 
-    pub fn mem_write_32(&mut self, addr: usize, data: u32) {
+    fn mem_write_32(&mut self, addr: usize, data: u32) {
         let lo = (data & 0xFF) as u8;
         let lo1 = ((data >> 8) & 0xFF) as u8;
         let hi = ((data >> 16) & 0xFF) as u8;
         let hi1 = ((data >> 24) & 0xFF) as u8;
-        self.mem_write(addr, lo);
-        self.mem_write(addr+1, lo1);
-        self.mem_write(addr+2, hi);
-        self.mem_write(addr+3, hi1);
+        self.write_byte(addr, lo);
+        self.write_byte(addr+1, lo1);
+        self.write_byte(addr+2, hi);
+        self.write_byte(addr+3, hi1);
     }
 
-    pub fn load_mem(&mut self) -> io::Result<()> {
-        let f = File::open("./gba_bios.gba")?;
-        let mut reader = BufReader::new(f);
-        let mut buffer = Vec::new();
+    /* The CPU acknowledges an interrupt by writing a 1 to its bit in IF, which clears it (mem_write sets them, like the hardware requesting one) */
+    fn store(&mut self, addr: usize, width: Width, data: u32) {
+        let size = match width { Width::Byte => 1, Width::Half => 2, Width::Word => 4 };
+        let flags = [self.io[IF - IO_START], self.io[IF - IO_START + 1]];
+        match width {
+            Width::Byte => self.mem_write(addr, data as u8),
+            Width::Half => self.mem_write_16(addr, data as u16),
+            Width::Word => self.mem_write_32(addr, data),
+        }
+        for (i, flag) in flags.iter().enumerate() {
+            if (addr..addr + size).contains(&(IF + i)) {
+                self.io[IF - IO_START + i] = flag & !self.io[IF - IO_START + i];
+            }
+        }
+    }
 
-        reader.read_to_end(&mut buffer)?;
+    fn access_cycles(&self, addr: usize, width: Width, cycle: Cycle) -> u32 {
+        match Self::mirror(addr) {
+            MEM_START ..= BIOS_END | WRAM1_START ..= WRAM1_END | IO_START ..= IO_END | OAM_START ..= OAM_END => 1,
+            WRAM0_START ..= WRAM0_END => {
+                match width {                                   //2 wait states on a 16-bit bus
+                    Width::Word => 6,
+                    _           => 3,
+                }
+            },
+            OBJ_START ..= OBJ_END | VRAM_START ..= VRAM_END => {
+                match width {                                   //no wait states, but a 16-bit bus
+                    Width::Word => 2,
+                    _           => 1,
+                }
+            },
+            GPK0_START ..= GPK0_END => self.rom_cycles(0, addr, width, cycle),
+            GPK1_START ..= GPK1_END => self.rom_cycles(1, addr, width, cycle),
+            GPK2_START ..= GPK2_END => self.rom_cycles(2, addr, width, cycle),
+            GPKSRAM_START ..= GPKSRAM_END => {
+                1 + FIRST_ACCESS[(self.waitcnt() & 0b11) as usize]      //8-bit bus, only ever byte accesses
+            },
+            _   => 1
+        }
+    }
+
+    fn read(&mut self, addr: usize, width: Width, cycle: Cycle) -> (u32, u32) {
+        let data = self.load(addr, width);
+        let cycles = self.access_cycles(addr, width, cycle);
+        self.data_access(addr, cycles);
+        (data, cycles)
+    }
+
+    fn write(&mut self, addr: usize, width: Width, cycle: Cycle, data: u32) -> u32 {
+        self.store(addr, width, data);
+        let cycles = self.access_cycles(addr, width, cycle);
+        self.data_access(addr, cycles);
+        cycles
+    }
+
+    /* Opcode fetches are the only kind of access that can be served by the prefetch buffer */
+    fn fetch(&mut self, addr: usize, width: Width, cycle: Cycle) -> (u32, u32) {
+        let data = self.load(addr, width);
+        (data, self.fetch_cycles(addr, width, cycle))
+    }
 
-        for i in 0..(buffer.len()-1) {
-            self.mem_write(i, buffer[i]);
+    fn fetch_cycles(&mut self, addr: usize, width: Width, cycle: Cycle) -> u32 {
+        if !(GPK0_START ..= GPK2_END).contains(&addr) {
+            let cycles = self.access_cycles(addr, width, cycle);
+            self.prefetch_step(cycles);
+            return cycles
+        }
+
+        let halfwords = if width == Width::Word {2} else {1};
+        if self.prefetch_enabled() && self.prefetch.active && addr == self.prefetch.head {
+            /* Wait for any halfwords still on their way */
+            let mut cycles = 0;
+            while self.prefetch.count < halfwords {
+                cycles += self.access_cycles(self.prefetch.next, Width::Half, Cycle::S) - self.prefetch.progress;
+                self.prefetch.progress = 0;
+                self.prefetch.count += 1;
+                self.prefetch.next += 2;
+            }
+            self.prefetch.count -= halfwords;
+            self.prefetch.head += 2*halfwords as usize;
+
+            if cycles == 0 {
+                cycles = 1;                 //straight out of the buffer
+                self.prefetch_step(1);
+            }
+            cycles
+        } else {
+            /* Missed the buffer, the prefetcher carries on from after this opcode */
+            let cycles = self.access_cycles(addr, width, cycle);
+            self.prefetch = Prefetch {
+                active: true,
+                head: addr + 2*halfwords as usize,
+                next: addr + 2*halfwords as usize,
+                count: 0,
+                progress: 0,
+            };
+            cycles
         }
-        Ok(())
     }
-}
 
+    /* The bus is free for the prefetcher */
+    fn idle(&mut self, cycles: u32) {
+        self.prefetch_step(cycles);
+    }
+
+    /* Decoded instructions from ROM and work RAM come out of the cache, everything else is decoded every time */
+    fn decoded_arm(&mut self, addr: usize) -> ArmInst {
+        if let Some(inst) = self.icache.arm(addr) {
+            return inst
+        }
+        let inst = ArmInst::new(self.load(addr, Width::Word));
+        if Self::cacheable(addr) {
+            self.icache.insert_arm(addr, inst);
+        }
+        inst
+    }
+
+    fn decoded_thumb(&mut self, addr: usize) -> ThumbInst {
+        if let Some(inst) = self.icache.thumb(addr) {
+            return inst
+        }
+        let inst = ThumbInst::new(self.load(addr, Width::Half) as u16);
+        if Self::cacheable(addr) {
+            self.icache.insert_thumb(addr, inst);
+        }
+        inst
+    }
+
+    fn opcode(&mut self, addr: usize) -> u32 {
+        if Self::cacheable(addr) {
+            self.decoded_arm(addr).inst
+        } else {
+            self.load(addr, Width::Word)
+        }
+    }
+
+    fn code_generation(&self, addr: usize) -> Option<u32> {
+        if Self::cacheable(addr) {
+            Some(self.icache.generation(addr))
+        } else {
+            None
+        }
+    }
+
+    /* The same regions mem_read knows about */
+    fn mapped(&self, addr: usize) -> bool {
+        matches!(Self::mirror(addr), MEM_START ..= BIOS_END | WRAM0_START ..= WRAM0_END | WRAM1_START ..= WRAM1_END | IO_START ..= IO_END
+            | OBJ_START ..= OBJ_END | VRAM_START ..= VRAM_END | OAM_START ..= OAM_END | GPK0_START ..= GPK2_END | GPKSRAM_START ..= GPKSRAM_END)
+    }
+}
@@ -0,0 +1,128 @@
+use std::collections::HashMap;
+use std::hash::{ BuildHasherDefault, Hasher };
+
+use crate::decode::{ ArmInst, ThumbInst };
+
+/*
+The decoded instruction cache.
+
+Decoding an instruction (pattern matching its type and pulling out every field) costs more than running most of them, so the
+decoded form of each instruction is kept around, keyed by its address. Memory is split into pages, which are only created once
+something in them has been run, and a write anywhere in a page throws the whole page away so code that changes itself (or code
+that gets loaded over the top of old code) is decoded again.
+
+ARM and Thumb instructions are kept separately, as the same address can be run in either state.
+*/
+
+const PAGE_BITS: usize = 10;                    //1K pages
+const PAGE_MASK: usize = (1 << PAGE_BITS) - 1;
+
+/*
+The default hasher is built to stand up to keys chosen by an attacker, which addresses aren't, and is slow enough to show up when
+looking something up for every block that runs. This just spreads the bits of the key out with a multiply.
+*/
+#[derive(Clone, Copy, Debug, Default)]
+pub struct AddrHasher(u64);
+
+impl Hasher for AddrHasher {
+    fn finish(&self) -> u64 {
+        self.0
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.write_u64(*byte as u64);
+        }
+    }
+
+    fn write_u64(&mut self, value: u64) {
+        self.0 = (self.0.rotate_left(5) ^ value).wrapping_mul(0x517CC1B727220A95);
+    }
+
+    fn write_u32(&mut self, value: u32) {
+        self.write_u64(value as u64);
+    }
+
+    fn write_usize(&mut self, value: usize) {
+        self.write_u64(value as u64);
+    }
+
+    fn write_u8(&mut self, value: u8) {
+        self.write_u64(value as u64);
+    }
+}
+
+/* A HashMap keyed by addresses */
+pub type AddrMap<K, V> = HashMap<K, V, BuildHasherDefault<AddrHasher>>;
+
+#[derive(Clone, Debug)]
+struct Page {
+    arm: Box<[Option<ArmInst>]>,     //one for each word
+    thumb: Box<[Option<ThumbInst>]>, //one for each halfword
+}
+
+impl Page {
+    fn new() -> Self {
+        Page {
+            arm: vec![None; (PAGE_MASK + 1) >> 2].into_boxed_slice(),
+            thumb: vec![None; (PAGE_MASK + 1) >> 1].into_boxed_slice(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct InstCache {
+    pages: AddrMap<usize, Page>,
+    generations: AddrMap<usize, u32>,   //how many times each page has been thrown away, for anything built from decoded code
+}
+
+#[allow(dead_code)]
+impl InstCache {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /* The decoded ARM instruction at addr, if it has been cached */
+    pub fn arm(&self, addr: usize) -> Option<ArmInst> {
+        self.pages.get(&(addr >> PAGE_BITS)).and_then(|page| page.arm[(addr & PAGE_MASK) >> 2])
+    }
+
+    pub fn insert_arm(&mut self, addr: usize, inst: ArmInst) {
+        let page = self.pages.entry(addr >> PAGE_BITS).or_insert_with(Page::new);
+        page.arm[(addr & PAGE_MASK) >> 2] = Some(inst);
+    }
+
+    /* The decoded Thumb instruction at addr, if it has been cached */
+    pub fn thumb(&self, addr: usize) -> Option<ThumbInst> {
+        self.pages.get(&(addr >> PAGE_BITS)).and_then(|page| page.thumb[(addr & PAGE_MASK) >> 1])
+    }
+
+    pub fn insert_thumb(&mut self, addr: usize, inst: ThumbInst) {
+        let page = self.pages.entry(addr >> PAGE_BITS).or_insert_with(Page::new);
+        page.thumb[(addr & PAGE_MASK) >> 1] = Some(inst);
+    }
+
+    /* Something was written to addr, so anything decoded from its page can't be trusted any more */
+    pub fn invalidate(&mut self, addr: usize) {
+        if !self.pages.is_empty() && self.pages.remove(&(addr >> PAGE_BITS)).is_some() {
+            *self.generations.entry(addr >> PAGE_BITS).or_default() += 1;
+        }
+    }
+
+    /* Changes whenever the code in the page containing addr is written over */
+    pub fn generation(&self, addr: usize) -> u32 {
+        self.generations.get(&(addr >> PAGE_BITS)).copied().unwrap_or(0)
+    }
+
+    /* Whether there is anything cached in the page containing addr */
+    pub fn contains(&self, addr: usize) -> bool {
+        self.pages.contains_key(&(addr >> PAGE_BITS))
+    }
+
+    pub fn clear(&mut self) {
+        for page in self.pages.keys() {
+            *self.generations.entry(*page).or_default() += 1;
+        }
+        self.pages.clear();
+    }
+}
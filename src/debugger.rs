@@ -0,0 +1,322 @@
+use std::collections::BTreeSet;
+use std::fmt;
+
+use crate::arm7tdmi::Core;
+use crate::decode::{ ArmInst, ThumbInst };
+use crate::memory::{ Memory, Cycle, Width };
+use crate::recomp::Recompiler;
+
+/*
+Running the core under a debugger: an instruction at a time, or until it reaches a breakpoint or touches a watched address.
+
+Watchpoints are done by wrapping the memory, the CPU only ever goes through the Memory trait so every data access it makes is
+seen on the way past. Opcode fetches aren't, so a read watchpoint only goes off for loads, and execute watchpoints are checked
+against R15 between instructions instead. Instructions are run by the block recompiler with blocks of one instruction, so it can
+stop between any two of them.
+*/
+
+#[allow(dead_code)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum WatchKind {
+    Read,
+    Write,
+    Access,     //either
+    Execute,
+}
+
+/* A range of addresses to watch, value only lets through accesses of that value (what was read or written, at its width) */
+#[allow(dead_code)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Watchpoint {
+    pub kind: WatchKind,
+    pub addr: u32,
+    pub len: u32,
+    pub value: Option<u32>,
+}
+
+#[allow(dead_code)]
+impl Watchpoint {
+    /*
+    KIND:START[-END][=VALUE], e.g. write:0x03001234=0. KIND is read, write, access or exec, END isn't watched and is
+    START+1 if it's left out. Numbers are decimal or hex with 0x in front
+    */
+    pub fn parse(text: &str) -> Option<Watchpoint> {
+        let number = |text: &str| match text.strip_prefix("0x").or(text.strip_prefix("0X")) {
+            Some(hex) => u32::from_str_radix(hex, 16).ok(),
+            None => text.parse().ok(),
+        };
+        let (kind, rest) = text.split_once(':')?;
+        let kind = match kind {
+            "read" | "r" => WatchKind::Read,
+            "write" | "w" => WatchKind::Write,
+            "access" | "rw" => WatchKind::Access,
+            "exec" | "x" => WatchKind::Execute,
+            _ => return None,
+        };
+        let (range, value) = match rest.split_once('=') {
+            Some((range, value)) => (range, Some(number(value)?)),
+            None => (rest, None),
+        };
+        let (addr, len) = match range.split_once('-') {
+            Some((start, end)) => (number(start)?, number(end)?.checked_sub(number(start)?).filter(|&len| len > 0)?),
+            None => (number(range)?, 1),
+        };
+        Some(Watchpoint { kind, addr, len, value })
+    }
+
+    fn contains(&self, addr: u32, size: u32) -> bool {
+        addr < self.addr.wrapping_add(self.len) && self.addr < addr.wrapping_add(size)
+    }
+
+    fn hit(&self, addr: u32, width: Width, write: bool, value: u32) -> bool {
+        let size = match width {Width::Byte => 1, Width::Half => 2, Width::Word => 4};
+        let kind = match self.kind {
+            WatchKind::Read => !write,
+            WatchKind::Write => write,
+            WatchKind::Access => true,
+            WatchKind::Execute => false,
+        };
+        kind && self.contains(addr, size) && self.value.is_none_or(|wanted| wanted == value)
+    }
+}
+
+impl fmt::Display for Watchpoint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let kind = match self.kind {WatchKind::Read => "read", WatchKind::Write => "write", WatchKind::Access => "access", WatchKind::Execute => "exec"};
+        write!(f, "{}:0x{:08X}", kind, self.addr)?;
+        if self.len != 1 {
+            write!(f, "-0x{:08X}", self.addr.wrapping_add(self.len))?;
+        }
+        if let Some(value) = self.value {
+            write!(f, "=0x{:X}", value)?;
+        }
+        Ok(())
+    }
+}
+
+/* An access that set off a watchpoint, pc is the instruction that made it */
+#[allow(dead_code)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Hit {
+    pub kind: WatchKind,
+    pub addr: u32,
+    pub value: u32,
+    pub write: bool,
+    pub pc: u32,
+}
+
+impl fmt::Display for Hit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.kind {
+            WatchKind::Execute => write!(f, "Execute watchpoint at 0x{:08X}", self.addr),
+            _ => write!(f, "{:?} watchpoint: 0x{:X} {} 0x{:08X} by the instruction at 0x{:08X}",
+                self.kind, self.value, if self.write {"written to"} else {"read from"}, self.addr, self.pc),
+        }
+    }
+}
+
+/*
+A memory with watchpoints on it, the first access to hit one since it was last cleared is kept in hit. pc should be set to the
+instruction about to run, so the hit can say which one it was. Only the accesses the CPU makes (read and write) are watched, load and
+store are left for the emulator's own look at the interrupt registers and the BIOS calls run natively
+*/
+#[allow(dead_code)]
+pub struct Watched<M: Memory> {
+    pub bus: M,
+    pub watchpoints: Vec<Watchpoint>,
+    pub hit: Option<Hit>,
+    pub pc: u32,
+}
+
+#[allow(dead_code)]
+impl<M: Memory> Watched<M> {
+    pub fn new(bus: M) -> Self {
+        Watched {
+            bus,
+            watchpoints: Vec::new(),
+            hit: None,
+            pc: 0,
+        }
+    }
+
+    fn check(&mut self, addr: usize, width: Width, write: bool, value: u32) {
+        if self.hit.is_none() {
+            let addr = addr as u32;
+            self.hit = self.watchpoints.iter().find(|watch| watch.hit(addr, width, write, value))
+                .map(|watch| Hit { kind: watch.kind, addr, value, write, pc: self.pc });
+        }
+    }
+
+    /* The execute watchpoint the instruction at pc is in, if there is one */
+    pub fn executes(&self, pc: u32) -> Option<Hit> {
+        self.watchpoints.iter().find(|watch| watch.kind == WatchKind::Execute && watch.contains(pc, 1))
+            .map(|_| Hit { kind: WatchKind::Execute, addr: pc, value: 0, write: false, pc })
+    }
+}
+
+/* What an access of width moves */
+fn masked(width: Width, data: u32) -> u32 {
+    match width {
+        Width::Byte => data & 0xFF,
+        Width::Half => data & 0xFFFF,
+        Width::Word => data,
+    }
+}
+
+impl<M: Memory> Memory for Watched<M> {
+    fn mem_read(&mut self, addr: usize) -> u8 {
+        self.bus.mem_read(addr)
+    }
+
+    fn mem_write(&mut self, addr: usize, data: u8) {
+        self.bus.mem_write(addr, data)
+    }
+
+    fn mem_read_16(&mut self, addr: usize) -> u16 {
+        self.bus.mem_read_16(addr)
+    }
+
+    fn mem_read_32(&mut self, addr: usize) -> u32 {
+        self.bus.mem_read_32(addr)
+    }
+
+    fn mem_write_16(&mut self, addr: usize, data: u16) {
+        self.bus.mem_write_16(addr, data)
+    }
+
+    fn mem_write_32(&mut self, addr: usize, data: u32) {
+        self.bus.mem_write_32(addr, data)
+    }
+
+    fn load(&mut self, addr: usize, width: Width) -> u32 {
+        self.bus.load(addr, width)
+    }
+
+    fn store(&mut self, addr: usize, width: Width, data: u32) {
+        self.bus.store(addr, width, data)
+    }
+
+    fn access_cycles(&self, addr: usize, width: Width, cycle: Cycle) -> u32 {
+        self.bus.access_cycles(addr, width, cycle)
+    }
+
+    fn read(&mut self, addr: usize, width: Width, cycle: Cycle) -> (u32, u32) {
+        let (data, cycles) = self.bus.read(addr, width, cycle);
+        self.check(addr, width, false, data);
+        (data, cycles)
+    }
+
+    fn write(&mut self, addr: usize, width: Width, cycle: Cycle, data: u32) -> u32 {
+        self.check(addr, width, true, masked(width, data));
+        self.bus.write(addr, width, cycle, data)
+    }
+
+    fn fetch(&mut self, addr: usize, width: Width, cycle: Cycle) -> (u32, u32) {
+        self.bus.fetch(addr, width, cycle)
+    }
+
+    fn fetch_cycles(&mut self, addr: usize, width: Width, cycle: Cycle) -> u32 {
+        self.bus.fetch_cycles(addr, width, cycle)
+    }
+
+    fn idle(&mut self, cycles: u32) {
+        self.bus.idle(cycles)
+    }
+
+    fn decoded_arm(&mut self, addr: usize) -> ArmInst {
+        self.bus.decoded_arm(addr)
+    }
+
+    fn decoded_thumb(&mut self, addr: usize) -> ThumbInst {
+        self.bus.decoded_thumb(addr)
+    }
+
+    fn opcode(&mut self, addr: usize) -> u32 {
+        self.bus.opcode(addr)
+    }
+
+    fn code_generation(&self, addr: usize) -> Option<u32> {
+        self.bus.code_generation(addr)
+    }
+
+    fn mapped(&self, addr: usize) -> bool {
+        self.bus.mapped(addr)
+    }
+}
+
+/* Why the core stopped */
+#[allow(dead_code)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Stop {
+    Stepped,
+    Breakpoint(u32),
+    Watchpoint(Hit),                //R15 is the instruction after the one that did it, or the one to be run for Execute
+    Interrupted,                    //stopped for the caller, a Ctrl-C or whatever it was running until
+}
+
+#[allow(dead_code)]
+pub struct Debugger<M: Memory + 'static> {
+    pub breakpoints: BTreeSet<u32>,
+    pub cycles: u64,                //run so far
+    recompiler: Recompiler<Watched<M>>,
+}
+
+impl<M: Memory + 'static> Default for Debugger<M> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[allow(dead_code)]
+impl<M: Memory + 'static> Debugger<M> {
+    pub fn new() -> Self {
+        Debugger {
+            breakpoints: BTreeSet::new(),
+            cycles: 0,
+            recompiler: Recompiler::with_max_block(1),
+        }
+    }
+
+    /* Run one instruction, returning the number of cycles it took and whether it hit a watchpoint */
+    pub fn step(&mut self, core: &mut Core, bus: &mut Watched<M>) -> (u32, Stop) {
+        bus.hit = None;
+        bus.pc = core.reg.gp[15];
+        let cycles = self.recompiler.step(core, bus);
+        self.cycles += cycles as u64;
+        match bus.hit.take() {
+            Some(hit) => (cycles, Stop::Watchpoint(hit)),
+            None => (cycles, Stop::Stepped),
+        }
+    }
+
+    /*
+    Run until a breakpoint or watchpoint, the instruction at R15 always runs even if there's a breakpoint (or execute
+    watchpoint) on it. interrupted is
+    asked every so often whether to stop anyway (e.g. Ctrl-C in the debugger)
+    */
+    pub fn run(&mut self, core: &mut Core, bus: &mut Watched<M>, mut interrupted: impl FnMut() -> bool) -> Stop {
+        let mut count: u32 = 0;
+        self.run_until(core, bus, |_, _| {
+            count = count.wrapping_add(1);
+            count.is_multiple_of(1024) && interrupted()
+        })
+    }
+
+    /* The same, but done is asked after every instruction, with the cycles run so far. It stops with Interrupted */
+    pub fn run_until(&mut self, core: &mut Core, bus: &mut Watched<M>, mut done: impl FnMut(&Core, u64) -> bool) -> Stop {
+        loop {
+            if let (_, Stop::Watchpoint(hit)) = self.step(core, bus) {
+                return Stop::Watchpoint(hit);
+            }
+            if self.breakpoints.contains(&core.reg.gp[15]) {
+                return Stop::Breakpoint(core.reg.gp[15]);
+            }
+            if let Some(hit) = bus.executes(core.reg.gp[15]) {
+                return Stop::Watchpoint(hit);
+            }
+            if done(core, self.cycles) {
+                return Stop::Interrupted;
+            }
+        }
+    }
+}
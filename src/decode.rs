@@ -1,5 +1,6 @@
 use bitpat::bitpat;
 use std::fmt;
+use std::collections::HashMap;
 
 /*
 This document provides the method for classifying instructions based on their type (pattern-matching) and the various enums and functions associated with this process.
@@ -78,9 +79,9 @@ pub enum ThumbInstType {
 #[allow(dead_code)]
 pub fn decode_arm(inst: u32) -> ArmInstType {
     if bitpat!( _ _ _ _ 0 0 0 1 0 0 1 0 1 1 1 1 1 1 1 1 1 1 1 1 0 0 0 1 _ _ _ _ )(inst) {ArmInstType::BranchAndExchange}                       else
-    if bitpat!( _ _ _ _ 0 0 0 1 0 _ 0 0 1 1 1 1 _ _ _ _ 0 0 0 0 0 0 0 0 0 0 0 0 )(inst) {ArmInstType::PSRTransfer}                             else
-    if bitpat!( _ _ _ _ 0 0 0 1 0 _ 1 0 1 0 0 1 1 1 1 1 0 0 0 0 0 0 0 0 _ _ _ _ )(inst) {ArmInstType::PSRTransfer}                             else
-    if bitpat!( _ _ _ _ 0 0 _ 1 0 _ 1 0 1 0 0 0 1 1 1 1 _ _ _ _ _ _ _ _ _ _ _ _ )(inst) {ArmInstType::PSRTransfer}                             else
+    if bitpat!( _ _ _ _ 0 0 0 1 0 _ 0 0 1 1 1 1 _ _ _ _ 0 0 0 0 0 0 0 0 0 0 0 0 )(inst) ||
+       bitpat!( _ _ _ _ 0 0 0 1 0 _ 1 0 _ _ _ _ 1 1 1 1 0 0 0 0 0 0 0 0 _ _ _ _ )(inst) ||
+       bitpat!( _ _ _ _ 0 0 1 1 0 _ 1 0 _ _ _ _ 1 1 1 1 _ _ _ _ _ _ _ _ _ _ _ _ )(inst) {ArmInstType::PSRTransfer}                             else
     if bitpat!( _ _ _ _ 0 0 0 1 0 _ 0 0 _ _ _ _ _ _ _ _ 0 0 0 0 1 0 0 1 _ _ _ _ )(inst) {ArmInstType::SingleDataSwap}                          else
     if bitpat!( _ _ _ _ 0 0 0 0 0 0 _ _ _ _ _ _ _ _ _ _ _ _ _ _ 1 0 0 1 _ _ _ _ )(inst) {ArmInstType::Multiply}                                else
     if bitpat!( _ _ _ _ 0 0 0 0 1 _ _ _ _ _ _ _ _ _ _ _ _ _ _ _ 1 0 0 1 _ _ _ _ )(inst) {ArmInstType::MultiplyLong}                            else
@@ -95,6 +96,52 @@ pub fn decode_arm(inst: u32) -> ArmInstType {
     {ArmInstType::Undefined} // if nothing matches
 }
 
+/* The index into a 4096 entry table of ARM instructions, bits 27-20 followed by bits 7-4 */
+pub const fn arm_lut_index(inst: u32) -> usize {
+    (((inst >> 16) & 0xFF0) | ((inst >> 4) & 0xF)) as usize
+}
+
+/*
+Classify an instruction from just the bits used by arm_lut_index, so it can be done once for each table entry.
+These bits are enough to tell every instruction type apart, the rest of the bits only need checking for encodings that aren't
+valid anyway. Coprocessor instructions are undefined as the GBA doesn't have any coprocessors.
+*/
+#[allow(dead_code)]
+pub const fn decode_arm_lut(index: usize) -> ArmInstType {
+    let hi = index >> 4;    //bits 27-20
+    let lo = index & 0xF;   //bits 7-4
+
+    match hi >> 5 {
+        0b000 => {
+            if lo == 0b1001 {
+                if hi & 0xFC == 0x00 {ArmInstType::Multiply}
+                else if hi & 0xF8 == 0x08 {ArmInstType::MultiplyLong}
+                else if hi & 0xFB == 0x10 {ArmInstType::SingleDataSwap}
+                else {ArmInstType::Undefined}
+            } else if lo & 0b1001 == 0b1001 {
+                if hi & 0x04 == 0 {ArmInstType::HalfwordDataTransferRegisterOffset} else {ArmInstType::HalfwordDataTransferImmediateOffset}
+            } else if hi & 0xF9 == 0x10 {   //the test instructions without S set
+                if lo == 0 {ArmInstType::PSRTransfer}
+                else if hi == 0x12 && lo == 0b0001 {ArmInstType::BranchAndExchange}
+                else {ArmInstType::Undefined}
+            } else {
+                ArmInstType::DataProcessing
+            }
+        },
+        0b001 => {
+            if hi & 0xFB == 0x32 {ArmInstType::PSRTransfer}
+            else if hi & 0xFB == 0x30 {ArmInstType::Undefined}
+            else {ArmInstType::DataProcessing}
+        },
+        0b010 => ArmInstType::SingleDataTransfer,
+        0b011 => if lo & 0b1 == 0b1 {ArmInstType::Undefined} else {ArmInstType::SingleDataTransfer},
+        0b100 => ArmInstType::BlockDataTransfer,
+        0b101 => ArmInstType::Branch,
+        0b111 if hi & 0x10 != 0 => ArmInstType::SoftwareInterrupt,
+        _ => ArmInstType::Undefined,
+    }
+}
+
 /* Same as above, these are not in the order they appear */
 #[allow(dead_code)]
 pub fn decode_thumb(inst: u16) -> ThumbInstType {
@@ -120,13 +167,135 @@ pub fn decode_thumb(inst: u16) -> ThumbInstType {
     {ThumbInstType::Undefined} // if nothing matches
 }
 
+/* Which PSR transfer an instruction is: 0 is MRS, 1 is MSR from a register and 2 is MSR from an immediate */
 #[allow(dead_code)]
 pub fn decode_psr_transfer(inst: u32) -> u32 {
     if bitpat!( _ _ _ _ 0 0 0 1 0 _ 0 0 1 1 1 1 _ _ _ _ 0 0 0 0 0 0 0 0 0 0 0 0 )(inst) {0} else
-    if bitpat!( _ _ _ _ 0 0 0 1 0 _ 1 0 1 0 0 1 1 1 1 1 0 0 0 0 0 0 0 0 _ _ _ _ )(inst) {1} else
+    if bitpat!( _ _ _ _ 0 0 0 1 0 _ 1 0 _ _ _ _ 1 1 1 1 0 0 0 0 0 0 0 0 _ _ _ _ )(inst) {1} else
     {2}
 }
 
+/*
+An ARM instruction with its type and fields already pulled out, so they only have to be worked out once for each instruction
+instead of on every cycle it runs for. The fields are named the same as where they are used in exec::step_arm.
+*/
+#[allow(dead_code)]
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct ArmInst {
+    pub inst: u32,
+    pub insttype: ArmInstType,
+    pub psrop: u32,     //decode_psr_transfer, only for PSR transfers
+    pub cond: u32,
+    pub i: u32,
+    pub opcode: u32,
+    pub l: u32,
+    pub rn: u32,
+    pub rd: u32,
+    pub shift: u32,
+    pub rm: u32,
+    pub rs: u32,
+    pub imm: u32,
+    pub a: u32,
+    pub b: u32,
+    pub u: u32,
+    pub p: u32,
+    pub s: u32,
+    pub h: u32,
+    pub off: u32,
+    pub rlist: u32,
+    pub cp: u32,
+    pub boff: u32,
+}
+
+#[allow(dead_code)]
+impl ArmInst {
+    pub fn new(inst: u32) -> Self {
+        let insttype = decode_arm(inst);
+        ArmInst {
+            inst,
+            insttype,
+            psrop: if insttype == ArmInstType::PSRTransfer {decode_psr_transfer(inst)} else {0},
+            cond: (inst & 0xF0000000) >> 28,
+            i: (inst & 0x2000000) >> 25,
+            opcode: (inst & 0x1E00000) >> 21,
+            l: (inst & 0x100000) >> 20,
+            rn: (inst & 0xF0000) >> 16,
+            rd: (inst & 0xF000) >> 12,
+            shift: (inst & 0xFF0) >> 4,
+            rm: inst & 0x0000000F,
+            rs: (inst & 0xF00) >> 8,
+            imm: inst & 0x000000FF,
+            a: (inst & 0x200000) >> 21,
+            b: (inst & 0x400000) >> 22,
+            u: (inst & 0x800000) >> 23,
+            p: (inst & 0x1000000) >> 24,
+            s: (inst & 0x40) >> 6,
+            h: (inst & 0x20) >> 5,
+            off: inst & 0x00000FFF,
+            rlist: inst & 0x0000FFFF,
+            cp: (inst & 0xD) >> 5,
+            boff: inst & 0x00FFFFFF,
+        }
+    }
+}
+
+/* A Thumb instruction with its type, and the ARM instruction it runs as (if there is one) already decoded */
+#[allow(dead_code)]
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct ThumbInst {
+    pub inst: u16,
+    pub insttype: ThumbInstType,
+    pub arm: Option<ArmInst>,
+}
+
+#[allow(dead_code)]
+impl ThumbInst {
+    pub fn new(inst: u16) -> Self {
+        ThumbInst {
+            inst,
+            insttype: decode_thumb(inst),
+            arm: thumb_equivalent(inst).map(ArmInst::new),
+        }
+    }
+}
+
+/*
+The ARM instruction that does exactly what a Thumb one does, which is what the engines run it as. translate_thumb gives the ARM
+instruction each format is built from, but for a few formats it isn't the same: the shifts by a register, STRH, and the offsets Thumb
+counts in words. Those are put right here. Branches have no equivalent (ARM branch offsets are in words) and neither does adding to
+the word aligned PC, the engines do those themselves. PC relative loads are left as the load for a word aligned PC.
+*/
+#[allow(dead_code)]
+pub fn thumb_equivalent(inst: u16) -> Option<u32> {
+    let inst32 = inst as u32;
+    let (rd0, rs) = (inst32 & 0x7, (inst32 & 0x38) >> 3);
+    let (rd1, off8) = ((inst32 & 0x700) >> 8, inst32 & 0xFF);
+    match decode_thumb(inst) {
+        /* MOVS rd,rd,<shift> rs */
+        ThumbInstType::ALUOperation if matches!((inst32 & 0x3C0) >> 6, 0x2 ..= 0x4 | 0x7) => {
+            let shift = match (inst32 & 0x3C0) >> 6 { 0x2 => 0, 0x3 => 1, 0x4 => 2, _ => 3 };
+            Some(0b11100001101100000000000000010000 | rd0 | (shift << 5) | (rs << 8) | (rd0 << 12))
+        },
+        /* STRH, which needs the H bit set like the loads */
+        ThumbInstType::LoadStoreSignExtendedByteHalfword if inst32 & 0xC00 == 0 => translate_thumb(inst).map(|arm| arm | 0x20),
+        /* Word offsets count in words, byte offsets don't */
+        ThumbInstType::LoadStoreWithImmediateOffset if inst32 & 0x1000 == 0 => {
+            translate_thumb(inst).map(|arm| (arm & !0xFFF) | (((inst32 & 0x7C0) >> 6) << 2))
+        },
+        ThumbInstType::SPRelativeLoadStore => translate_thumb(inst).map(|arm| (arm & !0xFFF) | (off8 << 2)),
+        /* An 8 bit immediate rotated right by 30 is the same as it times 4 */
+        ThumbInstType::LoadAddress if inst32 & 0x800 != 0 => Some(0b11100010100011010000111100000000 | off8 | (rd1 << 12)),
+        ThumbInstType::AddOffsetToStackPointer => {
+            let opcode = if inst32 & 0x80 == 0 {0b0100} else {0b0010};
+            Some(0b11100010000011011101111100000000 | (inst32 & 0x7F) | (opcode << 21))
+        },
+        /* CMP of the PC only sets the flags, where ARM's CMPP with Rd as the PC would restore the CPSR */
+        ThumbInstType::HiRegisterOperationsBranchExchange if inst32 & 0x300 == 0x100 => translate_thumb(inst).map(|arm| arm & !0xF000),
+        ThumbInstType::LoadAddress | ThumbInstType::UnconditionalBranch | ThumbInstType::ConditionalBranch => None,
+        _ => translate_thumb(inst),
+    }
+}
+
 /* Translate a Thumb instruction into it's ARM equivalent */
 #[allow(dead_code)]
 pub fn translate_thumb(inst: u16) -> Option<u32> { /* output is Some(x) if there is an equivalent arm inst, otherwise None */
@@ -257,152 +426,466 @@ pub fn translate_thumb(inst: u16) -> Option<u32> { /* output is Some(x) if there
     }
 }
 
+
 /* Assorted disassembly functions */
 
-fn instcond(inst: u32) -> String { // Instruction condition
-    let cond = inst >> 28;
-    let condition = match cond {
-        0x0 => "EQ",
-        0x1 => "NE",
-        0x2 => "CS",
-        0x3 => "CC",
-        0x4 => "MI",
-        0x5 => "PL",
-        0x6 => "VS",
-        0x7 => "VC",
-        0x8 => "HI",
-        0x9 => "LS",
-        0xA => "GE",
-        0xB => "LT",
-        0xC => "GT",
-        0xD => "LE",
-        0xE => "AL",
-        _   => "UN" 
-    };
-    condition.to_string()
+/* How numbers are written out */
+#[allow(dead_code)]
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum HexStyle {
+    Plain,  //0x1A0
+    Hash,   //#0x1A0
+    Suffix, //1A0h
 }
 
-fn dataop(inst: u32) -> String {
-    let opcode = match (inst >> 21) & 0xF {
-        0x0 => "AND",
-        0x1 => "EOR",
-        0x2 => "SUB",
-        0x3 => "RSB",
-        0x4 => "ADD",
-        0x5 => "ADC",
-        0x6 => "SBC",
-        0x7 => "RSC",
-        0x8 => "TST",
-        0x9 => "TEQ",
-        0xA => "CMP",
-        0xB => "CMN",
-        0xC => "ORR",
-        0xD => "MOV",
-        0xE => "BIC",
-        0xF => "MVN",
-        _   => panic!("Opcode {} does not exist", (inst >> 20) & 0xF)
-    };
-    opcode.to_string()
+/* Where the condition goes, UAL puts it after the other suffixes (ldrbeq, andseq) and pre-UAL straight after the base mnemonic (ldreqb, andeqs) */
+#[allow(dead_code)]
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Syntax {
+    Ual,
+    PreUal,
+}
+
+/* How the disassembler writes instructions, the default is the style of the out.txt listing */
+#[allow(dead_code)]
+#[derive(Debug, Clone)]
+pub struct DisasmFormat {
+    pub syntax: Syntax,
+    pub hex: HexStyle,
+    pub column: usize,                  //width the mnemonic is padded to before the operands
+    pub symbols: HashMap<u32, String>,  //names shown instead of branch targets and PC relative addresses
+}
+
+impl Default for DisasmFormat {
+    fn default() -> Self {
+        DisasmFormat {
+            syntax: Syntax::Ual,
+            hex: HexStyle::Plain,
+            column: 10,
+            symbols: HashMap::new(),
+        }
+    }
+}
+
+#[allow(dead_code)]
+impl DisasmFormat {
+    fn hex(&self, value: u32) -> String {
+        match self.hex {
+            HexStyle::Plain => format!("0x{:X}", value),
+            HexStyle::Hash => format!("#0x{:X}", value),
+            HexStyle::Suffix => {
+                let digits = format!("{:X}", value);
+                /* A leading letter would read as a label */
+                if digits.starts_with(|c: char| c.is_ascii_alphabetic()) {format!("0{}h", digits)} else {format!("{}h", digits)}
+            }
+        }
+    }
+
+    /* An offset that is added or subtracted depending on the U bit */
+    fn offset(&self, up: bool, value: u32) -> String {
+        let hex = self.hex(value);
+        match (up, self.hex) {
+            (true, _) => hex,
+            (false, HexStyle::Hash) => ["#-", &hex[1..]].concat(),
+            (false, _) => ["-", &hex].concat(),
+        }
+    }
+
+    /* Addresses never take a #, and are replaced with a symbol when there is one */
+    fn address(&self, addr: u32) -> String {
+        match self.symbols.get(&addr) {
+            Some(name) => name.clone(),
+            None if self.hex == HexStyle::Hash => format!("0x{:X}", addr),
+            None => self.hex(addr),
+        }
+    }
+
+    fn mnemonic(&self, base: &str, suffix: &str, cond: u32) -> String {
+        match self.syntax {
+            Syntax::Ual => [base, suffix, instcond(cond)].concat(),
+            Syntax::PreUal => [base, instcond(cond), suffix].concat(),
+        }
+    }
+
+    fn line(&self, mnemonic: String, operands: String) -> String {
+        format!("{:<width$}{}", mnemonic, operands, width = self.column.max(mnemonic.len() + 1))
+    }
+
+    /* The register form of the second operand, with its shift */
+    fn shifted(&self, inst: u32) -> String {
+        let rm = reg(inst & 0xF);
+        let kind = ((inst >> 5) & 0b11) as usize;
+        let amount = (inst >> 7) & 0x1F;
+
+        if (inst >> 4) & 0b1 == 1 {
+            format!("{},{} {}", rm, SHIFTS[kind], reg((inst >> 8) & 0xF))
+        } else {
+            match (kind, amount) {
+                (0, 0) => rm.to_string(),
+                (3, 0) => format!("{},rrx", rm),
+                (_, 0) => format!("{},{} {}", rm, SHIFTS[kind], self.hex(32)),
+                _ => format!("{},{} {}", rm, SHIFTS[kind], self.hex(amount)),
+            }
+        }
+    }
+
+    /* The brackets of a load or store, pre-indexed with optional writeback or post-indexed */
+    fn indexed(&self, inst: u32, offset: String) -> String {
+        let rn = reg((inst >> 16) & 0xF);
+        let pre = (inst >> 24) & 0b1 == 1;
+        let writeback = (inst >> 21) & 0b1 == 1;
+
+        if pre {
+            format!("[{},{}]{}", rn, offset, if writeback {"!"} else {""})
+        } else {
+            format!("[{}],{}", rn, offset)
+        }
+    }
+}
+
+const SHIFTS: [&str; 4] = ["lsl", "lsr", "asr", "ror"];
+
+const DATAOPS: [&str; 16] = ["and", "eor", "sub", "rsb", "add", "adc", "sbc", "rsc", "tst", "teq", "cmp", "cmn", "orr", "mov", "bic", "mvn"];
+
+fn instcond(cond: u32) -> &'static str { // Instruction condition
+    match cond & 0xF {
+        0x0 => "eq",
+        0x1 => "ne",
+        0x2 => "cs",
+        0x3 => "cc",
+        0x4 => "mi",
+        0x5 => "pl",
+        0x6 => "vs",
+        0x7 => "vc",
+        0x8 => "hi",
+        0x9 => "ls",
+        0xA => "ge",
+        0xB => "lt",
+        0xC => "gt",
+        0xD => "le",
+        0xE => "",
+        _   => "nv"
+    }
+}
+
+fn reg(r: u32) -> &'static str {
+    const NAMES: [&str; 16] = ["r0", "r1", "r2", "r3", "r4", "r5", "r6", "r7", "r8", "r9", "r10", "r11", "r12", "sp", "lr", "pc"];
+    NAMES[(r & 0xF) as usize]
+}
+
+fn reglist(rlist: u32) -> String {
+    let regs: Vec<&str> = (0..16).filter(|r| (rlist >> r) & 0b1 == 1).map(reg).collect();
+    format!("{{{}}}", regs.join(","))
+}
+
+/* The names of the BIOS functions, which is what the comment field of a SWI picks (None past the last one) */
+#[allow(dead_code)]
+pub fn swi_name(number: u32) -> Option<&'static str> {
+    const NAMES: [&str; 0x2B] = [
+        "SoftReset", "RegisterRamReset", "Halt", "Stop", "IntrWait", "VBlankIntrWait", "Div", "DivArm",
+        "Sqrt", "ArcTan", "ArcTan2", "CpuSet", "CpuFastSet", "GetBiosChecksum", "BgAffineSet", "ObjAffineSet",
+        "BitUnPack", "LZ77UnCompWram", "LZ77UnCompVram", "HuffUnComp", "RLUnCompWram", "RLUnCompVram", "Diff8bitUnFilterWram", "Diff8bitUnFilterVram",
+        "Diff16bitUnFilter", "SoundBias", "SoundDriverInit", "SoundDriverMode", "SoundDriverMain", "SoundDriverVSync", "SoundChannelClear", "MidiKey2Freq",
+        "MusicPlayerOpen", "MusicPlayerStart", "MusicPlayerStop", "MusicPlayerContinue", "MusicPlayerFadeOut", "MultiBoot", "HardReset", "CustomHalt",
+        "SoundDriverVSyncOff", "SoundDriverVSyncOn", "SoundGetJumpList",
+    ];
+    NAMES.get(number as usize).copied()
+}
+
+/* Disassemble an instruction at address pc in the default format */
+#[allow(dead_code)]
+pub fn disassemble_arm(inst: u32, pc: u32) -> String {
+    disassemble_arm_with(inst, pc, &DisasmFormat::default())
 }
 
-pub fn disassemble_arm(inst: u32) -> String {
-    let insttype = decode_arm(inst);
-    let mut cond = instcond(inst);
-    cond = if cond == "AL" {"".to_string()} else {cond};
-    match insttype {
+#[allow(dead_code)]
+pub fn disassemble_arm_with(inst: u32, pc: u32, format: &DisasmFormat) -> String {
+    let cond = inst >> 28;
+    let rn = (inst >> 16) & 0xF;
+    let rd = (inst >> 12) & 0xF;
+    let rs = (inst >> 8) & 0xF;
+    let rm = inst & 0xF;
+    let s = if (inst >> 20) & 0b1 == 1 {"s"} else {""};
+    let load = (inst >> 20) & 0b1 == 1;
+    let up = (inst >> 23) & 0b1 == 1;
+
+    /* Classified the same way the interpreter's table is, so loosely encoded instructions show as what actually runs */
+    let (mnemonic, operands) = match decode_arm_lut(arm_lut_index(inst)) {
         ArmInstType::BranchAndExchange => {
-            return ["BX".to_string(), cond].concat()
+            (format.mnemonic("bx", "", cond), reg(rm).to_string())
         },
         ArmInstType::Branch => {
-            if (inst >> 24) & 0b1 == 1 {
-                return ["BL".to_string(), cond].concat()
-            } else {
-                return ["B".to_string(), cond].concat()
-            }
+            let base = if (inst >> 24) & 0b1 == 1 {"bl"} else {"b"};
+            let target = pc.wrapping_add(8).wrapping_add((((inst << 8) as i32) >> 6) as u32);
+            (format.mnemonic(base, "", cond), format.address(target))
         },
         ArmInstType::DataProcessing => {
-            let opcode = dataop(inst);
-            let s = if (inst >> 20) & 0b1 == 1 {"S"} else {""};
-            
-            if opcode == "CMP" || opcode == "CMN" || opcode == "TEQ" || opcode == "TST" {
-                return [opcode, cond].concat()
+            let opcode = (inst >> 21) & 0xF;
+            let immediate = (inst >> 25) & 0b1 == 1;
+            let operand = if immediate {
+                format.hex((inst & 0xFF).rotate_right(((inst >> 8) & 0xF) * 2))
             } else {
-                return [opcode, cond, s.to_string()].concat()
+                format.shifted(inst)
+            };
+
+            match opcode {
+                /* The test instructions always set the flags so the S is left off */
+                0x8..=0xB => (format.mnemonic(DATAOPS[opcode as usize], "", cond), format!("{},{}", reg(rn), operand)),
+                0xD | 0xF => (format.mnemonic(DATAOPS[opcode as usize], s, cond), format!("{},{}", reg(rd), operand)),
+                /* Adding to or subtracting from the PC is how ARM code gets an address, so show the address */
+                0x2 | 0x4 if immediate && rn == 15 => {
+                    let value = (inst & 0xFF).rotate_right(((inst >> 8) & 0xF) * 2);
+                    let target = if opcode == 0x4 {pc.wrapping_add(8).wrapping_add(value)} else {pc.wrapping_add(8).wrapping_sub(value)};
+                    (format.mnemonic(DATAOPS[opcode as usize], s, cond), format!("{},={}", reg(rd), format.address(target)))
+                },
+                _ => (format.mnemonic(DATAOPS[opcode as usize], s, cond), format!("{},{},{}", reg(rd), reg(rn), operand)),
             }
         },
         ArmInstType::PSRTransfer => {
-            let instruction = if (inst >> 18) & 0b11 == 0b11 {"MSR"} else {"MSR"};
+            let psr = if (inst >> 22) & 0b1 == 1 {"spsr"} else {"cpsr"};
+            let fields: String = [(19, 'f'), (18, 's'), (17, 'x'), (16, 'c')].iter()
+                .filter(|(bit, _)| (inst >> bit) & 0b1 == 1).map(|(_, c)| c).collect();
 
-            return [instruction.to_string(), cond].concat()
+            match ((inst >> 21) & 0b1, (inst >> 25) & 0b1) {
+                (0, _) => (format.mnemonic("mrs", "", cond), format!("{},{}", reg(rd), psr)),
+                (_, 0) => (format.mnemonic("msr", "", cond), format!("{}_{},{}", psr, fields, reg(rm))),
+                _      => (format.mnemonic("msr", "", cond), format!("{}_{},{}", psr, fields, format.hex((inst & 0xFF).rotate_right(((inst >> 8) & 0xF) * 2)))),
+            }
         },
         ArmInstType::Multiply => {
-            let s = if (inst >> 20) & 0b1 == 1 {"S"} else {""};
-            let a = if (inst >> 21) & 0b1 == 1 {"MLA"} else {"MUL"};
-
-            return [a.to_string(), cond, s.to_string()].concat()
+            /* Rd and Rn are the other way round to everywhere else */
+            if (inst >> 21) & 0b1 == 1 {
+                (format.mnemonic("mla", s, cond), format!("{},{},{},{}", reg(rn), reg(rm), reg(rs), reg(rd)))
+            } else {
+                (format.mnemonic("mul", s, cond), format!("{},{},{}", reg(rn), reg(rm), reg(rs)))
+            }
         },
         ArmInstType::MultiplyLong => {
-            let multype = ((inst >> 22) & 0b1, (inst >> 21) & 0b1);
-            let s = if (inst >> 20) & 0b1 == 1 {"S"} else {""};
-
-            let instruction = match multype {
-                (0, 0)  => {"UMULL"},
-                (0, 1)  => {"UMLAL"},
-                (1, 0)  => {"SMULL"},
-                (1, 1)  => {"SMLAL"},
-                _       => panic!("Multiply long instruction {:?} does not exist", multype)
+            let base = match ((inst >> 22) & 0b1, (inst >> 21) & 0b1) {
+                (0, 0) => "umull",
+                (0, _) => "umlal",
+                (_, 0) => "smull",
+                _      => "smlal",
             };
-
-            return [instruction.to_string(), cond, s.to_string()].concat()
+            (format.mnemonic(base, s, cond), format!("{},{},{},{}", reg(rd), reg(rn), reg(rm), reg(rs)))
+        },
+        ArmInstType::SingleDataSwap => {
+            let b = if (inst >> 22) & 0b1 == 1 {"b"} else {""};
+            (format.mnemonic("swp", b, cond), format!("{},{},[{}]", reg(rd), reg(rm), reg(rn)))
         },
         ArmInstType::SingleDataTransfer => {
-            let instruction = if (inst >> 20) & 0b1 == 1 {"LDR"} else {"STR"};
-            let rn = (inst >> 16) & 0xf;
-            let rd = (inst >> 12) & 0xf;
-
-            let t = if (inst >> 21) & 0b1 == 1 {"T"} else {""};
-            let b = if (inst >> 22) & 0b1 == 1 {"B"} else {""};
+            let base = if load {"ldr"} else {"str"};
+            let b = if (inst >> 22) & 0b1 == 1 {"b"} else {""};
+            /* Post-indexing with writeback set is the user mode (translated) transfer */
+            let t = if (inst >> 24) & 0b1 == 0 && (inst >> 21) & 0b1 == 1 {"t"} else {""};
+            let offset = if (inst >> 25) & 0b1 == 0 {
+                format.offset(up, inst & 0xFFF)
+            } else {
+                [if up {""} else {"-"}, &format.shifted(inst)].concat()
+            };
 
-            return [instruction.to_string(), cond, b.to_string(), t.to_string(), " ".to_string(), rd.to_string(), " ".to_string(), rn.to_string()].concat()
+            (format.mnemonic(base, &[b, t].concat(), cond), format!("{},{}", reg(rd), format.indexed(inst, offset)))
         },
         ArmInstType::HalfwordDataTransferRegisterOffset | ArmInstType::HalfwordDataTransferImmediateOffset => {
-            let instruction = if (inst >> 20) & 0b1 == 1 {"LDR"} else {"STR"};
-            let sh = ((inst >> 6) & 0b1, (inst >> 5) & 0b1);
-
-            let transfertype = match sh {
-                (0, 1)  => "H",
-                (1, 0)  => if instruction=="LDR" {"SB"} else {""},
-                (1, 1)  => if instruction=="LDR" {"SH"} else {""},
-                _       => unimplemented!()
+            let base = if load {"ldr"} else {"str"};
+            let suffix = match (inst >> 5) & 0b11 {
+                0b01 => "h",
+                0b10 => "sb",
+                0b11 => "sh",
+                _    => return "Undefined".to_string(),
+            };
+            let offset = if (inst >> 22) & 0b1 == 1 {
+                format.offset(up, ((inst >> 4) & 0xF0) | (inst & 0xF))
+            } else {
+                [if up {""} else {"-"}, reg(rm)].concat()
             };
 
-            return [instruction.to_string(), cond, transfertype.to_string()].concat()
-        }
+            (format.mnemonic(base, suffix, cond), format!("{},{}", reg(rd), format.indexed(inst, offset)))
+        },
         ArmInstType::BlockDataTransfer => {
-            let instruction = if (inst >> 20) & 0b1 == 1 {"LDM"} else {"STM"};
-            let transfertype = ((inst >> 24) & 0b1, (inst >> 23) & 0b1);
-
-            let addr_mode = match transfertype {
-                (1, 1) => "IB",
-                (1, 0) => "IA",
-                (0, 1) => "DB",
-                (0, 0) => "DA",
-                _ => panic!("Addressing mode {:?} does not exist", transfertype)
+            /* Named after the kind of stack each addressing mode makes, full/empty and ascending/descending */
+            let mode = match (load, (inst >> 24) & 0b1 == 1, up) {
+                (true, true, true)    => "ed",
+                (true, false, true)   => "fd",
+                (true, true, false)   => "ea",
+                (true, false, false)  => "fa",
+                (false, true, true)   => "fa",
+                (false, false, true)  => "ea",
+                (false, true, false)  => "fd",
+                (false, false, false) => "ed",
             };
-            
-            return [instruction.to_string(), cond, addr_mode.to_string()].concat()
-        },
-        ArmInstType::SingleDataSwap => {
-            let b = if (inst >> 22) & 0b1 == 1 {"B"} else {""};
-            return ["SWP".to_string(), cond, b.to_string()].concat()
+            let base = if load {"ldm"} else {"stm"};
+            let writeback = if (inst >> 21) & 0b1 == 1 {"!"} else {""};
+            let user = if (inst >> 22) & 0b1 == 1 {"^"} else {""};
+
+            (format.mnemonic(base, mode, cond), format!("{}{},{}{}", reg(rn), writeback, reglist(inst & 0xFFFF), user))
         },
         ArmInstType::SoftwareInterrupt => {
-            return ["SWI".to_string(), cond].concat()
+            /* Without a name the whole comment field is shown, which is what it assembles back from */
+            let comment = swi_name((inst >> 16) & 0xFF).map_or_else(|| format.hex(inst & 0xFFFFFF), str::to_string);
+            (format.mnemonic("swi", "", cond), comment)
         },
-
         ArmInstType::Undefined => {
             return "Undefined".to_string()
         }
-        
-    }
+    };
+
+    format.line(mnemonic, operands)
+}
+
+/* Disassemble a Thumb instruction at address pc in the default format, without looking at the memory around it */
+#[allow(dead_code)]
+pub fn disassemble_thumb(inst: u16, pc: u32) -> String {
+    disassemble_thumb_with(inst, pc, &DisasmFormat::default(), &mut |_| None)
+}
+
+/*
+The same again, with read giving the halfword at an address if it is known. This is used to show the value a PC relative load
+will get, and to join the two halves of a BL into one branch.
+*/
+#[allow(dead_code)]
+pub fn disassemble_thumb_with(inst: u16, pc: u32, format: &DisasmFormat, read: &mut dyn FnMut(u32) -> Option<u16>) -> String {
+    let inst = inst as u32;
+    let rd = inst & 0x7;
+    let rs = (inst >> 3) & 0x7;
+    let rn = (inst >> 6) & 0x7;
+    let r8 = (inst >> 8) & 0x7;
+    /* UAL spells out that these set the flags, the original Thumb syntax left it implied */
+    let s = if format.syntax == Syntax::Ual {"s"} else {""};
+
+    let (mnemonic, operands) = match decode_thumb(inst as u16) {
+        ThumbInstType::MoveShiftedRegister => {
+            let op = ((inst >> 11) & 0b11) as usize;
+            let amount = match (op, (inst >> 6) & 0x1F) {
+                (1 | 2, 0) => 32,
+                (_, amount) => amount,
+            };
+            (format.mnemonic(SHIFTS[op], s, 0xE), format!("{},{},{}", reg(rd), reg(rs), format.hex(amount)))
+        },
+        ThumbInstType::AddSubtract => {
+            let base = if (inst >> 9) & 0b1 == 1 {"sub"} else {"add"};
+            let operand = if (inst >> 10) & 0b1 == 1 {format.hex(rn)} else {reg(rn).to_string()};
+            (format.mnemonic(base, s, 0xE), format!("{},{},{}", reg(rd), reg(rs), operand))
+        },
+        ThumbInstType::MoveCompareAddSubtractImmediate => {
+            let op = (inst >> 11) & 0b11;
+            let base = ["mov", "cmp", "add", "sub"][op as usize];
+            (format.mnemonic(base, if op == 1 {""} else {s}, 0xE), format!("{},{}", reg(r8), format.hex(inst & 0xFF)))
+        },
+        ThumbInstType::ALUOperation => {
+            const ALUOPS: [&str; 16] = ["and", "eor", "lsl", "lsr", "asr", "adc", "sbc", "ror", "tst", "neg", "cmp", "cmn", "orr", "mul", "bic", "mvn"];
+            let op = (inst >> 6) & 0xF;
+            let flags = if matches!(op, 0x8 | 0xA | 0xB) {""} else {s};
+            (format.mnemonic(ALUOPS[op as usize], flags, 0xE), format!("{},{}", reg(rd), reg(rs)))
+        },
+        ThumbInstType::HiRegisterOperationsBranchExchange => {
+            let hd = rd | ((inst >> 4) & 0x8);
+            let hs = (inst >> 3) & 0xF;
+            match (inst >> 8) & 0b11 {
+                0 => (format.mnemonic("add", "", 0xE), format!("{},{}", reg(hd), reg(hs))),
+                1 => (format.mnemonic("cmp", "", 0xE), format!("{},{}", reg(hd), reg(hs))),
+                2 => (format.mnemonic("mov", "", 0xE), format!("{},{}", reg(hd), reg(hs))),
+                _ => (format.mnemonic("bx", "", 0xE), reg(hs).to_string()),
+            }
+        },
+        ThumbInstType::PCRelativeLoad => {
+            /* The PC is read as 4 ahead with bit 1 cleared */
+            let addr = (pc.wrapping_add(4) & !0b11).wrapping_add((inst & 0xFF) << 2);
+            let operands = match (read(addr), read(addr.wrapping_add(2))) {
+                (Some(lo), Some(hi)) => format!("{},[{}] ;={}", reg(r8), format.address(addr), format.hex(lo as u32 | (hi as u32) << 16)),
+                _ => format!("{},[{}]", reg(r8), format.address(addr)),
+            };
+            (format.mnemonic("ldr", "", 0xE), operands)
+        },
+        ThumbInstType::LoadStoreWithRegisterOffset => {
+            let base = if (inst >> 11) & 0b1 == 1 {"ldr"} else {"str"};
+            let b = if (inst >> 10) & 0b1 == 1 {"b"} else {""};
+            (format.mnemonic(base, b, 0xE), format!("{},[{},{}]", reg(rd), reg(rs), reg(rn)))
+        },
+        ThumbInstType::LoadStoreSignExtendedByteHalfword => {
+            let base = match ((inst >> 11) & 0b1, (inst >> 10) & 0b1, format.syntax) {
+                (0, 0, _)               => "strh",
+                (0, _, Syntax::Ual)     => "ldrsb",
+                (0, _, Syntax::PreUal)  => "ldsb",
+                (_, 0, _)               => "ldrh",
+                (_, _, Syntax::Ual)     => "ldrsh",
+                (_, _, Syntax::PreUal)  => "ldsh",
+            };
+            (format.mnemonic(base, "", 0xE), format!("{},[{},{}]", reg(rd), reg(rs), reg(rn)))
+        },
+        ThumbInstType::LoadStoreWithImmediateOffset => {
+            let base = if (inst >> 11) & 0b1 == 1 {"ldr"} else {"str"};
+            let offset = (inst >> 6) & 0x1F;
+            let (b, offset) = if (inst >> 12) & 0b1 == 1 {("b", offset)} else {("", offset << 2)};
+            (format.mnemonic(base, b, 0xE), format!("{},[{},{}]", reg(rd), reg(rs), format.hex(offset)))
+        },
+        ThumbInstType::LoadStoreHalfword => {
+            let base = if (inst >> 11) & 0b1 == 1 {"ldrh"} else {"strh"};
+            (format.mnemonic(base, "", 0xE), format!("{},[{},{}]", reg(rd), reg(rs), format.hex(((inst >> 6) & 0x1F) << 1)))
+        },
+        ThumbInstType::SPRelativeLoadStore => {
+            let base = if (inst >> 11) & 0b1 == 1 {"ldr"} else {"str"};
+            (format.mnemonic(base, "", 0xE), format!("{},[sp,{}]", reg(r8), format.hex((inst & 0xFF) << 2)))
+        },
+        ThumbInstType::LoadAddress => {
+            let offset = (inst & 0xFF) << 2;
+            if (inst >> 11) & 0b1 == 1 {
+                (format.mnemonic("add", "", 0xE), format!("{},sp,{}", reg(r8), format.hex(offset)))
+            } else {
+                let addr = (pc.wrapping_add(4) & !0b11).wrapping_add(offset);
+                (format.mnemonic("add", "", 0xE), format!("{},={}", reg(r8), format.address(addr)))
+            }
+        },
+        ThumbInstType::AddOffsetToStackPointer => {
+            let base = if (inst >> 7) & 0b1 == 1 {"sub"} else {"add"};
+            (format.mnemonic(base, "", 0xE), format!("sp,{}", format.hex((inst & 0x7F) << 2)))
+        },
+        ThumbInstType::PushPopRegisters => {
+            let (base, extra) = if (inst >> 11) & 0b1 == 1 {("pop", 1 << 15)} else {("push", 1 << 14)};
+            let rlist = (inst & 0xFF) | if (inst >> 8) & 0b1 == 1 {extra} else {0};
+            (format.mnemonic(base, "", 0xE), reglist(rlist))
+        },
+        ThumbInstType::MultipleLoadStore => {
+            let base = if (inst >> 11) & 0b1 == 1 {"ldm"} else {"stm"};
+            (format.mnemonic(base, "ia", 0xE), format!("{}!,{}", reg(r8), reglist(inst & 0xFF)))
+        },
+        ThumbInstType::ConditionalBranch => {
+            let cond = (inst >> 8) & 0xF;
+            /* Condition AL here is undefined, NV is the SWI */
+            if cond == 0xE {
+                return "Undefined".to_string()
+            }
+            let target = pc.wrapping_add(4).wrapping_add((((inst << 24) as i32) >> 23) as u32);
+            (format.mnemonic("b", "", cond), format.address(target))
+        },
+        ThumbInstType::SoftwareInterrupt => {
+            let comment = swi_name(inst & 0xFF).map_or_else(|| format.hex(inst & 0xFF), str::to_string);
+            (format.mnemonic("swi", "", 0xE), comment)
+        },
+        ThumbInstType::UnconditionalBranch => {
+            let target = pc.wrapping_add(4).wrapping_add((((inst << 21) as i32) >> 20) as u32);
+            (format.mnemonic("b", "", 0xE), format.address(target))
+        },
+        ThumbInstType::LongBranchWithLink => {
+            let offset = inst & 0x7FF;
+            if (inst >> 11) & 0b1 == 1 {
+                /* The second half on its own, it branches to LR plus the offset */
+                (format.mnemonic("bl", "", 0xE), format!("lr+{}", format.hex(offset << 1)))
+            } else {
+                /* The first half only sets LR up, the branch happens in the half after it */
+                let lr = pc.wrapping_add(4).wrapping_add((((offset << 21) as i32) >> 9) as u32);
+                match read(pc.wrapping_add(2)) {
+                    Some(next) if next >> 11 == 0b11111 => {
+                        (format.mnemonic("bl", "", 0xE), format.address(lr.wrapping_add((next as u32 & 0x7FF) << 1)))
+                    },
+                    _ => (format.mnemonic("add", "", 0xE), format!("lr,={}", format.address(lr))),
+                }
+            }
+        },
+        ThumbInstType::Undefined => {
+            return "Undefined".to_string()
+        }
+    };
+
+    format.line(mnemonic, operands)
 }
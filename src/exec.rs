@@ -1,8 +1,9 @@
 use crate::arm7tdmi;
+use crate::bios;
 use crate::decode;
-use crate::bus;
+use crate::memory::{ Memory, Cycle, Width };
 
-use super::{ decode::ArmInstType };
+use super::{ decode::ArmInstType, decode::ThumbInstType };
 
 /* 
     In a basic sense, because each instruction takes a variable amount of cycles, there is a cycle counter in the core struct which keeps track of the current step.
@@ -18,37 +19,169 @@ use super::{ decode::ArmInstType };
     }
 */
 
+/* Timed bus accesses, every access takes at least the one cycle of the step it happens in, anything over that is a wait state */
+fn bus_read<M: Memory>(core: &mut arm7tdmi::Core, bus: &mut M, addr: u32, width: Width, cycle: Cycle) -> u32 {
+    let (data, cycles) = bus.read(addr as usize, width, cycle);
+    core.waitstates += cycles - 1;
+    data
+}
+
+fn bus_write<M: Memory>(core: &mut arm7tdmi::Core, bus: &mut M, addr: u32, width: Width, cycle: Cycle, data: u32) {
+    let cycles = bus.write(addr as usize, width, cycle, data);
+    core.waitstates += cycles - 1;
+}
+
+fn bus_fetch<M: Memory>(core: &mut arm7tdmi::Core, bus: &mut M, addr: u32, width: Width, cycle: Cycle) -> u32 {
+    let (data, cycles) = bus.fetch(addr as usize, width, cycle);
+    core.waitstates += cycles - 1;
+    data
+}
+
+/* Fetch the instruction at R15 into the pipeline and move R15 on to the one after it (halfwords in Thumb state) */
+fn prefetch<M: Memory>(core: &mut arm7tdmi::Core, bus: &mut M, cycle: Cycle) {
+    let (width, size) = if core.reg.cpsr.state {(Width::Half, 2)} else {(Width::Word, 4)};
+    core.addrbus = core.reg.gp[15];
+    core.instbus = bus_fetch(core, bus, core.addrbus, width, cycle);
+    core.fetch();
+    core.reg.gp[15] = core.reg.gp[15].wrapping_add(size);
+}
+
+/* R15 has been written, everything in the pipeline is thrown away and it starts again from the new address with a non-sequential fetch */
+fn flush<M: Memory>(core: &mut arm7tdmi::Core, bus: &mut M) {
+    core.reg.gp[15] &= if core.reg.cpsr.state {!0b1} else {!0b11};
+    prefetch(core, bus, Cycle::N);
+}
+
+/*
+Run the next instruction through the cycle-stepped core from start to finish, returning how many cycles it took.
+
+Between instructions R15 holds the address of the next instruction, which is already sitting in the pipeline. While it runs R15 is
+the address being fetched: 8 ahead in ARM state, then 12 once the prefetch in its first cycle has happened (4 then 6 in Thumb).
+If R15 was moved from outside (on reset, or by another engine) the pipeline is refilled first, which costs the two fetches.
+
+Thumb instructions run as the ARM instruction that does the same (see decode::thumb_equivalent), the rest are done by step_thumb.
+*/
+#[allow(dead_code)]
+pub fn run_arm<M: Memory>(core: &mut arm7tdmi::Core, bus: &mut M) -> u32 {
+    core.cycle = 0;
+    core.waitstates = 0;
+
+    let size = if core.reg.cpsr.state {2} else {4};
+    let mut refill = 0;
+    if core.reg.prefetched == Some(core.reg.gp[15]) {
+        core.reg.gp[15] = core.reg.gp[15].wrapping_add(2 * size);
+    } else {
+        flush(core, bus);
+        prefetch(core, bus, Cycle::S);
+        refill = 2;
+    }
+
+    /* What was fetched is what runs, even if memory has been written since, so the cached decode is only used if it still matches */
+    let (pc, inst) = (core.reg.gp[15].wrapping_sub(2 * size), core.reg.pipeline[1]);
+    let arm = if core.reg.cpsr.state {
+        let decoded = bus.decoded_thumb(pc as usize);
+        let decoded = if decoded.inst as u32 == inst {decoded} else {decode::ThumbInst::new(inst as u16)};
+        match (decoded.insttype, decoded.arm) {
+            /* PC relative loads see it word aligned, which is 2 less than R15 when it isn't */
+            (ThumbInstType::PCRelativeLoad, Some(arm)) if pc & 0b10 != 0 => Some(decode::ArmInst { off: arm.off.wrapping_sub(2), ..arm }),
+            (_, arm) => arm,
+        }
+    } else {
+        let decoded = bus.decoded_arm(pc as usize);
+        Some(if decoded.inst == inst {decoded} else {decode::ArmInst::new(inst)})
+    };
+
+    let mut state = None;
+    while state.is_none() {
+        state = match arm {
+            Some(ref arm) => step_arm_decoded(core, bus, arm),
+            None => step_thumb(core, bus, inst as u16),
+        };
+        core.cycle += 1;
+    }
+
+    let size = if core.reg.cpsr.state {2} else {4};
+    core.reg.gp[15] = core.reg.gp[15].wrapping_sub(2 * size);
+    core.reg.prefetched = Some(core.reg.gp[15]);
+    refill + core.cycle as u32 + core.waitstates
+}
+
+/*
+Step one of the Thumb instructions that has no ARM equivalent: the branches, each half of BL and adding to the PC. R15 is 4 ahead
+of the instruction, and they take the same cycles as their ARM counterparts (2S+1N for a branch, 1S for the rest).
+*/
+#[allow(dead_code)]
+pub fn step_thumb<M: Memory>(core: &mut arm7tdmi::Core, bus: &mut M, inst: u16) -> Option<bool> {
+    let insttype = decode::decode_thumb(inst);
+    let inst = inst as u32;
+
+    match (insttype, core.cycle) {
+        (ThumbInstType::ConditionalBranch, 0) if !core.cond_codes((inst >> 8) & 0xF) => {
+            prefetch(core, bus, Cycle::S);
+            Some(false)
+        },
+        (ThumbInstType::ConditionalBranch, 0) => {
+            core.alubus = core.reg.read(15).wrapping_add((((inst << 24) as i32) >> 23) as u32);
+            prefetch(core, bus, Cycle::S);
+            None
+        },
+        (ThumbInstType::UnconditionalBranch, 0) => {
+            core.alubus = core.reg.read(15).wrapping_add((((inst << 21) as i32) >> 20) as u32);
+            prefetch(core, bus, Cycle::S);
+            None
+        },
+        /* The first half of BL puts the high part of the offset in LR */
+        (ThumbInstType::LongBranchWithLink, 0) if inst & (1 << 11) == 0 => {
+            let lr = core.reg.read(15).wrapping_add((((inst << 21) as i32) >> 9) as u32);
+            core.reg.write(14, lr);
+            prefetch(core, bus, Cycle::S);
+            Some(true)
+        },
+        /* And the second adds the low part, with the return address (and the Thumb bit) left in LR */
+        (ThumbInstType::LongBranchWithLink, 0) => {
+            core.alubus = core.reg.read(14).wrapping_add((inst & 0x7FF) << 1);
+            core.datareg = core.reg.read(15).wrapping_sub(2) | 0b1;
+            prefetch(core, bus, Cycle::S);
+            None
+        },
+        (ThumbInstType::LoadAddress, 0) => {
+            let value = (core.reg.read(15) & !0b11).wrapping_add((inst & 0xFF) << 2);
+            core.reg.write(((inst >> 8) & 0x7) as usize, value);
+            prefetch(core, bus, Cycle::S);
+            Some(true)
+        },
+        (ThumbInstType::ConditionalBranch | ThumbInstType::UnconditionalBranch | ThumbInstType::LongBranchWithLink, 1) => {
+            if insttype == ThumbInstType::LongBranchWithLink {
+                core.reg.write(14, core.datareg);
+            }
+            core.reg.gp[15] = core.alubus;
+            flush(core, bus);
+            None
+        },
+        (ThumbInstType::ConditionalBranch | ThumbInstType::UnconditionalBranch | ThumbInstType::LongBranchWithLink, 2) => {
+            prefetch(core, bus, Cycle::S);
+            Some(true)
+        },
+        _   =>  panic!("Thumb instruction {:04X} ({:?}) has no cycle {}", inst, insttype, core.cycle+1)
+    }
+}
+
+/* Step an instruction that hasn't been decoded yet, see step_arm_decoded */
+#[allow(dead_code)]
+pub fn step_arm<M: Memory>(core: &mut arm7tdmi::Core, bus: &mut M, inst: u32) -> Option<bool> {
+    step_arm_decoded(core, bus, &decode::ArmInst::new(inst))
+}
+
 /* Return types:
     Some(true) - instruction is complete (move to next instruction)
     Some(false) - Condition was not met (NOP time)
     None - instruction not complete (move to next cycle)
+
+R15 is expected to be 8 ahead of the instruction as it would be in the pipeline, every instruction prefetches from there in its first cycle.
 */
 #[allow(unused_variables)]
-pub fn step_arm(core: &mut arm7tdmi::Core, bus: &mut bus::Bus, inst: u32) -> Option<bool> {
-    /* I should probably make a struct for this so then I don't have to do it every time */
-    /* That would probably be less dumb lol */
-    let insttype = decode::decode_arm(inst);
-    let cond: u32 = (inst & 0xF0000000) >> 28;
-    let i: u32  = (inst & 0x2000000) >> 25;
-    let opcode: u32 = (inst & 0x1E00000) >> 21;
-    let l: u32 = (inst & 0x100000) >> 20;
-    let rn: u32 = (inst & 0xF0000) >> 16;
-    let rd: u32 = (inst & 0xF000) >> 12;
-    let shift: u32 = (inst & 0xFF0) >> 4;
-    let rm: u32 = inst & 0x0000000F;
-    let rs: u32 = (inst & 0xF00) >> 8;
-    let imm: u32 = inst & 0x000000FF;
-    let a: u32 = (inst & 0x200000) >> 21;
-    let b: u32 = (inst & 0x400000) >> 22;
-    let u: u32 = (inst & 0x800000) >> 23;
-    let p: u32 = (inst & 0x1000000) >> 24;
-    let s: u32 = (inst & 0x40) >> 6;
-    let h: u32 = (inst & 0x20) >> 5;
-    let off: u32 = inst & 0x00000FFF;
-    let rlist: u32 = inst & 0x0000FFFF;
-    let cp: u32 = (inst & 0xD) >> 5;
-    let boff: u32 = inst & 0x00FFFFFF;
-    core.fetch(); //prefetch the next instruction
+pub fn step_arm_decoded<M: Memory>(core: &mut arm7tdmi::Core, bus: &mut M, decoded: &decode::ArmInst) -> Option<bool> {
+    let decode::ArmInst { inst, insttype, psrop, cond, i, opcode, l, rn, rd, shift, rm, rs, imm, a, b, u, p, s, h, off, rlist, cp, boff } = *decoded;
 
     /* If the condition is not met, one cycle is added (one step of this function = one cycle) */
     if core.cond_codes(cond) {
@@ -59,9 +192,11 @@ pub fn step_arm(core: &mut arm7tdmi::Core, bus: &mut bus::Bus, inst: u32) -> Opt
                         // Branch destination and core state is extracted
                         // Prefetch performed from current PC
                         core.abus = core.reg.read(15);
-                        core.barrelbus = ((boff << 2) as i32) as u32; // sign extend offset
+                        core.barrelbus = (((boff << 8) as i32) >> 6) as u32; // sign extend offset
                         core.aluop = 4;
+                        core.setcond = false;
                         core.alu();
+                        prefetch(core, bus, Cycle::S);
                         None
                     },
                     1   =>  {
@@ -69,14 +204,13 @@ pub fn step_arm(core: &mut arm7tdmi::Core, bus: &mut bus::Bus, inst: u32) -> Opt
                             core.reg.write(14, core.addrbus);
                         }
                         // Fetch is performed from branch destination
-                        core.addrbus = core.alubus;
-                        core.databus = bus.mem_read_32(core.addrbus as usize);
-                        core.fetch();
+                        core.reg.gp[15] = core.alubus;
+                        flush(core, bus);
                         None
                     },
                     2   =>  {
                         // Fetch from destination +L, refilling instruction pipeline
-                        core.fetch();
+                        prefetch(core, bus, Cycle::S);
                         // If link, subtract four from r14 to simplify return
                         if p == 1 {
                             core.abus = core.reg.read(14);
@@ -95,196 +229,149 @@ pub fn step_arm(core: &mut arm7tdmi::Core, bus: &mut bus::Bus, inst: u32) -> Opt
                     0   =>  {
                         // Branch destination and core state is extracted
                         // Prefetch performed from current PC
-                        core.fetch();
                         core.abus = core.reg.read(rm as usize);
-                        core.reg.write(15, core.abus);
+                        prefetch(core, bus, Cycle::S);
+                        core.reg.gp[15] = core.abus;
                         core.reg.cpsr.state = (core.abus & 0b1) == 1; //update the processor mode
                         None
                     },
                     1   =>  {
                         // Fetch is performed from the branch destination address using new instruction width
-                        core.fetch();
+                        flush(core, bus);
                         None
                     },
                     2   =>  {
                         // Fetch from destination address plus instruction width (to refill pipeline)
-                        core.fetch();
+                        prefetch(core, bus, Cycle::S);
                         Some(true)
                     }
                     _   =>  panic!("Branch and Exchange instruction does not have more than 3 cycles; Found {}", core.cycle+1)
                 }
             },
             ArmInstType::DataProcessing => {
+                /* A shift by a register takes an internal cycle to read Rs before the operation can happen, by then R15 has moved on */
+                let regshift = i == 0 && (shift & 0b1) == 1;
+                let execute = regshift as u8;
+                let writes = !(0x8..=0xB).contains(&opcode); //not TST, TEQ, CMP, CMN
+
+                if core.cycle == 0 {
+                    if i == 0 {
+                        core.decode_shift(shift);
+                    } else {
+                        core.decode_shift_imm(off);
+                    }
+                    if regshift {
+                        prefetch(core, bus, Cycle::S);
+                    }
+                }
+
                 match core.cycle {
-                    0   =>  {
-                        core.fetch();
+                    c if c == execute   =>  {
+                        if regshift {
+                            bus.idle(1); //internal cycle for the shift
+                        }
                         core.abus = core.reg.read(rn as usize);
-                        if i==0 {
-                            core.bbus = core.reg.read(rm as usize);
-                            core.decode_shift(shift);
-                        } else {
-                            core.bbus = imm;
-                            core.decode_shift_imm(shift);
+                        core.bbus = if i == 0 {core.reg.read(rm as usize)} else {imm};
+                        core.setcond = l == 1;
+                        if !regshift {
+                            prefetch(core, bus, Cycle::S);
                         }
-                    
-                        if core.shiftamnt > 0 {
-                            core.barrel_shift();
-                            core.aluop = opcode as u8;
-                            core.alu();
-                        } else {
-                            core.barrel_shift();
-                            core.aluop = opcode as u8;
-                            core.alu();
+
+                        core.barrel_shift();
+                        core.aluop = opcode as u8;
+                        core.alu();
+                        if writes {
                             core.reg.write(rd as usize, core.alubus);
                         }
-                        
-                        if core.shiftamnt > 0 {
-                            None
-                        }else if rd != 0xF {
+                        if rd == 0xF && l == 1 { //returning from an exception, the SPSR goes back to the CPSR (for TSTP, TEQP, CMPP and CMNP too)
+                            core.reg.transfer_spsr();
+                        }
+
+                        if rd != 0xF || !writes {
                             /* normal end */
                             Some(true)
                         } else {
                             None
                         }
                     },
-                    1   =>  {
-                        if (core.shiftamnt > 0) && (rd == 0xF) {
-                            core.reg.write(rd as usize, core.alubus);
-                            None
-                        } else if rd == 0xF {
-                            core.fetch();
-                            None
-                        } else {
-                            core.reg.write(rd as usize, core.alubus);
-                            Some(true)
-                        }
-                    },
-                    2   =>  {
-                        core.fetch();
-                        if (rd == 0xF) && (core.shiftamnt == 0) {
-                            /* End of dest=pc */
-                            Some(true)
-                        } else {
-                            None
-                        }
+                    c if c == execute + 1   =>  {
+                        flush(core, bus);
+                        None
                     },
-                    3   =>  {
-                        core.fetch();
+                    c if c == execute + 2   =>  {
+                        /* End of dest=pc */
+                        prefetch(core, bus, Cycle::S);
                         Some(true)
-                        //Shift and destination is pc
-                        //end of shift(Rs) and dest=pc
-                    }
+                    },
+                    0   =>  None, //Rs is read for the shift
                     _   =>  panic!("Data processing instructions do not have more than 4 cycles; Found {}", core.cycle+1)
                 }
             },
             ArmInstType::PSRTransfer => {
+                /* Timed like a data operation, but the PC is never involved so it's always a single cycle */
+                prefetch(core, bus, Cycle::S);
+                if psrop == 0 { // MRS
+                    let psr: u32 = core.reg.read_psr(b);
+                    core.reg.write(rd as usize, psr);
+                } else { // MSR, the field mask is in the Rn field
+                    let word: u32 = if i == 0 {   // the value is a register
+                        core.reg.read(rm as usize)
+                    } else {                    // the value is a rotated immediate value
+                        core.bbus = imm;
+                        core.decode_shift_imm(off);
+                        core.barrel_shift();
+                        core.barrelbus
+                    };
+                    core.reg.write_psr(b, rn, word);
+                }
+                Some(true)
+            }
+            ArmInstType::Multiply | ArmInstType::MultiplyLong => {
+                /* Multiplies use the register fields differently: Rd (or RdHi) is bits 16-19, Rn (or RdLo) is 12-15 */
+                let long = insttype == ArmInstType::MultiplyLong;
                 match core.cycle {
                     0 => {
-                        core.fetch();
-                        core.abus = decode::decode_psr_transfer(inst);
-                        if i == 0 {
-                            core.bbus = core.reg.read(rm as usize);
+                        /* Prefetch, and the operands are loaded into the multiplier */
+                        prefetch(core, bus, Cycle::S);
+                        core.setcond = l == 1;
+
+                        let accumulate: u64 = if a == 0 {
+                            0
+                        } else if long {
+                            ((core.reg.read(rn as usize) as u64) << 32) | core.reg.read(rd as usize) as u64
                         } else {
-                            core.bbus = imm;
-                            core.shiftamnt = rs;
-                            core.barrelfunc = 3;
-                        }
+                            core.reg.read(rd as usize) as u64
+                        };
+                        let (rm_val, rs_val) = (core.reg.read(rm as usize), core.reg.read(rs as usize));
+                        core.mul_setup(rm_val, rs_val, accumulate, !long || b == 1);
+
+                        /* Internal cycles on top of the m multiplier cycles: 1 to accumulate, 1 for the upper word */
+                        core.multicycle = a as u8 + long as u8;
                         None
                     },
-                    1 => {
-                        if core.abus==0 {
-                            let psr: u32 = core.reg.read_psr(b);
-                            core.reg.write(rd as usize, psr);
-                        } else if core.abus==1 {
-                            let psr: u32 = core.reg.read(rm as usize);
-                            core.reg.write_psr(b, psr);
+                    _ => {
+                        bus.idle(1);
+                        if !core.mul_done() {
+                            core.mul();
                         } else {
-                            let word: u32 = if i==0 {   // the value is a register
-                                core.reg.read(rm as usize)
-                            } else {                    // the value is a shifted immediate value
-                                core.barrel_shift();
-                                core.barrelbus
-                            };
-                            core.reg.write_psr(b, word);
+                            core.multicycle -= 1;
                         }
-                        None
-                    },
-                    2 => {
-                        Some(true)
-                    }
-                    _   =>  panic!("PSR Transfer instruction do not have more than 3 cycles; Found {}", core.cycle+1)
-                }
-            }
-            ArmInstType::Multiply => {
-                match core.cycle {
-                    0 => {
-                        core.fetch();
-                        /* The only cycle that isn't internal */
-                        /* Tbh this is probably just a fetch, but im gonna make it setup shit */
-                        None
-                    },
-                    1 => {
-                        None
-                    },
-                    2 => {
-                        /* This cycle is repeated depending on how many multiplies are needed */
-                        None
-                    }
-                    3 => {
-                        /* The m+1th cycle (the last for a generic multiply) */
-                        if a==1 { //accumulate
-                            let result: u32 = core.reg.read(rn as usize).wrapping_add(core.reg.read(rs as usize) * core.reg.read(rm as usize));
-                            core.reg.write(rd as usize, result);
-                            None
-                            /* add rn to the calculated value */
-                        } else { //normal multiply
-                            let result: u32 = core.reg.read(rs as usize) * core.reg.read(rm as usize);
 
-                            core.reg.write(rd as usize, result);
+                        if core.mul_done() && core.multicycle == 0 {
+                            let result = core.mul_result(long);
+                            if long {
+                                core.reg.write(rd as usize, result as u32);
+                                core.reg.write(rn as usize, (result >> 32) as u32);
+                            } else {
+                                core.reg.write(rn as usize, result as u32);
+                            }
                             Some(true)
-                        }
-                    },
-                    4 => {
-                        /* The m+2th cycle (the last for a multiply accumulate) */
-                        Some(true)
-                    }
-                    _ => panic!("Multiply instruction does not have more than 5 cycles; Found {}", core.cycle+1)
-                }
-            },
-            ArmInstType::MultiplyLong => {
-                /* This is the exact same as above, but takes an extra step to shift out the extra bits */
-                match core.cycle {
-                    0 => {
-                        None
-                    },
-                    1 => {
-                        None
-                    },
-                    2 => {
-                        /* Same as above, this is the repeated cycle */
-                        None
-                    },
-                    3 => {
-                        None
-                    },
-                    4 => {
-                        None
-                    },
-                    5 => {
-                        if a==1 {
-                            let accumulate: i64 = (core.reg.read(rn as usize)|core.reg.read(rd as usize)) as i64;
-                            let result: i64 = accumulate + (core.reg.read(rs as usize)*core.reg.read(rm as usize)) as i32 as i64;
-                            None
                         } else {
-                            Some(true)
+                            None
                         }
-                    },
-                    6 => {
-                        Some(true)
                     }
-                    _ => panic!("Multiply Long instruction does not have more than 7 cycles; Found {}", core.cycle+1)
                 }
-            }
+            },
             ArmInstType::SingleDataTransfer => {
                 /* This guy has to handle byte and word value types */
                 match core.cycle {
@@ -292,20 +379,21 @@ pub fn step_arm(core: &mut arm7tdmi::Core, bus: &mut bus::Bus, inst: u32) -> Opt
                         core.abus = core.reg.read(rn as usize);
                         core.bbus = 
                         if i == 1 {
-                            core.decode_shift(off&0xFF0);
+                            core.decode_shift(shift);
                             core.reg.read(rm as usize)
                         } else {
-                            core.shiftamnt = 0;
-                            core.barrelfunc = 0;
+                            core.decode_shift(0);
                             off
                         };
 
                         core.barrel_shift();
                         core.aluop = 0b10 << (u & 0b1); /* if u==0 add else sub */
+                        core.setcond = false; //address calculation doesn't touch the flags
                         core.alu();
-                        core.addrbus = core.alubus;
+                        /* A store's prefetch is followed by the non-sequential write, so it's non-sequential too */
+                        prefetch(core, bus, if l == 0 {Cycle::N} else {Cycle::S});
+                        core.addrbus = if p == 1 {core.alubus} else {core.abus}; //post-indexed transfers use the base
                         core.datareg = core.alubus;
-                        println!("Load/Store address: 0x{:08x}", core.alubus);
                         None
                     },
                     1 => {
@@ -314,35 +402,28 @@ pub fn step_arm(core: &mut arm7tdmi::Core, bus: &mut bus::Bus, inst: u32) -> Opt
                               core.reg.write(rn as usize, core.alubus);
                             }
                             if b==1 { //byte or word quantity
-                              bus.mem_write(core.addrbus as usize, core.reg.read(rd as usize) as u8); //byte
+                              let data = core.reg.read(rd as usize);
+                              bus_write(core, bus, core.addrbus, Width::Byte, Cycle::N, data); //byte
                             } else {
-                                bus.mem_write_32(core.addrbus as usize, core.reg.read(rd as usize));   //word
+                                let data = core.reg.read(rd as usize);
+                                bus_write(core, bus, core.addrbus & !0b11, Width::Word, Cycle::N, data);   //word, misaligned is forced into line
                             }
                             Some(true)
                             /* end of store */
                         } else { /* The load instruction */
                             if a==1 || p==0 { //register write-back
-                                println!("Alubus: {:08x}", core.alubus);
                                 core.reg.write(rn as usize, core.alubus);
                             }
                             if b==1 {
-                                core.datareg = bus.mem_read(core.addrbus as usize) as u32; //byte (zero extended)
-                                println!("Addr")
-                            } else {
-                                println!("Datareg before: {:08x}", core.datareg);
-                                core.datareg = bus.mem_read_32(core.addrbus as usize); //word
-                                println!("Addrbus: {:08x}", core.addrbus);
-                                println!("Datareg after: {:08x}", core.datareg);
-                            }
-                            /* End of load unless rn = pc */
-                            if rd == 15 { //source/dest is pc
-                              core.reg.pipeline = [0,0,0];
+                                core.datareg = bus_read(core, bus, core.addrbus, Width::Byte, Cycle::N); //byte (zero extended)
+                            } else { //word, misaligned is rotated
+                                core.datareg = bus_read(core, bus, core.addrbus & !0b11, Width::Word, Cycle::N).rotate_right(8 * (core.addrbus & 0b11));
                             }
                             None
                         }
                     },
                     2 => {
-                        println!("Datareg: 0x{:08x}", core.datareg);
+                        bus.idle(1); //internal cycle to write the register
                         core.reg.write(rd as usize, core.datareg);
                         if rd == 15 {
                             None
@@ -350,8 +431,8 @@ pub fn step_arm(core: &mut arm7tdmi::Core, bus: &mut bus::Bus, inst: u32) -> Opt
                             Some(true)
                         }
                     },
-                    3 => {core.fetch(); None},
-                    4 => {core.fetch(); Some(true)}, //end of load pc
+                    3 => {flush(core, bus); None},
+                    4 => {prefetch(core, bus, Cycle::S); Some(true)}, //end of load pc
                     _ => panic!("Data transfer instruction does not have more than 5 cycles; Found {}", core.cycle+1)
                 }
             },
@@ -362,13 +443,16 @@ pub fn step_arm(core: &mut arm7tdmi::Core, bus: &mut bus::Bus, inst: u32) -> Opt
                         core.bbus = if insttype == ArmInstType::HalfwordDataTransferRegisterOffset {
                             core.reg.read(rm as usize)
                         } else {
-                            (rs >> 4) | rm
+                            (rs << 4) | rm
                         };
                         
-                        core.shiftamnt = 0;
-                        core.barrelfunc = 0;
+                        core.decode_shift(0);
                         core.barrel_shift();
-                        core.aluop = 0b10 << (!u & 0b1);
+                        core.aluop = 0b10 << (u & 0b1);
+                        core.setcond = false;
+                        core.alu();
+                        prefetch(core, bus, if l == 0 {Cycle::N} else {Cycle::S});
+                        core.addrbus = if p == 1 {core.alubus} else {core.abus};
                         None
                     },
                     1 => {
@@ -382,10 +466,10 @@ pub fn step_arm(core: &mut arm7tdmi::Core, bus: &mut bus::Bus, inst: u32) -> Opt
                                 3 => (core.reg.read(rd as usize) as i16) as u16,
                                 _ => panic!("Store operation {} does not exist", (s<<1)|h)
                             };
-                            if h==1 { //Write type
-                                bus.mem_write_16(core.addrbus as usize, source);
+                            if h==1 { //Write type, misaligned is forced into line
+                                bus_write(core, bus, core.addrbus & !0b1, Width::Half, Cycle::N, source as u32);
                             } else {
-                                bus.mem_write(core.addrbus as usize, source as u8);
+                                bus_write(core, bus, core.addrbus, Width::Byte, Cycle::N, source as u32);
                             }
                             Some(true)
                             /* End of store */
@@ -393,123 +477,138 @@ pub fn step_arm(core: &mut arm7tdmi::Core, bus: &mut bus::Bus, inst: u32) -> Opt
                             if a==1 || p==0 {
                                 core.reg.write(rn as usize, core.alubus);
                             }
-                            core.datareg = match (s<<1)|h {
-                                1 => bus.mem_read_16(core.addrbus as usize) as u32,
-                                2 => (bus.mem_read(core.addrbus as usize) as i8) as u32,
-                                3 => (bus.mem_read_16(core.addrbus as usize) as i16) as u32,
+                            core.datareg = match (s<<1)|h { //misaligned LDRH is rotated, misaligned LDRSH only loads the byte
+                                1 => bus_read(core, bus, core.addrbus & !0b1, Width::Half, Cycle::N).rotate_right(8 * (core.addrbus & 0b1)),
+                                3 if core.addrbus & 0b1 == 0 => (bus_read(core, bus, core.addrbus, Width::Half, Cycle::N) as i16) as u32,
+                                2 | 3 => (bus_read(core, bus, core.addrbus, Width::Byte, Cycle::N) as i8) as u32,
                                 _ => panic!("Load operation {} does not exist", ((s<<1)|h))
                             };
-                            if rn == 15 {
-                                core.reg.pipeline = [0,0,0];
-                                None
-                            } else {
-                                Some(true)
-                            }
+                            None
                         }
                     },
                     2 => {
-                        core.reg.write(rn as usize, core.datareg);
-                        None
+                        bus.idle(1); //internal cycle to write the register
+                        core.reg.write(rd as usize, core.datareg);
+                        if rd == 15 {
+                            None
+                        } else {
+                            Some(true)
+                        }
                     },
-                    3 => { core.fetch(); None },
-                    4 => { core.fetch(); None },
+                    3 => { flush(core, bus); None },
+                    4 => { prefetch(core, bus, Cycle::S); Some(true) }, //end of load pc
                     _ => panic!("Halfword data transfer does not have more than 5 cycles; Found {}", core.cycle+1)
                 }
             },
             ArmInstType::BlockDataTransfer => {
+                /* An empty list transfers R15 on its own, but the base moves as if all 16 registers were transferred */
+                let list = if rlist == 0 {0x8000} else {rlist};
+                let count = list.count_ones();
+                /* With S set (the B bit position), LDM with R15 restores the CPSR and anything else uses the User bank */
+                let restore = b == 1 && l == 1 && (list & 0x8000) != 0;
+                let userbank = b == 1 && !restore;
+
                 match core.cycle {
                     0 => {
-                        core.fetch();
-                        core.calc_reg_transfer(rlist);
-                        core.alubus = core.reg.read(rn as usize) - if u==0 {4*core.multicycle as u32} else {0};
-                        core.multicycle -= 1;
+                        core.calc_reg_transfer(list);
+
+                        /* Registers are always transferred lowest first to the lowest address */
+                        let base = core.reg.read(rn as usize);
+                        let size = if rlist == 0 {0x40} else {4*count};
+                        prefetch(core, bus, if l == 0 {Cycle::N} else {Cycle::S});
+                        core.addrbus = match (p, u) {
+                            (0, 1)  =>  base,
+                            (1, 1)  =>  base.wrapping_add(4),
+                            (0, _)  =>  base.wrapping_sub(size).wrapping_add(4),
+                            _       =>  base.wrapping_sub(size),
+                        };
+                        core.alubus = if u == 1 {base.wrapping_add(size)} else {base.wrapping_sub(size)};
                         None
                     },
-                    1 => {
-                        if p==1 {
-                            core.alubus += 4;
-                        }
-                        if l==1 { //load
-                            core.datareg = bus.mem_read_32(core.alubus as usize);
-                            None
-                        } else { //store
-                            if core.multicycle != 0 {
-                                bus.mem_write_32(core.alubus as usize, core.reg.read(core.transferblock[core.multicycle as usize] as usize));
-                                core.multicycle -= 1;
-                                core.cycle -= 1;
-                                if p==0 {
-                                    core.alubus += 4;
-                                }
-                                None
-                            } else {
-                                Some(true)
+                    c if c as u32 <= count => {
+                        let index = core.transferblock[c as usize - 1] as usize;
+                        let cycle = if c == 1 {Cycle::N} else {Cycle::S}; //only the first transfer is non-sequential
+
+                        if l == 1 { //load
+                            let data = bus_read(core, bus, core.addrbus, Width::Word, cycle);
+                            /* Write-back happens first, so a loaded base overwrites it */
+                            if c == 1 && a == 1 {
+                                core.reg.write(rn as usize, core.alubus);
                             }
-                        }
-                    },
-                    2 => {
-                        if core.multicycle != 0 {
-                            if p==1 {
-                                core.alubus += 4;
+                            if userbank {
+                                *core.reg.banked(arm7tdmi::MODE_USR, index) = data;
+                            } else {
+                                core.reg.write(index, data);
                             }
-                            core.reg.write(core.transferblock[core.multicycle as usize] as usize,core.datareg);
-                            core.datareg = bus.mem_read_32(core.alubus as usize);
-                            core.addrbus = core.reg.read(rn as usize);
-                            core.multicycle -= 1;
-                            core.cycle -= 1;
-                            if p==0 {
-                                core.alubus += 4;
+                        } else { //store
+                            let data = if userbank {*core.reg.banked(arm7tdmi::MODE_USR, index)} else {core.reg.read(index)};
+                            bus_write(core, bus, core.addrbus, Width::Word, cycle, data);
+                            /* Write-back happens after the first store, the base is only stored unchanged if it comes first */
+                            if c == 1 && a == 1 {
+                                core.reg.write(rn as usize, core.alubus);
                             }
-                            None
-                        } else if core.transferblock[core.multicycle as usize] == 15 {
-                            core.fetch();
-                            None
+                        }
+                        core.addrbus = core.addrbus.wrapping_add(4);
+
+                        if l == 0 && c as u32 == count {
+                            Some(true)
                         } else {
+                            None
+                        }
+                    },
+                    c if c as u32 == count + 1 => {
+                        bus.idle(1); //internal cycle to write the last register
+                        if restore {
+                            core.reg.transfer_spsr();
+                        }
+                        if list & 0x8000 == 0 {
                             Some(true)
+                        } else {
+                            None
                         }
                     },
-                    3 => {
-                        core.fetch();
+                    c if c as u32 == count + 2 => {
+                        flush(core, bus);
                         None
                     },
-                    4 => {
-                        core.fetch();
+                    c if c as u32 == count + 3 => {
+                        prefetch(core, bus, Cycle::S);
                         Some(true)
-                    }
-                    _ => panic!("Block data transfer does not have more than 4 cycles; Found {}", core.cycle+1)
+                    },
+                    _ => panic!("Block data transfer does not have more than {} cycles; Found {}", count + 4, core.cycle+1)
                 }
             }
             ArmInstType::SingleDataSwap => {
                 match core.cycle {
                     0   =>  {
                         //prefetch and address things
-                        core.fetch();
+                        prefetch(core, bus, Cycle::S);
+                        core.addrbus = core.reg.read(rn as usize);
                         None
                     },
                     1   =>  {
-                        //Data fetched from external memory
-                        core.addrbus = core.reg.read(rn as usize);
-                        /* read from memory: bus.mem_read() */
-                        core.databus = if b == 1 {
-                            bus.mem_read(core.addrbus as usize) as u32
+                        //Data fetched from external memory, a misaligned word is rotated like LDR
+                        core.datareg = if b == 1 {
+                            bus_read(core, bus, core.addrbus, Width::Byte, Cycle::N)
                         } else {
-                            bus.mem_read_32(core.addrbus as usize)
+                            bus_read(core, bus, core.addrbus & !0b11, Width::Word, Cycle::N).rotate_right(8 * (core.addrbus & 0b11))
                         };
-                        core.datareg = core.databus;
                         None
                     },
                     2   =>  {
                         //Contents of source register is written to external memory
                         core.databus = core.reg.read(rm as usize);
                         if b == 1 {
-                            bus.mem_write(core.addrbus as usize, core.databus as u8);
+                            bus_write(core, bus, core.addrbus, Width::Byte, Cycle::N, core.databus);
                         } else {
-                            bus.mem_write_32(core.addrbus as usize, core.databus);
+                            bus_write(core, bus, core.addrbus & !0b11, Width::Word, Cycle::N, core.databus);
                         }
                         None
                     },
                     3   =>  {
                         //Cycle 2 data is written to destination register
-                        core.reg.write(core.addrbus as usize, core.databus);
+                        bus.idle(1);
+                        core.reg.write(rd as usize, core.datareg);
                         Some(true)
                     },
                     _   =>  panic!("Data swap instruction does not have more than 4 cycles; Found {}", core.cycle+1)
@@ -518,21 +617,26 @@ pub fn step_arm(core: &mut arm7tdmi::Core, bus: &mut bus::Bus, inst: u32) -> Opt
             ArmInstType::SoftwareInterrupt => {
                 match core.cycle {
                     0   =>  {
-                        //Forced address is constructed, mode change may take place
-
-                        core.abus = core.reg.read(15);
-                        core.addrbus = 0x0;
-                        core.reg.write(14, core.abus);
-                        core.reg.spsr_svc = core.reg.cpsr;
+                        //Forced address is constructed, mode change takes place with the return address in r14
+                        let link = core.reg.gp[15].wrapping_sub(if core.reg.cpsr.state {2} else {4});
+                        prefetch(core, bus, Cycle::S);
+                        if core.hle_bios { //the call is run natively, and it returns straight away
+                            core.addrbus = bios::call(core, bus, bios::number(inst, core.reg.cpsr.state)).unwrap_or(link);
+                        } else {
+                            core.reg.exception(arm7tdmi::MODE_SVC, link);
+                            core.addrbus = 0x08;
+                        }
                         None
                     },
                     1   =>  {
-                        //Modification to return address to facilitate return
+                        //Fetch from the vector
+                        core.reg.gp[15] = core.addrbus;
+                        flush(core, bus);
                         None
                     },
                     2   =>  {
                         //Refill the pipeline
-                        core.fetch();
+                        prefetch(core, bus, Cycle::S);
                         Some(true)
                     },
                     _   =>  panic!("Software interrupt instruction does not have more than 3 cycles; Found {}", core.cycle+1)
@@ -542,18 +646,25 @@ pub fn step_arm(core: &mut arm7tdmi::Core, bus: &mut bus::Bus, inst: u32) -> Opt
                 match core.cycle {
                     0   =>  {
                         //Good old prefetch
+                        core.datareg = core.reg.gp[15].wrapping_sub(if core.reg.cpsr.state {2} else {4});
+                        prefetch(core, bus, Cycle::S);
                         None
                     },
                     1   =>  {
-                        //Idk (probably instruction trap offset calculation)
+                        //Instruction trap, the mode changes with the return address in r14
+                        bus.idle(1);
+                        core.reg.exception(arm7tdmi::MODE_UND, core.datareg);
                         None
                     },
                     2   =>  {
                         //Fetch from instruction trap
+                        core.reg.gp[15] = 0x04;
+                        flush(core, bus);
                         None
                     },
                     3   =>  {
                         //Fetch again to fill pipeline
+                        prefetch(core, bus, Cycle::S);
                         Some(true)
                     },
                     _   =>  panic!("Undefined instructions do not have more than 4 cycles; Found {}", core.cycle+1)
@@ -562,6 +673,7 @@ pub fn step_arm(core: &mut arm7tdmi::Core, bus: &mut bus::Bus, inst: u32) -> Opt
             //_   =>  panic!("Instruction {} not implemented", insttype)
         }
     } else {
+        prefetch(core, bus, Cycle::S);
         Some(false)
     }
 }
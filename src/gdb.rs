@@ -0,0 +1,401 @@
+use std::collections::BTreeSet;
+use std::io::{ self, Read, Write };
+use std::net::TcpStream;
+
+use crate::arm7tdmi::{ Core, Reg, Status };
+use crate::debugger::{ Debugger, Stop, WatchKind, Watched, Watchpoint };
+use crate::memory::Memory;
+
+/*
+A stub for GDB's remote serial protocol, so arm-none-eabi-gdb can debug code running on the emulator over TCP:
+
+    target remote localhost:2345
+
+Packets are $data#checksum, each one acknowledged with + until GDB asks for no-ack mode. While the core runs the connection
+is polled for a Ctrl-C (a lone 0x03 byte). GDB learns the registers from a target description, which has the usual ARM core
+registers followed by the CPSR and every banked register and SPSR, so they can all be looked at from any mode. Breakpoints
+don't touch memory, software and hardware ones are both just addresses to stop at. `load` works as well, it writes the ELF
+through M/X packets, so there's no need to load the ROM any other way.
+*/
+
+/* The registers in the order GDB numbers them, r0-r15 are the ones the current mode sees */
+pub const REGISTERS: [&str; 44] = [
+    "r0", "r1", "r2", "r3", "r4", "r5", "r6", "r7", "r8", "r9", "r10", "r11", "r12", "sp", "lr", "pc",
+    "cpsr",
+    "r8_usr", "r9_usr", "r10_usr", "r11_usr", "r12_usr", "r13_usr", "r14_usr",
+    "r8_fiq", "r9_fiq", "r10_fiq", "r11_fiq", "r12_fiq", "r13_fiq", "r14_fiq",
+    "r13_svc", "r14_svc", "r13_abt", "r14_abt", "r13_irq", "r14_irq", "r13_und", "r14_und",
+    "spsr_fiq", "spsr_svc", "spsr_abt", "spsr_irq", "spsr_und",
+];
+
+/* What GDB is told about the registers */
+#[allow(dead_code)]
+pub fn target_xml() -> String {
+    let reg = |name: &str| {
+        let kind = match name {"sp" => "data_ptr", "pc" => "code_ptr", _ => "uint32"};
+        format!("    <reg name=\"{}\" bitsize=\"32\" type=\"{}\"/>\n", name, kind)
+    };
+    let core: String = REGISTERS[..17].iter().map(|name| reg(name)).collect();
+    let banked: String = REGISTERS[17..].iter().map(|name| reg(name)).collect();
+    format!("<?xml version=\"1.0\"?>\n<!DOCTYPE target SYSTEM \"gdb-target.dtd\">\n<target version=\"1.0\">\n  <architecture>arm</architecture>\n  \
+        <feature name=\"org.gnu.gdb.arm.core\">\n{}  </feature>\n  <feature name=\"org.gba.arm7tdmi.banked\">\n{}  </feature>\n</target>\n", core, banked)
+}
+
+/* Where register n is kept, None for the ones that aren't a plain u32 (CPSR and the SPSRs) */
+fn slot(reg: &mut Reg, n: usize) -> Option<&mut u32> {
+    let mode = reg.cpsr.mode();
+    match n {
+        0..=15 => Some(reg.banked(mode, n)),
+        17..=23 => Some(&mut reg.gp[n - 9]),
+        24..=30 => Some(&mut reg.fiq[n - 24]),
+        31 | 32 => Some(&mut reg.svc[n - 31]),
+        33 | 34 => Some(&mut reg.abt[n - 33]),
+        35 | 36 => Some(&mut reg.irq[n - 35]),
+        37 | 38 => Some(&mut reg.und[n - 37]),
+        _ => None,
+    }
+}
+
+fn status(reg: &mut Reg, n: usize) -> Option<&mut Status> {
+    match n {
+        16 => Some(&mut reg.cpsr),
+        39 => Some(&mut reg.spsr_fiq),
+        40 => Some(&mut reg.spsr_svc),
+        41 => Some(&mut reg.spsr_abt),
+        42 => Some(&mut reg.spsr_irq),
+        43 => Some(&mut reg.spsr_und),
+        _ => None,
+    }
+}
+
+#[allow(dead_code)]
+pub fn read_register(reg: &Reg, n: usize) -> Option<u32> {
+    let mut reg = *reg;
+    slot(&mut reg, n).map(|value| *value).or_else(|| status(&mut reg, n).map(|sr| u32::from(*sr)))
+}
+
+/* Writing the PC throws away anything the stepped core has prefetched */
+#[allow(dead_code)]
+pub fn write_register(reg: &mut Reg, n: usize, value: u32) -> bool {
+    if n == 15 {
+        reg.prefetched = None;
+    }
+    if let Some(slot) = slot(reg, n) {
+        *slot = value;
+    } else if let Some(sr) = status(reg, n) {
+        *sr = Status::from(value);
+    } else {
+        return false;
+    }
+    true
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn unhex(text: &str) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..text.len()).step_by(2).map(|i| u8::from_str_radix(text.get(i..i + 2)?, 16).ok()).collect()
+}
+
+fn number(text: &str) -> Option<u32> {
+    u32::from_str_radix(text, 16).ok()
+}
+
+/* addr,len */
+fn range(text: &str) -> Option<(u32, u32)> {
+    let (addr, len) = text.split_once(',')?;
+    Some((number(addr)?, number(len)?))
+}
+
+/* Write bytes as halfwords where it can, video memory doesn't take byte writes as they are */
+fn poke<M: Memory>(bus: &mut M, addr: u32, bytes: &[u8]) -> bool {
+    if !(0..bytes.len() as u32).all(|i| bus.mapped(addr.wrapping_add(i) as usize)) {
+        return false;
+    }
+    let mut i = 0;
+    while i < bytes.len() {
+        let at = addr.wrapping_add(i as u32) as usize;
+        if at & 0b1 == 0 && i + 1 < bytes.len() {
+            bus.mem_write_16(at, u16::from_le_bytes([bytes[i], bytes[i + 1]]));
+            i += 2;
+        } else {
+            bus.mem_write(at, bytes[i]);
+            i += 1;
+        }
+    }
+    true
+}
+
+/* The packet layer */
+struct Connection {
+    stream: TcpStream,
+    pending: Vec<u8>,
+    no_ack: bool,
+}
+
+impl Connection {
+    fn byte(&mut self) -> io::Result<Option<u8>> {
+        if !self.pending.is_empty() {
+            return Ok(Some(self.pending.remove(0)));
+        }
+        let mut byte = [0];
+        match self.stream.read(&mut byte)? {
+            0 => Ok(None),
+            _ => Ok(Some(byte[0])),
+        }
+    }
+
+    /* The next packet with escapes undone, None once GDB has gone. Acks and stray Ctrl-Cs in between are skipped */
+    fn packet(&mut self) -> io::Result<Option<Vec<u8>>> {
+        loop {
+            match self.byte()? {
+                None => return Ok(None),
+                Some(b'$') => break,
+                Some(_) => continue,
+            }
+        }
+        let mut data = Vec::new();
+        let mut sum: u8 = 0;
+        loop {
+            let Some(byte) = self.byte()? else {return Ok(None)};
+            if byte == b'#' {
+                break;
+            }
+            sum = sum.wrapping_add(byte);
+            if byte == b'}' {
+                let Some(escaped) = self.byte()? else {return Ok(None)};
+                sum = sum.wrapping_add(escaped);
+                data.push(escaped ^ 0x20);
+            } else {
+                data.push(byte);
+            }
+        }
+        let (Some(high), Some(low)) = (self.byte()?, self.byte()?) else {return Ok(None)};
+        let good = std::str::from_utf8(&[high, low]).ok().and_then(|text| u8::from_str_radix(text, 16).ok()) == Some(sum);
+
+        if !self.no_ack {
+            self.stream.write_all(if good {b"+"} else {b"-"})?;
+        }
+        if good {Ok(Some(data))} else {self.packet()}
+    }
+
+    fn send(&mut self, data: &str) -> io::Result<()> {
+        let sum = data.bytes().fold(0u8, |sum, byte| sum.wrapping_add(byte));
+        loop {
+            self.stream.write_all(format!("${}#{:02x}", data, sum).as_bytes())?;
+            if self.no_ack {
+                return Ok(());
+            }
+            match self.byte()? {
+                Some(b'-') => continue,
+                _ => return Ok(()),
+            }
+        }
+    }
+
+    /* Whether GDB has sent a Ctrl-C, without waiting for anything */
+    fn interrupted(&mut self) -> bool {
+        if self.stream.set_nonblocking(true).is_err() {
+            return false;
+        }
+        let mut buffer = [0; 64];
+        let read = self.stream.read(&mut buffer);
+        let _ = self.stream.set_nonblocking(false);
+        match read {
+            Ok(count) => {
+                self.pending.extend_from_slice(&buffer[..count]);
+                match self.pending.iter().position(|&byte| byte == 0x03) {
+                    Some(i) => {
+                        self.pending.remove(i);
+                        true
+                    },
+                    None => false,
+                }
+            },
+            Err(_) => false,
+        }
+    }
+}
+
+#[allow(dead_code)]
+pub struct GdbStub<M: Memory + 'static> {
+    pub debugger: Debugger<M>,
+    software: BTreeSet<u32>,
+    hardware: BTreeSet<u32>,
+    stop: Stop,
+}
+
+impl<M: Memory + 'static> Default for GdbStub<M> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[allow(dead_code)]
+impl<M: Memory + 'static> GdbStub<M> {
+    pub fn new() -> Self {
+        GdbStub {
+            debugger: Debugger::new(),
+            software: BTreeSet::new(),
+            hardware: BTreeSet::new(),
+            stop: Stop::Interrupted,
+        }
+    }
+
+    /* Talk to GDB until it detaches, kills the target or goes away */
+    pub fn serve(&mut self, stream: TcpStream, core: &mut Core, bus: &mut Watched<M>) -> io::Result<()> {
+        stream.set_nodelay(true)?;
+        let mut connection = Connection { stream, pending: Vec::new(), no_ack: false };
+
+        while let Some(raw) = connection.packet()? {
+            let packet = String::from_utf8_lossy(&raw).into_owned();
+            match packet.as_str() {
+                "D" => return connection.send("OK"),
+                "k" => return Ok(()),
+                "QStartNoAckMode" => {
+                    connection.send("OK")?;
+                    connection.no_ack = true;
+                },
+                "c" | "s" => {
+                    let reply = self.resume(&packet, core, bus, &mut connection);
+                    connection.send(&reply)?;
+                },
+                _ if packet.starts_with('c') || packet.starts_with('s') => {
+                    let reply = match number(&packet[1..]) {
+                        Some(addr) => {
+                            write_register(&mut core.reg, 15, addr);
+                            self.resume(&packet[..1], core, bus, &mut connection)
+                        },
+                        None => "E01".to_string(),
+                    };
+                    connection.send(&reply)?;
+                },
+                _ => {
+                    let reply = self.command(&raw, core, bus).unwrap_or_else(|| "E01".to_string());
+                    connection.send(&reply)?;
+                },
+            }
+        }
+        Ok(())
+    }
+
+    fn resume(&mut self, command: &str, core: &mut Core, bus: &mut Watched<M>, connection: &mut Connection) -> String {
+        self.stop = if command == "s" {
+            self.debugger.step(core, bus).1
+        } else {
+            self.debugger.run(core, bus, || connection.interrupted())
+        };
+        self.stop_reply()
+    }
+
+    fn stop_reply(&self) -> String {
+        match self.stop {
+            Stop::Stepped => "T05".to_string(),
+            Stop::Interrupted => "T02".to_string(),
+            Stop::Breakpoint(addr) if self.software.contains(&addr) => "T05swbreak:;".to_string(),
+            Stop::Breakpoint(_) => "T05hwbreak:;".to_string(),
+            Stop::Watchpoint(hit) => {
+                let name = match hit.kind {
+                    WatchKind::Write => "watch",
+                    WatchKind::Read => "rwatch",
+                    WatchKind::Access => "awatch",
+                    WatchKind::Execute => return "T05hwbreak:;".to_string(),
+                };
+                format!("T05{}:{:x};", name, hit.addr)
+            },
+        }
+    }
+
+    /* Everything that doesn't run the core, None is an error. X carries binary data, the rest are text */
+    fn command(&mut self, packet: &[u8], core: &mut Core, bus: &mut Watched<M>) -> Option<String> {
+        if let Some(rest) = packet.strip_prefix(b"X") {
+            let colon = rest.iter().position(|&byte| byte == b':')?;
+            let (addr, len) = range(std::str::from_utf8(&rest[..colon]).ok()?)?;
+            let data = &rest[colon + 1..];
+            return (data.len() == len as usize && poke(bus, addr, data)).then(|| "OK".to_string());
+        }
+        let packet = std::str::from_utf8(packet).ok()?;
+        let (kind, rest) = packet.split_at(1);
+        match kind {
+            "?" => Some(self.stop_reply()),
+            "g" => Some((0..REGISTERS.len()).map(|n| hex(&read_register(&core.reg, n).unwrap_or(0).to_le_bytes())).collect()),
+            "G" => {
+                let bytes = unhex(rest)?;
+                for (n, value) in bytes.chunks_exact(4).enumerate().take(REGISTERS.len()) {
+                    write_register(&mut core.reg, n, u32::from_le_bytes([value[0], value[1], value[2], value[3]]));
+                }
+                Some("OK".to_string())
+            },
+            "p" => Some(hex(&read_register(&core.reg, number(rest)? as usize)?.to_le_bytes())),
+            "P" => {
+                let (n, value) = rest.split_once('=')?;
+                let value = unhex(value)?;
+                let value = u32::from_le_bytes(value.try_into().ok()?);
+                write_register(&mut core.reg, number(n)? as usize, value).then(|| "OK".to_string())
+            },
+            "m" => {
+                let (addr, len) = range(rest)?;
+                let readable = (0..len).take_while(|&i| bus.mapped(addr.wrapping_add(i) as usize)).count() as u32;
+                let bytes: Vec<u8> = (0..readable).map(|i| bus.mem_read(addr.wrapping_add(i) as usize)).collect();
+                if bytes.is_empty() && len > 0 {None} else {Some(hex(&bytes))}
+            },
+            "M" => {
+                let (place, data) = rest.split_once(':')?;
+                let (addr, len) = range(place)?;
+                let bytes = unhex(data)?;
+                (bytes.len() == len as usize && poke(bus, addr, &bytes)).then(|| "OK".to_string())
+            },
+            "Z" | "z" => {
+                let mut fields = rest.split(',');
+                let (kind, addr, len) = (fields.next()?, number(fields.next()?)?, number(fields.next()?)?);
+                let insert = packet.starts_with('Z');
+                match kind {
+                    "0" | "1" => {
+                        let set = if kind == "0" {&mut self.software} else {&mut self.hardware};
+                        if insert {set.insert(addr);} else {set.remove(&addr);}
+                        self.debugger.breakpoints = self.software.union(&self.hardware).copied().collect();
+                    },
+                    "2" | "3" | "4" => {
+                        let kind = match kind {"2" => WatchKind::Write, "3" => WatchKind::Read, _ => WatchKind::Access};
+                        let watch = Watchpoint { kind, addr, len, value: None };
+                        if insert {
+                            bus.watchpoints.push(watch);
+                        } else {
+                            bus.watchpoints.retain(|other| *other != watch);
+                        }
+                    },
+                    _ => return Some(String::new()),
+                }
+                Some("OK".to_string())
+            },
+            "H" | "T" => Some("OK".to_string()),
+            "q" | "Q" => Some(self.query(packet)),
+            _ => Some(String::new()),   //not supported
+        }
+    }
+
+    fn query(&self, packet: &str) -> String {
+        if packet.starts_with("qSupported") {
+            return "PacketSize=4000;qXfer:features:read+;QStartNoAckMode+;swbreak+;hwbreak+".to_string();
+        }
+        if let Some(request) = packet.strip_prefix("qXfer:features:read:target.xml:") {
+            let Some((offset, len)) = range(request) else {return "E01".to_string()};
+            let xml = target_xml();
+            let start = (offset as usize).min(xml.len());
+            let end = (start + len as usize).min(xml.len());
+            return format!("{}{}", if end == xml.len() {"l"} else {"m"}, &xml[start..end]);
+        }
+        match packet {
+            "qAttached" => "1".to_string(),
+            "qC" => "QC1".to_string(),
+            "qfThreadInfo" => "m1".to_string(),
+            "qsThreadInfo" => "l".to_string(),
+            "qSymbol::" => "OK".to_string(),
+            _ => String::new(),
+        }
+    }
+}
@@ -0,0 +1,568 @@
+use crate::arm7tdmi::{ self, Core };
+use crate::bios;
+use crate::decode::{ self, ArmInstType, ThumbInstType };
+use crate::exec;
+use crate::memory::{ Memory, Cycle, Width };
+
+/*
+The fast interpreter.
+
+Instead of stepping through the internal busses a cycle at a time like exec::step_arm, each instruction is run in one go and its cost
+in cycles is added up from the bus accesses and internal cycles it makes. Instructions are dispatched through a table of 4096 handlers
+indexed by bits 27-20 and 7-4 (see decode::arm_lut_index), which is only built once instead of pattern matching every instruction.
+
+The ALU, barrel shifter and multiplier of the core are still used, so both engines get the same results. Opcodes come out of the
+decoded instruction cache where the memory has one, and the opcode fetches made along the way are only timed, not read again.
+
+While an instruction runs here R15 already points at the next instruction, reading it as an operand gives the address of the
+instruction + 8 like the real pipeline (+ 12 when it's stored, or used alongside a register specified shift).
+
+Thumb instructions run as the ARM instruction they're the same as (see decode::thumb_equivalent), reading R15 gives the address + 4.
+Branches don't have one as their offsets are in halfwords, so they're done here, and each half of BL is done on its own.
+*/
+
+/* The execution engines, which can be swapped between instructions */
+#[allow(dead_code)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Engine {
+    Stepped,    //exec::step_arm, one cycle at a time
+    Fast,       //a whole instruction at a time
+    Block,      //recomp::Recompiler, whole blocks at a time
+}
+
+#[allow(dead_code)]
+impl Engine {
+    pub fn from_name(name: &str) -> Option<Engine> {
+        match name {
+            "stepped"   =>  Some(Engine::Stepped),
+            "fast"      =>  Some(Engine::Fast),
+            "block"     =>  Some(Engine::Block),
+            _           =>  None
+        }
+    }
+}
+
+/* Runs a whole instruction, returning how many cycles it took */
+pub type Handler<M> = fn(&mut Core, &mut M, u32) -> u32;
+
+/* The handler for each type of instruction, the condition has to be checked before calling it */
+pub fn handler<M: Memory>(insttype: ArmInstType) -> Handler<M> {
+    match insttype {
+        ArmInstType::DataProcessing                         =>  data_processing::<M>,
+        ArmInstType::PSRTransfer                            =>  psr_transfer::<M>,
+        ArmInstType::Multiply | ArmInstType::MultiplyLong   =>  multiply::<M>,
+        ArmInstType::SingleDataSwap                         =>  single_data_swap::<M>,
+        ArmInstType::BranchAndExchange                      =>  branch_and_exchange::<M>,
+        ArmInstType::HalfwordDataTransferRegisterOffset |
+        ArmInstType::HalfwordDataTransferImmediateOffset    =>  halfword_transfer::<M>,
+        ArmInstType::SingleDataTransfer                     =>  single_data_transfer::<M>,
+        ArmInstType::BlockDataTransfer                      =>  block_data_transfer::<M>,
+        ArmInstType::Branch                                 =>  branch_with_link::<M>,
+        ArmInstType::SoftwareInterrupt                      =>  software_interrupt::<M>,
+        ArmInstType::Undefined                              =>  undefined::<M>,
+    }
+}
+
+pub struct Interpreter<M: Memory> {
+    pub engine: Engine,
+    table: Box<[Handler<M>]>,
+}
+
+#[allow(dead_code)]
+impl<M: Memory> Interpreter<M> {
+    pub fn new(engine: Engine) -> Self {
+        let table = (0..4096).map(|index| handler::<M>(decode::decode_arm_lut(index))).collect();
+
+        Interpreter {
+            engine,
+            table,
+        }
+    }
+
+    /*
+    Run the instruction at R15 with the selected engine, returning the number of cycles. R15 is left pointing at the next instruction.
+    Blocks need a recomp::Recompiler to keep them in, on their own here they run one instruction at a time like the fast interpreter.
+    */
+    pub fn step(&self, core: &mut Core, bus: &mut M) -> u32 {
+        /* Going to the IRQ vector refills the pipeline, which the stepped core does itself along with the first instruction there */
+        if bios::interrupt(core, bus) && self.engine != Engine::Stepped {
+            return branch(core, bus, core.reg.gp[15]);
+        }
+        if bios::halted(core, bus) {
+            return 1;
+        }
+        match self.engine {
+            Engine::Stepped =>  exec::run_arm(core, bus),
+            Engine::Fast | Engine::Block if core.reg.cpsr.state => self.step_thumb(core, bus),
+            Engine::Fast | Engine::Block => {
+                let pc = core.reg.gp[15];
+                let inst = bus.opcode(pc as usize);
+                core.reg.gp[15] = pc.wrapping_add(4);
+                self.execute(core, bus, inst)
+            }
+        }
+    }
+
+    fn step_thumb(&self, core: &mut Core, bus: &mut M) -> u32 {
+        let pc = core.reg.gp[15];
+        let decoded = bus.decoded_thumb(pc as usize);
+        let next = pc.wrapping_add(2);
+
+        match (decoded.insttype, decoded.arm) {
+            /* Adding to the PC is done here, it reads the PC word aligned so there's no ARM equivalent (1S) */
+            (ThumbInstType::LoadAddress, None) => {
+                core.reg.gp[15] = next;
+                let inst = decoded.inst as u32;
+                core.reg.write(((inst >> 8) & 0x7) as usize, (pc.wrapping_add(4) & !0b11).wrapping_add((inst & 0xFF) << 2));
+                prefetch(core, bus, Cycle::S)
+            },
+            /* PC relative loads read it word aligned, and never branch */
+            (ThumbInstType::PCRelativeLoad, Some(arm)) => {
+                core.reg.gp[15] = (pc.wrapping_add(4) & !0b11).wrapping_sub(2);
+                let cycles = self.execute(core, bus, arm.inst);
+                core.reg.gp[15] = next;
+                cycles
+            },
+            (_, Some(arm)) => {
+                core.reg.gp[15] = next;
+                self.execute(core, bus, arm.inst)
+            },
+            /* 2S+1N, or 1S if the condition fails */
+            (ThumbInstType::ConditionalBranch | ThumbInstType::UnconditionalBranch, None) => {
+                core.reg.gp[15] = next;
+                let inst = decoded.inst as u32;
+                let (cond, offset) = match decoded.insttype {
+                    ThumbInstType::ConditionalBranch => ((inst >> 8) & 0xF, (((inst << 24) as i32) >> 23) as u32),
+                    _ => (0xE, (((inst << 21) as i32) >> 20) as u32),
+                };
+                let cycles = prefetch(core, bus, Cycle::S);
+                if !core.cond_codes(cond) {
+                    return cycles;
+                }
+                cycles + branch(core, bus, pc.wrapping_add(4).wrapping_add(offset))
+            },
+            (ThumbInstType::LongBranchWithLink, None) => {
+                core.reg.gp[15] = next;
+                long_branch_with_link(core, bus, decoded.inst as u32)
+            },
+            _ => {
+                core.reg.gp[15] = next;
+                undefined(core, bus, decoded.inst as u32)
+            },
+        }
+    }
+
+    /* Run an instruction that has already been fetched, R15 should point at the one after it */
+    pub fn execute(&self, core: &mut Core, bus: &mut M, inst: u32) -> u32 {
+        if !core.cond_codes(inst >> 28) {
+            return prefetch(core, bus, Cycle::S);
+        }
+        (self.table[decode::arm_lut_index(inst)])(core, bus, inst)
+    }
+}
+
+/* Read a register as an operand */
+fn operand(core: &mut Core, r: u32) -> u32 {
+    if r == 15 {
+        core.reg.gp[15].wrapping_add(if core.reg.cpsr.state {2} else {4})
+    } else {
+        core.reg.read(r as usize)
+    }
+}
+
+/* The opcode fetch every instruction makes while it runs */
+fn prefetch<M: Memory>(core: &Core, bus: &mut M, cycle: Cycle) -> u32 {
+    if core.reg.cpsr.state {
+        bus.fetch_cycles(core.reg.gp[15].wrapping_add(2) as usize, Width::Half, cycle)
+    } else {
+        bus.fetch_cycles(core.reg.gp[15].wrapping_add(4) as usize, Width::Word, cycle)
+    }
+}
+
+/* Jump to addr, refilling the pipeline from there */
+fn branch<M: Memory>(core: &mut Core, bus: &mut M, addr: u32) -> u32 {
+    let (addr, width, size) = if core.reg.cpsr.state {(addr & !0b1, Width::Half, 2)} else {(addr & !0b11, Width::Word, 4)};
+    core.reg.gp[15] = addr;
+    bus.fetch_cycles(addr as usize, width, Cycle::N) + bus.fetch_cycles(addr.wrapping_add(size) as usize, width, Cycle::S)
+}
+
+/* 1S, +1I for a register specified shift, +1S+1N if the PC is written */
+fn data_processing<M: Memory>(core: &mut Core, bus: &mut M, inst: u32) -> u32 {
+    let (rn, rd, rm) = ((inst >> 16) & 0xF, (inst >> 12) & 0xF, inst & 0xF);
+    let opcode = (inst >> 21) & 0xF;
+    let regshift = inst & 0x02000010 == 0x10;
+    let mut cycles = prefetch(core, bus, Cycle::S);
+
+    if inst & 0x02000000 != 0 {
+        core.bbus = inst & 0xFF;
+        core.decode_shift_imm(inst & 0xFFF);
+    } else {
+        core.decode_shift((inst >> 4) & 0xFF);
+        core.bbus = operand(core, rm);
+    }
+    core.abus = operand(core, rn);
+
+    if regshift {
+        /* The PC has moved on again by the time the operands are read */
+        if rm == 15 { core.bbus = core.bbus.wrapping_add(4); }
+        if rn == 15 { core.abus = core.abus.wrapping_add(4); }
+        bus.idle(1);
+        cycles += 1;
+    }
+
+    core.setcond = inst & (1 << 20) != 0;
+    core.aluop = opcode as u8;
+    core.barrel_shift();
+    core.alu();
+
+    if !(0x8..=0xB).contains(&opcode) { //not TST, TEQ, CMP, CMN
+        if rd == 15 {
+            if core.setcond { //returning from an exception
+                core.reg.transfer_spsr();
+            }
+            let target = core.alubus;
+            cycles += branch(core, bus, target);
+        } else {
+            core.reg.write(rd as usize, core.alubus);
+        }
+    } else if rd == 15 { //TSTP, TEQP, CMPP and CMNP from 26 bit ARMs, the SPSR goes back to the CPSR like the stepped core
+        core.reg.transfer_spsr();
+    }
+    cycles
+}
+
+/* 1S */
+fn psr_transfer<M: Memory>(core: &mut Core, bus: &mut M, inst: u32) -> u32 {
+    let b = (inst >> 22) & 0b1;
+    let cycles = prefetch(core, bus, Cycle::S);
+
+    if decode::decode_psr_transfer(inst) == 0 {
+        let psr = core.reg.read_psr(b);
+        core.reg.write(((inst >> 12) & 0xF) as usize, psr);
+    } else {
+        let word = if inst & 0x02000000 == 0 {
+            operand(core, inst & 0xF)
+        } else {
+            core.bbus = inst & 0xFF;
+            core.decode_shift_imm(inst & 0xFFF);
+            core.barrel_shift();
+            core.barrelbus
+        };
+        core.reg.write_psr(b, (inst >> 16) & 0xF, word);
+    }
+    cycles
+}
+
+/* 1S+mI, +1I to accumulate, +1I for a long multiply */
+fn multiply<M: Memory>(core: &mut Core, bus: &mut M, inst: u32) -> u32 {
+    let (hi, lo, rs, rm) = ((inst >> 16) & 0xF, (inst >> 12) & 0xF, (inst >> 8) & 0xF, inst & 0xF);
+    let long = inst & (1 << 23) != 0;
+    let accumulate = inst & (1 << 21) != 0;
+    let signed = !long || inst & (1 << 22) != 0;
+    let mut cycles = prefetch(core, bus, Cycle::S);
+
+    core.setcond = inst & (1 << 20) != 0;
+    let acc: u64 = if !accumulate {
+        0
+    } else if long {
+        ((core.reg.read(hi as usize) as u64) << 32) | core.reg.read(lo as usize) as u64
+    } else {
+        core.reg.read(lo as usize) as u64
+    };
+    let (rm_val, rs_val) = (core.reg.read(rm as usize), core.reg.read(rs as usize));
+    core.mul_setup(rm_val, rs_val, acc, signed);
+
+    let mut internal = accumulate as u32 + long as u32;
+    while !core.mul_done() {
+        core.mul();
+        internal += 1;
+    }
+    bus.idle(internal);
+    cycles += internal;
+
+    let result = core.mul_result(long);
+    if long {
+        core.reg.write(hi as usize, (result >> 32) as u32);
+        core.reg.write(lo as usize, result as u32);
+    } else {
+        core.reg.write(hi as usize, result as u32);
+    }
+    cycles
+}
+
+/* LDR 1S+1N+1I, +1S+1N if the PC is loaded. STR 2N */
+fn single_data_transfer<M: Memory>(core: &mut Core, bus: &mut M, inst: u32) -> u32 {
+    let (rn, rd) = ((inst >> 16) & 0xF, (inst >> 12) & 0xF);
+    let (p, u, byte, w, load) = (inst & (1 << 24) != 0, inst & (1 << 23) != 0, inst & (1 << 22) != 0, inst & (1 << 21) != 0, inst & (1 << 20) != 0);
+
+    let offset = if inst & 0x02000000 == 0 {
+        inst & 0xFFF
+    } else {
+        core.decode_shift((inst >> 4) & 0xFF);
+        core.bbus = operand(core, inst & 0xF);
+        core.barrel_shift();
+        core.barrelbus
+    };
+    let base = operand(core, rn);
+    let moved = if u {base.wrapping_add(offset)} else {base.wrapping_sub(offset)};
+    let addr = if p {moved} else {base};
+    let writeback = !p || w;
+
+    if load {
+        let mut cycles = prefetch(core, bus, Cycle::S);
+        let data = if byte {
+            let (data, access) = bus.read(addr as usize, Width::Byte, Cycle::N);
+            cycles += access;
+            data
+        } else { //a misaligned word is rotated
+            let (data, access) = bus.read((addr & !0b11) as usize, Width::Word, Cycle::N);
+            cycles += access;
+            data.rotate_right(8 * (addr & 0b11))
+        };
+        if writeback {
+            core.reg.write(rn as usize, moved);
+        }
+        bus.idle(1);
+        cycles += 1;
+
+        if rd == 15 {
+            cycles += branch(core, bus, data);
+        } else {
+            core.reg.write(rd as usize, data);
+        }
+        cycles
+    } else {
+        let mut cycles = prefetch(core, bus, Cycle::N);
+        let data = if rd == 15 {operand(core, 15).wrapping_add(4)} else {core.reg.read(rd as usize)};
+        cycles += if byte {
+            bus.write(addr as usize, Width::Byte, Cycle::N, data)
+        } else {
+            bus.write((addr & !0b11) as usize, Width::Word, Cycle::N, data)
+        };
+        if writeback {
+            core.reg.write(rn as usize, moved);
+        }
+        cycles
+    }
+}
+
+/* Same as single data transfers */
+fn halfword_transfer<M: Memory>(core: &mut Core, bus: &mut M, inst: u32) -> u32 {
+    let (rn, rd) = ((inst >> 16) & 0xF, (inst >> 12) & 0xF);
+    let (p, u, w, load) = (inst & (1 << 24) != 0, inst & (1 << 23) != 0, inst & (1 << 21) != 0, inst & (1 << 20) != 0);
+
+    let offset = if inst & (1 << 22) != 0 {
+        ((inst >> 4) & 0xF0) | (inst & 0xF)
+    } else {
+        operand(core, inst & 0xF)
+    };
+    let base = operand(core, rn);
+    let moved = if u {base.wrapping_add(offset)} else {base.wrapping_sub(offset)};
+    let addr = if p {moved} else {base};
+    let writeback = !p || w;
+
+    if load {
+        let mut cycles = prefetch(core, bus, Cycle::S);
+        let (data, access) = match (inst >> 5) & 0b11 {
+            1   =>  { //LDRH, misaligned is rotated
+                let (data, access) = bus.read((addr & !0b1) as usize, Width::Half, Cycle::N);
+                (data.rotate_right(8 * (addr & 0b1)), access)
+            },
+            3 if addr & 0b1 == 0 => { //LDRSH
+                let (data, access) = bus.read(addr as usize, Width::Half, Cycle::N);
+                (data as u16 as i16 as u32, access)
+            },
+            _   =>  { //LDRSB, and misaligned LDRSH which only loads the byte
+                let (data, access) = bus.read(addr as usize, Width::Byte, Cycle::N);
+                (data as u8 as i8 as u32, access)
+            },
+        };
+        cycles += access;
+        if writeback {
+            core.reg.write(rn as usize, moved);
+        }
+        bus.idle(1);
+        cycles += 1;
+
+        if rd == 15 {
+            cycles += branch(core, bus, data);
+        } else {
+            core.reg.write(rd as usize, data);
+        }
+        cycles
+    } else { //STRH
+        let mut cycles = prefetch(core, bus, Cycle::N);
+        let data = if rd == 15 {operand(core, 15).wrapping_add(4)} else {core.reg.read(rd as usize)};
+        cycles += bus.write((addr & !0b1) as usize, Width::Half, Cycle::N, data & 0xFFFF);
+        if writeback {
+            core.reg.write(rn as usize, moved);
+        }
+        cycles
+    }
+}
+
+/* LDM nS+1N+1I, +1S+1N if the PC is loaded. STM (n-1)S+2N */
+fn block_data_transfer<M: Memory>(core: &mut Core, bus: &mut M, inst: u32) -> u32 {
+    let rn = (inst >> 16) & 0xF;
+    let (p, u, s, w, load) = (inst & (1 << 24) != 0, inst & (1 << 23) != 0, inst & (1 << 22) != 0, inst & (1 << 21) != 0, inst & (1 << 20) != 0);
+    let rlist = inst & 0xFFFF;
+
+    /* An empty list transfers R15 on its own, but the base moves as if all 16 registers were transferred */
+    let list = if rlist == 0 {0x8000} else {rlist};
+    let count = list.count_ones();
+    /* With S set, LDM with R15 restores the CPSR and anything else uses the User bank */
+    let restore = s && load && list & 0x8000 != 0;
+    let userbank = s && !restore;
+
+    /* Registers are always transferred lowest first to the lowest address */
+    let base = core.reg.read(rn as usize);
+    let size = if rlist == 0 {0x40} else {4*count};
+    let mut addr = match (p, u) {
+        (false, true)   =>  base,
+        (true, true)    =>  base.wrapping_add(4),
+        (false, false)  =>  base.wrapping_sub(size).wrapping_add(4),
+        (true, false)   =>  base.wrapping_sub(size),
+    };
+    let written = if u {base.wrapping_add(size)} else {base.wrapping_sub(size)};
+
+    let mut cycles = prefetch(core, bus, if load {Cycle::S} else {Cycle::N});
+    let mut pc = None;
+
+    for (n, index) in (0..16).filter(|r| (list >> r) & 0b1 == 0b1).enumerate() {
+        let cycle = if n == 0 {Cycle::N} else {Cycle::S};
+
+        if load {
+            let (data, access) = bus.read((addr & !0b11) as usize, Width::Word, cycle);
+            cycles += access;
+            /* Write-back happens first, so a loaded base overwrites it */
+            if n == 0 && w {
+                core.reg.write(rn as usize, written);
+            }
+            if index == 15 {
+                pc = Some(data);
+            } else if userbank {
+                *core.reg.banked(arm7tdmi::MODE_USR, index) = data;
+            } else {
+                core.reg.write(index, data);
+            }
+        } else {
+            let data = if index == 15 {
+                operand(core, 15).wrapping_add(4)
+            } else if userbank {
+                *core.reg.banked(arm7tdmi::MODE_USR, index)
+            } else {
+                core.reg.read(index)
+            };
+            cycles += bus.write((addr & !0b11) as usize, Width::Word, cycle, data);
+            /* Write-back happens after the first store, the base is only stored unchanged if it comes first */
+            if n == 0 && w {
+                core.reg.write(rn as usize, written);
+            }
+        }
+        addr = addr.wrapping_add(4);
+    }
+
+    if load {
+        bus.idle(1);
+        cycles += 1;
+        if let Some(target) = pc {
+            if restore {
+                core.reg.transfer_spsr();
+            }
+            cycles += branch(core, bus, target);
+        }
+    }
+    cycles
+}
+
+/* 1S+2N+1I */
+fn single_data_swap<M: Memory>(core: &mut Core, bus: &mut M, inst: u32) -> u32 {
+    let (rn, rd, rm) = ((inst >> 16) & 0xF, (inst >> 12) & 0xF, inst & 0xF);
+    let byte = inst & (1 << 22) != 0;
+    let addr = core.reg.read(rn as usize);
+    let mut cycles = prefetch(core, bus, Cycle::S);
+
+    let data = if byte {
+        let (data, access) = bus.read(addr as usize, Width::Byte, Cycle::N);
+        cycles += access;
+        data
+    } else { //a misaligned word is rotated like LDR
+        let (data, access) = bus.read((addr & !0b11) as usize, Width::Word, Cycle::N);
+        cycles += access;
+        data.rotate_right(8 * (addr & 0b11))
+    };
+
+    let source = core.reg.read(rm as usize);
+    cycles += if byte {
+        bus.write(addr as usize, Width::Byte, Cycle::N, source)
+    } else {
+        bus.write((addr & !0b11) as usize, Width::Word, Cycle::N, source)
+    };
+
+    bus.idle(1);
+    core.reg.write(rd as usize, data);
+    cycles + 1
+}
+
+/* 2S+1N */
+fn branch_with_link<M: Memory>(core: &mut Core, bus: &mut M, inst: u32) -> u32 {
+    let cycles = prefetch(core, bus, Cycle::S);
+    let offset = (((inst & 0xFFFFFF) << 8) as i32 >> 6) as u32; //sign extended and shifted left by 2
+    let target = operand(core, 15).wrapping_add(offset);
+
+    if inst & (1 << 24) != 0 {
+        let link = core.reg.gp[15];
+        core.reg.write(14, link);
+    }
+    cycles + branch(core, bus, target)
+}
+
+/* 2S+1N */
+fn branch_and_exchange<M: Memory>(core: &mut Core, bus: &mut M, inst: u32) -> u32 {
+    let cycles = prefetch(core, bus, Cycle::S);
+    let target = operand(core, inst & 0xF);
+
+    core.reg.cpsr.state = target & 0b1 == 0b1;
+    cycles + branch(core, bus, target)
+}
+
+/* The first half puts the top of the offset in LR (1S), the second branches to LR plus the rest of it (2S+1N) */
+fn long_branch_with_link<M: Memory>(core: &mut Core, bus: &mut M, inst: u32) -> u32 {
+    let cycles = prefetch(core, bus, Cycle::S);
+    let offset = inst & 0x7FF;
+
+    if inst & (1 << 11) == 0 {
+        let high = (((offset << 21) as i32) >> 9) as u32;  //sign extended and shifted left by 12
+        let lr = operand(core, 15).wrapping_add(high);
+        core.reg.write(14, lr);
+        cycles
+    } else {
+        let target = core.reg.read(14).wrapping_add(offset << 1);
+        let link = core.reg.gp[15] | 0b1;
+        core.reg.write(14, link);
+        cycles + branch(core, bus, target)
+    }
+}
+
+/* 2S+1N */
+fn software_interrupt<M: Memory>(core: &mut Core, bus: &mut M, inst: u32) -> u32 {
+    let cycles = prefetch(core, bus, Cycle::S);
+    let link = core.reg.gp[15];
+
+    if core.hle_bios {
+        let target = bios::call(core, bus, bios::number(inst, core.reg.cpsr.state)).unwrap_or(link);
+        return cycles + branch(core, bus, target);
+    }
+    core.reg.exception(arm7tdmi::MODE_SVC, link);
+    cycles + branch(core, bus, 0x08)
+}
+
+/* 2S+1I+1N */
+fn undefined<M: Memory>(core: &mut Core, bus: &mut M, _inst: u32) -> u32 {
+    let cycles = prefetch(core, bus, Cycle::S);
+    let link = core.reg.gp[15];
+
+    core.reg.exception(arm7tdmi::MODE_UND, link);
+    bus.idle(1);
+    cycles + 1 + branch(core, bus, 0x04)
+}
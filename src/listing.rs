@@ -0,0 +1,259 @@
+use std::collections::HashMap;
+
+use crate::decode::{ self, ArmInstType, ThumbInstType, DisasmFormat };
+
+/*
+Listings of whole ROM and BIOS images, laid out like out.txt: the address, the instruction and its disassembly.
+
+Which parts are Thumb is worked out by following the code from its entry points, through branches and through BX to
+addresses the code has just put in a register (with a PC relative ADD or a literal load, the ways compilers do it).
+Everything that is never reached is listed as ARM, which is also how data ends up looking in out.txt.
+*/
+
+fn word(image: &[u8], base: u32, addr: u32) -> Option<u32> {
+    let i = addr.wrapping_sub(base) as usize;
+    image.get(i..i + 4).map(|bytes| u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
+fn half(image: &[u8], base: u32, addr: u32) -> Option<u16> {
+    let i = addr.wrapping_sub(base) as usize;
+    image.get(i..i + 2).map(|bytes| u16::from_le_bytes([bytes[0], bytes[1]]))
+}
+
+/* Where to start following code from, the exception vectors of a BIOS or the entry point in a ROM header. True is Thumb */
+#[allow(dead_code)]
+pub fn entry_points(base: u32) -> Vec<(u32, bool)> {
+    if base == 0 {
+        (0..8).map(|vector| (vector * 4, false)).collect()
+    } else {
+        vec![(base, false)]
+    }
+}
+
+/* Follow every path from the entry points, giving the state each instruction that gets reached runs in */
+#[allow(dead_code)]
+pub fn code_states(image: &[u8], base: u32, entries: &[(u32, bool)]) -> HashMap<u32, bool> {
+    let mut states = HashMap::new();
+    let mut pending = entries.to_vec();
+
+    while let Some((start, thumb)) = pending.pop() {
+        /* The values known to be in each register, only along this path */
+        let mut known: [Option<u32>; 16] = [None; 16];
+        let mut addr = start;
+
+        while !states.contains_key(&addr) {
+            let carry_on = if thumb {
+                let Some(inst) = half(image, base, addr) else {break};
+                states.insert(addr, true);
+                follow_thumb(image, base, addr, inst, &mut known, &mut pending, &mut states)
+            } else {
+                let Some(inst) = word(image, base, addr) else {break};
+                states.insert(addr, false);
+                follow_arm(image, base, addr, inst, &mut known, &mut pending)
+            };
+
+            match carry_on {
+                Some(next) => addr = next,
+                None => break,
+            }
+        }
+    }
+    states
+}
+
+/* Where a BX goes, if the register holds a known address */
+fn exchange(known: Option<u32>, pending: &mut Vec<(u32, bool)>) {
+    if let Some(target) = known {
+        let thumb = target & 0b1 == 1;
+        pending.push((if thumb {target & !0b1} else {target & !0b11}, thumb));
+    }
+}
+
+/* Note where an ARM instruction can go, and return the next address if it can carry on to it */
+fn follow_arm(image: &[u8], base: u32, addr: u32, inst: u32, known: &mut [Option<u32>; 16], pending: &mut Vec<(u32, bool)>) -> Option<u32> {
+    let always = inst >> 28 == 0xE;
+    let rn = ((inst >> 16) & 0xF) as usize;
+    let rd = ((inst >> 12) & 0xF) as usize;
+    let next = Some(addr.wrapping_add(4));
+    let imm = (inst & 0xFF).rotate_right(((inst >> 8) & 0xF) * 2);
+
+    match decode::decode_arm_lut(decode::arm_lut_index(inst)) {
+        ArmInstType::Undefined => None,
+        ArmInstType::Branch => {
+            pending.push((addr.wrapping_add(8).wrapping_add((((inst << 8) as i32) >> 6) as u32), false));
+            if always && (inst >> 24) & 0b1 == 0 {None} else {next}
+        },
+        ArmInstType::BranchAndExchange => {
+            exchange(known[(inst & 0xF) as usize], pending);
+            if always {None} else {next}
+        },
+        ArmInstType::DataProcessing => {
+            let opcode = (inst >> 21) & 0xF;
+            let immediate = (inst >> 25) & 0b1 == 1;
+            known[rd] = match opcode {
+                0x4 if immediate && rn == 15 => Some(addr.wrapping_add(8).wrapping_add(imm)),
+                0x2 if immediate && rn == 15 => Some(addr.wrapping_add(8).wrapping_sub(imm)),
+                0xD if immediate => Some(imm),
+                0x8..=0xB => known[rd],     //the tests don't write anything
+                _ => None,
+            };
+            if rd == 15 && always && !(0x8..=0xB).contains(&opcode) {None} else {next}
+        },
+        ArmInstType::SingleDataTransfer => {
+            let load = (inst >> 20) & 0b1 == 1;
+            if load {
+                /* ldr rd,[pc,offset] is a literal */
+                let literal = (inst >> 25) & 0b1 == 0 && (inst >> 21) & 0b1 == 0 && (inst >> 24) & 0b1 == 1 && rn == 15;
+                let offset = inst & 0xFFF;
+                let addr = if (inst >> 23) & 0b1 == 1 {addr.wrapping_add(8).wrapping_add(offset)} else {addr.wrapping_add(8).wrapping_sub(offset)};
+                known[rd] = if literal && (inst >> 22) & 0b1 == 0 {word(image, base, addr)} else {None};
+            }
+            if (inst >> 21) & 0b1 == 1 || (inst >> 24) & 0b1 == 0 {
+                known[rn] = None;
+            }
+            if load && rd == 15 && always {None} else {next}
+        },
+        ArmInstType::BlockDataTransfer => {
+            let load = (inst >> 20) & 0b1 == 1;
+            for (r, value) in known.iter_mut().enumerate() {
+                if (load && (inst >> r) & 0b1 == 1) || ((inst >> 21) & 0b1 == 1 && r == rn) {
+                    *value = None;
+                }
+            }
+            if load && (inst >> 15) & 0b1 == 1 && always {None} else {next}
+        },
+        ArmInstType::Multiply | ArmInstType::MultiplyLong => {
+            known[rn] = None;
+            known[rd] = None;
+            next
+        },
+        ArmInstType::HalfwordDataTransferRegisterOffset | ArmInstType::HalfwordDataTransferImmediateOffset | ArmInstType::SingleDataSwap | ArmInstType::PSRTransfer => {
+            known[rd] = None;
+            known[rn] = None;
+            next
+        },
+        ArmInstType::SoftwareInterrupt => next,
+    }
+}
+
+/* The same for a Thumb instruction */
+fn follow_thumb(image: &[u8], base: u32, addr: u32, inst: u16, known: &mut [Option<u32>; 16], pending: &mut Vec<(u32, bool)>, states: &mut HashMap<u32, bool>) -> Option<u32> {
+    let inst = inst as u32;
+    let rd = (inst & 0x7) as usize;
+    let r8 = ((inst >> 8) & 0x7) as usize;
+    let next = Some(addr.wrapping_add(2));
+
+    match decode::decode_thumb(inst as u16) {
+        ThumbInstType::Undefined => None,
+        ThumbInstType::ConditionalBranch => {
+            if (inst >> 8) & 0xF == 0xE {
+                return None
+            }
+            pending.push((addr.wrapping_add(4).wrapping_add((((inst << 24) as i32) >> 23) as u32), true));
+            next
+        },
+        ThumbInstType::UnconditionalBranch => {
+            pending.push((addr.wrapping_add(4).wrapping_add((((inst << 21) as i32) >> 20) as u32), true));
+            None
+        },
+        ThumbInstType::LongBranchWithLink => {
+            /* A pair is taken as one instruction */
+            match half(image, base, addr.wrapping_add(2)) {
+                Some(second) if (inst >> 11) & 0b1 == 0 && second >> 11 == 0b11111 => {
+                    let lr = addr.wrapping_add(4).wrapping_add(((((inst & 0x7FF) << 21) as i32) >> 9) as u32);
+                    pending.push((lr.wrapping_add((second as u32 & 0x7FF) << 1), true));
+                    states.insert(addr.wrapping_add(2), true);
+                    known[14] = None;
+                    Some(addr.wrapping_add(4))
+                },
+                _ => next,
+            }
+        },
+        ThumbInstType::HiRegisterOperationsBranchExchange => {
+            let hd = rd | ((inst >> 4) & 0x8) as usize;
+            let hs = ((inst >> 3) & 0xF) as usize;
+            match (inst >> 8) & 0b11 {
+                3 => {
+                    exchange(known[hs], pending);
+                    None
+                },
+                1 => next,
+                op => {
+                    known[hd] = if op == 2 {known[hs]} else {None};
+                    if hd == 15 {None} else {next}
+                },
+            }
+        },
+        ThumbInstType::PCRelativeLoad => {
+            known[r8] = word(image, base, (addr.wrapping_add(4) & !0b11).wrapping_add((inst & 0xFF) << 2));
+            next
+        },
+        ThumbInstType::LoadAddress => {
+            known[r8] = if (inst >> 11) & 0b1 == 0 {Some((addr.wrapping_add(4) & !0b11).wrapping_add((inst & 0xFF) << 2))} else {None};
+            next
+        },
+        ThumbInstType::MoveCompareAddSubtractImmediate => {
+            known[r8] = match (inst >> 11) & 0b11 {
+                0 => Some(inst & 0xFF),
+                1 => known[r8],
+                _ => None,
+            };
+            next
+        },
+        ThumbInstType::PushPopRegisters => {
+            let pop = (inst >> 11) & 0b1 == 1;
+            if pop {
+                for (r, value) in known.iter_mut().enumerate().take(8) {
+                    if (inst >> r) & 0b1 == 1 {
+                        *value = None;
+                    }
+                }
+            }
+            if pop && (inst >> 8) & 0b1 == 1 {None} else {next}
+        },
+        ThumbInstType::MultipleLoadStore => {
+            known.iter_mut().take(8).for_each(|value| *value = None);
+            next
+        },
+        ThumbInstType::SPRelativeLoadStore => {
+            known[r8] = None;
+            next
+        },
+        ThumbInstType::SoftwareInterrupt | ThumbInstType::AddOffsetToStackPointer => next,
+        _ => {
+            known[rd] = None;
+            next
+        },
+    }
+}
+
+/* The whole image as a listing, the same layout as out.txt */
+#[allow(dead_code)]
+pub fn listing(image: &[u8], base: u32, entries: &[(u32, bool)], format: &DisasmFormat) -> String {
+    let states = code_states(image, base, entries);
+    let end = base.wrapping_add(image.len() as u32);
+    let mut lines = String::new();
+    let mut addr = base;
+
+    while addr < end {
+        let thumb = states.get(&addr).copied().unwrap_or(false) || addr & 0b11 != 0 || end - addr < 4;
+        if thumb {
+            let Some(inst) = half(image, base, addr) else {break};
+            let text = decode::disassemble_thumb_with(inst, addr, format, &mut |addr| half(image, base, addr));
+            /* The two halves of a BL go on one line */
+            let pair = decode::decode_thumb(inst) == ThumbInstType::LongBranchWithLink && text.starts_with("bl") && (inst >> 11) & 0b1 == 0;
+            if pair {
+                lines.push_str(&format!("{:08X}  {:08X}  {}\n", addr, word(image, base, addr).unwrap_or(0), text));
+                addr = addr.wrapping_add(4);
+            } else {
+                lines.push_str(&format!("{:08X}  {:04X}      {}\n", addr, inst, text));
+                addr = addr.wrapping_add(2);
+            }
+        } else {
+            let inst = word(image, base, addr).unwrap_or(0);
+            lines.push_str(&format!("{:08X}  {:08X}  {}\n", addr, inst, decode::disassemble_arm_with(inst, addr, format)));
+            addr = addr.wrapping_add(4);
+        }
+    }
+    lines
+}
@@ -0,0 +1,96 @@
+use std::fmt;
+
+use crate::arm7tdmi::Core;
+use crate::memory::Memory;
+use crate::trace::TraceEntry;
+
+/*
+Running the core in lockstep with a reference trace, in the Mgba trace format so a log from mGBA or NanoBoyAdvance can be
+used as it is. Before each instruction the whole register file and the CPSR are checked against the next line of the trace,
+and the first line that doesn't match is where the emulator went wrong (the instruction before it is the one to look at).
+*/
+
+/* Where the core first stopped matching the reference */
+#[allow(dead_code)]
+#[derive(Clone, Debug)]
+pub struct Divergence {
+    pub line: usize,                    //in the reference, from 1
+    pub expected: TraceEntry,
+    pub actual: TraceEntry,
+    pub previous: Option<TraceEntry>,   //the instruction that ran last, None if the starting state is already different
+}
+
+/* Why the reference couldn't be followed to the end */
+#[allow(dead_code)]
+#[derive(Clone, Debug)]
+pub enum LockstepError {
+    Diverged(Box<Divergence>),
+    Unreadable { line: usize, text: String },   //a line that isn't an mGBA trace line
+}
+
+impl fmt::Display for LockstepError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LockstepError::Diverged(divergence) => divergence.fmt(f),
+            LockstepError::Unreadable { line, text } => writeln!(f, "Line {} of the reference isn't an mGBA trace line: {}", line, text),
+        }
+    }
+}
+
+/* The flags of a CPSR, capitals for the ones that are set */
+fn flags(cpsr: u32) -> String {
+    let flag = |bit: u32, name: char| if cpsr & (1 << bit) != 0 {name} else {name.to_ascii_lowercase()};
+    format!("{}{}{}{} {}{}{} mode 0x{:02X}", flag(31, 'N'), flag(30, 'Z'), flag(29, 'C'), flag(28, 'V'), flag(7, 'I'), flag(6, 'F'), flag(5, 'T'), cpsr & 0x1F)
+}
+
+impl fmt::Display for Divergence {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Diverged at line {} of the reference", self.line)?;
+        match &self.previous {
+            Some(previous) => writeln!(f, ", after 0x{:08X}: {}", previous.pc(), previous.disassembly)?,
+            None => writeln!(f, ", before anything ran")?,
+        }
+
+        for r in 0..16 {
+            if self.expected.gp[r] != self.actual.gp[r] {
+                writeln!(f, "  r{:<2}   expected {:08X}, got {:08X}", r, self.expected.gp[r], self.actual.gp[r])?;
+            }
+        }
+        if self.expected.cpsr != self.actual.cpsr {
+            writeln!(f, "  cpsr  expected {:08X} ({}), got {:08X} ({})", self.expected.cpsr, flags(self.expected.cpsr), self.actual.cpsr, flags(self.actual.cpsr))?;
+        }
+        if self.expected.opcode != self.actual.opcode {
+            writeln!(f, "  next  expected {:08X}: {}", self.expected.opcode, self.expected.disassembly)?;
+            writeln!(f, "        got      {:08X}: {}", self.actual.opcode, self.actual.disassembly)?;
+        }
+        Ok(())
+    }
+}
+
+/*
+Check the core against every line of the reference, calling step to run one instruction between them (the way the CPU
+tests do it, so it can be any engine). Returns how many lines matched, blank lines and ones starting with # are skipped
+*/
+#[allow(dead_code)]
+pub fn lockstep<M: Memory>(core: &mut Core, bus: &mut M, reference: &str, mut step: impl FnMut(&mut Core, &mut M)) -> Result<usize, LockstepError> {
+    let mut previous = None;
+    let mut matched = 0;
+
+    for (i, line) in reference.lines().enumerate() {
+        if line.trim().is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let Some(expected) = TraceEntry::parse(line) else {
+            return Err(LockstepError::Unreadable { line: i + 1, text: line.to_string() });
+        };
+        let actual = TraceEntry::capture(&core.reg, bus);
+
+        if actual.gp != expected.gp || actual.cpsr != expected.cpsr || actual.opcode != expected.opcode {
+            return Err(LockstepError::Diverged(Box::new(Divergence { line: i + 1, expected, actual, previous })));
+        }
+        step(core, bus);
+        previous = Some(actual);
+        matched += 1;
+    }
+    Ok(matched)
+}
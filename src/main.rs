@@ -3,13 +3,38 @@ mod decode;
 mod arm7tdmi;
 mod exec;
 mod bus;
+mod memory;
+mod interp;
+mod cache;
+mod recomp;
+mod bios;
+mod asm;
+mod listing;
+mod trace;
+mod lockstep;
+mod debugger;
+mod gdb;
+mod repl;
 
 pub use decode::*;
 pub use arm7tdmi::*;
 pub use exec::*;
 pub use bus::*;
+pub use memory::*;
+pub use interp::*;
+pub use cache::*;
+pub use recomp::*;
+pub use bios::*;
+pub use asm::*;
+pub use listing::*;
+pub use trace::*;
+pub use lockstep::*;
+pub use debugger::*;
+pub use gdb::*;
+pub use repl::*;
 
-use std::{thread, time};
+use std::{env, thread, time};
+use std::net::TcpListener;
 
 /*
 This is here like this so that the rust analyser will actually give me what's
@@ -18,55 +43,162 @@ cargo knows what to do when building/running etc.
 */
 
 
-fn test_inst(core: &mut arm7tdmi::Core, bus: &mut bus::Bus, inst: u32) {
-    core.cycle = 0;
+/* A number on the command line, hex with 0x in front */
+fn parse_number(text: &str) -> u32 {
+    match text.strip_prefix("0x").or(text.strip_prefix("0X")) {
+        Some(hex) => u32::from_str_radix(hex, 16),
+        None => text.parse(),
+    }.expect("The address should be a number")
+}
 
-    println!("Inst: 0x{:x}, Type: {}, Dissassembly: {}, PC: 0x{:x}", inst, decode::decode_arm(inst), decode::disassemble_arm(inst), core.reg.gp[15]);
+/*
+disasm FILE lists a ROM or BIOS image like out.txt. --base sets where it's loaded, otherwise anything bigger than the
+BIOS goes at the start of the ROM. --arm and --thumb add more places to follow the code from
+*/
+fn disasm(args: &[String]) {
+    let path = args.get(2).expect("disasm needs the image to list");
+    let image = std::fs::read(path).unwrap_or_else(|error| panic!("Couldn't read {} ({})", path, error));
 
-    let mut state = None;
+    let base = args.iter().position(|arg| arg == "--base")
+        .and_then(|i| args.get(i + 1))
+        .map(|text| parse_number(text))
+        .unwrap_or(if image.len() > 0x4000 {0x08000000} else {0});
 
-    while state == None {
-        println!("{}", core.reg);
-        state = exec::step_arm(core, bus, inst);
-        core.cycle += 1;
+    let mut entries = listing::entry_points(base);
+    for (i, arg) in args.iter().enumerate() {
+        if arg == "--arm" || arg == "--thumb" {
+            let addr = parse_number(args.get(i + 1).expect("--arm and --thumb need an address"));
+            entries.push((addr, arg == "--thumb"));
+        }
     }
 
-    core.reg.gp[15] += 4;
+    print!("{}", listing::listing(&image, base, &entries, &decode::DisasmFormat::default()));
+}
+
+/* Stop for a watchpoint, with what set it off and the trace leading up to it */
+fn halt(hit: debugger::Hit, core: &arm7tdmi::Core, tracer: &mut Option<trace::Tracer>) {
+    if let Some(tracer) = tracer {
+        tracer.dump(&mut std::io::stdout()).expect("The trace should be written out");
+    }
+    println!("{}\n{}", hit, core.reg);
 }
 
 fn main() { //I will probably make this do some thing later, but it will stay like this for now
+    let args: Vec<String> = env::args().collect();
+    if args.get(1).map(String::as_str) == Some("disasm") {
+        disasm(&args);
+        return
+    }
+
     let one_second = time::Duration::from_millis(500);
 
-    println!("Hello, world!");
+    /* debug and --gdb hand the core over to a debugger, which don't need the show */
+    let debugging = args.get(1).map(String::as_str) == Some("debug") || args.iter().any(|arg| arg == "--gdb");
+    if !debugging {
+        println!("Hello, world!");
+
+        thread::sleep(one_second);
+
+        println!("{}", decode::decode_arm(0xEA00002E));
+        println!("{}", decode::decode_arm(0x51AEFF24));
+        println!("{}", decode::decode_arm(0x21A29A69));
+        println!("{}", decode::decode_arm(0x0A82843D));
+        println!("{}", decode::decode_arm(0xAD09E484));
+        println!("{}", decode::decode_arm(0x988B2411));
+        println!("{}", decode::decode_arm(0x217F81C0));
+        println!("{}", decode::decode_arm(0x19BE52A3));
+        println!("{}", decode::decode_arm(0x20CE0993));
+
+        println!("{}", decode::disassemble_arm(0xEA00002E, 0));
+        println!("{}", decode::disassemble_arm(0x51AEFF24, 0));
+        println!("{}", decode::disassemble_arm(0x21A29A69, 0));
+        println!("{}", decode::disassemble_arm(0x0A82843D, 0));
+        println!("{}", decode::disassemble_arm(0xAD09E484, 0));
+        println!("{}", decode::disassemble_arm(0x988B2411, 0));
+        println!("{}", decode::disassemble_arm(0x217F81C0, 0));
+        println!("{}", decode::disassemble_arm(0x19BE52A3, 0));
+        println!("{}", decode::disassemble_arm(0x20CE0993, 0));
 
-    thread::sleep(one_second);
+        thread::sleep(one_second);
+    }
 
-    println!("{}", decode::decode_arm(0xEA00002E));
-    println!("{}", decode::decode_arm(0x51AEFF24));
-    println!("{}", decode::decode_arm(0x21A29A69));
-    println!("{}", decode::decode_arm(0x0A82843D));
-    println!("{}", decode::decode_arm(0xAD09E484));
-    println!("{}", decode::decode_arm(0x988B2411));
-    println!("{}", decode::decode_arm(0x217F81C0));
-    println!("{}", decode::decode_arm(0x19BE52A3));
-    println!("{}", decode::decode_arm(0x20CE0993));
+    /* --engine fast runs whole instructions at a time instead of stepping through each cycle, --engine block whole blocks */
+    let engine = args.iter().position(|arg| arg == "--engine")
+        .and_then(|i| args.get(i + 1))
+        .map(|name| interp::Engine::from_name(name).expect("The engine should be stepped, fast or block"))
+        .unwrap_or(interp::Engine::Stepped);
+    let interpreter: interp::Interpreter<debugger::Watched<bus::Bus>> = interp::Interpreter::new(engine);
 
-    println!("{}", decode::disassemble_arm(0xEA00002E));
-    println!("{}", decode::disassemble_arm(0x51AEFF24));
-    println!("{}", decode::disassemble_arm(0x21A29A69));
-    println!("{}", decode::disassemble_arm(0x0A82843D));
-    println!("{}", decode::disassemble_arm(0xAD09E484));
-    println!("{}", decode::disassemble_arm(0x988B2411));
-    println!("{}", decode::disassemble_arm(0x217F81C0));
-    println!("{}", decode::disassemble_arm(0x19BE52A3));
-    println!("{}", decode::disassemble_arm(0x20CE0993));
+    /*
+    --trace plain|mgba logs every instruction instead of printing the registers, to --trace-file or the terminal.
+    --trace-range START-END only logs the instructions in there, --trace-ring N only keeps the last N lines, written out at the end
+    */
+    let option = |name: &str| args.iter().position(|arg| arg == name).and_then(|i| args.get(i + 1));
+    let mut tracer = option("--trace").map(|name| {
+        let format = trace::TraceFormat::from_name(name).expect("The trace format should be plain or mgba");
+        let mut tracer = match option("--trace-ring") {
+            Some(capacity) => trace::Tracer::ring(format, capacity.parse().expect("The ring should be a number of lines")),
+            None => match option("--trace-file") {
+                Some(path) => trace::Tracer::new(format, Box::new(std::io::BufWriter::new(std::fs::File::create(path).expect("The trace file should be writable")))),
+                None => trace::Tracer::new(format, Box::new(std::io::stdout())),
+            },
+        };
+        tracer.range = option("--trace-range").map(|range| {
+            let (start, end) = range.split_once('-').expect("The range should be START-END");
+            parse_number(start)..parse_number(end)
+        });
+        tracer
+    });
 
-    thread::sleep(one_second);
+    /* Blocks of one instruction when tracing or watching, otherwise a whole block would go by as one and a watchpoint would
+    stop at the end of the block instead of the instruction that set it off */
+    let single = tracer.is_some() || args.iter().any(|arg| arg == "--watch");
+    let mut recompiler: recomp::Recompiler<debugger::Watched<bus::Bus>> = if single {recomp::Recompiler::with_max_block(1)} else {recomp::Recompiler::new()};
 
     let mut core = arm7tdmi::Core::new();
         let mut bus = bus::Bus::new();
 
-        println!("{:?}", bus.load_mem());
+        /* --hle runs the BIOS calls natively instead of loading ./gba_bios.gba, which is also what happens if it can't be loaded */
+        if args.iter().any(|arg| arg == "--hle") {
+            core.hle_bios = true;
+        } else if let Err(error) = bus.load_mem() {
+            println!("Couldn't load the BIOS ({}), running the BIOS calls natively", error);
+            core.hle_bios = true;
+        }
+        if core.hle_bios {
+            bios::install(&mut bus);
+        }
+
+        /* --direct skips the BIOS intro, without a BIOS image there's no intro to run anyway */
+        if core.hle_bios || args.iter().any(|arg| arg == "--direct") {
+            bios::direct_boot(&mut core, &mut bus);
+        } else {
+            core.reset();
+        }
+
+        /* --watch KIND:START[-END][=VALUE] halts when memory there is read, written or run from, as many as are wanted */
+        let mut bus = debugger::Watched::new(bus);
+        for (i, arg) in args.iter().enumerate() {
+            if arg == "--watch" {
+                let spec = args.get(i + 1).expect("--watch needs a watchpoint");
+                bus.watchpoints.push(debugger::Watchpoint::parse(spec).unwrap_or_else(|| panic!("The watchpoint should be KIND:START[-END][=VALUE], not {}", spec)));
+            }
+        }
+
+        /* debug runs the command line debugger on the terminal */
+        if args.get(1).map(String::as_str) == Some("debug") {
+            repl::Repl::new().run(&mut core, &mut bus, &mut std::io::stdin().lock(), &mut std::io::stdout()).expect("Lost the terminal");
+            return
+        }
+
+        /* --gdb PORT hands the core to arm-none-eabi-gdb (target remote localhost:PORT) instead of running it here */
+        if let Some(port) = option("--gdb") {
+            let listener = TcpListener::bind(("127.0.0.1", port.parse::<u16>().expect("The port should be a number"))).expect("Couldn't listen for GDB");
+            println!("Waiting for GDB on port {}", port);
+            let (stream, _) = listener.accept().expect("GDB couldn't connect");
+            gdb::GdbStub::new().serve(stream, &mut core, &mut bus).expect("Lost the connection to GDB");
+            return
+        }
         
         let mut instructions = 200;
         
@@ -78,18 +210,51 @@ fn main() { //I will probably make this do some thing later, but it will stay li
         
         while instructions < 300 {
             let instruction = bus.mem_read_32(core.reg.gp[15] as usize);
-            test_inst(&mut core, &mut bus, instruction);
+            if let Some(tracer) = &mut tracer {
+                tracer.record(&core.reg, &mut bus);
+            } else if core.reg.cpsr.state {
+                let disassembly = decode::disassemble_thumb_with(instruction as u16, core.reg.gp[15], &decode::DisasmFormat::default(), &mut |addr| Some(bus.mem_read_16(addr as usize)));
+                println!("Inst: 0x{:x}, Type: {:?}, Dissassembly: {}, PC: 0x{:x}", instruction as u16, decode::decode_thumb(instruction as u16), disassembly, core.reg.gp[15]);
+            } else {
+                println!("Inst: 0x{:x}, Type: {}, Dissassembly: {}, PC: 0x{:x}", instruction, decode::decode_arm(instruction), decode::disassemble_arm(instruction, core.reg.gp[15]), core.reg.gp[15]);
+            }
+            if let Some(hit) = bus.executes(core.reg.gp[15]) {
+                halt(hit, &core, &mut tracer);
+                return
+            }
+            /* The stepped core fetches through its own pipeline, so the instruction read here is only for show */
+            bus.pc = core.reg.gp[15];
+            let cycles = match interpreter.engine {
+                interp::Engine::Block   =>  recompiler.step(&mut core, &mut bus),
+                _                       =>  interpreter.step(&mut core, &mut bus),
+            };
+            if let Some(hit) = bus.hit.take() {
+                halt(hit, &core, &mut tracer);
+                return
+            }
+            if tracer.is_none() {
+                println!("{}\nCycles: {}", core.reg, cycles);
+            }
             instructions -= 1;
             if old_inst == instruction {
                 inst_same_counter += 1;
             }
             if inst_same_counter > 10 {
+                if let Some(tracer) = &mut tracer {
+                    tracer.dump(&mut std::io::stdout()).expect("The trace should be written out");
+                }
                 panic!("Infinite loop")
             }
             old_inst = instruction;
 
             //print!("\x1B[2J\x1B[1;1H");
-            thread::sleep(one_second);
+            if tracer.is_none() {
+                thread::sleep(one_second);
+            }
+        }
+
+        if let Some(tracer) = &mut tracer {
+            tracer.dump(&mut std::io::stdout()).expect("The trace should be written out");
         }
 
         assert_eq!(core.reg.gp[12], 0);
@@ -0,0 +1,170 @@
+/*
+The interface between the CPU and whatever it is connected to.
+
+The processor only ever talks to memory through this trait, so it can be run against the full GBA bus, a flat block of memory for
+testing, or something wrapping either of those (e.g. to trace accesses). Only byte reads and writes have to be provided, the wider
+accesses are built out of them and every access is assumed to take a single cycle unless the timing functions are overridden.
+*/
+
+use crate::decode::{ ArmInst, ThumbInst };
+
+/* The type of bus cycle, the CPU signals whether an access follows on from the last one */
+#[allow(dead_code)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Cycle {
+    N,  //Non-sequential
+    S,  //Sequential
+}
+
+/* The width of a bus access */
+#[allow(dead_code)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Width {
+    Byte,
+    Half,
+    Word,
+}
+
+#[allow(dead_code)]
+pub trait Memory {
+    fn mem_read(&mut self, addr: usize) -> u8;
+
+    fn mem_write(&mut self, addr: usize, data: u8);
+
+    fn mem_read_16(&mut self, addr: usize) -> u16 {
+        let lo = self.mem_read(addr) as u16;
+        let hi = self.mem_read(addr+1) as u16;
+        lo | (hi << 8)
+    }
+
+    fn mem_read_32(&mut self, addr: usize) -> u32 {
+        let lo = self.mem_read_16(addr) as u32;
+        let hi = self.mem_read_16(addr+2) as u32;
+        lo | (hi << 16)
+    }
+
+    fn mem_write_16(&mut self, addr: usize, data: u16) {
+        self.mem_write(addr, data as u8);
+        self.mem_write(addr+1, (data >> 8) as u8);
+    }
+
+    fn mem_write_32(&mut self, addr: usize, data: u32) {
+        self.mem_write_16(addr, data as u16);
+        self.mem_write_16(addr+2, (data >> 16) as u16);
+    }
+
+    /* Untimed read or write of any width */
+    fn load(&mut self, addr: usize, width: Width) -> u32 {
+        match width {
+            Width::Byte => self.mem_read(addr) as u32,
+            Width::Half => self.mem_read_16(addr) as u32,
+            Width::Word => self.mem_read_32(addr),
+        }
+    }
+
+    fn store(&mut self, addr: usize, width: Width, data: u32) {
+        match width {
+            Width::Byte => self.mem_write(addr, data as u8),
+            Width::Half => self.mem_write_16(addr, data as u16),
+            Width::Word => self.mem_write_32(addr, data),
+        }
+    }
+
+    /* How many cycles an access to addr takes */
+    fn access_cycles(&self, _addr: usize, _width: Width, _cycle: Cycle) -> u32 {
+        1
+    }
+
+    /* Timed read, returns the data and the number of cycles the access took */
+    fn read(&mut self, addr: usize, width: Width, cycle: Cycle) -> (u32, u32) {
+        (self.load(addr, width), self.access_cycles(addr, width, cycle))
+    }
+
+    /* Timed write, returns the number of cycles the access took */
+    fn write(&mut self, addr: usize, width: Width, cycle: Cycle, data: u32) -> u32 {
+        self.store(addr, width, data);
+        self.access_cycles(addr, width, cycle)
+    }
+
+    /* Timed opcode fetch */
+    fn fetch(&mut self, addr: usize, width: Width, cycle: Cycle) -> (u32, u32) {
+        self.read(addr, width, cycle)
+    }
+
+    /* An opcode fetch where only the timing is needed, as the instruction has already been decoded */
+    fn fetch_cycles(&mut self, addr: usize, width: Width, cycle: Cycle) -> u32 {
+        self.fetch(addr, width, cycle).1
+    }
+
+    /* Internal CPU cycles, nothing is on the bus */
+    fn idle(&mut self, _cycles: u32) {}
+
+    /* The decoded instruction at addr (untimed), memories with an instruction cache can avoid decoding it again */
+    fn decoded_arm(&mut self, addr: usize) -> ArmInst {
+        ArmInst::new(self.load(addr, Width::Word))
+    }
+
+    fn decoded_thumb(&mut self, addr: usize) -> ThumbInst {
+        ThumbInst::new(self.load(addr, Width::Half) as u16)
+    }
+
+    /* The ARM opcode at addr (untimed), which memories with an instruction cache can get without reading it again */
+    fn opcode(&mut self, addr: usize) -> u32 {
+        self.load(addr, Width::Word)
+    }
+
+    /*
+    A number that changes whenever the code at addr is written over, so anything built from it knows when to throw it away.
+    None if writes to addr aren't kept track of, in which case nothing built from the code there can be kept.
+    */
+    fn code_generation(&self, _addr: usize) -> Option<u32> {
+        None
+    }
+
+    /* Whether anything is at addr, for things like a debugger that can ask for any address without it being an error */
+    fn mapped(&self, _addr: usize) -> bool {
+        true
+    }
+}
+
+/*
+A plain block of memory starting at address 0, where everything takes a single cycle. Writes are counted a page at a time so
+anything built from the code in it knows when to throw it away (only the ones through mem_write, not straight to mem).
+*/
+const FLAT_PAGE_BITS: usize = 8;
+
+#[allow(dead_code)]
+#[derive(Clone, Debug)]
+pub struct FlatMemory {
+    pub mem: Vec<u8>,
+    generations: Vec<u32>,
+}
+
+#[allow(dead_code)]
+impl FlatMemory {
+    pub fn new(size: usize) -> Self {
+        FlatMemory {
+            mem: vec![0; size],
+            generations: vec![0; (size >> FLAT_PAGE_BITS) + 1],
+        }
+    }
+}
+
+impl Memory for FlatMemory {
+    fn mem_read(&mut self, addr: usize) -> u8 {
+        self.mem[addr]
+    }
+
+    fn mem_write(&mut self, addr: usize, data: u8) {
+        self.mem[addr] = data;
+        self.generations[addr >> FLAT_PAGE_BITS] += 1;
+    }
+
+    fn code_generation(&self, addr: usize) -> Option<u32> {
+        self.generations.get(addr >> FLAT_PAGE_BITS).copied()
+    }
+
+    fn mapped(&self, addr: usize) -> bool {
+        addr < self.mem.len()
+    }
+}
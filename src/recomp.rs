@@ -0,0 +1,683 @@
+use std::rc::Rc;
+
+use crate::arm7tdmi::Core;
+use crate::bios;
+use crate::cache::AddrMap;
+use crate::decode::{ ArmInst, ArmInstType, ThumbInstType };
+use crate::interp::{ self, Engine, Interpreter };
+use crate::memory::{ Memory, Cycle, Width };
+
+/*
+The block recompiler.
+
+Straight-line code is translated a basic block at a time into a small intermediate representation (IR), which is then turned into a
+list of closures, one for each instruction, that are run one after another (threaded code). No native code is generated. Going
+through the IR lets a couple of things be done once for the whole block instead of every time it runs:
+
+- Constant folding, R15 is always known so reading it is just a constant, and anything worked out from constants is folded into
+  a single Set of the register. Literal pool loads end up with a constant address this way.
+- Flag liveness, the S bit is dropped from any instruction whose flags are all overwritten before anything reads them, which
+  lets it run without going through the ALU of the core. Comparisons that end up setting nothing become a Nop.
+
+A block ends at a branch (B, BL, and the Thumb conditional and unconditional branches, which are part of the block as its Exit),
+or just before anything that changes the PC some other way, changes the mode or state, or causes an exception. Those are left
+to the fast interpreter, which is also what runs instructions that aren't worth translating (e.g. multiplies, LDM/STM and SWP)
+from inside an ARM block.
+
+Blocks are kept by address and thrown away when the code they came from is written over (see Memory::code_generation), a block
+that writes over its own code (or might have, for STM and SWP) stops straight after the store so the rest is decoded again. Code
+in memory where writes aren't kept track of (e.g. the BIOS) is never made into a block, the fast interpreter runs it. Timing is the
+same as the fast interpreter, every instruction still makes its opcode fetch and data accesses on the bus.
+
+Thumb instructions that don't translate into the IR are left to the fast interpreter the same way, which runs them as ARM ones.
+*/
+
+const MAX_BLOCK: usize = 64;    //instructions
+const AL: u32 = 0xE;
+
+/* Flags for liveness, in the same order as the CPSR */
+const FLAG_N: u8 = 0b1000;
+const FLAG_Z: u8 = 0b0100;
+const FLAG_C: u8 = 0b0010;
+const FLAG_ALL: u8 = 0b1111;
+
+/* A register, or a constant if it's R15 or its value is known */
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Operand {
+    Reg(u8),
+    Imm(u32),
+}
+
+/* The second operand of a data processing instruction (or the offset of a load or store) */
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Shifter {
+    Const(u32),                             //leaves the shifter carry alone
+    RotImm(u32),                            //a rotated immediate (bits 11-0), the carry is bit 31 of the result
+    Shift { rm: Operand, shift: u32 },      //through the barrel shifter, shift is bits 11-4 of the instruction
+}
+
+/* What a load or store transfers */
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Access {
+    Word,
+    Byte,
+    Half,
+    SignedByte,
+    SignedHalf,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Op {
+    Nop,
+    Set { rd: u8, value: u32 },
+    Alu { opcode: u8, rd: u8, rn: Operand, op2: Shifter, flags: bool },
+    Load { access: Access, rd: u8, rn: u8, base: Operand, offset: Shifter, pre: bool, up: bool, writeback: bool },
+    Store { access: Access, rd: Operand, rn: u8, base: Operand, offset: Shifter, pre: bool, up: bool, writeback: bool },
+    Interpret(u32),                         //an ARM instruction run by the fast interpreter
+}
+
+/* An instruction in a block, along with where it came from */
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Inst {
+    pub pc: u32,
+    pub cond: u32,
+    pub op: Op,
+}
+
+/* Where to go at the end of a block */
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Exit {
+    Next(u32),                                                              //carry on from here
+    Branch { cond: u32, target: u32, link: Option<u32>, next: u32 },
+}
+
+/* Runs an instruction, returning how many cycles it took. Err if it wrote over the code in its own block */
+type Thread<M> = Box<dyn Fn(&mut Core, &mut M) -> Result<u32, u32>>;
+
+#[allow(dead_code)]
+pub struct Block<M: Memory> {
+    pub start: u32,
+    pub end: u32,
+    pub thumb: bool,
+    pub insts: Vec<Inst>,
+    pub exit: Exit,
+    thread: Vec<Thread<M>>,
+    generations: Vec<(usize, Option<u32>)>,
+}
+
+#[allow(dead_code)]
+impl<M: Memory> Block<M> {
+    /* Whether the code has been written since the block was built */
+    fn valid(&self, bus: &M) -> bool {
+        self.generations.iter().all(|&(addr, generation)| generation.is_some() && bus.code_generation(addr) == generation)
+    }
+}
+
+pub struct Recompiler<M: Memory> {
+    interpreter: Interpreter<M>,
+    blocks: AddrMap<(u32, bool), Rc<Block<M>>>,
+    max_block: usize,
+}
+
+impl<M: Memory + 'static> Default for Recompiler<M> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/* What an instruction becomes */
+enum Translation {
+    Inst(Op),
+    Exit(Exit),
+    Stop,       //ends the block before the instruction
+}
+
+#[allow(dead_code)]
+impl<M: Memory + 'static> Recompiler<M> {
+    pub fn new() -> Self {
+        Self::with_max_block(MAX_BLOCK)
+    }
+
+    /* Blocks of at most max_block instructions, 1 runs an instruction per step so each one can be traced */
+    pub fn with_max_block(max_block: usize) -> Self {
+        Recompiler {
+            interpreter: Interpreter::new(Engine::Fast),
+            blocks: AddrMap::default(),
+            max_block,
+        }
+    }
+
+    /* Throw away every block */
+    pub fn clear(&mut self) {
+        self.blocks.clear();
+    }
+
+    /* Run the block at R15, or a single instruction if there isn't one there, returning the number of cycles. R15 is left pointing at the next instruction */
+    pub fn step(&mut self, core: &mut Core, bus: &mut M) -> u32 {
+        let key = (core.reg.gp[15], core.reg.cpsr.state);
+        /*
+        Code that writes aren't kept track of in would have to be translated again every time, so it's interpreted instead.
+        Interrupts and halting are left to the interpreter too, they only happen between blocks.
+        */
+        if bus.code_generation(key.0 as usize).is_none() || core.halted || bios::irq_pending(core, bus) {
+            return self.interpreter.step(core, bus);
+        }
+        let block = match self.blocks.get(&key) {
+            Some(block) if block.valid(bus) => block.clone(),
+            _ => {
+                let block = Rc::new(Self::compile_upto(bus, key.0, key.1, self.max_block));
+                if !block.valid(bus) {
+                    self.blocks.remove(&key);
+                    return self.interpreter.step(core, bus);
+                }
+                self.blocks.insert(key, block.clone());
+                block
+            }
+        };
+
+        if block.insts.is_empty() && block.exit == Exit::Next(block.start) {
+            return self.interpreter.step(core, bus);
+        }
+
+        let size = if block.thumb {2} else {4};
+        let mut cycles = 0;
+        for (inst, thread) in block.insts.iter().zip(block.thread.iter()) {
+            match thread(core, bus) {
+                Ok(taken) => cycles += taken,
+                Err(taken) => { //wrote over itself
+                    self.blocks.remove(&key);
+                    core.reg.gp[15] = inst.pc.wrapping_add(size);
+                    return cycles + taken;
+                }
+            }
+        }
+
+        let width = if block.thumb {Width::Half} else {Width::Word};
+        match block.exit {
+            Exit::Next(next) => core.reg.gp[15] = next,
+            Exit::Branch { cond, target, link, next } => {
+                cycles += bus.fetch_cycles(next.wrapping_add(size) as usize, width, Cycle::S);
+                if core.cond_codes(cond) {
+                    if let Some(link) = link {
+                        core.reg.write(14, link);
+                    }
+                    core.reg.gp[15] = target;
+                    cycles += bus.fetch_cycles(target as usize, width, Cycle::N) + bus.fetch_cycles(target.wrapping_add(size) as usize, width, Cycle::S);
+                } else {
+                    core.reg.gp[15] = next;
+                }
+            }
+        }
+        cycles
+    }
+
+    /* Translate the block starting at pc */
+    pub fn compile(bus: &mut M, pc: u32, thumb: bool) -> Block<M> {
+        Self::compile_upto(bus, pc, thumb, MAX_BLOCK)
+    }
+
+    pub fn compile_upto(bus: &mut M, pc: u32, thumb: bool, max_block: usize) -> Block<M> {
+        let size = if thumb {2} else {4};
+        let mut insts = Vec::new();
+        let mut addr = pc;
+        let mut exit = None;
+
+        /* Stopping at the end of memory, rather than reading past it */
+        while insts.len() < max_block && exit.is_none() && bus.mapped(addr as usize) {
+            let translation = if thumb {translate_thumb(bus, addr)} else {translate_arm(&bus.decoded_arm(addr as usize), addr)};
+            match translation {
+                Translation::Inst(op) => insts.push(Inst { pc: addr, cond: if thumb {AL} else {bus.decoded_arm(addr as usize).cond}, op }),
+                Translation::Exit(branch) => exit = Some(branch),
+                Translation::Stop => break,
+            }
+            addr = addr.wrapping_add(size);
+        }
+        let end = if exit.is_some() {addr} else {pc.wrapping_add(size * insts.len() as u32)};
+        let exit = exit.unwrap_or(Exit::Next(end));
+
+        flag_liveness(&mut insts);
+        fold_constants(&mut insts);
+
+        let last = end.wrapping_sub(size).max(pc);
+        let thread = insts.iter().map(|inst| thread::<M>(inst, thumb, pc, end)).collect();
+        Block {
+            start: pc,
+            end,
+            thumb,
+            insts,
+            exit,
+            thread,
+            generations: vec![(pc as usize, bus.code_generation(pc as usize)), (last as usize, bus.code_generation(last as usize))],
+        }
+    }
+}
+
+/* Sign extend the bottom bits of value */
+fn sign_extend(value: u32, bits: u32) -> u32 {
+    (((value << (32 - bits)) as i32) >> (32 - bits)) as u32
+}
+
+/* Translate an ARM instruction, r15 is what reading R15 gives (the address of the instruction + 8 for ARM and + 4 for Thumb) */
+fn translate_with(decoded: &ArmInst, r15: u32, thumb: bool) -> Translation {
+    let operand = |r: u32| if r == 15 {Operand::Imm(r15)} else {Operand::Reg(r as u8)};
+    let (rn, rd, rm) = (decoded.rn, decoded.rd, decoded.rm);
+
+    match decoded.insttype {
+        ArmInstType::DataProcessing => {
+            let regshift = decoded.i == 0 && decoded.shift & 0b1 == 1;
+            /* Writing R15 branches, and TSTP and the others with it as Rd restore the CPSR, both are left to the interpreter */
+            if rd == 15 || (regshift && (rn == 15 || rm == 15 || decoded.rs == 15)) {
+                return Translation::Stop;
+            }
+            let op2 = if decoded.i == 1 {
+                if decoded.off >> 8 == 0 {Shifter::Const(decoded.imm)} else {Shifter::RotImm(decoded.off)}
+            } else {
+                Shifter::Shift { rm: operand(rm), shift: decoded.shift }
+            };
+            Translation::Inst(Op::Alu { opcode: decoded.opcode as u8, rd: rd as u8, rn: operand(rn), op2, flags: decoded.l == 1 })
+        },
+        ArmInstType::SingleDataTransfer | ArmInstType::HalfwordDataTransferRegisterOffset | ArmInstType::HalfwordDataTransferImmediateOffset => {
+            let halfword = decoded.insttype != ArmInstType::SingleDataTransfer;
+            let pre = decoded.p == 1;
+            let writeback = !pre || decoded.a == 1;
+            let register = if halfword {decoded.insttype == ArmInstType::HalfwordDataTransferRegisterOffset} else {decoded.i == 1};
+            if (decoded.l == 1 && rd == 15) || (writeback && rn == 15) || (register && rm == 15) {
+                return Translation::Stop;
+            }
+
+            let offset = match (halfword, register) {
+                (false, false)  =>  Shifter::Const(decoded.off),
+                (false, true)   =>  Shifter::Shift { rm: operand(rm), shift: decoded.shift },
+                (true, false)   =>  Shifter::Const((decoded.rs << 4) | rm),
+                (true, true)    =>  Shifter::Shift { rm: operand(rm), shift: 0 },
+            };
+            let access = match (halfword, (decoded.s << 1) | decoded.h) {
+                (false, _)  =>  if decoded.b == 1 {Access::Byte} else {Access::Word},
+                (true, 1)   =>  Access::Half,
+                (true, 2)   =>  Access::SignedByte,
+                (true, _)   =>  Access::SignedHalf,
+            };
+            let (up, base) = (decoded.u == 1, operand(rn));
+
+            if decoded.l == 1 {
+                Translation::Inst(Op::Load { access, rd: rd as u8, rn: rn as u8, base, offset, pre, up, writeback })
+            } else {
+                /* The stored PC is another instruction on */
+                let rd = if rd == 15 {Operand::Imm(r15.wrapping_add(4))} else {Operand::Reg(rd as u8)};
+                let access = if access == Access::Byte || !halfword {access} else {Access::Half};
+                Translation::Inst(Op::Store { access, rd, rn: rn as u8, base, offset, pre, up, writeback })
+            }
+        },
+        /* Multiplies don't gain anything from the IR, and the rest need the ARM interpreter */
+        _ if thumb => Translation::Stop,
+        ArmInstType::Multiply | ArmInstType::MultiplyLong if rn != 15 && rd != 15 => Translation::Inst(Op::Interpret(decoded.inst)),
+        ArmInstType::SingleDataSwap if rd != 15 => Translation::Inst(Op::Interpret(decoded.inst)),
+        ArmInstType::PSRTransfer if decoded.psrop == 0 && rd != 15 => Translation::Inst(Op::Interpret(decoded.inst)),
+        ArmInstType::BlockDataTransfer if decoded.rlist & 0x8000 == 0 && decoded.rlist != 0 && rn != 15 => Translation::Inst(Op::Interpret(decoded.inst)),
+        _ => Translation::Stop,
+    }
+}
+
+fn translate_arm(decoded: &ArmInst, pc: u32) -> Translation {
+    if decoded.insttype == ArmInstType::Branch {
+        return Translation::Exit(Exit::Branch {
+            cond: decoded.cond,
+            target: pc.wrapping_add(8).wrapping_add(sign_extend(decoded.boff, 24) << 2),
+            link: if decoded.p == 1 {Some(pc.wrapping_add(4))} else {None},
+            next: pc.wrapping_add(4),
+        });
+    }
+    translate_with(decoded, pc.wrapping_add(8), false)
+}
+
+fn translate_thumb<M: Memory>(bus: &mut M, pc: u32) -> Translation {
+    let decoded = bus.decoded_thumb(pc as usize);
+    let inst = decoded.inst as u32;
+    let r15 = pc.wrapping_add(4);
+
+    match decoded.insttype {
+        ThumbInstType::ConditionalBranch => Translation::Exit(Exit::Branch {
+            cond: (inst >> 8) & 0xF,
+            target: r15.wrapping_add(sign_extend(inst & 0xFF, 8) << 1),
+            link: None,
+            next: pc.wrapping_add(2),
+        }),
+        ThumbInstType::UnconditionalBranch => Translation::Exit(Exit::Branch {
+            cond: AL,
+            target: r15.wrapping_add(sign_extend(inst & 0x7FF, 11) << 1),
+            link: None,
+            next: pc.wrapping_add(2),
+        }),
+        /* Adding to the word aligned PC has no ARM equivalent, so it's known while translating */
+        ThumbInstType::LoadAddress if (inst >> 11) & 0b1 == 0 => {
+            Translation::Inst(Op::Set { rd: ((inst >> 8) & 0x7) as u8, value: (r15 & !0b11).wrapping_add((inst & 0xFF) << 2) })
+        },
+        /* Anything that runs as an ARM instruction, PC relative addresses are word aligned */
+        ThumbInstType::PCRelativeLoad => match decoded.arm {
+            Some(arm) => translate_with(&arm, r15 & !0b11, true),
+            None => Translation::Stop,
+        },
+        ThumbInstType::LongBranchWithLink | ThumbInstType::SoftwareInterrupt | ThumbInstType::Undefined |
+        ThumbInstType::MultipleLoadStore | ThumbInstType::PushPopRegisters => Translation::Stop,
+        _ => match decoded.arm {
+            Some(arm) => translate_with(&arm, r15, true),
+            None => Translation::Stop,
+        },
+    }
+}
+
+/* Whether the barrel shifter always sets the carry for this operand */
+fn sets_carry(op2: &Shifter) -> bool {
+    match *op2 {
+        Shifter::Const(_) => false,
+        Shifter::RotImm(_) => true,
+        Shifter::Shift { shift, .. } => shift & 0b1 == 0 && shift & 0xFF != 0,  //an immediate shift other than LSL #0
+    }
+}
+
+/* Whether the operand is an RRX, which shifts the carry in */
+fn rrx(op2: &Shifter) -> bool {
+    matches!(*op2, Shifter::Shift { shift, .. } if shift & 0x7 == 0b110 && shift >> 3 == 0)
+}
+
+/* The flags an instruction reads, the ones it might write and the ones it is sure to write */
+fn flag_usage(inst: &Inst) -> (u8, u8, u8) {
+    let (reads, may, must) = match inst.op {
+        Op::Alu { opcode, op2, flags, .. } => {
+            let carry_in = (0x5..=0x7).contains(&opcode) || rrx(&op2);
+            let reads = if carry_in {FLAG_C} else {0};
+            let logical = matches!(opcode, 0x0 | 0x1 | 0x8 | 0x9 | 0xC..=0xF);
+            if !flags {
+                (reads, 0, 0)
+            } else if logical {
+                let carry = if sets_carry(&op2) {FLAG_C} else {0};
+                (reads, FLAG_N | FLAG_Z | FLAG_C, FLAG_N | FLAG_Z | carry)
+            } else {
+                (reads, FLAG_ALL, FLAG_ALL)
+            }
+        },
+        Op::Load { offset, .. } | Op::Store { offset, .. } if rrx(&offset) => (FLAG_C, 0, 0),
+        Op::Interpret(_) => (FLAG_ALL, FLAG_ALL, 0),
+        _ => (0, 0, 0),
+    };
+    if inst.cond == AL {(reads, may, must)} else {(FLAG_ALL, may, 0)}
+}
+
+/* Going backwards from the end of the block (where everything is live), drop the flags nothing is going to read */
+fn flag_liveness(insts: &mut [Inst]) {
+    let mut live = FLAG_ALL;
+    for inst in insts.iter_mut().rev() {
+        let (_, may, _) = flag_usage(inst);
+        if let Op::Alu { ref mut flags, .. } = inst.op {
+            if may & live == 0 {
+                *flags = false;
+            }
+        }
+        let (reads, _, must) = flag_usage(inst);
+        live = (live & !must) | reads;
+    }
+}
+
+/* The value of an immediate shift, None if it needs the carry flag */
+fn fold_shift(value: u32, shift: u32) -> Option<u32> {
+    let amount = shift >> 3;
+    match (shift >> 1) & 0b11 {
+        0 => Some(value << amount),
+        1 => Some(if amount == 0 {0} else {value >> amount}),
+        2 => Some(((value as i32) >> if amount == 0 {31} else {amount}) as u32),
+        _ => if amount == 0 {None} else {Some(value.rotate_right(amount))},
+    }
+}
+
+/* The result of a data processing operation that doesn't use the carry */
+fn fold_alu(opcode: u8, a: u32, b: u32) -> Option<u32> {
+    match opcode {
+        0x0 => Some(a & b),
+        0x1 => Some(a ^ b),
+        0x2 => Some(a.wrapping_sub(b)),
+        0x3 => Some(b.wrapping_sub(a)),
+        0x4 => Some(a.wrapping_add(b)),
+        0xC => Some(a | b),
+        0xD => Some(b),
+        0xE => Some(a & !b),
+        0xF => Some(!b),
+        _ => None,
+    }
+}
+
+/* Substitute known registers, and fold anything that only depends on constants */
+fn fold_constants(insts: &mut [Inst]) {
+    let mut known: [Option<u32>; 16] = [None; 16];
+
+    let substitute = |known: &[Option<u32>; 16], operand: Operand| match operand {
+        Operand::Reg(r) => known[r as usize].map_or(operand, Operand::Imm),
+        imm => imm,
+    };
+    let substitute_shift = |known: &[Option<u32>; 16], op2: Shifter, flags: bool| match op2 {
+        Shifter::RotImm(imm) if !flags => Shifter::Const((imm & 0xFF).rotate_right((imm >> 8) * 2)),
+        Shifter::Shift { rm, shift } => {
+            let rm = substitute(known, rm);
+            match rm {
+                Operand::Imm(value) if !flags && shift & 0b1 == 0 => fold_shift(value, shift).map_or(Shifter::Shift { rm, shift }, Shifter::Const),
+                _ => Shifter::Shift { rm, shift },
+            }
+        },
+        op2 => op2,
+    };
+
+    for inst in insts.iter_mut() {
+        let always = inst.cond == AL;
+        inst.op = match inst.op {
+            Op::Alu { opcode, rd, rn, op2, flags } => {
+                let rn = substitute(&known, rn);
+                let op2 = substitute_shift(&known, op2, flags);
+                let test = (0x8..=0xB).contains(&opcode);
+                let value = match (rn, op2) {
+                    (Operand::Imm(a), Shifter::Const(b)) if !flags => fold_alu(opcode, a, b),
+                    (_, Shifter::Const(b)) if !flags && (opcode == 0xD || opcode == 0xF) => fold_alu(opcode, 0, b),
+                    _ => None,
+                };
+
+                if test && !flags {
+                    Op::Nop
+                } else if let (Some(value), false) = (value, test) {
+                    known[rd as usize] = if always {Some(value)} else {None};
+                    Op::Set { rd, value }
+                } else {
+                    if !test {
+                        known[rd as usize] = None;
+                    }
+                    Op::Alu { opcode, rd, rn, op2, flags }
+                }
+            },
+            Op::Load { access, rd, rn, base, offset, pre, up, writeback } => {
+                let base = substitute(&known, base);
+                let offset = substitute_shift(&known, offset, false);
+                if writeback {
+                    known[rn as usize] = None;
+                }
+                known[rd as usize] = None;
+                Op::Load { access, rd, rn, base, offset, pre, up, writeback }
+            },
+            Op::Store { access, rd, rn, base, offset, pre, up, writeback } => {
+                let (rd, base) = (substitute(&known, rd), substitute(&known, base));
+                let offset = substitute_shift(&known, offset, false);
+                if writeback {
+                    known[rn as usize] = None;
+                }
+                Op::Store { access, rd, rn, base, offset, pre, up, writeback }
+            },
+            Op::Interpret(inst) => {
+                known = [None; 16];
+                Op::Interpret(inst)
+            },
+            op => op,
+        };
+    }
+}
+
+/* Read an operand */
+fn value(core: &mut Core, operand: Operand) -> u32 {
+    match operand {
+        Operand::Reg(r) => core.reg.read(r as usize),
+        Operand::Imm(value) => value,
+    }
+}
+
+/* The second operand through the barrel shifter of the core, leaving it ready for the ALU */
+fn shift(core: &mut Core, op2: Shifter) {
+    match op2 {
+        Shifter::Const(value) => {
+            core.bbus = value;
+            core.decode_shift_imm(0);
+        },
+        Shifter::RotImm(imm) => {
+            core.bbus = imm & 0xFF;
+            core.decode_shift_imm(imm);
+        },
+        Shifter::Shift { rm, shift } => {
+            core.bbus = value(core, rm);
+            core.decode_shift(shift);
+        },
+    }
+    core.barrel_shift();
+}
+
+/* The offset of a load or store, which can't use a register specified shift */
+fn offset(core: &mut Core, offset: Shifter) -> u32 {
+    match offset {
+        Shifter::Const(value) => value,
+        Shifter::Shift { rm, shift } => {
+            let value = value(core, rm);
+            fold_shift(value, shift).unwrap_or((value >> 1) | ((core.reg.cpsr.c as u32) << 31))
+        },
+        Shifter::RotImm(_) => unreachable!(),
+    }
+}
+
+/* Turn an instruction into a closure that runs it */
+fn thread<M: Memory + 'static>(inst: &Inst, thumb: bool, start: u32, end: u32) -> Thread<M> {
+    let (fetch, width) = if thumb {(inst.pc.wrapping_add(4) as usize, Width::Half)} else {(inst.pc.wrapping_add(8) as usize, Width::Word)};
+    let prefetch = move |bus: &mut M, cycle: Cycle| bus.fetch_cycles(fetch, width, cycle);
+
+    let run: Thread<M> = match inst.op {
+        Op::Nop => Box::new(move |_, bus| Ok(prefetch(bus, Cycle::S))),
+        Op::Set { rd, value } => Box::new(move |core, bus| {
+            core.reg.write(rd as usize, value);
+            Ok(prefetch(bus, Cycle::S))
+        }),
+        /* The common case of no flags and a simple second operand doesn't need the core */
+        Op::Alu { opcode, rd, rn, op2: Shifter::Const(b), flags: false } if fold_alu(opcode, 0, 0).is_some() => Box::new(move |core, bus| {
+            let a = value(core, rn);
+            core.reg.write(rd as usize, fold_alu(opcode, a, b).unwrap_or_default());
+            Ok(prefetch(bus, Cycle::S))
+        }),
+        Op::Alu { opcode, rd, rn, op2: Shifter::Shift { rm, shift }, flags: false }
+            if fold_alu(opcode, 0, 0).is_some() && shift & 0b1 == 0 && fold_shift(0, shift).is_some() => Box::new(move |core, bus| {
+            let (a, b) = (value(core, rn), value(core, rm));
+            core.reg.write(rd as usize, fold_alu(opcode, a, fold_shift(b, shift).unwrap_or_default()).unwrap_or_default());
+            Ok(prefetch(bus, Cycle::S))
+        }),
+        Op::Alu { opcode, rd, rn, op2, flags } => {
+            let regshift = matches!(op2, Shifter::Shift { shift, .. } if shift & 0b1 == 1);
+            Box::new(move |core, bus| {
+                let mut cycles = prefetch(bus, Cycle::S);
+                core.abus = value(core, rn);
+                shift(core, op2);
+                if regshift {
+                    bus.idle(1);
+                    cycles += 1;
+                }
+                core.setcond = flags;
+                core.aluop = opcode;
+                core.alu();
+                if !(0x8..=0xB).contains(&opcode) {
+                    core.reg.write(rd as usize, core.alubus);
+                }
+                Ok(cycles)
+            })
+        },
+        Op::Load { access, rd, rn, base, offset: off, pre, up, writeback } => Box::new(move |core, bus| {
+            let base = value(core, base);
+            let off = offset(core, off);
+            let moved = if up {base.wrapping_add(off)} else {base.wrapping_sub(off)};
+            let addr = if pre {moved} else {base};
+
+            let mut cycles = prefetch(bus, Cycle::S);
+            let (data, access) = match access {
+                Access::Word => {
+                    let (data, access) = bus.read((addr & !0b11) as usize, Width::Word, Cycle::N);
+                    (data.rotate_right(8 * (addr & 0b11)), access)
+                },
+                Access::Byte => bus.read(addr as usize, Width::Byte, Cycle::N),
+                Access::Half => {
+                    let (data, access) = bus.read((addr & !0b1) as usize, Width::Half, Cycle::N);
+                    (data.rotate_right(8 * (addr & 0b1)), access)
+                },
+                Access::SignedHalf if addr & 0b1 == 0 => {
+                    let (data, access) = bus.read(addr as usize, Width::Half, Cycle::N);
+                    (data as u16 as i16 as u32, access)
+                },
+                _ => {
+                    let (data, access) = bus.read(addr as usize, Width::Byte, Cycle::N);
+                    (data as u8 as i8 as u32, access)
+                },
+            };
+            cycles += access;
+            if writeback {
+                core.reg.write(rn as usize, moved);
+            }
+            bus.idle(1);
+            core.reg.write(rd as usize, data);
+            Ok(cycles + 1)
+        }),
+        Op::Store { access, rd, rn, base, offset: off, pre, up, writeback } => Box::new(move |core, bus| {
+            let base = value(core, base);
+            let off = offset(core, off);
+            let moved = if up {base.wrapping_add(off)} else {base.wrapping_sub(off)};
+            let addr = if pre {moved} else {base};
+
+            let mut cycles = prefetch(bus, Cycle::N);
+            let data = value(core, rd);
+            let addr = match access {
+                Access::Byte => addr,
+                Access::Half => addr & !0b1,
+                _ => addr & !0b11,
+            };
+            cycles += match access {
+                Access::Byte => bus.write(addr as usize, Width::Byte, Cycle::N, data),
+                Access::Half => bus.write(addr as usize, Width::Half, Cycle::N, data & 0xFFFF),
+                _ => bus.write(addr as usize, Width::Word, Cycle::N, data),
+            };
+            if writeback {
+                core.reg.write(rn as usize, moved);
+            }
+            if (start..end).contains(&addr) {Err(cycles)} else {Ok(cycles)}
+        }),
+        Op::Interpret(word) => {
+            let insttype = crate::decode::decode_arm(word);
+            let handler = interp::handler::<M>(insttype);
+            let next = inst.pc.wrapping_add(4);
+            /* Where STM and SWP write isn't known here, so a write anywhere in the pages of the block counts as writing over it */
+            let writes = insttype == ArmInstType::SingleDataSwap || (insttype == ArmInstType::BlockDataTransfer && word & (1 << 20) == 0);
+            let (first, last) = (start as usize, end.wrapping_sub(4) as usize);
+            Box::new(move |core, bus| {
+                let code = if writes {Some((bus.code_generation(first), bus.code_generation(last)))} else {None};
+                core.reg.gp[15] = next;
+                let cycles = handler(core, bus, word);
+                match code {
+                    Some(code) if code != (bus.code_generation(first), bus.code_generation(last)) => Err(cycles),
+                    _ => Ok(cycles),
+                }
+            })
+        },
+    };
+
+    /* The condition is checked first, failing it is just the opcode fetch */
+    let cond = inst.cond;
+    if cond == AL {
+        run
+    } else {
+        Box::new(move |core, bus| if core.cond_codes(cond) {run(core, bus)} else {Ok(prefetch(bus, Cycle::S))})
+    }
+}
@@ -0,0 +1,418 @@
+use std::io::{ self, BufRead, Write };
+
+use crate::arm7tdmi::{ Core, Reg };
+use crate::arm7tdmi::{ MODE_USR, MODE_FIQ, MODE_IRQ, MODE_SVC, MODE_ABT, MODE_UND, MODE_SYS };
+use crate::debugger::{ Debugger, Stop, Watched, Watchpoint };
+use crate::decode::{ self, ArmInstType, ThumbInstType, DisasmFormat };
+use crate::memory::Memory;
+
+/*
+The debug mode, a command line debugger for when there's no GDB about. It runs on the same Debugger as the GDB stub, one
+instruction at a time, and shows the state of the core in between. An empty line does the last command again, so stepping is
+just pressing enter.
+
+There's no PPU yet, so VBlank is worked out from the cycles that have been run: 1232 cycles a line, 228 lines a frame and VBlank
+from line 160 on, the same as the real thing as long as nothing stops the CPU. There's no Ctrl-C either, so continue, next and
+finish give up once they have run for CONTINUE_LIMIT cycles without stopping, and a game that waits for an interrupt that never
+comes, or a call that never returns, doesn't hang it.
+*/
+
+const CYCLES_PER_LINE: u64 = 1232;
+const CYCLES_PER_FRAME: u64 = 228 * CYCLES_PER_LINE;
+const VBLANK_START: u64 = 160 * CYCLES_PER_LINE;
+const CONTINUE_LIMIT: u64 = 16 * 1024 * 1024;   //about a second
+
+const HELP: &str = "\
+break [ADDR]        b   set a breakpoint, or list them
+delete [ADDR]       d   remove one, or all of them
+step [N]            s   run N instructions (1)
+next [N]            n   the same, running calls and SWIs to where they return
+continue [N]        c   run until something stops it, or N instructions
+finish              fin run until the current function returns to LR
+vblank              vb  run until the next VBlank
+regs                r   the registers and CPSR of the current mode
+banked                  every banked register and SPSR
+watch [SPEC]            add a watchpoint, KIND:START[-END][=VALUE] (read, write, access or exec), or list them
+unwatch [SPEC]          remove one, or all of them
+x ADDR [LEN]            hexdump memory (64 bytes)
+poke ADDR VALUE [b|h|w] write to memory (a word)
+disasm [ADDR] [N]   l   disassemble around the PC, or from ADDR (Thumb if it's odd)
+help                h   this
+quit                q   leave
+ADDR and VALUE are decimal, hex with 0x in front, or a register (r0-r15, sp, lr, pc)";
+
+/* The name the register file goes by in a mode */
+fn mode_name(mode: u8) -> &'static str {
+    match mode {
+        MODE_USR => "User",
+        MODE_FIQ => "FIQ",
+        MODE_IRQ => "IRQ",
+        MODE_SVC => "Supervisor",
+        MODE_ABT => "Abort",
+        MODE_UND => "Undefined",
+        MODE_SYS => "System",
+        _ => "Unset",
+    }
+}
+
+/* A PSR with its flags, capitals for the ones that are set */
+fn psr(word: u32) -> String {
+    let flag = |bit: u32, name: char| if word & (1 << bit) != 0 {name} else {name.to_ascii_lowercase()};
+    format!("{:08X}  {}{}{}{} {}{}{}  {}", word, flag(31, 'N'), flag(30, 'Z'), flag(29, 'C'), flag(28, 'V'), flag(7, 'I'), flag(6, 'F'), flag(5, 'T'), mode_name((word & 0x1F) as u8))
+}
+
+/* The registers and CPSR as the current mode sees them */
+#[allow(dead_code)]
+pub fn registers(reg: &Reg) -> String {
+    const NAMES: [&str; 16] = ["r0", "r1", "r2", "r3", "r4", "r5", "r6", "r7", "r8", "r9", "r10", "r11", "r12", "sp", "lr", "pc"];
+    let gp = reg.current();
+    let mut text = String::new();
+    for row in 0..4 {
+        let cells: Vec<String> = (row * 4..row * 4 + 4).map(|r| format!("{:>3} {:08X}", NAMES[r], gp[r])).collect();
+        text.push_str(&cells.join("   "));
+        text.push('\n');
+    }
+    text.push_str(&format!("cpsr {}\n", psr(u32::from(reg.cpsr))));
+    let mut reg = *reg;
+    if let Some(spsr) = reg.spsr() {
+        text.push_str(&format!("spsr {}\n", psr(u32::from(*spsr))));
+    }
+    text
+}
+
+/* Every bank, whatever the mode */
+#[allow(dead_code)]
+pub fn banked(reg: &Reg) -> String {
+    let row = |name: &str, first: usize, values: &[u32], spsr: Option<u32>| {
+        let cells: Vec<String> = values.iter().enumerate().map(|(i, value)| format!("r{}={:08X}", first + i, value)).collect();
+        let spsr = spsr.map(|word| format!("  spsr {}", psr(word))).unwrap_or_default();
+        format!("{:<4}{}{}\n", name, cells.join(" "), spsr)
+    };
+    [
+        row("usr", 8, &reg.gp[8..15], None),
+        row("fiq", 8, &reg.fiq, Some(u32::from(reg.spsr_fiq))),
+        row("svc", 13, &reg.svc, Some(u32::from(reg.spsr_svc))),
+        row("abt", 13, &reg.abt, Some(u32::from(reg.spsr_abt))),
+        row("irq", 13, &reg.irq, Some(u32::from(reg.spsr_irq))),
+        row("und", 13, &reg.und, Some(u32::from(reg.spsr_und))),
+    ].concat()
+}
+
+/* LEN bytes from ADDR, 16 to a line with their characters, ?? for anything that isn't mapped */
+#[allow(dead_code)]
+pub fn hexdump<M: Memory>(bus: &mut M, addr: u32, len: u32) -> String {
+    let mut text = String::new();
+    let mut line = addr & !0xF;
+    while line < addr.wrapping_add(len) {
+        let mut hex = String::new();
+        let mut chars = String::new();
+        for at in line..=line | 0xF {
+            let byte = (at >= addr && at < addr.wrapping_add(len) && bus.mapped(at as usize)).then(|| bus.mem_read(at as usize));
+            match byte {
+                Some(byte) => {
+                    hex.push_str(&format!("{:02X} ", byte));
+                    chars.push(if byte.is_ascii_graphic() || byte == b' ' {byte as char} else {'.'});
+                },
+                None => {
+                    hex.push_str(if at >= addr && at < addr.wrapping_add(len) {"?? "} else {"   "});
+                    chars.push(' ');
+                },
+            }
+            if at & 0x7 == 0x7 {
+                hex.push(' ');
+            }
+        }
+        text.push_str(&format!("{:08X}  {} {}\n", line, hex, chars.trim_end()));
+        line = match line.checked_add(16) {
+            Some(next) => next,
+            None => break,
+        };
+    }
+    text
+}
+
+/* The instruction at addr in the layout of a listing, and how long it is (a BL pair is one) */
+fn instruction<M: Memory>(bus: &mut M, addr: u32, thumb: bool) -> (String, u32) {
+    let size = if thumb {2} else {4};
+    if !(0..size).all(|i| bus.mapped(addr.wrapping_add(i) as usize)) {
+        return (format!("{:08X}  ????????", addr), size);
+    }
+    let format = DisasmFormat::default();
+    if thumb {
+        let inst = bus.mem_read_16(addr as usize);
+        let text = decode::disassemble_thumb_with(inst, addr, &format, &mut |at| bus.mapped(at as usize).then(|| bus.mem_read_16(at as usize)));
+        if decode::decode_thumb(inst) == ThumbInstType::LongBranchWithLink && text.starts_with("bl") && (inst >> 11) & 0b1 == 0 {
+            let word = bus.mem_read_32(addr as usize);
+            (format!("{:08X}  {:08X}  {}", addr, word, text), 4)
+        } else {
+            (format!("{:08X}  {:04X}      {}", addr, inst, text), 2)
+        }
+    } else {
+        let inst = bus.mem_read_32(addr as usize);
+        (format!("{:08X}  {:08X}  {}", addr, inst, decode::disassemble_arm(inst, addr)), 4)
+    }
+}
+
+/* count instructions from addr, the one at pc marked with => */
+#[allow(dead_code)]
+pub fn disassembly<M: Memory>(bus: &mut M, addr: u32, count: u32, thumb: bool, pc: u32) -> String {
+    let mut text = String::new();
+    let mut at = addr;
+    for _ in 0..count {
+        let (line, size) = instruction(bus, at, thumb);
+        text.push_str(if at == pc {"=> "} else {"   "});
+        text.push_str(&line);
+        text.push('\n');
+        at = at.wrapping_add(size);
+    }
+    text
+}
+
+/* Where a call at pc comes back to, if the instruction there is one (BL or SWI) */
+fn return_address<M: Memory>(bus: &mut M, pc: u32, thumb: bool) -> Option<u32> {
+    if thumb {
+        let inst = bus.mem_read_16(pc as usize);
+        match decode::decode_thumb(inst) {
+            ThumbInstType::LongBranchWithLink if (inst >> 11) & 0b1 == 0 => Some(pc.wrapping_add(4)),
+            ThumbInstType::SoftwareInterrupt => Some(pc.wrapping_add(2)),
+            _ => None,
+        }
+    } else {
+        let inst = bus.mem_read_32(pc as usize);
+        match decode::decode_arm(inst) {
+            ArmInstType::Branch if (inst >> 24) & 0b1 == 1 => Some(pc.wrapping_add(4)),
+            ArmInstType::SoftwareInterrupt => Some(pc.wrapping_add(4)),
+            _ => None,
+        }
+    }
+}
+
+#[allow(dead_code)]
+pub struct Repl<M: Memory + 'static> {
+    pub debugger: Debugger<M>,
+    last: String,
+}
+
+impl<M: Memory + 'static> Default for Repl<M> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[allow(dead_code)]
+impl<M: Memory + 'static> Repl<M> {
+    pub fn new() -> Self {
+        Repl {
+            debugger: Debugger::new(),
+            last: String::new(),
+        }
+    }
+
+    /* Read commands until quit or the end of the input */
+    pub fn run(&mut self, core: &mut Core, bus: &mut Watched<M>, input: &mut dyn BufRead, out: &mut dyn Write) -> io::Result<()> {
+        write!(out, "{}", self.location(core, bus))?;
+        loop {
+            write!(out, "(gba) ")?;
+            out.flush()?;
+            let mut line = String::new();
+            if input.read_line(&mut line)? == 0 {
+                return Ok(());
+            }
+            match self.command(&line, core, bus) {
+                Some(text) => write!(out, "{}", text)?,
+                None => return Ok(()),
+            }
+        }
+    }
+
+    /* Do one command line, giving what it prints. None is quit */
+    pub fn command(&mut self, line: &str, core: &mut Core, bus: &mut Watched<M>) -> Option<String> {
+        let line = if line.trim().is_empty() {self.last.clone()} else {line.trim().to_string()};
+        self.last = line.clone();
+
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let Some((&name, args)) = words.split_first() else {return Some(String::new())};
+        let reg = core.reg.current();
+        let value = |i: usize| args.get(i).map(|text| parse(text, &reg));
+        let count = |i: usize, default: u32| match value(i) {
+            Some(Some(count)) => Ok(count),
+            Some(None) => Err(format!("{} isn't a number\n", args[i])),
+            None => Ok(default),
+        };
+
+        let text = match name {
+            "quit" | "q" | "exit" => return None,
+            "help" | "h" => format!("{}\n", HELP),
+            "break" | "b" => match value(0) {
+                Some(Some(addr)) => {
+                    self.debugger.breakpoints.insert(addr);
+                    format!("Breakpoint at 0x{:08X}\n", addr)
+                },
+                Some(None) => format!("{} isn't an address\n", args[0]),
+                None if self.debugger.breakpoints.is_empty() => "No breakpoints\n".to_string(),
+                None => self.debugger.breakpoints.iter().map(|addr| format!("0x{:08X}\n", addr)).collect(),
+            },
+            "delete" | "d" => match value(0) {
+                Some(Some(addr)) if self.debugger.breakpoints.remove(&addr) => format!("Removed the breakpoint at 0x{:08X}\n", addr),
+                Some(_) => format!("There's no breakpoint at {}\n", args[0]),
+                None => {
+                    self.debugger.breakpoints.clear();
+                    "Removed every breakpoint\n".to_string()
+                },
+            },
+            "step" | "s" => match count(0, 1) {
+                Ok(count) => self.step(core, bus, count, false),
+                Err(error) => error,
+            },
+            "next" | "n" => match count(0, 1) {
+                Ok(count) => self.step(core, bus, count, true),
+                Err(error) => error,
+            },
+            "continue" | "c" => match count(0, 0) {
+                Ok(0) => {
+                    let start = self.debugger.cycles;
+                    let stop = self.debugger.run_until(core, bus, |_, cycles| cycles - start >= CONTINUE_LIMIT);
+                    self.stopped(stop, core, bus, &still_running())
+                },
+                Ok(count) => {
+                    let mut left = count;
+                    let stop = self.debugger.run_until(core, bus, |_, _| {
+                        left -= 1;
+                        left == 0
+                    });
+                    self.stopped(stop, core, bus, &format!("Ran {} instructions", count))
+                },
+                Err(error) => error,
+            },
+            "finish" | "fin" => {
+                let target = reg[14] & !0b1;
+                let start = self.debugger.cycles;
+                let stop = self.debugger.run_until(core, bus, |core, cycles| core.reg.gp[15] == target || cycles - start >= CONTINUE_LIMIT);
+                let reason = if core.reg.gp[15] == target {format!("Returned to 0x{:08X}", target)} else {still_running()};
+                self.stopped(stop, core, bus, &reason)
+            },
+            "vblank" | "vb" => {
+                let cycles = self.debugger.cycles;
+                let frame = cycles - cycles % CYCLES_PER_FRAME;
+                let target = if cycles % CYCLES_PER_FRAME < VBLANK_START {frame + VBLANK_START} else {frame + CYCLES_PER_FRAME + VBLANK_START};
+                let stop = self.debugger.run_until(core, bus, |_, cycles| cycles >= target);
+                self.stopped(stop, core, bus, &format!("VBlank of frame {} (cycle {})", target / CYCLES_PER_FRAME, self.debugger.cycles))
+            },
+            "watch" => match args.first() {
+                Some(spec) => match Watchpoint::parse(spec) {
+                    Some(watch) => {
+                        bus.watchpoints.push(watch);
+                        format!("Watching {}\n", watch)
+                    },
+                    None => format!("{} should be KIND:START[-END][=VALUE]\n", spec),
+                },
+                None if bus.watchpoints.is_empty() => "No watchpoints\n".to_string(),
+                None => bus.watchpoints.iter().map(|watch| format!("{}\n", watch)).collect(),
+            },
+            "unwatch" => match args.first() {
+                Some(spec) => match Watchpoint::parse(spec) {
+                    Some(watch) if bus.watchpoints.contains(&watch) => {
+                        bus.watchpoints.retain(|other| *other != watch);
+                        format!("Stopped watching {}\n", watch)
+                    },
+                    _ => format!("There's no watchpoint {}\n", spec),
+                },
+                None => {
+                    bus.watchpoints.clear();
+                    "Removed every watchpoint\n".to_string()
+                },
+            },
+            "regs" | "r" => registers(&core.reg),
+            "banked" => banked(&core.reg),
+            "x" => match (value(0), count(1, 64)) {
+                (Some(Some(addr)), Ok(len)) => hexdump(bus, addr, len),
+                (None, _) => "x needs an address\n".to_string(),
+                (Some(None), _) => format!("{} isn't an address\n", args[0]),
+                (_, Err(error)) => error,
+            },
+            "poke" => match (value(0), value(1), args.get(2).copied().unwrap_or("w")) {
+                (Some(Some(addr)), Some(Some(data)), width) if bus.mapped(addr as usize) => {
+                    match width {
+                        "b" => bus.mem_write(addr as usize, data as u8),
+                        "h" => bus.mem_write_16(addr as usize, data as u16),
+                        "w" => bus.mem_write_32(addr as usize, data),
+                        _ => return Some(format!("The width should be b, h or w, not {}\n", width)),
+                    }
+                    hexdump(bus, addr, match width {"b" => 1, "h" => 2, _ => 4})
+                },
+                (Some(Some(addr)), Some(Some(_)), _) => format!("Nothing is mapped at 0x{:08X}\n", addr),
+                _ => "poke needs an address and a value\n".to_string(),
+            },
+            "disasm" | "l" => {
+                let thumb = core.reg.cpsr.state;
+                let size = if thumb {2} else {4};
+                match (value(0), count(1, 10)) {
+                    (Some(Some(addr)), Ok(count)) => disassembly(bus, addr & !0b1, count, thumb || addr & 0b1 == 1, reg[15]),
+                    (None, _) => disassembly(bus, reg[15].wrapping_sub(4 * size), 10, thumb, reg[15]),
+                    (Some(None), _) => format!("{} isn't an address\n", args[0]),
+                    (_, Err(error)) => error,
+                }
+            },
+            _ => format!("Unknown command {}, try help\n", name),
+        };
+        Some(text)
+    }
+
+    /* count instructions, over calls as well if over is set, stopping early for breakpoints and watchpoints */
+    fn step(&mut self, core: &mut Core, bus: &mut Watched<M>, count: u32, over: bool) -> String {
+        for _ in 0..count {
+            let pc = core.reg.gp[15];
+            let stop = match return_address(bus, pc, core.reg.cpsr.state).filter(|_| over) {
+                Some(target) => {
+                    let start = self.debugger.cycles;
+                    match self.debugger.run_until(core, bus, |core, cycles| core.reg.gp[15] == target || cycles - start >= CONTINUE_LIMIT) {
+                        Stop::Interrupted if core.reg.gp[15] == target => Stop::Stepped,
+                        stop => stop,
+                    }
+                },
+                None => self.debugger.step(core, bus).1,
+            };
+            if stop != Stop::Stepped {
+                return self.stopped(stop, core, bus, &still_running());
+            }
+        }
+        self.location(core, bus)
+    }
+
+    /* Why it stopped and where it is, reason is what to say for Interrupted */
+    fn stopped(&mut self, stop: Stop, core: &mut Core, bus: &mut Watched<M>, reason: &str) -> String {
+        let why = match stop {
+            Stop::Stepped => String::new(),
+            Stop::Breakpoint(addr) => format!("Breakpoint at 0x{:08X}\n", addr),
+            Stop::Watchpoint(hit) => format!("{}\n", hit),
+            Stop::Interrupted => format!("{}\n", reason),
+        };
+        why + &self.location(core, bus)
+    }
+
+    /* The instruction about to run */
+    fn location(&self, core: &Core, bus: &mut Watched<M>) -> String {
+        format!("=> {}\n", instruction(bus, core.reg.gp[15], core.reg.cpsr.state).0)
+    }
+}
+
+fn still_running() -> String {
+    format!("Still running after {} cycles", CONTINUE_LIMIT)
+}
+
+/* A number (hex with 0x) or the name of a register */
+fn parse(text: &str, reg: &[u32; 16]) -> Option<u32> {
+    let register = match text {
+        "sp" => Some(13),
+        "lr" => Some(14),
+        "pc" => Some(15),
+        _ => text.strip_prefix('r').and_then(|n| n.parse::<usize>().ok()).filter(|&n| n < 16),
+    };
+    if let Some(n) = register {
+        return Some(reg[n]);
+    }
+    match text.strip_prefix("0x").or(text.strip_prefix("0X")) {
+        Some(hex) => u32::from_str_radix(hex, 16).ok(),
+        None => text.parse().ok(),
+    }
+}
@@ -0,0 +1,206 @@
+use std::collections::VecDeque;
+use std::io::{ self, Write };
+use std::ops::Range;
+
+use crate::arm7tdmi::Reg;
+use crate::decode::{ self, DisasmFormat, HexStyle };
+use crate::memory::Memory;
+
+/*
+Execution traces, one line for each instruction as it's about to run: the PC, the opcode, its disassembly, all 16 registers
+and the CPSR. This is the main way of chasing CPU bugs, a trace from here can be diffed line by line with one from another
+emulator (or an older build of this one) to find the first instruction where they go different ways.
+
+Lines either go straight to a writer, or into a ring buffer that keeps only the last so many, which is what you want when
+something goes wrong a long way into a run and only the lead up to it matters.
+*/
+
+#[allow(dead_code)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TraceFormat {
+    Plain,  //out.txt's layout, then the registers and the CPSR
+    Mgba,   //the log mGBA and NanoBoyAdvance write for the BIOS boot
+}
+
+#[allow(dead_code)]
+impl TraceFormat {
+    pub fn from_name(name: &str) -> Option<TraceFormat> {
+        match name {
+            "plain" => Some(TraceFormat::Plain),
+            "mgba" | "nba" => Some(TraceFormat::Mgba),
+            _ => None,
+        }
+    }
+
+    /*
+    The line for the instruction at R15, with the registers of the current mode. mGBA shows R15 as the pipeline sees it (8 or 4 ahead) and the opcode after the
+    registers, a Thumb one right aligned. Only the registers and opcode will match another emulator, the disassembly is ours
+    */
+    pub fn line(&self, reg: &Reg, opcode: u32, disassembly: &str) -> String {
+        let thumb = reg.cpsr.state;
+        let gp = reg.current();
+        let pc = gp[15];
+        let cpsr = u32::from(reg.cpsr);
+        let opcode = if thumb {format!("{:>8}", format!("{:04X}", opcode))} else {format!("{:08X}", opcode)};
+
+        match self {
+            TraceFormat::Plain => {
+                let registers: Vec<String> = gp.iter().enumerate().map(|(r, value)| format!("r{}={:08X}", r, value)).collect();
+                format!("{:08X}  {}  {:<40} {} cpsr={:08X}", pc, opcode, disassembly, registers.join(" "), cpsr)
+            },
+            TraceFormat::Mgba => {
+                let r15 = pc.wrapping_add(if thumb {4} else {8});
+                let registers: Vec<String> = gp[..15].iter().chain(std::iter::once(&r15)).map(|value| format!("{:08X}", value)).collect();
+                format!("{} cpsr: {:08X} | {}: {}", registers.join(" "), cpsr, opcode, disassembly)
+            },
+        }
+    }
+
+    /* How the disassembly is written, mGBA's is closer to what assemblers take */
+    pub fn disasm_format(&self) -> DisasmFormat {
+        match self {
+            TraceFormat::Plain => DisasmFormat::default(),
+            TraceFormat::Mgba => DisasmFormat { hex: HexStyle::Hash, column: 0, ..Default::default() },
+        }
+    }
+}
+
+/* The opcode at R15 and its disassembly */
+fn instruction<M: Memory>(reg: &Reg, bus: &mut M, format: &DisasmFormat) -> (u32, String) {
+    let pc = reg.gp[15];
+    if reg.cpsr.state {
+        let opcode = bus.mem_read_16(pc as usize);
+        (opcode as u32, decode::disassemble_thumb_with(opcode, pc, format, &mut |addr| Some(bus.mem_read_16(addr as usize))))
+    } else {
+        let opcode = bus.mem_read_32(pc as usize);
+        (opcode, decode::disassemble_arm_with(opcode, pc, format))
+    }
+}
+
+/* One line of an mGBA trace, the state of the core before the instruction runs. R15 is what mGBA shows, 8 or 4 ahead */
+#[allow(dead_code)]
+#[derive(Clone, Debug, PartialEq)]
+pub struct TraceEntry {
+    pub gp: [u32; 16],
+    pub cpsr: u32,
+    pub opcode: u32,
+    pub disassembly: String,
+}
+
+#[allow(dead_code)]
+impl TraceEntry {
+    /* Read a line written in the Mgba format, by us or by mGBA and NanoBoyAdvance */
+    pub fn parse(line: &str) -> Option<TraceEntry> {
+        let (state, inst) = line.split_once(" | ")?;
+        let (registers, cpsr) = state.split_once(" cpsr: ")?;
+        let (opcode, disassembly) = inst.split_once(':')?;
+
+        let registers: Vec<u32> = registers.split_whitespace().map(|value| u32::from_str_radix(value, 16).ok()).collect::<Option<_>>()?;
+        Some(TraceEntry {
+            gp: registers.try_into().ok()?,
+            cpsr: u32::from_str_radix(cpsr.trim(), 16).ok()?,
+            opcode: u32::from_str_radix(opcode.trim(), 16).ok()?,
+            disassembly: disassembly.trim().to_string(),
+        })
+    }
+
+    /* The same for the core as it is now */
+    pub fn capture<M: Memory>(reg: &Reg, bus: &mut M) -> TraceEntry {
+        let format = TraceFormat::Mgba;
+        let (opcode, disassembly) = instruction(reg, bus, &format.disasm_format());
+        TraceEntry::parse(&format.line(reg, opcode, &disassembly)).expect("A line we wrote should read back")
+    }
+
+    /* The address of the instruction */
+    pub fn pc(&self) -> u32 {
+        self.gp[15].wrapping_sub(if self.cpsr & (1 << 5) != 0 {4} else {8})
+    }
+}
+
+/* Where the lines go */
+enum Sink {
+    Writer(Box<dyn Write>),
+    Ring { capacity: usize, lines: VecDeque<String> },
+}
+
+#[allow(dead_code)]
+pub struct Tracer {
+    pub format: TraceFormat,
+    pub range: Option<Range<u32>>,  //only instructions in here are logged, everything if None
+    pub count: u64,                 //instructions logged so far
+    disasm: DisasmFormat,
+    sink: Sink,
+}
+
+#[allow(dead_code)]
+impl Tracer {
+    /* Write each line as it's made */
+    pub fn new(format: TraceFormat, out: Box<dyn Write>) -> Self {
+        Tracer {
+            format,
+            range: None,
+            count: 0,
+            disasm: format.disasm_format(),
+            sink: Sink::Writer(out),
+        }
+    }
+
+    /* Keep the last capacity lines */
+    pub fn ring(format: TraceFormat, capacity: usize) -> Self {
+        Tracer {
+            format,
+            range: None,
+            count: 0,
+            disasm: format.disasm_format(),
+            sink: Sink::Ring { capacity, lines: VecDeque::with_capacity(capacity) },
+        }
+    }
+
+    /* Log the instruction R15 points at, before it runs. Call it once per instruction, not per cycle */
+    pub fn record<M: Memory>(&mut self, reg: &Reg, bus: &mut M) {
+        let pc = reg.gp[15];
+        if self.range.as_ref().is_some_and(|range| !range.contains(&pc)) {
+            return;
+        }
+
+        let (opcode, disassembly) = instruction(reg, bus, &self.disasm);
+        let line = self.format.line(reg, opcode, &disassembly);
+        self.count += 1;
+
+        match &mut self.sink {
+            Sink::Writer(out) => {
+                /* A trace that can't be written isn't worth stopping the emulator for */
+                let _ = writeln!(out, "{}", line);
+            },
+            Sink::Ring { capacity, lines } => {
+                if lines.len() == *capacity {
+                    lines.pop_front();
+                }
+                if *capacity > 0 {
+                    lines.push_back(line);
+                }
+            },
+        }
+    }
+
+    /* What's in the ring buffer, oldest first. Nothing is kept when writing straight out */
+    pub fn lines(&self) -> Vec<&str> {
+        match &self.sink {
+            Sink::Writer(_) => Vec::new(),
+            Sink::Ring { lines, .. } => lines.iter().map(String::as_str).collect(),
+        }
+    }
+
+    /* Write out and empty the ring buffer */
+    pub fn dump(&mut self, out: &mut dyn Write) -> io::Result<()> {
+        match &mut self.sink {
+            Sink::Writer(writer) => writer.flush(),
+            Sink::Ring { lines, .. } => {
+                for line in lines.drain(..) {
+                    writeln!(out, "{}", line)?;
+                }
+                Ok(())
+            },
+        }
+    }
+}
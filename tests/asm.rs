@@ -0,0 +1,153 @@
+#[path = "../src/arm7tdmi.rs"]
+mod arm7tdmi;
+#[path = "../src/decode.rs"]
+mod decode;
+#[path = "../src/exec.rs"]
+mod exec;
+#[path = "../src/bios.rs"]
+mod bios;
+#[path = "../src/memory.rs"]
+mod memory;
+#[path = "../src/interp.rs"]
+mod interp;
+#[path = "../src/asm.rs"]
+mod asm;
+
+/*
+The assembler, checked against the disassembler and by running what it makes
+*/
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use memory::Memory;
+    use decode::{ DisasmFormat, HexStyle, Syntax };
+    use interp::{ Engine, Interpreter };
+
+    /* Disassembling, assembling that and disassembling again has to give the same text. The words can differ in bits that don't matter */
+    fn round_trip_arm(inst: u32, pc: u32, format: &DisasmFormat) {
+        let text = decode::disassemble_arm_with(inst, pc, format);
+        if text == "Undefined" {
+            return
+        }
+        let program = asm::assemble(&text, pc).unwrap_or_else(|error| panic!("{} in {:?} (0x{:08X})", error, text, inst));
+        assert_eq!(decode::disassemble_arm_with(program.words()[0], pc, format), text, "0x{:08X}", inst);
+    }
+
+    fn round_trip_thumb(inst: u16, pc: u32, format: &DisasmFormat) {
+        let text = decode::disassemble_thumb_with(inst, pc, format, &mut |_| None);
+        if text == "Undefined" {
+            return
+        }
+        let program = asm::assemble(&format!(".thumb\n{}", text), pc).unwrap_or_else(|error| panic!("{} in {:?} (0x{:04X})", error, text, inst));
+        assert_eq!(decode::disassemble_thumb_with(program.halfwords()[0], pc, format, &mut |_| None), text, "0x{:04X}", inst);
+    }
+
+    #[test]
+    fn single_instructions() {
+        assert_eq!(asm::arm("b 0xC0"), 0xEA00002E);
+        assert_eq!(asm::arm("stmfd sp!,{r12,lr}"), 0xE92D5000);
+        assert_eq!(asm::arm("push {r12,lr}"), 0xE92D5000);
+        assert_eq!(asm::arm("ldr r12,[r11,r12,lsl 0x2]"), 0xE79BC10C);
+        assert_eq!(asm::arm("LDRB r4,[r0],#4"), 0xE4D04004);
+        assert_eq!(asm::arm("ldreqsb r1,[lr],-0x93"), 0x005E19D3);
+        assert_eq!(asm::arm("umlals r1,r2,r7,r5"), 0xE0B21597);
+        assert_eq!(asm::arm("msr cpsr_fc,r0"), 0xE129F000);
+        assert_eq!(asm::arm("ldmia r0,{r0-r3}"), 0xE890000F);
+        assert_eq!(asm::arm("swi Div"), 0xEF060000);
+
+        assert_eq!(asm::thumb("movs r0,5"), 0x2005);
+        assert_eq!(asm::thumb("adds r1,r0,#3"), 0x1CC1);
+        assert_eq!(asm::thumb("nop_sled: b nop_sled"), 0xE7FE);
+        assert_eq!(asm::thumb("mov r8,r8"), 0x46C0);
+        assert_eq!(asm::thumb("push {r4-r7,lr}"), 0xB5F0);
+        assert_eq!(asm::thumb("ldrh r0,[r1,2]"), 0x8848);
+        assert_eq!(asm::thumb("swi 0x06"), 0xDF06);
+    }
+
+    #[test]
+    fn round_trips() {
+        let default = DisasmFormat::default();
+        let other = DisasmFormat { syntax: Syntax::PreUal, hex: HexStyle::Hash, column: 0, ..Default::default() };
+        let suffix = DisasmFormat { hex: HexStyle::Suffix, ..Default::default() };
+
+        /* Everything in out.txt, then a spread of other words */
+        let listing = std::fs::read_to_string("out.txt").unwrap();
+        for line in listing.lines() {
+            let pc = u32::from_str_radix(&line[0..8], 16).unwrap();
+            let inst = u32::from_str_radix(&line[10..18], 16).unwrap();
+            round_trip_arm(inst, pc, &default);
+        }
+
+        let mut inst: u32 = 1;
+        for _ in 0..50000 {
+            inst = inst.wrapping_mul(1664525).wrapping_add(1013904223);
+            round_trip_arm(inst, 0x08000000, &default);
+            round_trip_arm(inst, 0x08000000, &other);
+            round_trip_arm(inst, 0x08000000, &suffix);
+        }
+
+        /* And every Thumb instruction */
+        for inst in 0..=0xFFFF {
+            round_trip_thumb(inst, 0x08000100, &default);
+            round_trip_thumb(inst, 0x08000100, &other);
+        }
+    }
+
+    #[test]
+    fn labels_and_literals() {
+        let program = asm::assemble("
+            start:  ldr r0,=0x12345678      ; goes in the pool
+                    ldr r1,=0xFF000000      ; a MOV can do this one
+                    ldr r2,=data
+                    b start
+                    .pool
+            data:   .word 0xDEADBEEF, start
+                    .hword -1
+                    .byte 1, 2
+        ", 0x08000000).unwrap();
+
+        assert_eq!(program.symbols["start"], 0x08000000);
+        assert_eq!(program.symbols["data"], 0x08000018);
+        assert_eq!(program.words(), vec![0xE59F0008, 0xE3A014FF, 0xE59F2004, 0xEAFFFFFB, 0x12345678, 0x08000018, 0xDEADBEEF, 0x08000000, 0x0201FFFF]);
+        assert_eq!(program.names()[&0x08000018], "data");
+
+        let error = asm::assemble("mov r0,r1\nmov r0,0x101\n", 0).unwrap_err();
+        assert_eq!(error.line, 2);
+        assert!(asm::assemble("ldr r0,[r1,0x1000]", 0).is_err());
+        assert!(asm::assemble(".thumb\nadd r9,r0,r1", 0).is_err());
+        assert!(asm::assemble("b nowhere", 0).is_err());
+    }
+
+    /* A snippet run like a CPU test would be */
+    #[test]
+    fn runs() {
+        let program = asm::assemble("
+                    mov r0,0
+                    mov r1,10
+            sum:    add r0,r0,r1
+                    subs r1,r1,1
+                    bne sum
+                    ldr r4,=0xCAFEBABE
+                    bl double
+            done:   b done
+
+            double: mov r0,r0,lsl 1
+                    bx lr
+        ", 0x100).unwrap();
+
+        let mut core = arm7tdmi::Core::new();
+        let mut bus = memory::FlatMemory::new(0x1000);
+        for (i, byte) in program.bytes.iter().enumerate() {
+            bus.mem_write(0x100 + i, *byte);
+        }
+        core.reg.gp[15] = 0x100;
+
+        let interpreter: Interpreter<memory::FlatMemory> = Interpreter::new(Engine::Fast);
+        while core.reg.gp[15] != program.symbols["done"] {
+            interpreter.step(&mut core, &mut bus);
+        }
+        assert_eq!(core.reg.gp[0], 110);
+        assert_eq!(core.reg.gp[4], 0xCAFEBABE);
+    }
+}
@@ -0,0 +1,339 @@
+#[path = "../src/arm7tdmi.rs"]
+mod arm7tdmi;
+#[path = "../src/decode.rs"]
+mod decode;
+#[path = "../src/exec.rs"]
+mod exec;
+#[path = "../src/bios.rs"]
+mod bios;
+#[path = "../src/memory.rs"]
+mod memory;
+#[path = "../src/interp.rs"]
+mod interp;
+#[path = "../src/bus.rs"]
+mod bus;
+#[path = "../src/cache.rs"]
+mod cache;
+#[path = "../src/recomp.rs"]
+mod recomp;
+
+/*
+The high level emulated BIOS calls, run with a SWI at 0x08000000 like a game would
+*/
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use memory::Memory;
+    use interp::{ Engine, Interpreter };
+
+    fn setup() -> (arm7tdmi::Core, bus::Bus) {
+        let mut core = arm7tdmi::Core::new();
+        let mut bus = bus::Bus::new();
+        bios::install(&mut bus);
+        bios::boot_state(&mut core, 0x08000000);
+        core.hle_bios = true;
+        (core, bus)
+    }
+
+    /* Put the SWI for a call at 0x08000000 and run it */
+    fn call(core: &mut arm7tdmi::Core, bus: &mut bus::Bus, number: u32) -> u32 {
+        let fast: Interpreter<bus::Bus> = Interpreter::new(Engine::Fast);
+        bus.mem_write_32(0x08000000, 0xEF000000 | (number << 16));
+        core.reg.gp[15] = 0x08000000;
+        fast.step(core, bus)
+    }
+
+    #[test]
+    fn swi_runs_natively() {
+        let (mut core, mut bus) = setup();
+        let stepped: Interpreter<bus::Bus> = Interpreter::new(Engine::Stepped);
+        bus.mem_write_32(0x08000000, 0xEF060000); //SWI 0x06 (Div)
+        core.reg.gp[0] = -7i32 as u32;
+        core.reg.gp[1] = 2;
+
+        stepped.step(&mut core, &mut bus);
+        assert_eq!((core.reg.gp[0] as i32, core.reg.gp[1] as i32, core.reg.gp[3]), (-3, -1, 3));
+        assert_eq!(core.reg.gp[15], 0x08000004);
+        assert_eq!(core.reg.cpsr.mode(), arm7tdmi::MODE_SYS);
+
+        /* Without it the exception is taken as normal */
+        core.hle_bios = false;
+        core.reg.gp[15] = 0x08000000;
+        stepped.step(&mut core, &mut bus);
+        assert_eq!(core.reg.gp[15], 0x08);
+        assert_eq!(core.reg.cpsr.mode(), arm7tdmi::MODE_SVC);
+        assert_eq!(core.reg.read(14), 0x08000004);
+    }
+
+    #[test]
+    fn arithmetic() {
+        let (mut core, mut bus) = setup();
+
+        core.reg.gp[0] = 2;
+        core.reg.gp[1] = 100;
+        call(&mut core, &mut bus, 0x07); //DivArm
+        assert_eq!((core.reg.gp[0], core.reg.gp[1]), (50, 0));
+
+        core.reg.gp[0] = 1000;
+        call(&mut core, &mut bus, 0x08); //Sqrt
+        assert_eq!(core.reg.gp[0], 31);
+
+        core.reg.gp[0] = 0x4000;
+        call(&mut core, &mut bus, 0x09); //ArcTan of 1.0
+        assert!((core.reg.gp[0] as i32 - 0x2000).abs() < 4);
+
+        core.reg.gp[0] = -0x100i32 as u32;
+        core.reg.gp[1] = 0;
+        call(&mut core, &mut bus, 0x0A); //ArcTan2
+        assert_eq!(core.reg.gp[0], 0x8000);
+        core.reg.gp[0] = 0x100;
+        core.reg.gp[1] = -0x100i32 as u32;
+        call(&mut core, &mut bus, 0x0A);
+        assert!((core.reg.gp[0] as i32 - 0xE000).abs() < 4);
+    }
+
+    #[test]
+    fn memory_copies() {
+        let (mut core, mut bus) = setup();
+        for i in 0..16 {
+            bus.mem_write_32(0x02000000 + 4*i, i as u32 + 1);
+        }
+
+        core.reg.gp[..3].copy_from_slice(&[0x02000000, 0x03000000, (1 << 26) | 3]);
+        call(&mut core, &mut bus, 0x0B); //CpuSet, 3 words
+        assert_eq!(bus.mem_read_32(0x03000008), 3);
+        assert_eq!(bus.mem_read_32(0x0300000C), 0);
+
+        core.reg.gp[..3].copy_from_slice(&[0x02000004, 0x03000100, (1 << 24) | 5]);
+        call(&mut core, &mut bus, 0x0B); //CpuSet, fill 5 halfwords
+        assert_eq!(bus.mem_read_16(0x03000108), 2);
+        assert_eq!(bus.mem_read_16(0x0300010A), 0);
+
+        core.reg.gp[..3].copy_from_slice(&[0x02000000, 0x03000200, 9]);
+        call(&mut core, &mut bus, 0x0C); //CpuFastSet, rounded up to 16 words
+        assert_eq!(bus.mem_read_32(0x0300023C), 16);
+    }
+
+    #[test]
+    fn decompression() {
+        let (mut core, mut bus) = setup();
+        let load = |bus: &mut bus::Bus, data: &[u8]| {
+            for (i, byte) in data.iter().enumerate() {
+                bus.mem_write(0x02000000 + i, *byte);
+            }
+        };
+        let read = |bus: &mut bus::Bus, len: usize| (0..len).map(|i| bus.mem_read(0x03000000 + i)).collect::<Vec<u8>>();
+        core.reg.gp[0] = 0x02000000;
+        core.reg.gp[1] = 0x03000000;
+
+        /* Three literals, then 6 bytes from 3 back, then another literal */
+        load(&mut bus, &[0x10, 10, 0, 0, 0x10, b'A', b'B', b'C', 0x30, 0x02, b'D']);
+        call(&mut core, &mut bus, 0x11); //LZ77UnCompWram
+        assert_eq!(read(&mut bus, 10), b"ABCABCABCD");
+
+        load(&mut bus, &[0x30, 6, 0, 0, 0x82, b'x', 0x00, b'y']);
+        call(&mut core, &mut bus, 0x15); //RLUnCompVram
+        assert_eq!(read(&mut bus, 6), b"xxxxxy");
+
+        /* A root with two data children, 'a' for 0 and 'b' for 1 */
+        load(&mut bus, &[0x28, 4, 0, 0, 0x01, 0xC0, b'a', b'b', 0, 0, 0, 0x60]);
+        call(&mut core, &mut bus, 0x13); //HuffUnComp
+        assert_eq!(read(&mut bus, 4), b"abba");
+
+        load(&mut bus, &[0x81, 4, 0, 0, 1, 1, 1, 1]);
+        call(&mut core, &mut bus, 0x16); //Diff8bitUnFilterWram
+        assert_eq!(read(&mut bus, 4), [1, 2, 3, 4]);
+
+        /* 1 bit units widened to 4 bits */
+        load(&mut bus, &[0b10110001, 0, 0, 0, 1, 0, 1, 4, 0, 0, 0, 0]);
+        core.reg.gp[2] = 0x02000004;
+        call(&mut core, &mut bus, 0x10); //BitUnPack
+        assert_eq!(bus.mem_read_32(0x03000000), 0x10110001);
+    }
+
+    #[test]
+    fn affine() {
+        let (mut core, mut bus) = setup();
+
+        /* Double size, rotated by a quarter turn */
+        bus.mem_write_16(0x02000000, 0x200);
+        bus.mem_write_16(0x02000002, 0x200);
+        bus.mem_write_16(0x02000004, 0x4000);
+        core.reg.gp[..4].copy_from_slice(&[0x02000000, 0x03000000, 1, 2]);
+        call(&mut core, &mut bus, 0x0F); //ObjAffineSet
+        let params: Vec<i16> = (0..4).map(|i| bus.mem_read_16(0x03000000 + 2*i) as i16).collect();
+        assert_eq!(params, [0, -0x200, 0x200, 0]);
+    }
+
+    #[test]
+    fn halting() {
+        let (mut core, mut bus) = setup();
+        let fast: Interpreter<bus::Bus> = Interpreter::new(Engine::Fast);
+
+        call(&mut core, &mut bus, 0x02); //Halt
+        assert_eq!(fast.step(&mut core, &mut bus), 1);
+        assert_eq!(core.reg.gp[15], 0x08000004);
+
+        /* An enabled interrupt being requested wakes it up */
+        bus.mem_write_16(0x04000200, 1);
+        bus.mem_write_16(0x04000202, 1);
+        bus.mem_write_32(0x08000004, 0xE3A00001); //MOV r0,#1
+        fast.step(&mut core, &mut bus);
+        assert_eq!(core.reg.gp[0], 1);
+
+        /* Writing a 1 to it acknowledges it */
+        bus.store(0x04000202, memory::Width::Half, 1);
+        assert_eq!(bus.mem_read_16(0x04000202), 0);
+
+        /* VBlankIntrWait waits for the handler to acknowledge a V-Blank */
+        call(&mut core, &mut bus, 0x05);
+        assert!(core.halted);
+        assert_eq!(bus.mem_read_16(0x04000208), 1);
+        bus.mem_write_16(0x03007FF8, 0x2);
+        assert_eq!(fast.step(&mut core, &mut bus), 1);
+        bus.mem_write_16(0x03007FF8, 0x3);
+        fast.step(&mut core, &mut bus);
+        assert!(!core.halted);
+        assert_eq!(bus.mem_read_16(0x03007FF8), 0x2);
+    }
+
+    #[test]
+    fn soft_reset() {
+        let (mut core, mut bus) = setup();
+        core.reg.gp[5] = 5;
+        bus.mem_write_32(0x03007E00, 0xFFFFFFFF);
+
+        call(&mut core, &mut bus, 0x00);
+        assert_eq!(core.reg.gp[15], 0x08000000);
+        assert_eq!(core.reg.gp[5], 0);
+        assert_eq!(core.reg.gp[13], bios::SP_USR);
+        assert_eq!(core.reg.svc[0], bios::SP_SVC);
+        assert_eq!(core.reg.cpsr.mode(), arm7tdmi::MODE_SYS);
+        assert_eq!(bus.mem_read_32(0x03007E00), 0);
+    }
+
+    #[test]
+    fn irq_trampoline() {
+        let (mut core, mut bus) = setup();
+        let fast: Interpreter<bus::Bus> = Interpreter::new(Engine::Fast);
+
+        /* The game's handler acknowledges the interrupt and returns */
+        bus.mem_write_32(0x03000000, 0xE3A03403); //MOV r3,#0x03000000
+        bus.mem_write_32(0x03000004, 0xE5830100); //STR r0,[r3,#0x100]
+        bus.mem_write_32(0x03000008, 0xE12FFF1E); //BX lr
+        bus.mem_write_32(0x03007FFC, 0x03000000);
+        core.reg.gp[3] = 0x33;
+
+        core.reg.exception(arm7tdmi::MODE_IRQ, 0x08000004);
+        core.reg.gp[15] = 0x18;
+        for _ in 0..20 {
+            if core.reg.gp[15] == 0x08000000 { break; }
+            fast.step(&mut core, &mut bus);
+        }
+
+        assert_eq!(core.reg.gp[15], 0x08000000);
+        assert_eq!(core.reg.cpsr.mode(), arm7tdmi::MODE_SYS);
+        assert_eq!(core.reg.gp[3], 0x33);
+        assert_eq!(bus.mem_read_32(0x03000100), 0x04000000);
+        assert_eq!(core.reg.irq[0], bios::SP_IRQ);
+    }
+
+    /*
+    A V-Blank coming in while VBlankIntrWait waits: the IRQ is taken, the game's handler acknowledges it in IF and to the BIOS, and
+    the wait ends back at the instruction after the SWI. Thumb code is where the IRQ goes back to the same state.
+    */
+    #[test]
+    fn irq_wakes_intr_wait() {
+        for engine in [Engine::Stepped, Engine::Fast, Engine::Block] {
+            let (mut core, mut bus) = setup();
+            let mut recompiler: recomp::Recompiler<bus::Bus> = recomp::Recompiler::new();
+            let interpreter: Interpreter<bus::Bus> = Interpreter::new(engine);
+            let mut step = |core: &mut arm7tdmi::Core, bus: &mut bus::Bus| match engine {
+                Engine::Block => recompiler.step(core, bus),
+                _ => interpreter.step(core, bus),
+            };
+
+            /* The handler, in ARM like the trampoline expects */
+            bus.mem_write_32(0x03000000, 0xE3A01001); //MOV r1,#1
+            bus.mem_write_32(0x03000004, 0xE2803C02); //ADD r3,r0,#0x200
+            bus.mem_write_32(0x03000008, 0xE1C310B2); //STRH r1,[r3,#2]      IF
+            bus.mem_write_32(0x0300000C, 0xE3A02403); //MOV r2,#0x03000000
+            bus.mem_write_32(0x03000010, 0xE2822C7F); //ADD r2,r2,#0x7F00
+            bus.mem_write_32(0x03000014, 0xE1C21FB8); //STRH r1,[r2,#0xF8]   BIOS_IF
+            bus.mem_write_32(0x03000018, 0xE12FFF1E); //BX lr
+            bus.mem_write_32(0x03007FFC, 0x03000000);
+            bus.mem_write_16(0x04000200, 1);
+
+            bus.mem_write_16(0x08000000, 0xDF05); //SWI 5 (VBlankIntrWait)
+            bus.mem_write_16(0x08000002, 0x2407); //MOVS r4,#7
+            bus.mem_write_16(0x08000004, 0xE7FE); //B .
+            core.reg.gp[15] = 0x08000000;
+            core.reg.cpsr.state = true;
+
+            step(&mut core, &mut bus);
+            assert!(core.halted);
+            for _ in 0..10 {
+                assert_eq!(step(&mut core, &mut bus), 1);
+            }
+            assert_eq!(core.reg.gp[15], 0x08000002);
+
+            bus.mem_write_16(0x04000202, 1);
+            step(&mut core, &mut bus);
+            assert_eq!(core.reg.cpsr.mode(), arm7tdmi::MODE_IRQ, "{:?}", engine);
+            assert_eq!(core.reg.read(14), 0x08000006);
+            assert!(core.reg.cpsr.irq_disabled());
+
+            for _ in 0..100 {
+                if core.reg.gp[15] == 0x08000004 { break; }
+                step(&mut core, &mut bus);
+            }
+            assert_eq!(core.reg.gp[15], 0x08000004, "{:?}", engine);
+            assert_eq!(core.reg.gp[4], 7);
+            assert!(!core.halted);
+            assert!(core.reg.cpsr.state);
+            assert!(!core.reg.cpsr.irq_disabled());
+            assert_eq!(core.reg.cpsr.mode(), arm7tdmi::MODE_SYS);
+            assert_eq!(bus.mem_read_16(0x04000202), 0);
+            assert_eq!(bus.mem_read_16(0x03007FF8), 0);
+        }
+    }
+
+    #[test]
+    fn direct_boot() {
+        let mut core = arm7tdmi::Core::new();
+        let mut bus = bus::Bus::new();
+        core.reg.gp[0] = 0x1234;
+
+        bios::direct_boot(&mut core, &mut bus);
+        assert_eq!(core.reg.gp[15], 0x08000000);
+        assert_eq!(core.reg.gp[0], 0);
+        assert_eq!(core.reg.cpsr.mode(), arm7tdmi::MODE_SYS);
+        assert_eq!(u32::from(core.reg.cpsr), 0x1F);
+        assert_eq!(core.reg.gp[13], 0x03007F00);
+        assert_eq!(core.reg.svc[0], 0x03007FE0);
+        assert_eq!(core.reg.irq[0], 0x03007FA0);
+        assert_eq!(bus.mem_read(0x04000300), 1); //POSTFLG
+        assert_eq!(bus.mem_read_16(0x04000000), 0x80);
+        assert_eq!(bus.mem_read_16(0x04000020), 0x100);
+
+        /* The stack pointers are banked, so they're where the game expects in each mode */
+        core.reg.exception(arm7tdmi::MODE_IRQ, 0);
+        assert_eq!(core.reg.read(13), 0x03007FA0);
+    }
+
+    #[test]
+    fn reset() {
+        /* A new core is already in the reset state, at the reset vector in Supervisor mode rather than the invalid mode 0 */
+        let mut core = arm7tdmi::Core::new();
+        assert_eq!(core.reg.gp[15], 0);
+        assert_eq!(u32::from(core.reg.cpsr), 0xD3);
+
+        /* And booting through a real BIOS puts it back there */
+        core.reg.gp[15] = 0x100;
+        core.reg.cpsr = arm7tdmi::Status::from(arm7tdmi::MODE_SYS as u32);
+        core.reset();
+        assert_eq!(core.reg.gp[15], 0);
+        assert_eq!(u32::from(core.reg.cpsr), 0xD3);
+    }
+}
@@ -0,0 +1,268 @@
+#[path = "../src/bus.rs"]
+mod bus;
+#[path = "../src/memory.rs"]
+mod memory;
+#[path = "../src/decode.rs"]
+mod decode;
+#[path = "../src/cache.rs"]
+mod cache;
+
+/*
+Tests for the memory bus, mostly the odd rules about what each region does with writes of different widths
+*/
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use memory::Memory;
+
+    #[test]
+    fn palette_byte_write() {
+        let mut bus = bus::Bus::new();
+
+        bus.mem_write(0x05000003, 0xAB);
+
+        assert_eq!(bus.mem_read_16(0x05000002), 0xABAB);
+        assert_eq!(bus.mem_read_16(0x05000000), 0);
+
+        bus.mem_write_16(0x050003FE, 0x1234);
+        assert_eq!(bus.mem_read_16(0x050003FE), 0x1234);
+    }
+
+    #[test]
+    fn bg_vram_byte_write() {
+        let mut bus = bus::Bus::new();
+
+        /* Tile modes, BG VRAM is the first 64K */
+        bus.mem_write(0x0600FFFE, 0x12);
+        assert_eq!(bus.mem_read_16(0x0600FFFE), 0x1212);
+
+        bus.mem_write(0x06010000, 0x12);
+        assert_eq!(bus.mem_read_16(0x06010000), 0);
+
+        /* Bitmap modes, BG VRAM is the first 80K */
+        bus.mem_write_16(0x04000000, 0x0003);
+        assert_eq!(bus.bg_mode(), 3);
+
+        bus.mem_write(0x06013FFF, 0x34);
+        assert_eq!(bus.mem_read_16(0x06013FFE), 0x3434);
+
+        bus.mem_write(0x06014000, 0x34);
+        assert_eq!(bus.mem_read_16(0x06014000), 0);
+    }
+
+    #[test]
+    fn obj_byte_writes_ignored() {
+        let mut bus = bus::Bus::new();
+
+        bus.mem_write(0x07000000, 0xFF);
+        assert_eq!(bus.mem_read_32(0x07000000), 0);
+
+        bus.mem_write(0x06017FFF, 0xFF);
+        assert_eq!(bus.mem_read_16(0x06017FFE), 0);
+    }
+
+    #[test]
+    fn video_wide_writes() {
+        let mut bus = bus::Bus::new();
+
+        bus.mem_write_16(0x07000000, 0xBEEF);
+        bus.mem_write_32(0x070003FC, 0xDEADBEEF);
+        assert_eq!(bus.mem_read_16(0x07000000), 0xBEEF);
+        assert_eq!(bus.mem_read_32(0x070003FC), 0xDEADBEEF);
+
+        bus.mem_write_32(0x06017FFC, 0x01234567);
+        assert_eq!(bus.mem_read_32(0x06017FFC), 0x01234567);
+    }
+
+    #[test]
+    fn other_byte_writes() {
+        let mut bus = bus::Bus::new();
+
+        bus.mem_write(0x03000001, 0x5A);
+        assert_eq!(bus.mem_read_16(0x03000000), 0x5A00);
+
+        bus.mem_write(0x0203FFFF, 0xA5);
+        assert_eq!(bus.mem_read(0x0203FFFF), 0xA5);
+    }
+
+    /* Both work RAMs repeat through the rest of their areas, with the same timing */
+    #[test]
+    fn work_ram_mirrors() {
+        let mut bus = bus::Bus::new();
+        use memory::{ Cycle::*, Width::* };
+
+        bus.mem_write_32(0x03FFFE00, 0x12345678);
+        assert_eq!(bus.mem_read_32(0x03007E00), 0x12345678);
+        bus.mem_write_16(0x02000004, 0xBEEF);
+        assert_eq!(bus.mem_read_16(0x02FC0004), 0xBEEF);
+
+        assert!(bus.mapped(0x03FFFFFF));
+        assert_eq!(bus.access_cycles(0x02040000, Word, N), 6);
+    }
+
+    #[test]
+    fn default_access_timing() {
+        let bus = bus::Bus::new();
+        use memory::{ Cycle::*, Width::* };
+
+        assert_eq!(bus.access_cycles(0x00000100, Word, N), 1);
+        assert_eq!(bus.access_cycles(0x03000000, Word, N), 1);
+        assert_eq!(bus.access_cycles(0x02000000, Byte, N), 3);
+        assert_eq!(bus.access_cycles(0x02000000, Word, S), 6);
+        assert_eq!(bus.access_cycles(0x05000000, Half, N), 1);
+        assert_eq!(bus.access_cycles(0x06000000, Word, N), 2);
+        assert_eq!(bus.access_cycles(0x07000000, Word, N), 1);
+
+        /* WS0 defaults to 4,2 */
+        assert_eq!(bus.access_cycles(0x08000100, Half, N), 5);
+        assert_eq!(bus.access_cycles(0x08000100, Half, S), 3);
+        assert_eq!(bus.access_cycles(0x08000100, Word, N), 8);
+        assert_eq!(bus.access_cycles(0x08000100, Word, S), 6);
+
+        /* WS1 4,4 and WS2 4,8 */
+        assert_eq!(bus.access_cycles(0x0A000100, Half, S), 5);
+        assert_eq!(bus.access_cycles(0x0C000100, Word, S), 18);
+
+        /* Sequential accesses can't cross a 128K block */
+        assert_eq!(bus.access_cycles(0x08020000, Half, S), 5);
+
+        assert_eq!(bus.access_cycles(0x0E000000, Byte, N), 5);
+    }
+
+    #[test]
+    fn waitcnt_access_timing() {
+        let mut bus = bus::Bus::new();
+        use memory::{ Cycle::*, Width::* };
+
+        /* SRAM 8, WS0 3,1, WS1 2,1, WS2 8,1 */
+        bus.mem_write_16(0x04000204, 0b0000_0111_1101_0111);
+        assert_eq!(bus.waitcnt(), 0x07D7);
+
+        assert_eq!(bus.access_cycles(0x0E000000, Byte, N), 9);
+        assert_eq!(bus.access_cycles(0x08000100, Half, N), 4);
+        assert_eq!(bus.access_cycles(0x08000100, Word, N), 6);
+        assert_eq!(bus.access_cycles(0x08000100, Word, S), 4);
+        assert_eq!(bus.access_cycles(0x0A000100, Half, N), 3);
+        assert_eq!(bus.access_cycles(0x0C000100, Word, N), 11);
+    }
+
+    #[test]
+    fn timed_access() {
+        let mut bus = bus::Bus::new();
+        use memory::{ Cycle::*, Width::* };
+
+        assert_eq!(bus.write(0x02000010, Word, N, 0xCAFEF00D), 6);
+        assert_eq!(bus.read(0x02000010, Half, S), (0xF00D, 3));
+        assert_eq!(bus.read(0x03000010, Word, N), (0, 1));
+    }
+
+    #[test]
+    fn prefetch_disabled() {
+        let mut bus = bus::Bus::new();
+        use memory::{ Cycle::*, Width::* };
+
+        assert_eq!(bus.fetch(0x08000000, Half, N).1, 5);
+        bus.idle(10);
+        assert_eq!(bus.fetch(0x08000002, Half, S).1, 3);
+    }
+
+    #[test]
+    fn prefetch_sequential_fetches() {
+        let mut bus = bus::Bus::new();
+        use memory::{ Cycle::*, Width::* };
+
+        bus.mem_write_16(0x04000204, 1 << 14);
+        assert!(bus.prefetch_enabled());
+
+        /* The first fetch misses, then two halfwords (3 cycles each) are fetched during internal cycles */
+        assert_eq!(bus.fetch(0x08000000, Half, N).1, 5);
+        bus.idle(6);
+        assert_eq!(bus.fetch(0x08000002, Half, S).1, 1);
+        assert_eq!(bus.fetch(0x08000004, Half, S).1, 1);
+
+        /* The next one has had 2 of its 3 cycles */
+        assert_eq!(bus.fetch(0x08000006, Half, S).1, 1);
+        assert_eq!(bus.fetch(0x08000008, Half, S).1, 3);
+
+        /* ARM opcodes need two halfwords */
+        bus.idle(3);
+        assert_eq!(bus.fetch(0x0800000A, Word, S).1, 3);
+        bus.idle(6);
+        assert_eq!(bus.fetch(0x0800000E, Word, S).1, 1);
+    }
+
+    #[test]
+    fn prefetch_fills_during_data_access() {
+        let mut bus = bus::Bus::new();
+        use memory::{ Cycle::*, Width::* };
+
+        bus.mem_write_16(0x04000204, 1 << 14);
+
+        assert_eq!(bus.fetch(0x08000000, Half, N).1, 5);
+        assert_eq!(bus.read(0x02000000, Word, N).1, 6);
+        assert_eq!(bus.fetch(0x08000002, Half, S).1, 1);
+        assert_eq!(bus.fetch(0x08000004, Half, S).1, 1);
+
+        /* The buffer holds 8 halfwords at most, by the time they're used up only one more has been fetched */
+        bus.idle(100);
+        for addr in (0x08000006..0x08000016).step_by(4) {
+            assert_eq!(bus.fetch(addr, Word, S).1, 1);
+        }
+        assert_eq!(bus.fetch(0x08000016, Word, S).1, 2);
+    }
+
+    #[test]
+    fn prefetch_disrupted() {
+        let mut bus = bus::Bus::new();
+        use memory::{ Cycle::*, Width::* };
+
+        bus.mem_write_16(0x04000204, 1 << 14);
+
+        /* A data access to ROM empties the buffer */
+        assert_eq!(bus.fetch(0x08000000, Half, N).1, 5);
+        bus.idle(6);
+        assert_eq!(bus.read(0x08001000, Half, N).1, 5);
+        assert_eq!(bus.fetch(0x08000002, Half, S).1, 3);
+
+        /* So does a branch */
+        bus.idle(6);
+        assert_eq!(bus.fetch(0x08000100, Half, N).1, 5);
+        bus.idle(3);
+        assert_eq!(bus.fetch(0x08000102, Half, S).1, 1);
+    }
+
+    #[test]
+    fn instruction_cache() {
+        let mut bus = bus::Bus::new();
+
+        bus.mem_write_32(0x03000100, 0xE0812003); //ADD r2,r1,r3
+        let inst = bus.decoded_arm(0x03000100);
+        assert_eq!(inst.insttype, decode::ArmInstType::DataProcessing);
+        assert_eq!((inst.rn, inst.rd, inst.rm), (1, 2, 3));
+        assert!(bus.cached(0x03000100));
+
+        /* Writing over the code throws the page away, so the new instruction is decoded */
+        bus.mem_write_16(0x030003FE, 0);
+        assert!(!bus.cached(0x03000100));
+        bus.mem_write_32(0x03000100, 0xEA000000); //B
+        assert_eq!(bus.decoded_arm(0x03000100).insttype, decode::ArmInstType::Branch);
+
+        /* Other pages are left alone */
+        bus.mem_write(0x03000400, 0);
+        assert!(bus.cached(0x03000100));
+
+        /* Thumb is cached separately, along with the ARM instruction it becomes */
+        bus.mem_write_16(0x08000000, 0x1C48); //ADD r0,r1,#1
+        let inst = bus.decoded_thumb(0x08000000);
+        assert_eq!(inst.insttype, decode::ThumbInstType::AddSubtract);
+        assert_eq!(inst.arm.map(|arm| arm.insttype), Some(decode::ArmInstType::DataProcessing));
+        assert!(bus.cached(0x08000000));
+
+        /* Code outside ROM and work RAM isn't cached */
+        bus.decoded_arm(0x00000000);
+        bus.decoded_arm(0x06000000);
+        assert!(!bus.cached(0x00000000));
+        assert!(!bus.cached(0x06000000));
+    }
+}
@@ -2,6 +2,12 @@
 mod arm7tdmi;
 #[path = "../src/bus.rs"]
 mod bus;
+#[path = "../src/memory.rs"]
+mod memory;
+#[path = "../src/decode.rs"]
+mod decode;
+#[path = "../src/cache.rs"]
+mod cache;
 
 #[cfg(test)]
 mod tests {
@@ -10,6 +16,7 @@ mod tests {
     #[test]
     fn alu_functions() {
         let mut core = arm7tdmi::Core::new();
+        core.reg.cpsr = arm7tdmi::Status::from(0); //so the whole CPSR is just the flags
         core.setcond = true; //to set flags
 
         /* AND */
@@ -40,7 +47,7 @@ mod tests {
         core.alu();
 
         assert_eq!(core.alubus, 0xF2E0D0C0);
-        assert_eq!(u32::from(core.reg.cpsr), 0xA0000000);
+        assert_eq!(u32::from(core.reg.cpsr), 0x80000000); //borrow, so C is clear
 
         /* RSB */
         core.aluop = 3;
@@ -48,7 +55,7 @@ mod tests {
         core.alu();
 
         assert_eq!(core.alubus, 0x0D1F2F40);
-        assert_eq!(u32::from(core.reg.cpsr), 0);
+        assert_eq!(u32::from(core.reg.cpsr), 0x20000000);
 
         /* ADD */
         core.aluop = 4;
@@ -79,17 +86,18 @@ mod tests {
         core.alu();
 
         assert_eq!(core.alubus, 0xF2E0D0C0);
-        assert_eq!(u32::from(core.reg.cpsr), 0xA0000000);
+        assert_eq!(u32::from(core.reg.cpsr), 0x80000000);
 
         /* RSC */
         core.aluop = 7;
+        core.reg.cpsr.c = true; //no borrow in
         core.abus = 0xFDF00000;
         core.barrelbus = 0x0FEEF001;
 
         core.alu();
 
         assert_eq!(core.alubus, 0x11FEF001);
-        assert_eq!(u32::from(core.reg.cpsr), 0x20000000);
+        assert_eq!(u32::from(core.reg.cpsr), 0);
 
         core.reg.cpsr.c = false;
         core.alubus = 0;
@@ -122,7 +130,7 @@ mod tests {
         core.alu();
 
         assert_eq!(core.alubus, 0);
-        assert_eq!(u32::from(core.reg.cpsr), 0xA0000000);
+        assert_eq!(u32::from(core.reg.cpsr), 0x80000000);
 
         /* CMN */
         core.aluop = 0xB;
@@ -171,6 +179,181 @@ mod tests {
         assert_eq!(u32::from(core.reg.cpsr), 0x80000000);
     }
 
+    /* Every condition against every combination of flags */
+    #[test]
+    fn condition_codes() {
+        let mut core = arm7tdmi::Core::new();
+
+        for nzcv in 0..16u32 {
+            core.reg.cpsr.n = nzcv & 0b1000 != 0;
+            core.reg.cpsr.z = nzcv & 0b0100 != 0;
+            core.reg.cpsr.c = nzcv & 0b0010 != 0;
+            core.reg.cpsr.v = nzcv & 0b0001 != 0;
+            let arm7tdmi::Status { n, z, c, v, .. } = core.reg.cpsr;
+
+            for cond in 0..16 {
+                let expected = match cond {
+                    0x0 => z,
+                    0x1 => !z,
+                    0x2 => c,
+                    0x3 => !c,
+                    0x4 => n,
+                    0x5 => !n,
+                    0x6 => v,
+                    0x7 => !v,
+                    0x8 => c && !z,
+                    0x9 => !c || z,
+                    0xA => n == v,
+                    0xB => n != v,
+                    0xC => !z && n == v,
+                    0xD => z || n != v,
+                    0xE => true,
+                    _   => false,
+                };
+                assert_eq!(core.cond_codes(cond), expected, "condition {:x} with NZCV {:04b}", cond, nzcv);
+            }
+        }
+
+        /* The flags the old LS got wrong */
+        core.reg.cpsr = arm7tdmi::Status::from(0x60000000); //Z and C
+        assert!(core.cond_codes(0x9));
+        assert!(!core.cond_codes(0x8));
+        core.reg.cpsr = arm7tdmi::Status::from(0);
+        assert!(core.cond_codes(0x9));
+    }
+
+    /* Every arithmetic operation against 64 bit arithmetic, for each carry in */
+    #[test]
+    fn arithmetic_flags() {
+        let mut core = arm7tdmi::Core::new();
+        core.setcond = true;
+
+        let edges = [0, 1, 2, 0x7FFFFFFE, 0x7FFFFFFF, 0x80000000, 0x80000001, 0xFFFFFFFE, 0xFFFFFFFF];
+        let mut x: u32 = 0x87654321;
+        let mut next = || { x ^= x << 13; x ^= x >> 17; x ^= x << 5; x };
+        let mut operands: Vec<(u32, u32)> = edges.iter().flat_map(|&a| edges.iter().map(move |&b| (a, b))).collect();
+        operands.extend((0..5000).map(|_| (next(), next())));
+
+        for (a, b) in operands {
+            for carry in [false, true] {
+                for op in [2u8, 3, 4, 5, 6, 7, 10, 11] {
+                    /* Reference: the operation on unsigned and signed 64 bit values */
+                    let (x, y, cin) = match op {
+                        2 | 10  =>  (a, b, 1),
+                        3       =>  (b, a, 1),
+                        6       =>  (a, b, carry as i64),
+                        7       =>  (b, a, carry as i64),
+                        4 | 11  =>  (a, b, 0),
+                        _       =>  (a, b, carry as i64),
+                    };
+                    let (unsigned, signed, c) = if op == 4 || op == 5 || op == 11 {
+                        let unsigned = x as i64 + y as i64 + cin;
+                        (unsigned, x as i32 as i64 + y as i32 as i64 + cin, unsigned > 0xFFFFFFFF)
+                    } else {
+                        let unsigned = x as i64 - y as i64 - (1 - cin);
+                        (unsigned, x as i32 as i64 - y as i32 as i64 - (1 - cin), unsigned >= 0)
+                    };
+                    let result = unsigned as u32;
+
+                    core.reg.cpsr = Default::default();
+                    core.reg.cpsr.c = carry;
+                    core.abus = a;
+                    core.barrelbus = b;
+                    core.alubus = 0;
+                    core.aluop = op;
+                    core.alu();
+
+                    let msg = format!("op {} {:08x}, {:08x} carry {}", op, a, b, carry);
+                    assert_eq!(core.alubus, if op < 8 {result} else {0}, "{}", msg);
+                    assert_eq!(core.reg.cpsr.n, (result as i32) < 0, "{}", msg);
+                    assert_eq!(core.reg.cpsr.z, result == 0, "{}", msg);
+                    assert_eq!(core.reg.cpsr.c, c, "{}", msg);
+                    assert_eq!(core.reg.cpsr.v, signed != result as i32 as i64, "{}", msg);
+                }
+            }
+        }
+    }
+
+    /* Carries and borrows through the carry in, as the ARM ARM gives them: C is NOT borrow for the subtractions */
+    #[test]
+    fn carry_and_borrow() {
+        let mut core = arm7tdmi::Core::new();
+        core.setcond = true;
+
+        /* op, a, b, carry in, result, NZCV */
+        let cases = [
+            (5, 0xFFFFFFFF, 0x00000000, true,  0x00000000, 0b0110),     //ADC wraps to 0 with a carry out
+            (5, 0xFFFFFFFF, 0x00000000, false, 0xFFFFFFFF, 0b1000),
+            (5, 0x7FFFFFFF, 0x00000000, true,  0x80000000, 0b1001),     //the carry in overflows
+            (5, 0x80000000, 0x80000000, false, 0x00000000, 0b0111),
+            (5, 0x00000001, 0x00000001, true,  0x00000003, 0b0000),
+            (6, 0x00000005, 0x00000003, true,  0x00000002, 0b0010),     //SBC with no borrow in
+            (6, 0x00000005, 0x00000003, false, 0x00000001, 0b0010),     //a borrow in takes one more
+            (6, 0x00000003, 0x00000005, true,  0xFFFFFFFE, 0b1000),     //borrow out clears C
+            (6, 0x00000000, 0x00000000, false, 0xFFFFFFFF, 0b1000),
+            (6, 0x00000001, 0x00000000, false, 0x00000000, 0b0110),
+            (6, 0x80000000, 0x00000001, true,  0x7FFFFFFF, 0b0011),
+            (6, 0x80000000, 0x00000000, false, 0x7FFFFFFF, 0b0011),     //only the borrow in overflows
+            (7, 0x00000003, 0x00000005, true,  0x00000002, 0b0010),     //RSC is b - a
+            (7, 0x00000003, 0x00000005, false, 0x00000001, 0b0010),
+            (7, 0x00000005, 0x00000003, true,  0xFFFFFFFE, 0b1000),
+            (7, 0x00000000, 0x00000000, false, 0xFFFFFFFF, 0b1000),
+            (7, 0x00000001, 0x80000000, true,  0x7FFFFFFF, 0b0011),
+        ];
+
+        for (op, a, b, carry, result, flags) in cases {
+            core.reg.cpsr = Default::default();
+            core.reg.cpsr.c = carry;
+            core.abus = a;
+            core.barrelbus = b;
+            core.aluop = op;
+            core.alu();
+
+            let msg = format!("op {} {:08x}, {:08x} carry {}", op, a, b, carry);
+            assert_eq!(core.alubus, result, "{}", msg);
+            assert_eq!(u32::from(core.reg.cpsr) >> 28, flags, "{}", msg);
+        }
+
+        /* The shifter's carry out, through MOVS: function, amount, value, carry in, result, NZCV */
+        let shifts = [
+            (0, 0,  0x80000001, true,  0x80000001, 0b1010),             //LSL #0 keeps C
+            (0, 1,  0x80000001, false, 0x00000002, 0b0010),             //bit 31 goes out
+            (1, 1,  0x00000001, false, 0x00000000, 0b0110),             //LSR #1, bit 0 goes out
+            (1, 0,  0x80000000, false, 0x00000000, 0b0110),             //LSR #32 carries bit 31
+            (2, 0,  0x80000000, false, 0xFFFFFFFF, 0b1010),             //ASR #32 fills with it
+            (2, 0,  0x7FFFFFFF, true,  0x00000000, 0b0100),
+            (3, 4,  0x00000008, false, 0x80000000, 0b1010),             //ROR carries the new bit 31
+            (3, 0,  0x00000001, false, 0x00000000, 0b0110),             //RRX, C in at the top and bit 0 out
+            (3, 0,  0x00000000, true,  0x80000000, 0b1000),
+        ];
+
+        for (func, amount, value, carry, result, flags) in shifts {
+            core.reg.cpsr = Default::default();
+            core.reg.cpsr.c = carry;
+            core.bbus = value;
+            core.barrelfunc = func;
+            core.shiftamnt = amount;
+            core.shiftreg = false;
+            core.barrel_shift();
+            core.aluop = 0xD;
+            core.alu();
+
+            let msg = format!("func {} amount {} {:08x} carry {}", func, amount, value, carry);
+            assert_eq!(core.alubus, result, "{}", msg);
+            assert_eq!(u32::from(core.reg.cpsr) >> 28, flags, "{}", msg);
+        }
+    }
+
+    #[test]
+    fn status_word() {
+        /* T is bit 5 */
+        let status = arm7tdmi::Status::from(0x900000BF);
+        assert!(status.n && status.v && status.state);
+        assert_eq!(status.mode(), arm7tdmi::MODE_SYS);
+        assert_eq!(u32::from(status), 0x900000BF);
+        assert_eq!(u32::from(arm7tdmi::Status::from(0x20)), 0x20);
+    }
+
     #[test]
     fn barrel_shifting() {
         let mut core = arm7tdmi::Core::new();
@@ -217,6 +400,114 @@ mod tests {
 
         assert_eq!(core.barrelbus, 0b01010010111000011100101100101101);
         assert_eq!(core.reg.cpsr.c, false);
+        assert!(!core.shiftcarry);
+    }
+
+    /* Shift amounts that don't fit in a 32 bit shift, each case is (function, amount, result, carry out) */
+    #[test]
+    fn barrel_shift_edge_cases() {
+        let mut core = arm7tdmi::Core::new();
+        let value = 0x80000001;
+        core.bbus = value;
+        core.reg.cpsr.c = true;
+
+        let immediate = [
+            (0, 0, value, true),                    //LSL #0, carry unchanged
+            (0, 1, 0x00000002, true),
+            (0, 31, 0x80000000, false),
+            (1, 0, 0, true),                        //LSR #32
+            (1, 1, 0x40000000, true),
+            (2, 0, 0xFFFFFFFF, true),               //ASR #32
+            (2, 31, 0xFFFFFFFF, false),
+            (3, 0, 0xC0000000, true),               //RRX
+            (3, 4, 0x18000000, false),
+        ];
+
+        for (func, amount, result, carry) in immediate {
+            core.barrelfunc = func;
+            core.shiftamnt = amount;
+            core.shiftreg = false;
+            core.barrel_shift();
+
+            assert_eq!(core.barrelbus, result, "immediate func {} amount {}", func, amount);
+            assert_eq!(core.shiftcarry, carry, "immediate func {} amount {}", func, amount);
+        }
+
+        let register = [
+            (0, 0, value, true),                    //a shift by 0 does nothing to any function
+            (1, 0, value, true),
+            (2, 0, value, true),
+            (3, 0, value, true),
+            (0, 32, 0, true),
+            (0, 33, 0, false),
+            (0, 255, 0, false),
+            (1, 32, 0, true),
+            (1, 33, 0, false),
+            (2, 32, 0xFFFFFFFF, true),
+            (2, 200, 0xFFFFFFFF, true),
+            (3, 32, value, true),                   //ROR by a multiple of 32 only sets the carry
+            (3, 64, value, true),
+            (3, 33, 0xC0000000, true),
+        ];
+
+        for (func, amount, result, carry) in register {
+            core.barrelfunc = func;
+            core.shiftamnt = amount;
+            core.shiftreg = true;
+            core.barrel_shift();
+
+            assert_eq!(core.barrelbus, result, "register func {} amount {}", func, amount);
+            assert_eq!(core.shiftcarry, carry, "register func {} amount {}", func, amount);
+        }
+
+        /* Positive values */
+        core.bbus = 0x7FFFFFFE;
+        core.reg.cpsr.c = false;
+
+        core.barrelfunc = 2;
+        core.shiftamnt = 0;
+        core.shiftreg = false;
+        core.barrel_shift();
+        assert_eq!((core.barrelbus, core.shiftcarry), (0, false));
+
+        core.barrelfunc = 3;
+        core.barrel_shift();
+        assert_eq!((core.barrelbus, core.shiftcarry), (0x3FFFFFFF, false));
+
+        core.shiftreg = true;
+        core.shiftamnt = 32;
+        core.barrel_shift();
+        assert_eq!((core.barrelbus, core.shiftcarry), (0x7FFFFFFE, false));
+
+        core.barrelfunc = 0;
+        core.barrel_shift();
+        assert_eq!((core.barrelbus, core.shiftcarry), (0, false));
+    }
+
+    /* The shifter carry only reaches the flags through a logical operation with S set */
+    #[test]
+    fn shifter_carry_out() {
+        let mut core = arm7tdmi::Core::new();
+        core.reg.cpsr = arm7tdmi::Status::from(0);
+        core.setcond = true;
+
+        core.bbus = 0x80000000;
+        core.barrelfunc = 0;
+        core.shiftamnt = 1;
+        core.barrel_shift();
+        core.aluop = 0xD; //MOV
+        core.alu();
+
+        assert_eq!(core.alubus, 0);
+        assert_eq!(u32::from(core.reg.cpsr), 0x60000000);
+
+        /* An add sets carry from its own result */
+        core.abus = 1;
+        core.aluop = 4;
+        core.alu();
+
+        assert_eq!(core.alubus, 1);
+        assert_eq!(u32::from(core.reg.cpsr), 0);
     }
 
     #[test]
@@ -270,7 +561,8 @@ mod tests {
 
         core.decode_shift(shift);
 
-        assert_eq!(core.shiftamnt, 0x000000AF&0x1F);
+        assert_eq!(core.shiftamnt, 0x000000AF);
+        assert!(core.shiftreg);
         assert_eq!(core.barrelfunc, 0b10);
     }
 
@@ -283,6 +575,70 @@ mod tests {
         core.decode_shift_imm(shift);
 
         assert_eq!(core.barrelfunc, 3);
-        assert_eq!(core.shiftamnt, 0x24000);
+        assert_eq!(core.shiftamnt, 18);
+        assert!(!core.shiftreg);
+
+        /* No rotation doesn't mean RRX here */
+        core.decode_shift_imm(0x0FF);
+
+        assert_eq!(core.barrelfunc, 0);
+        assert_eq!(core.shiftamnt, 0);
+    }
+
+    #[test]
+    fn multiplier() {
+        let mut core = arm7tdmi::Core::new();
+        core.setcond = true;
+
+        /* Compare against the native multiply for a spread of operands */
+        let mut x: u32 = 0x12345678;
+        let mut next = || { x ^= x << 13; x ^= x >> 17; x ^= x << 5; x };
+
+        for i in 0..2000 {
+            let (rm, mut rs, acc) = (next(), next(), ((next() as u64) << 32) | next() as u64);
+            rs >>= (i % 4) * 8; //make sure early termination is covered
+            if i % 8 == 7 { rs |= 0xFFFFFF00; }
+
+            core.mul_setup(rm, rs, acc, true);
+            while !core.mul_done() { core.mul(); }
+            let signed = core.mul_result(true);
+            assert_eq!(signed, (((rm as i32 as i64) * (rs as i32 as i64)) as u64).wrapping_add(acc), "{:08x} * {:08x}", rm, rs);
+            assert_eq!(core.reg.cpsr.n, (signed as i64) < 0);
+
+            core.mul_setup(rm, rs, acc, false);
+            while !core.mul_done() { core.mul(); }
+            let unsigned = core.mul_result(true);
+            assert_eq!(unsigned, (rm as u64 * rs as u64).wrapping_add(acc), "{:08x} * {:08x}", rm, rs);
+
+            core.mul_setup(rm, rs, acc & 0xFFFFFFFF, true);
+            while !core.mul_done() { core.mul(); }
+            let short = core.mul_result(false) as u32;
+            assert_eq!(short, rm.wrapping_mul(rs).wrapping_add(acc as u32));
+            assert_eq!(core.reg.cpsr.n, (short as i32) < 0);
+            assert_eq!(core.reg.cpsr.z, short == 0);
+        }
+
+        /* Zero flag comes from the whole result for a long multiply */
+        core.mul_setup(0x10000, 0x10000, 0, false);
+        while !core.mul_done() { core.mul(); }
+        assert_eq!(core.mul_result(true), 0x100000000);
+        assert!(!core.reg.cpsr.z);
+        assert!(!core.reg.cpsr.c);
+    }
+
+    #[test]
+    fn multiplier_cycles() {
+        /* Signed multiplies can skip top bytes of all 0s or all 1s */
+        assert_eq!(arm7tdmi::Core::mul_cycles(0x000000FF, true), 1);
+        assert_eq!(arm7tdmi::Core::mul_cycles(0xFFFFFF00, true), 1);
+        assert_eq!(arm7tdmi::Core::mul_cycles(0x0000FF00, true), 2);
+        assert_eq!(arm7tdmi::Core::mul_cycles(0xFFFF0000, true), 2);
+        assert_eq!(arm7tdmi::Core::mul_cycles(0x00FF0000, true), 3);
+        assert_eq!(arm7tdmi::Core::mul_cycles(0xFF800000, true), 3);
+        assert_eq!(arm7tdmi::Core::mul_cycles(0x80000000, true), 4);
+
+        /* Unsigned only skips 0s */
+        assert_eq!(arm7tdmi::Core::mul_cycles(0x000000FF, false), 1);
+        assert_eq!(arm7tdmi::Core::mul_cycles(0xFFFFFF00, false), 4);
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,159 @@
+#[path = "../src/arm7tdmi.rs"]
+mod arm7tdmi;
+#[path = "../src/decode.rs"]
+mod decode;
+#[path = "../src/exec.rs"]
+mod exec;
+#[path = "../src/bios.rs"]
+mod bios;
+#[path = "../src/memory.rs"]
+mod memory;
+#[path = "../src/interp.rs"]
+mod interp;
+#[path = "../src/recomp.rs"]
+mod recomp;
+#[path = "../src/cache.rs"]
+mod cache;
+#[path = "../src/bus.rs"]
+mod bus;
+#[path = "../src/asm.rs"]
+mod asm;
+#[path = "../src/debugger.rs"]
+mod debugger;
+
+/*
+Watchpoints: what they're written as, which accesses set them off and reporting the instruction that made the access, both
+under the debugger and with the engines run on their own the way main does it
+*/
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use memory::{ FlatMemory, Memory };
+    use debugger::{ Debugger, Hit, Stop, WatchKind, Watched, Watchpoint };
+    use interp::{ Engine, Interpreter };
+
+    fn program() -> (arm7tdmi::Core, Watched<FlatMemory>, HashMap<String, u32>) {
+        let program = asm::assemble("
+            start:  ldr r0,=data
+                    mov r1,5
+            first:  str r1,[r0]
+                    mov r1,0
+            second: strb r1,[r0,1]
+            load:   ldr r2,[r0]
+            spin:   b spin
+            data:   .word 0x11223344
+                    .pool
+        ", 0x100).unwrap();
+
+        let mut core = arm7tdmi::Core::new();
+        let mut bus = Watched::new(FlatMemory::new(0x1000));
+        for (i, byte) in program.bytes.iter().enumerate() {
+            bus.mem_write(0x100 + i, *byte);
+        }
+        core.reg.gp[15] = 0x100;
+        (core, bus, program.symbols)
+    }
+
+    fn run(watch: &str) -> (Stop, arm7tdmi::Core, HashMap<String, u32>) {
+        let (mut core, mut bus, symbols) = program();
+        let watch = watch.replace("data", &format!("0x{:x}", symbols["data"])).replace("spin", &format!("0x{:x}", symbols["spin"]));
+        bus.watchpoints.push(Watchpoint::parse(&watch).unwrap());
+        let mut debugger: Debugger<FlatMemory> = Debugger::new();
+        let stop = debugger.run_until(&mut core, &mut bus, |_, cycles| cycles > 1000);
+        (stop, core, symbols)
+    }
+
+    #[test]
+    fn parse() {
+        assert_eq!(Watchpoint::parse("write:0x03001234=0"), Some(Watchpoint { kind: WatchKind::Write, addr: 0x03001234, len: 1, value: Some(0) }));
+        assert_eq!(Watchpoint::parse("read:0x100-0x110"), Some(Watchpoint { kind: WatchKind::Read, addr: 0x100, len: 0x10, value: None }));
+        assert_eq!(Watchpoint::parse("rw:256=0x10"), Some(Watchpoint { kind: WatchKind::Access, addr: 0x100, len: 1, value: Some(0x10) }));
+        assert_eq!(Watchpoint::parse("exec:0x08000000-0x08000100"), Some(Watchpoint { kind: WatchKind::Execute, addr: 0x08000000, len: 0x100, value: None }));
+        assert_eq!(Watchpoint::parse("jump:0x100"), None);
+        assert_eq!(Watchpoint::parse("read:0x110-0x100"), None);
+        assert_eq!(Watchpoint::parse("read:0x100-0x100"), None);
+        assert_eq!(Watchpoint::parse("write:0x100=zero"), None);
+        assert_eq!(Watchpoint::parse("0x100"), None);
+
+        for text in ["write:0x03001234=0x0", "read:0x00000100-0x00000110", "access:0x02000000=0xFF", "exec:0x08000000"] {
+            assert_eq!(Watchpoint::parse(text).unwrap().to_string(), text);
+        }
+    }
+
+    #[test]
+    fn accesses() {
+        /* The first write to it, with the value it wrote and where from */
+        let (stop, core, symbols) = run("write:data");
+        assert_eq!(stop, Stop::Watchpoint(Hit { kind: WatchKind::Write, addr: symbols["data"], value: 5, write: true, pc: symbols["first"] }));
+        assert_eq!(core.reg.gp[15], symbols["first"] + 4);
+
+        /* Only writes of 0, a byte write counts if it touches the range */
+        let (stop, _, symbols) = run("write:data-0x200=0");
+        let Stop::Watchpoint(hit) = stop else {panic!("{:?}", stop)};
+        assert_eq!(hit, Hit { kind: WatchKind::Write, addr: symbols["data"] + 1, value: 0, write: true, pc: symbols["second"] });
+        assert_eq!(hit.to_string(), format!("Write watchpoint: 0x0 written to 0x{:08X} by the instruction at 0x{:08X}", symbols["data"] + 1, symbols["second"]));
+        assert_eq!(run("write:data=7").0, Stop::Interrupted);
+
+        /* Reads see the value that was read */
+        let (stop, core, symbols) = run("read:data");
+        assert_eq!(stop, Stop::Watchpoint(Hit { kind: WatchKind::Read, addr: symbols["data"], value: 5, write: false, pc: symbols["load"] }));
+        assert_eq!(core.reg.gp[2], 5);
+        let (stop, _, symbols) = run("access:data-0x200=0");
+        assert_eq!(stop, Stop::Watchpoint(Hit { kind: WatchKind::Access, addr: symbols["data"] + 1, value: 0, write: true, pc: symbols["second"] }));
+
+        /* Execute stops before the instruction runs, literal loads from the pool aren't fetches */
+        let (stop, core, symbols) = run("exec:spin");
+        assert_eq!(stop, Stop::Watchpoint(Hit { kind: WatchKind::Execute, addr: symbols["spin"], value: 0, write: false, pc: symbols["spin"] }));
+        assert_eq!(core.reg.gp[2], 5);
+        assert_eq!(run("exec:data-0x300").0, Stop::Interrupted);
+    }
+
+    /* Checking for interrupts before each instruction reads IE, IF and IME too, but only the program's own reads count */
+    #[test]
+    fn interrupt_registers() {
+        let program = asm::assemble("
+                    mov r0,r0
+                    mov r0,0x04000000
+                    add r0,r0,0x200
+            load:   ldrh r1,[r0]
+            spin:   b spin
+        ", 0x03000000).unwrap();
+
+        let mut core = arm7tdmi::Core::new();
+        let mut bus = Watched::new(bus::Bus::new());
+        for (i, byte) in program.bytes.iter().enumerate() {
+            bus.mem_write(0x03000000 + i, *byte);
+        }
+        core.reg.cpsr = arm7tdmi::Status::from(arm7tdmi::MODE_SYS as u32);
+        core.reg.gp[15] = 0x03000000;
+        bus.store(0x04000208, memory::Width::Half, 1);
+        bus.watchpoints.push(Watchpoint::parse("read:0x04000200-0x0400020C").unwrap());
+
+        let mut debugger: Debugger<bus::Bus> = Debugger::new();
+        assert_eq!(debugger.step(&mut core, &mut bus).1, Stop::Stepped);
+        let stop = debugger.run_until(&mut core, &mut bus, |_, cycles| cycles > 1000);
+        assert_eq!(stop, Stop::Watchpoint(Hit { kind: WatchKind::Read, addr: 0x04000200, value: 0, write: false, pc: program.symbols["load"] }));
+    }
+
+    /* main runs the engines itself, setting pc before each instruction and taking the hit after */
+    #[test]
+    fn engines() {
+        for engine in [Engine::Stepped, Engine::Fast] {
+            let (mut core, mut bus, symbols) = program();
+            bus.watchpoints.push(Watchpoint { kind: WatchKind::Write, addr: symbols["data"], len: 4, value: Some(0) });
+            let interpreter: Interpreter<Watched<FlatMemory>> = Interpreter::new(engine);
+            let hit = loop {
+                assert_ne!(core.reg.gp[15], symbols["spin"], "{:?} ran past the store", engine);
+                bus.pc = core.reg.gp[15];
+                interpreter.step(&mut core, &mut bus);
+                if let Some(hit) = bus.hit.take() {
+                    break hit;
+                }
+            };
+            assert_eq!(hit.pc, symbols["second"], "{:?}", engine);
+            assert_eq!(hit.addr, symbols["data"] + 1);
+        }
+    }
+}
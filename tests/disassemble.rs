@@ -0,0 +1,169 @@
+#[path = "../src/decode.rs"]
+mod decode;
+
+/*
+Disassembly of ARM and Thumb instructions, the default format is the one used by out.txt in the root of the repo
+*/
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use decode::{DisasmFormat, HexStyle, Syntax};
+
+    fn check(lines: &[(u32, u32, &str)]) {
+        for (pc, inst, expected) in lines {
+            assert_eq!(decode::disassemble_arm(*inst, *pc), *expected, "0x{:08X} at 0x{:X}", inst, pc);
+        }
+    }
+
+    #[test]
+    fn listing() {
+        /* Taken straight from out.txt */
+        check(&[
+            (0x00, 0xEA000018, "b         0x68"),
+            (0x1C, 0xE59FD1A0, "ldr       sp,[pc,0x1A0]"),
+            (0x20, 0xE92D5000, "stmfd     sp!,{r12,lr}"),
+            (0x24, 0xE14FC000, "mrs       r12,spsr"),
+            (0x30, 0xE3A0C302, "mov       r12,0x8000000"),
+            (0x3C, 0x1A000004, "bne       0x54"),
+            (0x40, 0x05DCE0B4, "ldrbeq    lr,[r12,0xB4]"),
+            (0x44, 0x021EE080, "andseq    lr,lr,0x80"),
+            (0x48, 0xE28FE004, "add       lr,=0x54"),
+            (0x5C, 0xE169F00C, "msr       spsr_fc,r12"),
+            (0x64, 0xE25EF004, "subs      pc,lr,0x4"),
+            (0x68, 0xE35E0000, "cmp       lr,0x0"),
+            (0xC4, 0xE9141FFF, "ldmea     r4,{r0,r1,r2,r3,r4,r5,r6,r7,r8,r9,r10,r11,r12}"),
+            (0x11C, 0x49582000, "ldmeami   r8,{sp}^"),
+            (0x14C, 0xE79BC10C, "ldr       r12,[r11,r12,lsl 0x2]"),
+            (0x2CC, 0xFA20F003, "bnv       0x83C2E0"),
+            (0x5B0, 0xF000211B, "andnv     r2,r0,r11,lsl r1"),
+            (0x6F0, 0xD1002E98, "swple     r2,r8,[r0]"),
+            (0x75C, 0x23684356, "msrcs     spsr_f,0x58000001"),
+            (0x1734, 0x43604B16, "msrmi     spsr_,0x5800"),
+            (0x1BC, 0x03007FA0, "Undefined"),
+        ]);
+    }
+
+    #[test]
+    fn operands() {
+        check(&[
+            /* LSR and ASR by 0 mean by 32, ROR by 0 is RRX */
+            (0, 0x60666021, "rsbvs     r6,r6,r1,lsr 0x20"),
+            (0, 0xE078E06C, "rsbs      lr,r8,r12,rrx"),
+            /* Post-indexing with writeback is the user mode transfer */
+            (0, 0x24FCD100, "ldrbtcs   sp,[r12],0x100"),
+            (0, 0xE5B01004, "ldr       r1,[r0,0x4]!"),
+            (0, 0x005E19D3, "ldrsbeq   r1,[lr],-0x93"),
+            (0, 0x000000B4, "strheq    r0,[r0],-r4"),
+            (0, 0xE1D0C0B8, "ldrh      r12,[r0,0x8]"),
+            (0, 0xE0030391, "mul       r3,r1,r3"),
+            (0, 0xE026FF91, "mla       r6,r1,pc,pc"),
+            (0, 0xE0832190, "umull     r2,r3,r0,r1"),
+            (0, 0xE12FFF1E, "bx        lr"),
+            (0, 0xE8BD8000, "ldmfd     sp!,{pc}"),
+            (0, 0xE8A00003, "stmea     r0!,{r0,r1}"),
+            (0x100, 0xEBFFFFFE, "bl        0x100"),
+            (0x100, 0xE24F0008, "sub       r0,=0x100"),
+            (0, 0xEF120000, "swi       LZ77UnCompVram"),
+            (0, 0x0F000000, "swieq     SoftReset"),
+            (0, 0xEFFF0000, "swi       0xFF0000"),
+            (0, 0xEF2C0001, "swi       0x2C0001"),
+        ]);
+    }
+
+    #[test]
+    fn formats() {
+        let pre_ual = DisasmFormat { syntax: Syntax::PreUal, ..Default::default() };
+        assert_eq!(decode::disassemble_arm_with(0x05DCE0B4, 0, &pre_ual), "ldreqb    lr,[r12,0xB4]");
+        assert_eq!(decode::disassemble_arm_with(0x021EE080, 0, &pre_ual), "andeqs    lr,lr,0x80");
+        assert_eq!(decode::disassemble_arm_with(0x49582000, 0, &pre_ual), "ldmmiea   r8,{sp}^");
+
+        let hash = DisasmFormat { hex: HexStyle::Hash, column: 0, ..Default::default() };
+        assert_eq!(decode::disassemble_arm_with(0xE51F0004, 0, &hash), "ldr r0,[pc,#-0x4]");
+        assert_eq!(decode::disassemble_arm_with(0xE1A00121, 0, &hash), "mov r0,r1,lsr #0x2");
+        assert_eq!(decode::disassemble_arm_with(0xEA000018, 0, &hash), "b 0x68");
+
+        let suffix = DisasmFormat { hex: HexStyle::Suffix, column: 0, ..Default::default() };
+        assert_eq!(decode::disassemble_arm_with(0xE3A0C0FF, 0, &suffix), "mov r12,0FFh");
+        assert_eq!(decode::disassemble_arm_with(0xE51F0010, 0, &suffix), "ldr r0,[pc,-10h]");
+    }
+
+    #[test]
+    fn symbols() {
+        let mut format = DisasmFormat::default();
+        format.symbols.insert(0x68, "reset".to_string());
+        format.symbols.insert(0x54, "irq_return".to_string());
+
+        assert_eq!(decode::disassemble_arm_with(0xEA000018, 0x00, &format), "b         reset");
+        assert_eq!(decode::disassemble_arm_with(0xE28FE004, 0x48, &format), "add       lr,=irq_return");
+        assert_eq!(decode::disassemble_arm_with(0xEA000000, 0x00, &format), "b         0x8");
+    }
+
+    fn check_thumb(lines: &[(u32, u16, &str)]) {
+        for (pc, inst, expected) in lines {
+            assert_eq!(decode::disassemble_thumb(*inst, *pc), *expected, "0x{:04X} at 0x{:X}", inst, pc);
+        }
+    }
+
+    #[test]
+    fn thumb() {
+        check_thumb(&[
+            (0, 0x0088, "lsls      r0,r1,0x2"),
+            (0, 0x0808, "lsrs      r0,r1,0x20"),
+            (0, 0x1A41, "subs      r1,r0,r1"),
+            (0, 0x1CC1, "adds      r1,r0,0x3"),
+            (0, 0x2005, "movs      r0,0x5"),
+            (0, 0x2AFF, "cmp       r2,0xFF"),
+            (0, 0x4348, "muls      r0,r1"),
+            (0, 0x4288, "cmp       r0,r1"),
+            (0, 0x46C0, "mov       r8,r8"),
+            (0, 0x4770, "bx        lr"),
+            (0, 0x5888, "ldr       r0,[r1,r2]"),
+            (0, 0x5E88, "ldrsh     r0,[r1,r2]"),
+            (0, 0x6848, "ldr       r0,[r1,0x4]"),
+            (0, 0x7848, "ldrb      r0,[r1,0x1]"),
+            (0, 0x8848, "ldrh      r0,[r1,0x2]"),
+            (0, 0x9001, "str       r0,[sp,0x4]"),
+            (0x100, 0xA001, "add       r0,=0x108"),
+            (0, 0xA801, "add       r0,sp,0x4"),
+            (0, 0xB082, "sub       sp,0x8"),
+            (0, 0xB500, "push      {lr}"),
+            (0, 0xBD10, "pop       {r4,pc}"),
+            (0, 0xC103, "stmia     r1!,{r0,r1}"),
+            (0x100, 0xD0FE, "beq       0x100"),
+            (0, 0xDE00, "Undefined"),
+            (0, 0xDF05, "swi       VBlankIntrWait"),
+            (0, 0xDF2C, "swi       0x2C"),
+            (0x200, 0xE7FE, "b         0x200"),
+            (0, 0xE800, "Undefined"),
+        ]);
+    }
+
+    #[test]
+    fn thumb_memory() {
+        let memory = [(0x100, 0x4801), (0x102, 0x4801), (0x104, 0xF7FF), (0x106, 0xFFFE), (0x108, 0x0000), (0x10A, 0x0400)];
+        let mut read = |addr: u32| memory.iter().find(|(a, _)| *a == addr).map(|(_, v)| *v);
+        let format = DisasmFormat::default();
+
+        /* Literals are word aligned, so both of these load the same one */
+        assert_eq!(decode::disassemble_thumb_with(0x4801, 0x100, &format, &mut read), "ldr       r0,[0x108] ;=0x4000000");
+        assert_eq!(decode::disassemble_thumb_with(0x4801, 0x102, &format, &mut read), "ldr       r0,[0x108] ;=0x4000000");
+        assert_eq!(decode::disassemble_thumb(0x4801, 0x100), "ldr       r0,[0x108]");
+
+        /* A BL pair becomes one branch, the halves on their own show what each of them does */
+        assert_eq!(decode::disassemble_thumb_with(0xF7FF, 0x104, &format, &mut read), "bl        0x104");
+        assert_eq!(decode::disassemble_thumb(0xF000, 0x100), "add       lr,=0x104");
+        assert_eq!(decode::disassemble_thumb(0xF802, 0x102), "bl        lr+0x4");
+    }
+
+    #[test]
+    fn thumb_formats() {
+        let mut format = DisasmFormat { syntax: Syntax::PreUal, ..Default::default() };
+        format.symbols.insert(0x108, "main".to_string());
+        let mut none = |_| None;
+
+        assert_eq!(decode::disassemble_thumb_with(0x2005, 0, &format, &mut none), "mov       r0,0x5");
+        assert_eq!(decode::disassemble_thumb_with(0x5E88, 0, &format, &mut none), "ldsh      r0,[r1,r2]");
+        assert_eq!(decode::disassemble_thumb_with(0xE002, 0x100, &format, &mut none), "b         main");
+    }
+}
@@ -0,0 +1,249 @@
+#[path = "../src/arm7tdmi.rs"]
+mod arm7tdmi;
+#[path = "../src/decode.rs"]
+mod decode;
+#[path = "../src/exec.rs"]
+mod exec;
+#[path = "../src/bios.rs"]
+mod bios;
+#[path = "../src/memory.rs"]
+mod memory;
+#[path = "../src/interp.rs"]
+mod interp;
+#[path = "../src/recomp.rs"]
+mod recomp;
+#[path = "../src/cache.rs"]
+mod cache;
+#[path = "../src/asm.rs"]
+mod asm;
+#[path = "../src/debugger.rs"]
+mod debugger;
+#[path = "../src/gdb.rs"]
+mod gdb;
+
+/*
+The GDB stub, driven by a scripted client over a real socket the way arm-none-eabi-gdb would drive it: loading a program
+through memory writes, stepping through ARM into Thumb, breakpoints, watchpoints, the banked registers and Ctrl-C.
+*/
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{ Read, Write };
+    use std::net::{ TcpListener, TcpStream };
+    use std::thread;
+    use std::time::Duration;
+    use memory::Memory;
+
+    struct Client {
+        stream: TcpStream,
+        ack: bool,
+    }
+
+    impl Client {
+        fn byte(&mut self) -> u8 {
+            let mut byte = [0];
+            self.stream.read_exact(&mut byte).unwrap();
+            byte[0]
+        }
+
+        fn send(&mut self, data: &str) {
+            self.send_binary(data.as_bytes());
+        }
+
+        /* Escapes the bytes that would end or confuse the packet, as GDB does for X */
+        fn send_binary(&mut self, data: &[u8]) {
+            let mut packet = Vec::new();
+            for &byte in data {
+                if matches!(byte, b'$' | b'#' | b'}' | b'*') {
+                    packet.extend_from_slice(&[b'}', byte ^ 0x20]);
+                } else {
+                    packet.push(byte);
+                }
+            }
+            let sum = packet.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte));
+            self.stream.write_all(b"$").unwrap();
+            self.stream.write_all(&packet).unwrap();
+            self.stream.write_all(format!("#{:02x}", sum).as_bytes()).unwrap();
+            if self.ack {
+                assert_eq!(self.byte(), b'+', "{} wasn't acknowledged", String::from_utf8_lossy(data));
+            }
+        }
+
+        fn reply(&mut self) -> String {
+            while self.byte() != b'$' {}
+            let mut data = Vec::new();
+            let mut sum: u8 = 0;
+            loop {
+                let byte = self.byte();
+                if byte == b'#' {
+                    break;
+                }
+                sum = sum.wrapping_add(byte);
+                data.push(byte);
+            }
+            let checksum = [self.byte(), self.byte()];
+            assert_eq!(u8::from_str_radix(std::str::from_utf8(&checksum).unwrap(), 16).unwrap(), sum);
+            if self.ack {
+                self.stream.write_all(b"+").unwrap();
+            }
+            String::from_utf8(data).unwrap()
+        }
+
+        fn request(&mut self, data: &str) -> String {
+            self.send(data);
+            self.reply()
+        }
+
+        fn register(&mut self, n: usize) -> u32 {
+            let reply = self.request(&format!("p{:x}", n));
+            u32::from_str_radix(&reply, 16).unwrap().swap_bytes()
+        }
+    }
+
+    fn hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+    }
+
+    #[test]
+    fn session() {
+        let program = asm::assemble("
+                    .arm
+            start:  mov r0,1
+                    ldr r1,=thumb+1
+                    bx r1
+                    .thumb
+            thumb:  movs r2,2
+                    ldr r3,=data
+                    ldr r4,[r3]
+                    str r2,[r3]
+            after:  movs r5,5
+            spin:   b spin
+                    .align 2
+            data:   .word 0xCAFEBABE
+                    .pool
+        ", 0x100).unwrap();
+        let symbols = program.symbols.clone();
+        let data = symbols["data"];
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        let client = thread::spawn(move || {
+            let mut gdb = Client { stream: TcpStream::connect(("127.0.0.1", port)).unwrap(), ack: true };
+
+            let supported = gdb.request("qSupported:multiprocess+;swbreak+;hwbreak+;xmlRegisters=arm");
+            assert!(supported.contains("qXfer:features:read+"));
+            assert!(supported.contains("swbreak+"));
+
+            let xml = gdb.request("qXfer:features:read:target.xml:0,fff");
+            assert!(xml.starts_with("l<?xml"));
+            assert!(xml.contains("org.gnu.gdb.arm.core"));
+            assert!(xml.contains("name=\"cpsr\""));
+            assert!(xml.contains("name=\"r13_svc\""));
+            assert!(gdb.request("qXfer:features:read:target.xml:0,10").starts_with('m'));
+
+            /* Load the program the way GDB's load does */
+            let image = hex(&program.bytes);
+            assert_eq!(gdb.request(&format!("M100,{:x}:{}", program.bytes.len(), image)), "OK");
+            assert_eq!(gdb.request(&format!("m100,{:x}", program.bytes.len())), image);
+            assert_eq!(gdb.request("Pf=00010000"), "OK");
+            assert_eq!(gdb.register(15), 0x100);
+
+            /* Single steps through ARM and into Thumb */
+            assert_eq!(gdb.request("s"), "T05");
+            assert_eq!(gdb.register(0), 1);
+            assert_eq!(gdb.register(15), 0x104);
+            assert_eq!(gdb.request("s"), "T05");
+            assert_eq!(gdb.request("s"), "T05");
+            assert_eq!(gdb.register(15), symbols["thumb"]);
+            assert_ne!(gdb.register(16) & (1 << 5), 0);
+            assert_eq!(gdb.request("s"), "T05");
+            assert_eq!(gdb.register(15), symbols["thumb"] + 2);
+            assert_eq!(gdb.register(2), 2);
+
+            /* A write watchpoint lets the load go by and stops after the store */
+            assert_eq!(gdb.request(&format!("Z2,{:x},4", symbols["data"])), "OK");
+            assert_eq!(gdb.request(&format!("Z1,{:x},2", symbols["spin"])), "OK");
+            assert_eq!(gdb.request("c"), format!("T05watch:{:x};", symbols["data"]));
+            assert_eq!(gdb.register(15), symbols["after"]);
+            assert_eq!(gdb.register(4), 0xCAFEBABE);
+            assert_eq!(gdb.request("c"), "T05hwbreak:;");
+            assert_eq!(gdb.register(15), symbols["spin"]);
+            assert_eq!(gdb.register(5), 5);
+            assert_eq!(gdb.request(&format!("z2,{:x},4", symbols["data"])), "OK");
+
+            /* The rest without acks */
+            assert_eq!(gdb.request("QStartNoAckMode"), "OK");
+            gdb.ack = false;
+
+            assert_eq!(gdb.request(&format!("m{:x},4", symbols["data"])), "02000000");
+            assert_eq!(gdb.request(&format!("M{:x},4:efbeadde", symbols["data"])), "OK");
+            assert_eq!(gdb.request(&format!("m{:x},4", symbols["data"])), "efbeadde");
+            assert_eq!(gdb.request("m10000,4"), "E01");
+            assert_eq!(gdb.request("M10000,1:00"), "E01");
+
+            /* X sends the bytes as they are, high ones and escaped ones included */
+            let binary = [0x80, 0xFF, b'#', b'}', 0xC3, 0x28, b'$', 0x00];
+            gdb.send_binary(&[b"X800,8:".as_slice(), &binary].concat());
+            assert_eq!(gdb.reply(), "OK");
+            assert_eq!(gdb.request("m800,8"), hex(&binary));
+            gdb.send_binary(b"X800,2:\xff");
+            assert_eq!(gdb.reply(), "E01");
+
+            /* Banked registers, in supervisor mode */
+            let cpsr = gdb.register(16) & !0x1F | 0x13;
+            assert_eq!(gdb.request(&format!("P10={:08x}", cpsr.swap_bytes())), "OK");
+            assert_eq!(gdb.register(16), cpsr);
+            assert_eq!(gdb.request("P1f=e07f0003"), "OK");
+            assert_eq!(gdb.register(13), 0x03007FE0);
+            assert_eq!(gdb.request("P16=00ff0003"), "OK");
+            assert_eq!(gdb.register(22), 0x0300FF00);
+            assert_eq!(gdb.register(13), 0x03007FE0);
+            assert_eq!(gdb.request("g").len(), gdb::REGISTERS.len() * 8);
+
+            /* Ctrl-C while it spins */
+            assert_eq!(gdb.request(&format!("z1,{:x},2", symbols["spin"])), "OK");
+            gdb.send("c");
+            thread::sleep(Duration::from_millis(50));
+            gdb.stream.write_all(&[0x03]).unwrap();
+            assert_eq!(gdb.reply(), "T02");
+            assert_eq!(gdb.register(15), symbols["spin"]);
+
+            assert_eq!(gdb.request("D"), "OK");
+        });
+
+        let mut core = arm7tdmi::Core::new();
+        let mut bus = debugger::Watched::new(memory::FlatMemory::new(0x1000));
+        let (stream, _) = listener.accept().unwrap();
+        let mut stub = gdb::GdbStub::new();
+        stub.serve(stream, &mut core, &mut bus).unwrap();
+        client.join().unwrap();
+
+        assert_eq!(bus.mem_read_32(data as usize), 0xDEADBEEF);
+        assert_eq!(core.reg.svc[0], 0x03007FE0);
+    }
+
+    #[test]
+    fn registers() {
+        let mut reg = arm7tdmi::Core::new().reg;
+        assert!(gdb::write_register(&mut reg, 16, 0x13));
+        for n in 17..gdb::REGISTERS.len() {
+            assert!(gdb::write_register(&mut reg, n, n as u32 | 0x10));
+        }
+        for n in 17..gdb::REGISTERS.len() {
+            assert_eq!(gdb::read_register(&reg, n), Some(n as u32 | 0x10), "{}", gdb::REGISTERS[n]);
+        }
+
+        /* r0-r15 are whatever the mode in the CPSR sees */
+        assert_eq!(gdb::read_register(&reg, 13), Some(31 | 0x10));
+        assert!(gdb::write_register(&mut reg, 16, 0x11));
+        assert_eq!(gdb::read_register(&reg, 13), Some(29 | 0x10));
+        assert_eq!(gdb::read_register(&reg, 8), Some(24 | 0x10));
+        assert!(gdb::write_register(&mut reg, 16, 0x1F));
+        assert_eq!(gdb::read_register(&reg, 8), Some(17 | 0x10));
+
+        assert!(!gdb::write_register(&mut reg, 44, 0));
+        assert_eq!(gdb::read_register(&reg, 44), None);
+    }
+}
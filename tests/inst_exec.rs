@@ -0,0 +1,351 @@
+#[path = "../src/arm7tdmi.rs"]
+mod arm7tdmi;
+#[path = "../src/decode.rs"]
+mod decode;
+#[path = "../src/exec.rs"]
+mod exec;
+#[path = "../src/bios.rs"]
+mod bios;
+#[path = "../src/memory.rs"]
+mod memory;
+
+/*
+These are for checking the results of executing each ARM instruction (as opposed to how long it takes, see inst_timing.rs)
+*/
+
+pub fn test_inst<M: memory::Memory>(core: &mut arm7tdmi::Core, bus: &mut M, inst: u32) {
+    core.cycle = 0;
+
+    let mut state = None;
+
+    while state.is_none() {
+        state = exec::step_arm(core, bus, inst);
+        core.cycle += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use memory::Memory;
+
+    #[test]
+    fn conditional_execution() {
+        let mut core = arm7tdmi::Core::new();
+        let mut bus = memory::FlatMemory::new(0x400);
+
+        core.reg.gp[1] = 5;
+
+        /* Equal values leave Z and C set, so LS passes and HI doesn't */
+        test_inst(&mut core, &mut bus, 0xE3510005); //CMP r1,#5
+        test_inst(&mut core, &mut bus, 0x93A00001); //MOVLS r0,#1
+        test_inst(&mut core, &mut bus, 0x83A02001); //MOVHI r2,#1
+        assert_eq!(core.reg.gp[0], 1);
+        assert_eq!(core.reg.gp[2], 0);
+
+        /* NV never executes */
+        test_inst(&mut core, &mut bus, 0xF3A03001); //MOVNV r3,#1
+        assert_eq!(core.reg.gp[3], 0);
+    }
+
+    #[test]
+    fn psr_transfer() {
+        let mut core = arm7tdmi::Core::new();
+        let mut bus = memory::FlatMemory::new(0x400);
+
+        /* Into Supervisor mode with interrupts disabled */
+        core.reg.gp[1] = 0x000000D3;
+        test_inst(&mut core, &mut bus, 0xE129F001); //MSR cpsr_fc,r1
+        test_inst(&mut core, &mut bus, 0xE10F0000); //MRS r0,cpsr
+        assert_eq!(core.reg.gp[0], 0x000000D3);
+        assert_eq!(core.reg.cpsr.mode(), arm7tdmi::MODE_SVC);
+
+        /* Only the flags field */
+        test_inst(&mut core, &mut bus, 0xE328F20F); //MSR cpsr_f,#0xF0000000
+        assert_eq!(u32::from(core.reg.cpsr), 0xF00000D3);
+        core.reg.gp[1] = 0x0000001F;
+        test_inst(&mut core, &mut bus, 0xE128F001); //MSR cpsr_f,r1
+        assert_eq!(u32::from(core.reg.cpsr), 0x000000D3);
+
+        /* The T bit can't be set and modes that don't exist are ignored */
+        core.reg.gp[1] = 0x00000034;
+        test_inst(&mut core, &mut bus, 0xE121F001); //MSR cpsr_c,r1
+        assert_eq!(u32::from(core.reg.cpsr), 0x00000013);
+
+        /* SPSR, then return to it with MOVS pc,lr */
+        core.reg.gp[1] = 0x6000001F;
+        test_inst(&mut core, &mut bus, 0xE169F001); //MSR spsr_fc,r1
+        test_inst(&mut core, &mut bus, 0xE14F0000); //MRS r0,spsr
+        assert_eq!(core.reg.gp[0], 0x6000001F);
+        test_inst(&mut core, &mut bus, 0xE1B0F00E); //MOVS pc,lr
+        assert_eq!(u32::from(core.reg.cpsr), 0x6000001F);
+
+        /* System mode has no SPSR, writes go nowhere and reads give the CPSR */
+        core.reg.gp[1] = 0;
+        test_inst(&mut core, &mut bus, 0xE169F001); //MSR spsr_fc,r1
+        test_inst(&mut core, &mut bus, 0xE14F0000); //MRS r0,spsr
+        assert_eq!(core.reg.gp[0], 0x6000001F);
+
+        /* User mode can change the flags and nothing else */
+        core.reg.gp[1] = 0x00000010;
+        test_inst(&mut core, &mut bus, 0xE129F001); //MSR cpsr_fc,r1
+        core.reg.gp[1] = 0xF00000DF;
+        test_inst(&mut core, &mut bus, 0xE129F001);
+        assert_eq!(u32::from(core.reg.cpsr), 0xF0000010);
+    }
+
+    #[test]
+    fn mode_bank_switch() {
+        let mut core = arm7tdmi::Core::new();
+        let mut bus = memory::FlatMemory::new(0x400);
+
+        core.reg.gp[1] = 0x13;
+        test_inst(&mut core, &mut bus, 0xE121F001); //MSR cpsr_c,r1
+        core.reg.write(13, 0x03007FE0);
+        core.reg.write(8, 8);
+
+        /* IRQ mode has its own r13 */
+        core.reg.gp[1] = 0x12;
+        test_inst(&mut core, &mut bus, 0xE121F001);
+        assert_eq!(core.reg.read(13), 0);
+        core.reg.write(13, 0x03007FA0);
+
+        /* FIQ banks r8 upwards */
+        core.reg.gp[1] = 0x11;
+        test_inst(&mut core, &mut bus, 0xE121F001);
+        assert_eq!(core.reg.read(8), 0);
+        assert_eq!(core.reg.read(13), 0);
+
+        /* System shares User's registers */
+        core.reg.gp[1] = 0x1F;
+        test_inst(&mut core, &mut bus, 0xE121F001);
+        assert_eq!(core.reg.read(8), 8);
+        assert_eq!(core.reg.read(13), 0);
+
+        core.reg.gp[1] = 0x13;
+        test_inst(&mut core, &mut bus, 0xE121F001);
+        assert_eq!(core.reg.read(13), 0x03007FE0);
+        core.reg.gp[1] = 0x12;
+        test_inst(&mut core, &mut bus, 0xE121F001);
+        assert_eq!(core.reg.read(13), 0x03007FA0);
+    }
+
+    #[test]
+    fn single_data_swap() {
+        let mut core = arm7tdmi::Core::new();
+        let mut bus = memory::FlatMemory::new(0x400);
+
+        bus.mem_write_32(0x100, 0x11223344);
+        core.reg.gp[1] = 0xAABBCCDD;
+        core.reg.gp[2] = 0x100;
+
+        test_inst(&mut core, &mut bus, 0xE1020091); //SWP r0,r1,[r2]
+        assert_eq!(core.reg.gp[0], 0x11223344);
+        assert_eq!(bus.mem_read_32(0x100), 0xAABBCCDD);
+        assert_eq!(core.reg.gp[2], 0x100);
+
+        core.reg.gp[1] = 0x55;
+        core.reg.gp[2] = 0x101;
+        test_inst(&mut core, &mut bus, 0xE1420091); //SWPB r0,r1,[r2]
+        assert_eq!(core.reg.gp[0], 0xCC);
+        assert_eq!(bus.mem_read_32(0x100), 0xAABB55DD);
+
+        /* A misaligned word is rotated */
+        test_inst(&mut core, &mut bus, 0xE1020091); //SWP r0,r1,[r2]
+        assert_eq!(core.reg.gp[0], 0xDDAABB55);
+        assert_eq!(bus.mem_read_32(0x100), 0x55);
+    }
+
+    /* Post-indexed transfers use the base as it was, and U picks whether the offset is added or taken away */
+    #[test]
+    fn transfer_addressing() {
+        let mut core = arm7tdmi::Core::new();
+        let mut bus = memory::FlatMemory::new(0x400);
+
+        bus.mem_write_32(0x100, 0xCAFEBEEF);
+        bus.mem_write_32(0xF8, 0x12345678);
+        core.reg.gp[0] = 0x100;
+        core.reg.gp[4] = 4;
+
+        test_inst(&mut core, &mut bus, 0xE4102004); //LDR r2,[r0],-#4
+        assert_eq!(core.reg.gp[2], 0xCAFEBEEF);
+        assert_eq!(core.reg.gp[0], 0xFC);
+
+        test_inst(&mut core, &mut bus, 0xE5802008); //STR r2,[r0,#8]
+        assert_eq!(bus.mem_read_32(0x104), 0xCAFEBEEF);
+        assert_eq!(core.reg.gp[0], 0xFC);
+
+        /* The top half of a halfword immediate offset is in bits 8-11 */
+        core.reg.gp[0] = 0x100;
+        test_inst(&mut core, &mut bus, 0xE0D011B2); //LDRH r1,[r0],#0x12
+        assert_eq!(core.reg.gp[1], 0xBEEF);
+        assert_eq!(core.reg.gp[0], 0x112);
+
+        test_inst(&mut core, &mut bus, 0xE14012B4); //STRH r1,[r0,#-0x24]
+        assert_eq!(bus.mem_read_16(0xEE), 0xBEEF);
+        assert_eq!(core.reg.gp[0], 0x112);
+
+        core.reg.gp[0] = 0xFC;
+        test_inst(&mut core, &mut bus, 0xE13030B4); //LDRH r3,[r0,-r4]!
+        assert_eq!(core.reg.gp[3], 0x5678);
+        assert_eq!(core.reg.gp[0], 0xF8);
+    }
+
+    #[test]
+    fn block_data_transfer() {
+        let mut core = arm7tdmi::Core::new();
+        core.reg.cpsr = arm7tdmi::Status::from(arm7tdmi::MODE_SYS as u32); //which has no banked R13
+        let mut bus = memory::FlatMemory::new(0x400);
+
+        /* Push and pop */
+        core.reg.gp[13] = 0x200;
+        core.reg.gp[1] = 0x11;
+        core.reg.gp[2] = 0x22;
+        test_inst(&mut core, &mut bus, 0xE92D0006); //STMDB r13!,{r1,r2}
+        assert_eq!(core.reg.gp[13], 0x1F8);
+        assert_eq!(bus.mem_read_32(0x1F8), 0x11);
+        assert_eq!(bus.mem_read_32(0x1FC), 0x22);
+
+        test_inst(&mut core, &mut bus, 0xE8BD0018); //LDMIA r13!,{r3,r4}
+        assert_eq!(core.reg.gp[13], 0x200);
+        assert_eq!((core.reg.gp[3], core.reg.gp[4]), (0x11, 0x22));
+
+        /* Addressing modes without write-back */
+        core.reg.gp[0] = 0x100;
+        test_inst(&mut core, &mut bus, 0xE9800006); //STMIB r0,{r1,r2}
+        assert_eq!((bus.mem_read_32(0x104), bus.mem_read_32(0x108)), (0x11, 0x22));
+        test_inst(&mut core, &mut bus, 0xE8000006); //STMDA r0,{r1,r2}
+        assert_eq!((bus.mem_read_32(0x0FC), bus.mem_read_32(0x100)), (0x11, 0x22));
+        test_inst(&mut core, &mut bus, 0xE9100030); //LDMDB r0,{r4,r5}
+        assert_eq!((core.reg.gp[4], core.reg.gp[5]), (0, 0x11));
+        assert_eq!(core.reg.gp[0], 0x100);
+
+        /* An empty list stores R15 (12 ahead by the time it's stored) and moves the base by 0x40 */
+        core.reg.gp[15] = 0x308;
+        test_inst(&mut core, &mut bus, 0xE8A00000); //STMIA r0!,{}
+        assert_eq!(bus.mem_read_32(0x100), 0x30C);
+        assert_eq!(core.reg.gp[0], 0x140);
+        /* Loading R15 refills the pipeline from there, leaving it 8 ahead */
+        bus.mem_write_32(0x30C, 0xE1A00000);
+        test_inst(&mut core, &mut bus, 0xE9300000); //LDMDB r0!,{}
+        assert_eq!(core.reg.gp[15], 0x314);
+        assert_eq!(core.reg.pipeline[1], 0xE1A00000);
+        assert_eq!(core.reg.gp[0], 0x100);
+    }
+
+    #[test]
+    fn block_transfer_base_in_list() {
+        let mut core = arm7tdmi::Core::new();
+        let mut bus = memory::FlatMemory::new(0x400);
+
+        /* The base is stored unchanged if it's the first register, otherwise it's already been written back */
+        core.reg.gp[1] = 0x100;
+        core.reg.gp[2] = 0x22;
+        test_inst(&mut core, &mut bus, 0xE8A10006); //STMIA r1!,{r1,r2}
+        assert_eq!(bus.mem_read_32(0x100), 0x100);
+        assert_eq!(core.reg.gp[1], 0x108);
+
+        core.reg.gp[1] = 0x11;
+        core.reg.gp[2] = 0x200;
+        test_inst(&mut core, &mut bus, 0xE8A20006); //STMIA r2!,{r1,r2}
+        assert_eq!(bus.mem_read_32(0x204), 0x208);
+
+        /* A loaded base wins over write-back */
+        core.reg.gp[2] = 0x200;
+        test_inst(&mut core, &mut bus, 0xE8B20006); //LDMIA r2!,{r1,r2}
+        assert_eq!(core.reg.gp[1], 0x11);
+        assert_eq!(core.reg.gp[2], 0x208);
+    }
+
+    #[test]
+    fn block_transfer_user_bank() {
+        let mut core = arm7tdmi::Core::new();
+        let mut bus = memory::FlatMemory::new(0x400);
+
+        core.reg.gp[13] = 0xAAAA;
+        core.reg.gp[14] = 0xBBBB;
+        core.reg.gp[1] = 0x13;
+        test_inst(&mut core, &mut bus, 0xE121F001); //MSR cpsr_c,r1
+        core.reg.write(13, 0x1313);
+        core.reg.write(14, 0x1414);
+
+        /* The S bit transfers User mode's registers */
+        core.reg.gp[0] = 0x100;
+        test_inst(&mut core, &mut bus, 0xE8C06000); //STMIA r0,{r13,r14}^
+        assert_eq!((bus.mem_read_32(0x100), bus.mem_read_32(0x104)), (0xAAAA, 0xBBBB));
+
+        bus.mem_write_32(0x100, 0xCCCC);
+        test_inst(&mut core, &mut bus, 0xE8D02000); //LDMIA r0,{r13}^
+        assert_eq!(core.reg.gp[13], 0xCCCC);
+        assert_eq!(core.reg.read(13), 0x1313);
+
+        /* With R15 in the list it restores the CPSR instead */
+        core.reg.gp[1] = 0x6000001F;
+        test_inst(&mut core, &mut bus, 0xE169F001); //MSR spsr_fc,r1
+        bus.mem_write_32(0x100, 0x08000000);
+        bus.mem_write_32(0x104, 0x355);
+        test_inst(&mut core, &mut bus, 0xE8D0A000); //LDMIA r0,{r13,r15}^
+        assert_eq!(u32::from(core.reg.cpsr), 0x6000001F);
+        assert_eq!(core.reg.gp[15], 0x35C);
+        assert_eq!(core.reg.svc[0], 0x08000000);
+    }
+
+    #[test]
+    fn multiply() {
+        let mut core = arm7tdmi::Core::new();
+        let mut bus = memory::FlatMemory::new(0x400);
+
+        core.reg.gp[1] = 0xFFFFFFFE;    //-2
+        core.reg.gp[2] = 0x00000003;
+        core.reg.gp[3] = 0x00000010;
+
+        test_inst(&mut core, &mut bus, 0xE0100291); //MULS r0,r1,r2
+        assert_eq!(core.reg.gp[0], 0xFFFFFFFA);
+        assert!(core.reg.cpsr.n);
+        assert!(!core.reg.cpsr.z);
+
+        test_inst(&mut core, &mut bus, 0xE0303291); //MLAS r0,r1,r2,r3
+        assert_eq!(core.reg.gp[0], 0x0000000A);
+        assert!(!core.reg.cpsr.n);
+
+        core.reg.gp[3] = 6;
+        test_inst(&mut core, &mut bus, 0xE0303291);
+        assert_eq!(core.reg.gp[0], 0);
+        assert!(core.reg.cpsr.z);
+    }
+
+    #[test]
+    fn multiply_long() {
+        let mut core = arm7tdmi::Core::new();
+        let mut bus = memory::FlatMemory::new(0x400);
+
+        core.reg.gp[2] = 0xFFFFFFFE;
+        core.reg.gp[3] = 0x00000003;
+
+        test_inst(&mut core, &mut bus, 0xE0910392); //UMULLS r0,r1,r2,r3
+        assert_eq!((core.reg.gp[1], core.reg.gp[0]), (0x00000002, 0xFFFFFFFA));
+        assert!(!core.reg.cpsr.n);
+
+        test_inst(&mut core, &mut bus, 0xE0C10392); //SMULL r0,r1,r2,r3
+        assert_eq!((core.reg.gp[1], core.reg.gp[0]), (0xFFFFFFFF, 0xFFFFFFFA));
+
+        /* Accumulates across RdHi:RdLo */
+        core.reg.gp[0] = 0x00000007;
+        core.reg.gp[1] = 0x00000000;
+        test_inst(&mut core, &mut bus, 0xE0F10392); //SMLALS r0,r1,r2,r3
+        assert_eq!((core.reg.gp[1], core.reg.gp[0]), (0x00000000, 0x00000001));
+        assert!(!core.reg.cpsr.n);
+        assert!(!core.reg.cpsr.z);
+
+        core.reg.gp[0] = 0x00000006;
+        core.reg.gp[1] = 0x00000000;
+        test_inst(&mut core, &mut bus, 0xE0F10392);
+        assert_eq!((core.reg.gp[1], core.reg.gp[0]), (0, 0));
+        assert!(core.reg.cpsr.z);
+
+        core.reg.gp[0] = 0xFFFFFFFF;
+        core.reg.gp[1] = 0x00000001;
+        test_inst(&mut core, &mut bus, 0xE0A10392); //UMLAL r0,r1,r2,r3
+        assert_eq!((core.reg.gp[1], core.reg.gp[0]), (0x00000004, 0xFFFFFFF9));
+    }
+}
@@ -37,6 +37,50 @@ mod tests {
         assert_eq!(decode::decode_arm(0b00000000101100010100001010010011), decode::ArmInstType::MultiplyLong);
     }
 
+    #[test]
+    fn test_arm_psrtransfer() {
+        assert_eq!(decode::decode_arm(0xE10F0000), decode::ArmInstType::PSRTransfer); //MRS r0,cpsr
+        assert_eq!(decode::decode_arm(0xE121F001), decode::ArmInstType::PSRTransfer); //MSR cpsr_c,r1
+        assert_eq!(decode::decode_arm(0xE328F20F), decode::ArmInstType::PSRTransfer); //MSR cpsr_f,#0xF0000000
+        assert_eq!(decode::decode_psr_transfer(0xE14F0000), 0);
+        assert_eq!(decode::decode_psr_transfer(0xE169F001), 1);
+        assert_eq!(decode::decode_psr_transfer(0xE328F20F), 2);
+    }
+
+    /* The lookup table has to agree with the full decoder for every valid instruction */
+    #[test]
+    fn test_arm_lut() {
+        let mut x: u32 = 0x2468ACE1;
+        let mut next = || { x ^= x << 13; x ^= x >> 17; x ^= x << 5; x };
+
+        for _ in 0..200000 {
+            let inst = (next() & 0x0FFFFFFF) | 0xE0000000;
+            let decoded = decode::decode_arm(inst);
+            let lut = decode::decode_arm_lut(decode::arm_lut_index(inst));
+
+            /* Only the full decoder checks the bits outside of the index for the rarer encodings */
+            let valid = match decoded {
+                decode::ArmInstType::HalfwordDataTransferRegisterOffset | decode::ArmInstType::HalfwordDataTransferImmediateOffset => inst & 0x60 != 0,
+                decode::ArmInstType::DataProcessing => inst & 0x01900000 != 0x01000000 && inst & 0x02000090 != 0x90,
+                _ => true,
+            };
+            if valid && lut != decode::ArmInstType::Undefined {
+                assert_eq!(lut, decoded, "{:08x}", inst);
+            }
+        }
+
+        assert_eq!(decode::decode_arm_lut(decode::arm_lut_index(0xE12FFF11)), decode::ArmInstType::BranchAndExchange);
+        assert_eq!(decode::decode_arm_lut(decode::arm_lut_index(0xE10F0000)), decode::ArmInstType::PSRTransfer);
+        assert_eq!(decode::decode_arm_lut(decode::arm_lut_index(0xE129F001)), decode::ArmInstType::PSRTransfer);
+        assert_eq!(decode::decode_arm_lut(decode::arm_lut_index(0xE328F20F)), decode::ArmInstType::PSRTransfer);
+        assert_eq!(decode::decode_arm_lut(decode::arm_lut_index(0xE1020091)), decode::ArmInstType::SingleDataSwap);
+        assert_eq!(decode::decode_arm_lut(decode::arm_lut_index(0xE0910392)), decode::ArmInstType::MultiplyLong);
+        assert_eq!(decode::decode_arm_lut(decode::arm_lut_index(0xE1D000B2)), decode::ArmInstType::HalfwordDataTransferImmediateOffset);
+        assert_eq!(decode::decode_arm_lut(decode::arm_lut_index(0xE6000010)), decode::ArmInstType::Undefined);
+        assert_eq!(decode::decode_arm_lut(decode::arm_lut_index(0xEE000000)), decode::ArmInstType::Undefined);
+        assert_eq!(decode::decode_arm_lut(decode::arm_lut_index(0xEF000000)), decode::ArmInstType::SoftwareInterrupt);
+    }
+
     #[test]
     fn test_arm_singledattrans() {
         assert_eq!(decode::decode_arm(0b00000110100001000011101010101010), decode::ArmInstType::SingleDataTransfer);
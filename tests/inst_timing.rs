@@ -4,62 +4,12 @@ mod arm7tdmi;
 mod decode;
 #[path = "../src/exec.rs"]
 mod exec;
+#[path = "../src/bios.rs"]
+mod bios;
+#[path = "../src/memory.rs"]
+mod memory;
 
-mod bus {
-    pub struct Bus {
-        mem: [u8; 0x400]
-    }
-
-    impl Bus {
-        pub fn new() -> Self {
-            Bus {
-                mem: [0; 0x400]
-            }
-        }
-
-        pub fn mem_read(&self, addr: usize) -> u8 {
-            self.mem[addr]
-        }
-
-        pub fn mem_write(&mut self, addr: usize, data: u8) {
-            self.mem[addr] = data;
-        }
-
-        pub fn mem_read_16(&mut self, addr: usize) -> u16 {
-            let lo = self.mem_read(addr) as u16;
-            let hi = self.mem_read(addr+1) as u16;
-            lo | (hi << 8)
-        }
-    
-        pub fn mem_read_32(&mut self, addr: usize) -> u32 {
-            let lo = self.mem_read(addr) as u32;
-            let lo1 = self.mem_read(addr+1) as u32;
-            let hi = self.mem_read(addr+2) as u32;
-            let hi1 = self.mem_read(addr+3) as u32;
-            lo | (lo1 << 8) | (hi << 16) | (hi1 << 24)
-        }
-    
-        pub fn mem_write_16(&mut self, addr: usize, data: u16) {
-            let lo = (data & 0xFF) as u8;
-            let hi = (data >> 8) as u8;
-            self.mem_write(addr, lo);
-            self.mem_write(addr+1, hi);
-        }
-    
-        pub fn mem_write_32(&mut self, addr: usize, data: u32) {
-            let lo = (data & 0xFF) as u8;
-            let lo1 = ((data >> 8) & 0xFF) as u8;
-            let hi = ((data >> 16) & 0xFF) as u8;
-            let hi1 = ((data >> 24) & 0xFF) as u8;
-            self.mem_write(addr, lo);
-            self.mem_write(addr+1, lo1);
-            self.mem_write(addr+2, hi);
-            self.mem_write(addr+3, hi1);
-        }
-    }
-}
-
-pub fn test_inst(core: &mut arm7tdmi::Core, bus: &mut bus::Bus, inst: u32) -> u8 {
+pub fn test_inst<M: memory::Memory>(core: &mut arm7tdmi::Core, bus: &mut M, inst: u32) -> u8 {
     let mut cycles = 0;
     core.cycle = 0;
 
@@ -81,25 +31,31 @@ mod tests {
     #[test]
     fn data_proc_cycle_timing() {
         let mut core = arm7tdmi::Core::new();
-        let mut bus = bus::Bus::new();
+        let mut bus = memory::FlatMemory::new(0x400);
 
         let instruction = 0xE0922001; //normal
         assert_eq!(test_inst(&mut core, &mut bus, instruction), 1);
 
-        let instruction = 0xE0922301; //shift
+        let instruction = 0xE0922301; //immediate shift
+        assert_eq!(test_inst(&mut core, &mut bus, instruction), 1);
+
+        let instruction = 0xE0922311; //shift(Rs)
         assert_eq!(test_inst(&mut core, &mut bus, instruction), 2);
 
         let instruction = 0xE092F001; //dest=pc
         assert_eq!(test_inst(&mut core, &mut bus, instruction), 3);
 
-        let instruction = 0xE092F301; //shift, dest=pc
+        let instruction = 0xE092F301; //immediate shift, dest=pc
+        assert_eq!(test_inst(&mut core, &mut bus, instruction), 3);
+
+        let instruction = 0xE092F311; //shift(Rs), dest=pc
         assert_eq!(test_inst(&mut core, &mut bus, instruction), 4);
     }
 
     #[test]
     fn branch_and_exchange_cycle_timing() {
         let mut core = arm7tdmi::Core::new();
-        let mut bus = bus::Bus::new();
+        let mut bus = memory::FlatMemory::new(0x400);
 
         let instruction = 0xE12FFF12;
         assert_eq!(test_inst(&mut core, &mut bus, instruction), 3);
@@ -108,7 +64,7 @@ mod tests {
     #[test]
     fn branch_cycle_timing() {
         let mut core = arm7tdmi::Core::new();
-        let mut bus = bus::Bus::new();
+        let mut bus = memory::FlatMemory::new(0x400);
 
         let instruction = 0xEB00004C;
         assert_eq!(test_inst(&mut core, &mut bus, instruction), 3);
@@ -117,7 +73,7 @@ mod tests {
     #[test]
     fn single_transfer_cycle_timing() {
         let mut core = arm7tdmi::Core::new();
-        let mut bus = bus::Bus::new();
+        let mut bus = memory::FlatMemory::new(0x400);
 
         /* Load register */
         let instruction = 0xE5B22115;
@@ -134,7 +90,7 @@ mod tests {
     #[test]
     fn single_data_swap_cycle_timing() {
         let mut core = arm7tdmi::Core::new();
-        let mut bus = bus::Bus::new();
+        let mut bus = memory::FlatMemory::new(0x400);
 
         let instruction = 0xE1028092;
         assert_eq!(test_inst(&mut core, &mut bus, instruction), 4);
@@ -143,7 +99,7 @@ mod tests {
      #[test]
     fn block_data_transfer_cycle_timing() {
         let mut core = arm7tdmi::Core::new();
-        let mut bus = bus::Bus::new();
+        let mut bus = memory::FlatMemory::new(0x400);
 
         /* load */
         let instruction = 0xe8900002; /* Single register */
@@ -165,4 +121,34 @@ mod tests {
         let instruction = 0xe88007ff; /* n registers (n>1) (n=11)*/
         assert_eq!(test_inst(&mut core, &mut bus, instruction), 12);
     } 
+
+    #[test]
+    fn multiply_cycle_timing() {
+        let mut core = arm7tdmi::Core::new();
+        let mut bus = memory::FlatMemory::new(0x400);
+
+        core.reg.gp[1] = 0x12345678;
+
+        /* MUL takes 1S + mI, MLA 1S + (m+1)I */
+        core.reg.gp[2] = 0x7F;
+        assert_eq!(test_inst(&mut core, &mut bus, 0xE0100291), 2);
+        assert_eq!(test_inst(&mut core, &mut bus, 0xE0303291), 3);
+
+        core.reg.gp[2] = 0xFFFF8000;
+        assert_eq!(test_inst(&mut core, &mut bus, 0xE0100291), 3);
+
+        core.reg.gp[2] = 0x00800000;
+        assert_eq!(test_inst(&mut core, &mut bus, 0xE0100291), 4);
+
+        core.reg.gp[2] = 0x80000000;
+        assert_eq!(test_inst(&mut core, &mut bus, 0xE0100291), 5);
+        assert_eq!(test_inst(&mut core, &mut bus, 0xE0303291), 6);
+
+        /* (U/S)MULL takes 1S + (m+1)I, (U/S)MLAL 1S + (m+2)I */
+        core.reg.gp[3] = 0xFFFFFFFF;
+        assert_eq!(test_inst(&mut core, &mut bus, 0xE0910392), 6);  //UMULLS
+        assert_eq!(test_inst(&mut core, &mut bus, 0xE0C10392), 3);  //SMULL
+        assert_eq!(test_inst(&mut core, &mut bus, 0xE0F10392), 4);  //SMLALS
+        assert_eq!(test_inst(&mut core, &mut bus, 0xE0A10392), 7);  //UMLAL
+    }
 }
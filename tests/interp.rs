@@ -0,0 +1,365 @@
+#[path = "../src/arm7tdmi.rs"]
+mod arm7tdmi;
+#[path = "../src/decode.rs"]
+mod decode;
+#[path = "../src/exec.rs"]
+mod exec;
+#[path = "../src/bios.rs"]
+mod bios;
+#[path = "../src/memory.rs"]
+mod memory;
+#[path = "../src/interp.rs"]
+mod interp;
+#[path = "../src/asm.rs"]
+mod asm;
+
+/*
+Tests for the fast interpreter, mostly running the same code through both engines and making sure they end up in the same place
+*/
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use memory::Memory;
+    use interp::{ Engine, Interpreter };
+
+    /* Load a program at 0x100 with some data to work on, and registers set up to point at it */
+    fn setup(program: &[u32]) -> (arm7tdmi::Core, memory::FlatMemory) {
+        let mut core = arm7tdmi::Core::new();
+        let mut bus = memory::FlatMemory::new(0x1000);
+        core.reg.cpsr = arm7tdmi::Status::from(arm7tdmi::MODE_SYS as u32); //so the registers set here are the ones in use
+
+        for (i, inst) in program.iter().enumerate() {
+            bus.mem_write_32(0x100 + 4*i, *inst);
+        }
+        for i in 0..0x40 {
+            bus.mem_write_32(0x800 + 4*i, 0x9E3779B9u32.wrapping_mul(i as u32 + 1));
+        }
+
+        core.reg.gp = [0x800, 0x810, 0x80000001, 3, 0xFFFFFFFF, 0x7FFFFFFF, 12, 0x12345678, 0x20, 0x847, 0xF0, 0, 0, 0x900, 0, 0x100];
+        (core, bus)
+    }
+
+    /* A bit of everything, finishing with R15 reads and branches that go through the stepped core's pipeline */
+    #[test]
+    fn engines_agree() {
+        let program = [
+            0xE0912003, //ADDS r2,r1,r3
+            0xE0B23004, //ADCS r3,r2,r4
+            0xE0D43005, //SBCS r3,r4,r5
+            0xE1B02637, //MOVS r2,r7,LSR r6
+            0xE1B02067, //MOVS r2,r7,RRX
+            0xE1B02047, //MOVS r2,r7,ASR #32
+            0xE3D224FF, //BICS r2,r2,#0xFF000000
+            0xE1540005, //CMP r4,r5
+            0x93A0A001, //MOVLS r10,#1
+            0x83A0B001, //MOVHI r11,#1
+            0xE0100792, //MULS r0,r2,r7
+            0xE0B21597, //UMLALS r1,r2,r7,r5
+            0xE0C32794, //SMULL r2,r3,r4,r7
+            0xE3A00B02, //MOV r0,#0x800
+            0xE5902004, //LDR r2,[r0,#4]
+            0xE5B03008, //LDR r3,[r0,#8]!
+            0xE4D04004, //LDRB r4,[r0],#4
+            0xE7907108, //LDR r7,[r0,r8,LSL #2]
+            0xE5807010, //STR r7,[r0,#16]
+            0xE1D030B2, //LDRH r3,[r0,#2]
+            0xE1D030D1, //LDRSB r3,[r0,#1]
+            0xE1C070B6, //STRH r7,[r0,#6]
+            0xE92D00FC, //STMDB r13!,{r2-r7}
+            0xE8BD0300, //LDMIA r13!,{r8,r9}
+            0xE99D0C00, //LDMIB r13,{r10,r11}
+            0xE8A00003, //STMIA r0!,{r0,r1}
+            0xE1001097, //SWP r1,r7,[r0]
+            0xE1401097, //SWPB r1,r7,[r0]
+            0xE10F9000, //MRS r9,cpsr
+            0xE328F20F, //MSR cpsr_f,#0xF0000000
+            0xE10F9000, //MRS r9,cpsr
+            0xE28FC004, //0x17C ADD r12,pc,#4
+            0xE52DF004, //0x180 STR pc,[r13,#-4]!
+            0xE080B81F, //0x184 ADD r11,r0,pc,LSL r8
+            0xEB000002, //0x188 BL 0x198
+            0xEA000003, //0x18C B 0x1A0
+            0xE3A00000, //0x190 MOV r0,#0
+            0xE3A00000, //0x194 MOV r0,#0
+            0xE08FA00E, //0x198 ADD r10,pc,lr
+            0xE1A0F00E, //0x19C MOV pc,lr
+        ];
+
+        let fast: Interpreter<memory::FlatMemory> = Interpreter::new(Engine::Fast);
+        let stepped: Interpreter<memory::FlatMemory> = Interpreter::new(Engine::Stepped);
+        let (mut core_fast, mut bus_fast) = setup(&program);
+        let (mut core_stepped, mut bus_stepped) = setup(&program);
+
+        for n in 0..100 {
+            if core_fast.reg.gp[15] == 0x1A0 { break; }
+            let inst = bus_fast.mem_read_32(core_fast.reg.gp[15] as usize);
+            let fast_cycles = fast.step(&mut core_fast, &mut bus_fast);
+            let stepped_cycles = stepped.step(&mut core_stepped, &mut bus_stepped);
+
+            assert_eq!(core_fast.reg.gp, core_stepped.reg.gp, "{:08x}", inst);
+            assert_eq!(u32::from(core_fast.reg.cpsr), u32::from(core_stepped.reg.cpsr), "{:08x}", inst);
+            /* The stepped core fills its pipeline before the first instruction */
+            assert_eq!(fast_cycles + if n == 0 {2} else {0}, stepped_cycles, "{:08x}", inst);
+        }
+        assert_eq!(core_stepped.reg.gp[15], 0x1A0);
+        assert_eq!(bus_fast.mem, bus_stepped.mem);
+    }
+
+    #[test]
+    fn branches() {
+        let (mut core, mut bus) = setup(&[
+            0xEB000002, //BL 0x110
+            0xE3A00001, //MOV r0,#1
+            0xE3A01001, //MOV r1,#1
+            0xE3A02001, //MOV r2,#1
+            0xE1A0F00E, //MOV pc,lr
+        ]);
+        let fast: Interpreter<memory::FlatMemory> = Interpreter::new(Engine::Fast);
+
+        assert_eq!(fast.step(&mut core, &mut bus), 3);
+        assert_eq!(core.reg.gp[15], 0x110);
+        assert_eq!(core.reg.gp[14], 0x104);
+
+        assert_eq!(fast.step(&mut core, &mut bus), 3);
+        assert_eq!(core.reg.gp[15], 0x104);
+        fast.step(&mut core, &mut bus);
+        assert_eq!(core.reg.gp[0], 1);
+
+        /* BX into Thumb */
+        core.reg.gp[15] = 0x100;
+        core.reg.gp[3] = 0x201;
+        fast.execute(&mut core, &mut bus, 0xE12FFF13); //BX r3
+        assert!(core.reg.cpsr.state);
+        assert_eq!(core.reg.gp[15], 0x200);
+    }
+
+    #[test]
+    fn pc_operand() {
+        let (mut core, mut bus) = setup(&[
+            0xE1A0000F, //MOV r0,pc
+            0xE08F1613, //ADD r1,pc,r3,LSL r6
+            0xE58DF000, //STR pc,[r13]
+        ]);
+        let fast: Interpreter<memory::FlatMemory> = Interpreter::new(Engine::Fast);
+
+        fast.step(&mut core, &mut bus);
+        assert_eq!(core.reg.gp[0], 0x108);
+        assert_eq!(fast.step(&mut core, &mut bus), 2);
+        assert_eq!(core.reg.gp[1], 0x3110);
+        fast.step(&mut core, &mut bus);
+        assert_eq!(bus.mem_read_32(0x900), 0x114);
+    }
+
+    #[test]
+    fn exceptions() {
+        let (mut core, mut bus) = setup(&[
+            0xEF000005, //SWI 5
+        ]);
+        let fast: Interpreter<memory::FlatMemory> = Interpreter::new(Engine::Fast);
+        core.reg.cpsr = arm7tdmi::Status::from(0x6000001F);
+
+        assert_eq!(fast.step(&mut core, &mut bus), 3);
+        assert_eq!(core.reg.gp[15], 0x08);
+        assert_eq!(core.reg.cpsr.mode(), arm7tdmi::MODE_SVC);
+        assert_eq!(u32::from(core.reg.cpsr), 0x60000093);
+        assert_eq!(core.reg.read(14), 0x104);
+        assert_eq!(u32::from(core.reg.spsr_svc), 0x6000001F);
+
+        /* Back again */
+        fast.execute(&mut core, &mut bus, 0xE1B0F00E); //MOVS pc,lr
+        assert_eq!(core.reg.gp[15], 0x104);
+        assert_eq!(u32::from(core.reg.cpsr), 0x6000001F);
+
+        /* Undefined instructions go to 0x04 */
+        core.reg.gp[15] = 0x104;
+        fast.execute(&mut core, &mut bus, 0xE6000010);
+        assert_eq!(core.reg.gp[15], 0x04);
+        assert_eq!(core.reg.cpsr.mode(), arm7tdmi::MODE_UND);
+    }
+
+    /* TST, TEQ, CMP and CMN with R15 as Rd (the P forms from 26 bit ARMs) restore the CPSR from the SPSR, where there is one */
+    #[test]
+    fn test_with_pc() {
+        let program = [
+            0xE150F001, //CMP pc,r0,r1 (CMPP)
+            0xE330F000, //TEQ pc,r0,#0 (TEQP)
+        ];
+        for engine in [Engine::Fast, Engine::Stepped] {
+            let (mut core, mut bus) = setup(&program);
+            let interpreter: Interpreter<memory::FlatMemory> = Interpreter::new(engine);
+            core.reg.cpsr = arm7tdmi::Status::from(arm7tdmi::MODE_IRQ as u32);
+            core.reg.spsr_irq = arm7tdmi::Status::from(0x8000001F);
+
+            interpreter.step(&mut core, &mut bus);
+            assert_eq!(u32::from(core.reg.cpsr), 0x8000001F, "{:?}", engine);
+            assert_eq!(core.reg.gp[15], 0x104, "{:?}", engine);
+            /* System mode has no SPSR, so only the flags are set */
+            interpreter.step(&mut core, &mut bus);
+            assert_eq!(u32::from(core.reg.cpsr), 0x0000001F, "{:?}", engine);
+            assert_eq!(core.reg.gp[15], 0x108, "{:?}", engine);
+        }
+    }
+
+    #[test]
+    fn condition_failed() {
+        let (mut core, mut bus) = setup(&[
+            0x03A00001, //MOVEQ r0,#1
+        ]);
+        let fast: Interpreter<memory::FlatMemory> = Interpreter::new(Engine::Fast);
+
+        assert_eq!(fast.step(&mut core, &mut bus), 1);
+        assert_eq!(core.reg.gp[0], 0x800);
+        assert_eq!(core.reg.gp[15], 0x104);
+    }
+
+    /* Misaligned words are rotated, halfwords too, signed halfwords only load the byte and stores are forced into line, on both engines */
+    #[test]
+    fn misaligned_loads() {
+        let program = [
+            0xE5901000, //LDR r1,[r0]
+            0xE1D020B0, //LDRH r2,[r0]
+            0xE1D030F0, //LDRSH r3,[r0]
+            0xE1D040FD, //LDRSH r4,[r0,#0xD]
+            0xE1D050DD, //LDRSB r5,[r0,#0xD]
+            0xE1D060FE, //LDRSH r6,[r0,#0xE]
+            0xE5807004, //STR r7,[r0,#4]
+            0xE1C070BA, //STRH r7,[r0,#0xA]
+        ];
+        for engine in [Engine::Fast, Engine::Stepped] {
+            let (mut core, mut bus) = setup(&program);
+            let interpreter: Interpreter<memory::FlatMemory> = Interpreter::new(engine);
+            bus.mem_write_32(0x800, 0x11223344);
+            bus.mem_write_32(0x80C, 0x80FF0000);
+            core.reg.gp[0] = 0x801;
+
+            while core.reg.gp[15] != 0x120 {
+                interpreter.step(&mut core, &mut bus);
+            }
+            assert_eq!(core.reg.gp[1..7], [0x44112233, 0x44000033, 0x00000033, 0xFFFF80FF, 0xFFFFFFFF, 0xFFFFFF80], "{:?}", engine);
+            assert_eq!(bus.mem_read_32(0x804), 0x12345678, "{:?}", engine);
+            assert_eq!(bus.mem_read_16(0x80A), 0x5678, "{:?}", engine);
+        }
+    }
+
+    /* Thumb runs as the ARM instructions it's the same as, with BL done a half at a time, on both engines */
+    #[test]
+    fn thumb() {
+        let program = asm::assemble("
+                    .thumb
+            start:  ldr r0,=0x12345678
+                    add r1,=data
+            here:   mov r2,pc
+                    movs r4,7
+                    push {r4}
+                    movs r4,0
+                    sub sp,8
+                    add r7,sp,4
+                    add sp,8
+                    bl func
+            back:   pop {r4}
+                    ldr r5,=arm
+                    bx r5
+            func:   mov r3,lr
+                    bx lr
+                    .align 2
+            data:   .word 0xCAFEBABE
+                    .pool
+                    .arm
+            arm:    mov r6,1
+            done:   b done
+        ", 0x100).unwrap();
+
+        let mut cycles = Vec::new();
+        for engine in [Engine::Fast, Engine::Stepped] {
+            let mut core = arm7tdmi::Core::new();
+            let mut bus = memory::FlatMemory::new(0x1000);
+            for (i, byte) in program.bytes.iter().enumerate() {
+                bus.mem_write(0x100 + i, *byte);
+            }
+            core.reg.cpsr = arm7tdmi::Status::from(arm7tdmi::MODE_SYS as u32 | 0x20);
+            core.reg.gp[13] = 0x900;
+            core.reg.gp[15] = 0x100;
+
+            let interpreter: Interpreter<memory::FlatMemory> = Interpreter::new(engine);
+            let mut total = 0;
+            while core.reg.gp[15] != program.symbols["done"] {
+                total += interpreter.step(&mut core, &mut bus);
+            }
+            assert_eq!(core.reg.gp[0], 0x12345678);
+            assert_eq!(core.reg.gp[1], program.symbols["data"]);
+            assert_eq!(core.reg.gp[2], program.symbols["here"] + 4);
+            assert_eq!(core.reg.gp[3], program.symbols["back"] | 1);
+            assert_eq!(core.reg.gp[4], 7);
+            assert_eq!(core.reg.gp[6], 1);
+            assert_eq!(core.reg.gp[7], 0x8F8);
+            assert_eq!(core.reg.gp[13], 0x900);
+            assert!(!core.reg.cpsr.state);
+            cycles.push(total);
+        }
+        /* The stepped core has to fill its pipeline first */
+        assert_eq!(cycles[0] + 2, cycles[1]);
+    }
+
+    /* The Thumb instructions whose ARM counterparts don't quite do the same thing, and branches in halfwords */
+    #[test]
+    fn thumb_shifts_and_branches() {
+        let program = asm::assemble("
+                    .thumb
+            start:  movs r0,1
+                    movs r1,3
+                    lsls r0,r1
+                    movs r2,0x80
+                    lsrs r2,r1
+                    movs r3,1
+                    rors r3,r3
+                    asrs r3,r1
+                    movs r4,0x10
+            loop:   subs r4,1
+                    bne loop
+                    ldr r5,=0x800
+                    strh r0,[r5,r4]
+                    str r2,[r5,4]
+                    ldr r6,[r5,4]
+                    str r3,[sp,8]
+                    ldr r7,[sp,8]
+                    cmp r6,0x10
+                    beq skip
+                    movs r6,0
+            skip:   b done
+                    movs r7,0
+            done:   b done
+                    .pool
+        ", 0x100).unwrap();
+
+        let mut cycles = Vec::new();
+        for engine in [Engine::Fast, Engine::Stepped] {
+            let mut core = arm7tdmi::Core::new();
+            let mut bus = memory::FlatMemory::new(0x1000);
+            for (i, byte) in program.bytes.iter().enumerate() {
+                bus.mem_write(0x100 + i, *byte);
+            }
+            core.reg.cpsr = arm7tdmi::Status::from(arm7tdmi::MODE_SYS as u32 | 0x20);
+            core.reg.gp[13] = 0x900;
+            core.reg.gp[15] = 0x100;
+
+            let interpreter: Interpreter<memory::FlatMemory> = Interpreter::new(engine);
+            let mut total = 0;
+            for _ in 0..100 {
+                if core.reg.gp[15] == program.symbols["done"] { break; }
+                total += interpreter.step(&mut core, &mut bus);
+            }
+            assert_eq!(core.reg.gp[15], program.symbols["done"], "{:?}", engine);
+            assert_eq!(core.reg.gp[0], 8);
+            assert_eq!(core.reg.gp[2], 0x10);
+            assert_eq!(core.reg.gp[3], 0xF0000000);
+            assert_eq!(core.reg.gp[4], 0);
+            assert_eq!(core.reg.gp[6], 0x10);
+            assert_eq!(core.reg.gp[7], 0xF0000000);
+            assert_eq!(bus.load(0x800, memory::Width::Word) & 0xFFFF, 8);
+            assert_eq!(bus.load(0x908, memory::Width::Word), 0xF0000000);
+            cycles.push(total);
+        }
+        assert_eq!(cycles[0] + 2, cycles[1]);
+    }
+}
@@ -0,0 +1,83 @@
+#[path = "../src/decode.rs"]
+mod decode;
+#[path = "../src/asm.rs"]
+mod asm;
+#[path = "../src/listing.rs"]
+mod listing;
+
+/*
+Listings of whole images, which parts are Thumb and which are ARM has to be worked out from the code
+*/
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use decode::DisasmFormat;
+
+    #[test]
+    fn mixed_program() {
+        let program = asm::assemble("
+            start:  add r0,=entry+1
+                    bx r0
+                    .word 0xE7FFDEFE
+                    .thumb
+            entry:  ldr r1,=0x4000000
+                    bl func
+            stop:   b stop
+            func:   mov r2,0x0
+                    bx lr
+                    .pool
+        ", 0x08000000).unwrap();
+
+        /* The word after the BX is never run, so it stays ARM like anything else nothing reaches */
+        let text = listing::listing(&program.bytes, 0x08000000, &listing::entry_points(0x08000000), &DisasmFormat::default());
+        assert_eq!(text.lines().collect::<Vec<_>>(), vec![
+            "08000000  E28F0005  add       r0,=0x800000D",
+            "08000004  E12FFF10  bx        r0",
+            "08000008  E7FFDEFE  Undefined",
+            "0800000C  4902      ldr       r1,[0x8000018] ;=0x4000000",
+            "0800000E  F801F000  bl        0x8000014",
+            "08000012  E7FE      b         0x8000012",
+            "08000014  2200      movs      r2,0x0",
+            "08000016  4770      bx        lr",
+            "08000018  04000000  streq     r0,[r0],-0x0",
+        ]);
+
+        /* Without the entry point there's nothing to say any of it is Thumb */
+        let text = listing::listing(&program.bytes, 0x08000000, &[], &DisasmFormat::default());
+        assert!(text.lines().all(|line| line.as_bytes()[14] != b' '), "{}", text);
+        assert!(text.contains("0800000C  F0004902  "));
+    }
+
+    #[test]
+    fn bios() {
+        let image = std::fs::read("gba_bios.gba").unwrap();
+        let states = listing::code_states(&image, 0, &listing::entry_points(0));
+
+        /* The reset code goes to Thumb with add r0,=0x11D then bx r0 */
+        assert_eq!(states.get(&0x118), Some(&false));
+        assert_eq!(states.get(&0x11C), Some(&true));
+        assert_eq!(states.get(&0x126), Some(&true));
+        assert_eq!(states.get(&0x128), Some(&false));
+
+        let text = listing::listing(&image, 0, &listing::entry_points(0), &DisasmFormat::default());
+        let lines: Vec<&str> = text.lines().collect();
+        let at = |addr: u32| *lines.iter().find(|line| line.starts_with(&format!("{:08X}", addr))).unwrap();
+        assert_eq!(at(0x000), "00000000  EA000018  b         0x68");
+        assert_eq!(at(0x114), "00000114  E28F0001  add       r0,=0x11D");
+        assert_eq!(at(0x11C), "0000011C  2000      movs      r0,0x0");
+        assert_eq!(at(0x11E), "0000011E  4958      ldr       r1,[0x280] ;=0xFFFFFE00");
+        assert_eq!(at(0x126), "00000126  4770      bx        lr");
+        assert_eq!(at(0x128), "00000128  E92D500F  stmfd     sp!,{r0,r1,r2,r3,r12,lr}");
+
+        /* Every ARM line is what the disassembler gives for that word */
+        for line in lines.iter().filter(|line| line.as_bytes()[14] != b' ') {
+            let addr = u32::from_str_radix(&line[0..8], 16).unwrap();
+            let inst = u32::from_str_radix(&line[10..18], 16).unwrap();
+            if states.get(&addr) != Some(&true) {
+                assert_eq!(*line, format!("{:08X}  {:08X}  {}", addr, inst, decode::disassemble_arm(inst, addr)));
+            }
+        }
+        assert_eq!(lines.len(), 5093);
+    }
+}
@@ -0,0 +1,108 @@
+#[path = "../src/arm7tdmi.rs"]
+mod arm7tdmi;
+#[path = "../src/decode.rs"]
+mod decode;
+#[path = "../src/exec.rs"]
+mod exec;
+#[path = "../src/bios.rs"]
+mod bios;
+#[path = "../src/memory.rs"]
+mod memory;
+#[path = "../src/interp.rs"]
+mod interp;
+#[path = "../src/recomp.rs"]
+mod recomp;
+#[path = "../src/bus.rs"]
+mod bus;
+#[path = "../src/cache.rs"]
+mod cache;
+#[path = "../src/trace.rs"]
+mod trace;
+#[path = "../src/lockstep.rs"]
+mod lockstep;
+
+/*
+Running the core in lockstep with traces in tests/traces, in the format mGBA and NanoBoyAdvance log in.
+
+bios_boot_snapshot.log is a regression snapshot, not a reference: it was recorded from this emulator with --trace mgba on the block
+engine, so all it shows is that every engine still does what that one did then. It is the BIOS from reset, through the Thumb loop
+that clears the top of IWRAM (through its mirror at 0x03FFFE00) and into the routine that sets up the I/O registers, stopping before
+it writes to registers the bus doesn't have. Only the stack pointers of each mode and the switch to Thumb were checked by hand against
+what the BIOS is known to leave behind. A log recorded with mGBA or NanoBoyAdvance would be a real reference, and can go next to it.
+*/
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use interp::{ Engine, Interpreter };
+    use recomp::Recompiler;
+    use lockstep::{ lockstep, LockstepError };
+
+    fn boot() -> (arm7tdmi::Core, bus::Bus) {
+        let mut core = arm7tdmi::Core::new();
+        let mut bus = bus::Bus::new();
+        bus.load_mem().unwrap();
+        core.reset();
+        (core, bus)
+    }
+
+    fn snapshot() -> String {
+        std::fs::read_to_string("tests/traces/bios_boot_snapshot.log").unwrap()
+    }
+
+    #[test]
+    fn bios_boot() {
+        /* The block engine one instruction at a time, and the other two */
+        let (mut core, mut bus) = boot();
+        let mut recompiler: Recompiler<bus::Bus> = Recompiler::with_max_block(1);
+        let matched = lockstep(&mut core, &mut bus, &snapshot(), |core, bus| {recompiler.step(core, bus);});
+        assert_eq!(matched.map_err(|divergence| divergence.to_string()), Ok(456));
+        assert_eq!(core.reg.gp[15], 0xAB4);
+
+        for engine in [Engine::Fast, Engine::Stepped] {
+            let (mut core, mut bus) = boot();
+            let interpreter: Interpreter<bus::Bus> = Interpreter::new(engine);
+            let matched = lockstep(&mut core, &mut bus, &snapshot(), |core, bus| {interpreter.step(core, bus);});
+            assert_eq!(matched.map_err(|divergence| divergence.to_string()), Ok(456), "{:?}", engine);
+            assert_eq!(core.reg.gp[15], 0xAB4);
+        }
+    }
+
+    /* Change the reference so it no longer matches and check what the report says */
+    #[test]
+    fn divergence() {
+        let reference = snapshot();
+        let mut lines: Vec<String> = reference.lines().map(str::to_string).collect();
+        lines[19] = lines[19].replacen("03007FE0", "03007FE4", 1);
+
+        let (mut core, mut bus) = boot();
+        let mut recompiler: Recompiler<bus::Bus> = Recompiler::with_max_block(1);
+        let error = lockstep(&mut core, &mut bus, &lines.join("\n"), |core, bus| {recompiler.step(core, bus);}).unwrap_err();
+        let LockstepError::Diverged(divergence) = error else {panic!("{}", error)};
+        assert_eq!(divergence.line, 20);
+        assert_eq!(divergence.previous.as_ref().map(|previous| previous.pc()), Some(0xEC));
+        assert_eq!(divergence.to_string(), "Diverged at line 20 of the reference, after 0x000000EC: mov lr,#0x0\n  r13   expected 03007FE4, got 03007FE0\n");
+
+        /* Flags are spelled out, and a different instruction shows both */
+        let mut lines: Vec<String> = reference.lines().map(str::to_string).collect();
+        lines[2] = lines[2].replace("cpsr: 600000D3 | 03A0E004: moveq lr,#0x4", "cpsr: 200000D3 | 13A0E004: movne lr,#0x4");
+        let (mut core, mut bus) = boot();
+        let divergence = lockstep(&mut core, &mut bus, &lines.join("\n"), |core, bus| {recompiler.step(core, bus);}).unwrap_err();
+        assert_eq!(divergence.to_string(), "Diverged at line 3 of the reference, after 0x00000068: cmp lr,#0x0\n  \
+            cpsr  expected 200000D3 (nzCv IFt mode 0x13), got 600000D3 (nZCv IFt mode 0x13)\n  \
+            next  expected 13A0E004: movne lr,#0x4\n        \
+            got      03A0E004: moveq lr,#0x4\n");
+
+        /* Blank lines and comments don't count */
+        let (mut core, mut bus) = boot();
+        let commented = format!("# from reset\n\n{}", reference.lines().take(3).collect::<Vec<_>>().join("\n"));
+        assert_eq!(lockstep(&mut core, &mut bus, &commented, |core, bus| {recompiler.step(core, bus);}).ok(), Some(3));
+
+        /* A line that can't be read is reported, not a panic */
+        let (mut core, mut bus) = boot();
+        let broken = format!("{}\nr0 r1 r2\n", reference.lines().take(2).collect::<Vec<_>>().join("\n"));
+        let error = lockstep(&mut core, &mut bus, &broken, |core, bus| {recompiler.step(core, bus);}).unwrap_err();
+        assert!(matches!(error, LockstepError::Unreadable { line: 3, .. }), "{}", error);
+        assert_eq!(error.to_string(), "Line 3 of the reference isn't an mGBA trace line: r0 r1 r2\n");
+    }
+}
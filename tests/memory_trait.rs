@@ -0,0 +1,106 @@
+#[path = "../src/arm7tdmi.rs"]
+mod arm7tdmi;
+#[path = "../src/decode.rs"]
+mod decode;
+#[path = "../src/exec.rs"]
+mod exec;
+#[path = "../src/bios.rs"]
+mod bios;
+#[path = "../src/memory.rs"]
+mod memory;
+#[path = "../src/bus.rs"]
+mod bus;
+#[path = "../src/cache.rs"]
+mod cache;
+
+use memory::{ Memory, Cycle, Width };
+
+/*
+The processor is generic over the memory it's connected to, these make sure that it runs against anything implementing the trait
+*/
+
+/* Wraps another memory and keeps a record of every timed access */
+struct Tracer<M: Memory> {
+    inner: M,
+    log: Vec<(char, usize, u32)>,
+}
+
+impl<M: Memory> Memory for Tracer<M> {
+    fn mem_read(&mut self, addr: usize) -> u8 {
+        self.inner.mem_read(addr)
+    }
+
+    fn mem_write(&mut self, addr: usize, data: u8) {
+        self.inner.mem_write(addr, data)
+    }
+
+    fn read(&mut self, addr: usize, width: Width, cycle: Cycle) -> (u32, u32) {
+        let (data, cycles) = self.inner.read(addr, width, cycle);
+        self.log.push(('r', addr, data));
+        (data, cycles)
+    }
+
+    fn write(&mut self, addr: usize, width: Width, cycle: Cycle, data: u32) -> u32 {
+        self.log.push(('w', addr, data));
+        self.inner.write(addr, width, cycle, data)
+    }
+}
+
+pub fn test_inst<M: Memory>(core: &mut arm7tdmi::Core, bus: &mut M, inst: u32) -> u32 {
+    core.cycle = 0;
+    core.waitstates = 0;
+
+    let mut state = None;
+
+    while state.is_none() {
+        state = exec::step_arm(core, bus, inst);
+        core.cycle += 1;
+    }
+
+    core.cycle as u32 + core.waitstates
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn flat_memory() {
+        let mut mem = memory::FlatMemory::new(0x10);
+
+        mem.mem_write_32(0, 0x12345678);
+        assert_eq!(mem.mem, [0x78, 0x56, 0x34, 0x12, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(mem.mem_read_16(2), 0x1234);
+
+        mem.store(4, Width::Half, 0xABCD);
+        assert_eq!(mem.load(4, Width::Word), 0xABCD);
+        assert_eq!(mem.read(5, Width::Byte, Cycle::N), (0xAB, 1));
+    }
+
+    #[test]
+    fn traced_store() {
+        let mut core = arm7tdmi::Core::new();
+        let mut mem = Tracer { inner: memory::FlatMemory::new(0x100), log: Vec::new() };
+
+        core.reg.gp[1] = 0x40;
+        core.reg.gp[2] = 0xDEADBEEF;
+
+        test_inst(&mut core, &mut mem, 0xE5812000); //STR r2,[r1]
+
+        /* The prefetch from R15 comes first */
+        assert_eq!(mem.log, [('r', 0, 0), ('w', 0x40, 0xDEADBEEF)]);
+        assert_eq!(mem.inner.mem_read_32(0x40), 0xDEADBEEF);
+    }
+
+    #[test]
+    fn bus_wait_states() {
+        let mut core = arm7tdmi::Core::new();
+        let mut bus = bus::Bus::new();
+
+        /* STR to IWRAM then EWRAM, the latter has 2 wait states on each half of the word */
+        core.reg.gp[1] = 0x03000000;
+        core.reg.gp[2] = 0x02000000;
+        assert_eq!(test_inst(&mut core, &mut bus, 0xE5813000), 2);
+        assert_eq!(test_inst(&mut core, &mut bus, 0xE5823000), 7);
+    }
+}
@@ -0,0 +1,170 @@
+#[path = "../src/arm7tdmi.rs"]
+mod arm7tdmi;
+#[path = "../src/decode.rs"]
+mod decode;
+#[path = "../src/exec.rs"]
+mod exec;
+#[path = "../src/bios.rs"]
+mod bios;
+#[path = "../src/memory.rs"]
+mod memory;
+
+/*
+The stepped core's three stage pipeline, which is where R15 reads come from and what runs after memory is written
+*/
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use memory::Memory;
+
+    /* Load a program at 0x100, the first run_arm has to fill the pipeline which costs 2 extra cycles */
+    fn setup(program: &[u32]) -> (arm7tdmi::Core, memory::FlatMemory) {
+        let mut core = arm7tdmi::Core::new();
+        let mut bus = memory::FlatMemory::new(0x1000);
+
+        for (i, inst) in program.iter().enumerate() {
+            bus.mem_write_32(0x100 + 4*i, *inst);
+        }
+        core.reg.gp[15] = 0x100;
+        (core, bus)
+    }
+
+    #[test]
+    fn pc_relative_reads() {
+        let (mut core, mut bus) = setup(&[
+            0xE28F0000, //0x100 ADD r0,pc,#0
+            0xE082131F, //0x104 ADD r1,r2,pc,LSL r3
+            0xE584F000, //0x108 STR pc,[r4]
+        ]);
+        core.reg.gp[4] = 0x800;
+
+        assert_eq!(exec::run_arm(&mut core, &mut bus), 3);
+        assert_eq!(core.reg.gp[0], 0x108);
+        assert_eq!(core.reg.gp[15], 0x104);
+
+        /* By the time a register shift has read Rs the prefetch has happened, and the same goes for stores */
+        assert_eq!(exec::run_arm(&mut core, &mut bus), 2);
+        assert_eq!(core.reg.gp[1], 0x110);
+        exec::run_arm(&mut core, &mut bus);
+        assert_eq!(bus.mem_read_32(0x800), 0x114);
+    }
+
+    #[test]
+    fn branch_flushes() {
+        let (mut core, mut bus) = setup(&[
+            0xEA00003E, //0x100 B 0x200
+        ]);
+        bus.mem_write_32(0x200, 0xE3A00001); //MOV r0,#1
+        bus.mem_write_32(0x204, 0xE3A01001); //MOV r1,#1
+
+        assert_eq!(exec::run_arm(&mut core, &mut bus), 5);
+        assert_eq!(core.reg.gp[15], 0x200);
+        assert_eq!(core.reg.prefetched, Some(0x200));
+        assert_eq!(core.reg.pipeline[..2], [0xE3A01001, 0xE3A00001]);
+
+        assert_eq!(exec::run_arm(&mut core, &mut bus), 1);
+        assert_eq!(core.reg.gp[0], 1);
+
+        /* Moving R15 from outside throws the pipeline away too */
+        core.reg.gp[15] = 0x200;
+        assert_eq!(exec::run_arm(&mut core, &mut bus), 3);
+        assert_eq!(core.reg.gp[15], 0x204);
+    }
+
+    #[test]
+    fn self_modifying_code() {
+        let (mut core, mut bus) = setup(&[
+            0xE5801000, //0x100 STR r1,[r0]
+            0xE3A02001, //0x104 MOV r2,#1
+        ]);
+        core.reg.gp[0] = 0x104;
+        core.reg.gp[1] = 0xE3A02007; //MOV r2,#7
+
+        /* The instruction after the store has already been fetched, so the old one runs */
+        exec::run_arm(&mut core, &mut bus);
+        exec::run_arm(&mut core, &mut bus);
+        assert_eq!(core.reg.gp[2], 1);
+
+        /* Running it again fetches the new one */
+        core.reg.gp[15] = 0x104;
+        exec::run_arm(&mut core, &mut bus);
+        assert_eq!(core.reg.gp[2], 7);
+    }
+
+    #[test]
+    fn thumb_refill() {
+        let (mut core, mut bus) = setup(&[
+            0xE12FFF10, //0x100 BX r0
+        ]);
+        bus.mem_write_16(0x200, 0x2005); //MOVS r0,#5
+        bus.mem_write_16(0x202, 0x1CC1); //ADDS r1,r0,#3
+        core.reg.gp[0] = 0x201;
+
+        /* The pipeline is refilled with halfwords, R15 ends up 4 ahead */
+        assert_eq!(exec::run_arm(&mut core, &mut bus), 5);
+        assert!(core.reg.cpsr.state);
+        assert_eq!(core.reg.gp[15], 0x200);
+        assert_eq!(core.reg.pipeline[..2], [0x1CC1, 0x2005]);
+    }
+
+    #[test]
+    fn software_interrupt() {
+        let (mut core, mut bus) = setup(&[
+            0xEF000000, //0x100 SWI 0
+        ]);
+
+        assert_eq!(exec::run_arm(&mut core, &mut bus), 5);
+        assert_eq!(core.reg.cpsr.mode(), arm7tdmi::MODE_SVC);
+        assert_eq!(core.reg.read(14), 0x104);
+        assert_eq!(core.reg.gp[15], 0x08);
+    }
+
+    /* Thumb runs through the pipeline too, with the instructions that have no ARM equivalent done on their own */
+    #[test]
+    fn thumb() {
+        let (mut core, mut bus) = setup(&[
+            0xE12FFF10, //0x100 BX r0
+        ]);
+        let program: [u16; 16] = [
+            0x2005, //0x200 MOVS r0,#5
+            0x1CC1, //0x202 ADDS r1,r0,#3
+            0x4A06, //0x204 LDR r2,[pc,#0x18]
+            0x4B06, //0x206 LDR r3,[pc,#0x18]
+            0xA401, //0x208 ADD r4,pc,#4
+            0xF000, //0x20A BL 0x214
+            0xF803, //0x20C
+            0x2805, //0x20E CMP r0,#5
+            0xD1FE, //0x210 BNE 0x210
+            0xE001, //0x212 B 0x218
+            0x2501, //0x214 MOVS r5,#1
+            0x46F7, //0x216 MOV pc,lr
+            0xDF05, //0x218 SWI 5
+            0x0000, 0x0000, 0x0000,
+        ];
+        for (i, inst) in program.iter().enumerate() {
+            bus.mem_write_16(0x200 + 2*i, *inst);
+        }
+        bus.mem_write_32(0x220, 0xCAFEBABE);
+        core.reg.gp[0] = 0x201;
+        exec::run_arm(&mut core, &mut bus);
+
+        /* Each instruction takes what it would in ARM state, and R15 reads as 4 ahead */
+        let steps: [(u32, u32); 14] = [
+            (1, 0x202), (1, 0x204), (3, 0x206), (3, 0x208), (1, 0x20A), (1, 0x20C), (3, 0x214),
+            (1, 0x216), (3, 0x20E), (1, 0x210), (1, 0x212), (3, 0x218), (3, 0x08), (1, 0x0C),
+        ];
+        for (i, (cycles, next)) in steps.iter().enumerate() {
+            assert_eq!((exec::run_arm(&mut core, &mut bus), core.reg.gp[15]), (*cycles, *next), "step {}", i);
+            if i == 8 {
+                assert_eq!(core.reg.gp[..6], [5, 8, 0xCAFEBABE, 0xCAFEBABE, 0x210, 1]);
+                assert_eq!(core.reg.read(14), 0x20F);
+            }
+        }
+
+        /* SWI returns to the instruction after it, and goes back to ARM state */
+        assert!(!core.reg.cpsr.state);
+        assert_eq!(core.reg.cpsr.mode(), arm7tdmi::MODE_SVC);
+        assert_eq!(core.reg.read(14), 0x21A);
+    }
+}
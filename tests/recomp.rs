@@ -0,0 +1,333 @@
+#[path = "../src/arm7tdmi.rs"]
+mod arm7tdmi;
+#[path = "../src/decode.rs"]
+mod decode;
+#[path = "../src/exec.rs"]
+mod exec;
+#[path = "../src/bios.rs"]
+mod bios;
+#[path = "../src/memory.rs"]
+mod memory;
+#[path = "../src/interp.rs"]
+mod interp;
+#[path = "../src/recomp.rs"]
+mod recomp;
+#[path = "../src/bus.rs"]
+mod bus;
+#[path = "../src/cache.rs"]
+mod cache;
+
+/*
+Tests for the block recompiler, which has to end up in the same place as the fast interpreter
+*/
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use memory::Memory;
+    use interp::{ Engine, Interpreter };
+    use recomp::{ Exit, Op, Operand, Recompiler };
+
+    /* A loop with a subroutine call and a bit of everything, ending up at 0x158 */
+    const PROGRAM: [u32; 21] = [
+        0xE3A0400A, //0x100 MOV r4,#10
+        0xE3A05000, //0x104 MOV r5,#0
+        0xE092B002, //0x108 ADDS r11,r2,r2
+        0xE0ACC639, //0x10C ADC r12,r12,r9,LSR r6
+        0xE0855004, //0x110 ADD r5,r5,r4
+        0xE0050594, //0x114 MUL r5,r4,r5
+        0xE4803004, //0x118 STR r3,[r0],#4
+        0xE5906000, //0x11C LDR r6,[r0]
+        0xE2544001, //0x120 SUBS r4,r4,#1
+        0x11A07006, //0x124 MOVNE r7,r6
+        0xE1570005, //0x128 CMP r7,r5
+        0xE3540000, //0x12C CMP r4,#0
+        0x1AFFFFF6, //0x130 BNE 0x110
+        0xEB000003, //0x134 BL 0x148
+        0xE92D00F0, //0x138 STMDB r13!,{r4-r7}
+        0xE8BD0300, //0x13C LDMIA r13!,{r8,r9}
+        0xE1B0A089, //0x140 MOVS r10,r9,LSL #1
+        0xEA000003, //0x144 B 0x158
+        0xE28F1004, //0x148 ADD r1,pc,#4
+        0xE5912000, //0x14C LDR r2,[r1]
+        0xE1A0F00E, //0x150 MOV pc,lr
+    ];
+
+    fn setup(program: &[u32]) -> (arm7tdmi::Core, memory::FlatMemory) {
+        let mut core = arm7tdmi::Core::new();
+        let mut bus = memory::FlatMemory::new(0x1000);
+        core.reg.cpsr = arm7tdmi::Status::from(arm7tdmi::MODE_SYS as u32); //so the registers set here are the ones in use
+
+        for (i, inst) in program.iter().enumerate() {
+            bus.mem_write_32(0x100 + 4*i, *inst);
+        }
+        bus.mem_write_32(0x154, 0x12345678);
+        for i in 0..0x40 {
+            bus.mem_write_32(0x800 + 4*i, 0x9E3779B9u32.wrapping_mul(i as u32 + 1));
+        }
+
+        core.reg.gp = [0x800, 0x810, 0x80000001, 3, 0xFFFFFFFF, 0x7FFFFFFF, 12, 0x12345678, 0x20, 0x847, 0xF0, 0, 0, 0x900, 0, 0x100];
+        (core, bus)
+    }
+
+    #[test]
+    fn blocks_agree() {
+        let fast: Interpreter<memory::FlatMemory> = Interpreter::new(Engine::Fast);
+        let mut recompiler: Recompiler<memory::FlatMemory> = Recompiler::new();
+        let (mut core_fast, mut bus_fast) = setup(&PROGRAM);
+        let (mut core_block, mut bus_block) = setup(&PROGRAM);
+
+        let (mut cycles_fast, mut cycles_block) = (0, 0);
+        for _ in 0..1000 {
+            if core_fast.reg.gp[15] == 0x158 { break; }
+            cycles_fast += fast.step(&mut core_fast, &mut bus_fast);
+        }
+        for _ in 0..1000 {
+            if core_block.reg.gp[15] == 0x158 { break; }
+            cycles_block += recompiler.step(&mut core_block, &mut bus_block);
+        }
+
+        assert_eq!(core_block.reg.gp[15], 0x158);
+        assert_eq!(core_fast.reg.gp, core_block.reg.gp);
+        assert_eq!(u32::from(core_fast.reg.cpsr), u32::from(core_block.reg.cpsr));
+        assert_eq!(bus_fast.mem, bus_block.mem);
+        assert_eq!(cycles_fast, cycles_block);
+    }
+
+    #[test]
+    fn constant_folding() {
+        let (_, mut bus) = setup(&PROGRAM);
+        let block = Recompiler::compile(&mut bus, 0x148, false);
+
+        /* R15 is a constant, and so is everything worked out from it */
+        assert_eq!(block.insts[0].op, Op::Set { rd: 1, value: 0x154 });
+        assert!(matches!(block.insts[1].op, Op::Load { base: Operand::Imm(0x154), .. }));
+        /* MOV pc,lr isn't part of the block */
+        assert_eq!(block.insts.len(), 2);
+        assert_eq!(block.exit, Exit::Next(0x150));
+
+        let block = Recompiler::compile(&mut bus, 0x100, false);
+        assert_eq!(block.insts[0].op, Op::Set { rd: 4, value: 10 });
+        assert_eq!(block.insts[1].op, Op::Set { rd: 5, value: 0 });
+    }
+
+    #[test]
+    fn flag_liveness() {
+        let (_, mut bus) = setup(&PROGRAM);
+        let block = Recompiler::compile(&mut bus, 0x110, false);
+        let op = |pc: u32| block.insts.iter().find(|inst| inst.pc == pc).unwrap().op;
+
+        /* SUBS is read by MOVNE, the first CMP is overwritten by the second, which is read by BNE */
+        assert!(matches!(op(0x120), Op::Alu { flags: true, .. }));
+        assert_eq!(op(0x128), Op::Nop);
+        assert!(matches!(op(0x12C), Op::Alu { flags: true, .. }));
+        assert_eq!(block.exit, Exit::Branch { cond: 0x1, target: 0x110, link: None, next: 0x134 });
+
+        /* ADDS is overwritten by MUL, which could read or write anything as it goes through the interpreter */
+        let block = Recompiler::compile(&mut bus, 0x108, false);
+        assert!(matches!(block.insts[0].op, Op::Alu { flags: true, .. }));
+        assert_eq!(block.insts[3].op, Op::Interpret(0xE0050594));
+
+        /* An RRX offset reads the carry, so ADDS has to set it even though CMP overwrites it after */
+        let (mut core, mut bus) = setup(&[
+            0xE2900001, //ADDS r0,r0,#1
+            0xE7913062, //LDR r3,[r1,r2,RRX]
+            0xE7813062, //STR r3,[r1,r2,RRX]
+            0xE1500000, //CMP r0,r0
+            0xEAFFFFFE, //B .
+        ]);
+        let block = Recompiler::compile(&mut bus, 0x100, false);
+        assert!(matches!(block.insts[0].op, Op::Alu { flags: true, .. }));
+
+        let mut recompiler: Recompiler<memory::FlatMemory> = Recompiler::new();
+        core.reg.gp[2] = 0x10;
+        core.reg.cpsr.c = true;
+        recompiler.step(&mut core, &mut bus);
+        assert_eq!(core.reg.gp[3], bus.mem_read_32(0x818));
+    }
+
+    #[test]
+    fn self_modifying_code() {
+        let mut core = arm7tdmi::Core::new();
+        let mut bus = bus::Bus::new();
+        let mut recompiler: Recompiler<bus::Bus> = Recompiler::new();
+
+        bus.mem_write_32(0x03000000, 0xE5802000); //STR r2,[r0]
+        bus.mem_write_32(0x03000004, 0xE3A03001); //MOV r3,#1
+        bus.mem_write_32(0x03000008, 0xEAFFFFFE); //B .
+        core.reg.gp[0] = 0x03000004;
+        core.reg.gp[2] = 0xE3A03005; //MOV r3,#5
+        core.reg.gp[15] = 0x03000000;
+
+        /* The block stops after the store and what's left is decoded again */
+        recompiler.step(&mut core, &mut bus);
+        assert_eq!(core.reg.gp[15], 0x03000004);
+        recompiler.step(&mut core, &mut bus);
+        assert_eq!(core.reg.gp[3], 5);
+        assert_eq!(core.reg.gp[15], 0x03000008);
+
+        /* Writes from anywhere else throw the block away too */
+        bus.mem_write_32(0x03000004, 0xE3A03007); //MOV r3,#7
+        core.reg.gp[15] = 0x03000004;
+        recompiler.step(&mut core, &mut bus);
+        assert_eq!(core.reg.gp[3], 7);
+
+        /* Stores that go through the interpreter as well */
+        bus.mem_write_32(0x03000000, 0xE8800004); //STMIA r0,{r2}
+        bus.mem_write_32(0x03000004, 0xE3A03001); //MOV r3,#1
+        core.reg.gp[2] = 0xE3A03006; //MOV r3,#6
+        core.reg.gp[15] = 0x03000000;
+        recompiler.step(&mut core, &mut bus);
+        assert_eq!(core.reg.gp[15], 0x03000004);
+        recompiler.step(&mut core, &mut bus);
+        assert_eq!(core.reg.gp[3], 6);
+
+        bus.mem_write_32(0x03000000, 0xE1002092); //SWP r2,r2,[r0]
+        bus.mem_write_32(0x03000004, 0xE3A03001); //MOV r3,#1
+        core.reg.gp[15] = 0x03000000;
+        recompiler.step(&mut core, &mut bus);
+        assert_eq!(core.reg.gp[15], 0x03000004);
+        recompiler.step(&mut core, &mut bus);
+        assert_eq!(core.reg.gp[3], 6);
+
+        /* Flat memory keeps track of what's written to it the same way */
+        let (mut core, mut bus) = setup(&[0xE3A03001, 0xEAFFFFFE]); //MOV r3,#1; B .
+        let mut recompiler: Recompiler<memory::FlatMemory> = Recompiler::new();
+        recompiler.step(&mut core, &mut bus);
+        assert_eq!(core.reg.gp[3], 1);
+        bus.mem_write_32(0x100, 0xE3A03009); //MOV r3,#9
+        core.reg.gp[15] = 0x100;
+        recompiler.step(&mut core, &mut bus);
+        assert_eq!(core.reg.gp[3], 9);
+    }
+
+    /* Translating stops at the end of memory instead of reading past it */
+    #[test]
+    fn end_of_memory() {
+        let (_, mut bus) = setup(&[]);
+        bus.mem_write_32(0xFF8, 0xE3A00001); //MOV r0,#1
+        bus.mem_write_32(0xFFC, 0xE3A01002); //MOV r1,#2
+
+        let block = Recompiler::compile(&mut bus, 0xFF8, false);
+        assert_eq!(block.insts.len(), 2);
+        assert_eq!(block.exit, Exit::Next(0x1000));
+
+        /* Work RAM carries on into its mirror, but nothing comes after the BIOS */
+        let mut bus = bus::Bus::new();
+        bus.mem_write_32(0x3FFC, 0xE3A00001); //MOV r0,#1
+        let block: recomp::Block<bus::Bus> = Recompiler::compile(&mut bus, 0x3FFC, false);
+        assert_eq!(block.insts.len(), 1);
+        assert_eq!(block.exit, Exit::Next(0x4000));
+    }
+
+    /* TSTP and the others with R15 as Rd restore the CPSR, so they end the block and the interpreter runs them */
+    #[test]
+    fn test_with_pc() {
+        let (mut core, mut bus) = setup(&[
+            0xE3A0A001, //MOV r10,#1
+            0xE150F001, //CMP pc,r0,r1 (CMPP)
+        ]);
+        let block = Recompiler::compile(&mut bus, 0x100, false);
+        assert_eq!(block.insts.len(), 1);
+        assert_eq!(block.exit, Exit::Next(0x104));
+
+        let mut recompiler: Recompiler<memory::FlatMemory> = Recompiler::new();
+        core.reg.cpsr = arm7tdmi::Status::from(arm7tdmi::MODE_IRQ as u32);
+        core.reg.spsr_irq = arm7tdmi::Status::from(0x8000001F);
+        while core.reg.gp[15] != 0x108 {
+            recompiler.step(&mut core, &mut bus);
+        }
+        assert_eq!(u32::from(core.reg.cpsr), 0x8000001F);
+        assert_eq!(core.reg.gp[10], 1);
+    }
+
+    #[test]
+    fn thumb_block() {
+        let (mut core, mut bus) = setup(&[]);
+        let mut recompiler: Recompiler<memory::FlatMemory> = Recompiler::new();
+
+        bus.mem_write_16(0x100, 0x2005); //MOVS r0,#5
+        bus.mem_write_16(0x102, 0x1CC1); //ADDS r1,r0,#3
+        bus.mem_write_16(0x104, 0x4801); //LDR r0,[pc,#4]
+        bus.mem_write_16(0x106, 0xE7FE); //B .
+        bus.mem_write_32(0x10C, 0xCAFEBABE);
+        core.reg.cpsr.state = true;
+
+        assert_eq!(recompiler.step(&mut core, &mut bus), 8);
+        assert_eq!(core.reg.gp[1], 8);
+        assert_eq!(core.reg.gp[0], 0xCAFEBABE);
+        assert_eq!(core.reg.gp[15], 0x106);
+    }
+
+    /* Adding to SP and PC in Thumb scales the offset by 4, running them as ARM instructions wouldn't */
+    #[test]
+    fn thumb_sp_and_pc() {
+        let fast: Interpreter<memory::FlatMemory> = Interpreter::new(Engine::Fast);
+        let mut recompiler: Recompiler<memory::FlatMemory> = Recompiler::new();
+        let (mut core, mut bus) = setup(&[]);
+
+        bus.mem_write_16(0x100, 0xB082); //SUB sp,#8
+        bus.mem_write_16(0x102, 0xAF01); //ADD r7,sp,#4
+        bus.mem_write_16(0x104, 0xA101); //ADD r1,pc,#4
+        bus.mem_write_16(0x106, 0xB002); //ADD sp,#8
+        bus.mem_write_16(0x108, 0xE7FE); //B .
+        core.reg.cpsr.state = true;
+        let mut expected = core;
+
+        while core.reg.gp[15] != 0x108 {
+            recompiler.step(&mut core, &mut bus);
+        }
+        while expected.reg.gp[15] != 0x108 {
+            fast.step(&mut expected, &mut bus);
+        }
+        assert_eq!(core.reg.gp, expected.reg.gp);
+        assert_eq!(core.reg.gp[7], 0x900 - 4);
+        assert_eq!(core.reg.gp[1], 0x10C);
+        assert_eq!(core.reg.gp[13], 0x900);
+    }
+
+    /* Shifts by a register, STRH and offsets in words run as what they are in Thumb, not what translate_thumb gives, and adding to SP
+    and PC scales the offset */
+    #[test]
+    fn thumb_equivalents() {
+        let fast: Interpreter<memory::FlatMemory> = Interpreter::new(Engine::Fast);
+        let mut recompiler: Recompiler<memory::FlatMemory> = Recompiler::new();
+        let (mut core, mut bus) = setup(&[]);
+
+        bus.mem_write_16(0x100, 0x4088); //LSLS r0,r1
+        bus.mem_write_16(0x102, 0x41C3); //RORS r3,r0
+        bus.mem_write_16(0x104, 0x5328); //STRH r0,[r5,r4]
+        bus.mem_write_16(0x106, 0x686E); //LDR r6,[r5,#4]
+        bus.mem_write_16(0x108, 0x9302); //STR r3,[sp,#8]
+        bus.mem_write_16(0x10A, 0x9F02); //LDR r7,[sp,#8]
+        bus.mem_write_16(0x10C, 0xB082); //SUB sp,#8
+        bus.mem_write_16(0x10E, 0xAA01); //ADD r2,sp,#4
+        bus.mem_write_16(0x110, 0xB002); //ADD sp,#8
+        bus.mem_write_16(0x112, 0xA101); //ADD r1,pc,#4
+        bus.mem_write_16(0x114, 0xE7FE); //B .
+        core.reg.gp[1] = 4;
+        core.reg.gp[4] = 2;
+        core.reg.gp[5] = 0x800;
+        core.reg.cpsr.state = true;
+        let mut expected = core;
+        let mut expected_bus = memory::FlatMemory::new(0x1000);
+        expected_bus.mem = bus.mem.clone();
+
+        while core.reg.gp[15] != 0x114 {
+            recompiler.step(&mut core, &mut bus);
+        }
+        while expected.reg.gp[15] != 0x114 {
+            fast.step(&mut expected, &mut expected_bus);
+        }
+        assert_eq!(core.reg.gp, expected.reg.gp);
+        assert_eq!(bus.mem, expected_bus.mem);
+        assert_eq!(core.reg.gp[0], 0x8000);
+        assert_eq!(core.reg.gp[3], 0x3);
+        assert_eq!(bus.load(0x802, memory::Width::Half), 0x8000);
+        assert_eq!(core.reg.gp[6], bus.load(0x804, memory::Width::Word));
+        assert_eq!(core.reg.gp[7], 3);
+        assert_eq!(core.reg.gp[2], 0x8FC);
+        assert_eq!(core.reg.gp[13], 0x900);
+        assert_eq!(core.reg.gp[1], 0x118);
+    }
+}
@@ -0,0 +1,201 @@
+#[path = "../src/arm7tdmi.rs"]
+mod arm7tdmi;
+#[path = "../src/decode.rs"]
+mod decode;
+#[path = "../src/exec.rs"]
+mod exec;
+#[path = "../src/bios.rs"]
+mod bios;
+#[path = "../src/memory.rs"]
+mod memory;
+#[path = "../src/interp.rs"]
+mod interp;
+#[path = "../src/recomp.rs"]
+mod recomp;
+#[path = "../src/cache.rs"]
+mod cache;
+#[path = "../src/asm.rs"]
+mod asm;
+#[path = "../src/debugger.rs"]
+mod debugger;
+#[path = "../src/repl.rs"]
+mod repl;
+
+/*
+The debug mode's commands, run on an assembled program the way they'd be typed in
+*/
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use memory::{ FlatMemory, Memory };
+    use debugger::Watched;
+    use repl::Repl;
+
+    fn program() -> (arm7tdmi::Core, Watched<FlatMemory>, HashMap<String, u32>) {
+        let program = asm::assemble("
+                    .arm
+            start:  mov r0,1
+                    bl func
+            back:   mov r2,3
+                    ldr r3,=0x12345678
+                    ldr r4,=data
+            store:  str r3,[r4]
+                    ldr r5,=thumb+1
+                    bx r5
+            func:   mov r1,2
+                    bx lr
+                    .thumb
+            thumb:  movs r6,6
+            spin:   b spin
+                    .align 2
+            data:   .word 0
+                    .pool
+        ", 0x100).unwrap();
+
+        let mut core = arm7tdmi::Core::new();
+        let mut bus = Watched::new(FlatMemory::new(0x1000));
+        for (i, byte) in program.bytes.iter().enumerate() {
+            bus.mem_write(0x100 + i, *byte);
+        }
+        core.reg.cpsr = arm7tdmi::Status::from(0xD3);
+        core.reg.gp[15] = 0x100;
+        (core, bus, program.symbols)
+    }
+
+    #[test]
+    fn stepping() {
+        let (mut core, mut bus, symbols) = program();
+        let mut repl: Repl<FlatMemory> = Repl::new();
+
+        assert!(repl.command("s", &mut core, &mut bus).unwrap().starts_with("=> 00000104  EB"));
+        assert_eq!(core.reg.gp[0], 1);
+
+        /* next runs the call, an empty line does it again */
+        assert!(repl.command("n", &mut core, &mut bus).unwrap().starts_with(&format!("=> {:08X}", symbols["back"])));
+        assert_eq!(core.reg.gp[1], 2);
+        repl.command("", &mut core, &mut bus);
+        assert_eq!(core.reg.gp[2], 3);
+
+        /* step goes into it and finish comes back out */
+        core.reg.gp[15] = 0x104;
+        repl.command("step", &mut core, &mut bus);
+        assert_eq!(core.reg.gp[15], symbols["func"]);
+        let finished = repl.command("fin", &mut core, &mut bus).unwrap();
+        assert!(finished.starts_with(&format!("Returned to 0x{:08X}\n=> ", symbols["back"])), "{}", finished);
+
+        assert_eq!(repl.command(&format!("b 0x{:x}", symbols["spin"]), &mut core, &mut bus).unwrap(), format!("Breakpoint at 0x{:08X}\n", symbols["spin"]));
+        assert_eq!(repl.command("b", &mut core, &mut bus).unwrap(), format!("0x{:08X}\n", symbols["spin"]));
+        let stopped = repl.command("c", &mut core, &mut bus).unwrap();
+        assert!(stopped.starts_with(&format!("Breakpoint at 0x{:08X}\n=> {:08X}  E7FE", symbols["spin"], symbols["spin"])), "{}", stopped);
+        assert_eq!(core.reg.gp[6], 6);
+        assert!(core.reg.cpsr.state);
+
+        repl.command("d", &mut core, &mut bus);
+        assert_eq!(repl.command("b", &mut core, &mut bus).unwrap(), "No breakpoints\n");
+        assert!(repl.command("c 10", &mut core, &mut bus).unwrap().starts_with("Ran 10 instructions\n"));
+
+        /* The loop takes 3 cycles, so it gets to VBlank within one */
+        let vblank = repl.command("vb", &mut core, &mut bus).unwrap();
+        assert!(vblank.starts_with("VBlank of frame 0"), "{}", vblank);
+        assert!((160 * 1232..160 * 1232 + 3).contains(&repl.debugger.cycles));
+        repl.command("vb", &mut core, &mut bus);
+        assert!((388 * 1232..388 * 1232 + 3).contains(&repl.debugger.cycles));
+    }
+
+    /* next and finish give up on a call that never comes back, the same as continue */
+    #[test]
+    fn runaway() {
+        let (mut core, mut bus, symbols) = program();
+        let mut repl: Repl<FlatMemory> = Repl::new();
+        let still_running = format!("Still running after {} cycles\n=> {:08X}", 16 * 1024 * 1024, symbols["func"]);
+
+        repl.command(&format!("poke 0x{:x} 0xEAFFFFFE", symbols["func"]), &mut core, &mut bus);
+        repl.command("s", &mut core, &mut bus);
+        let next = repl.command("n", &mut core, &mut bus).unwrap();
+        assert!(next.starts_with(&still_running), "{}", next);
+        assert!(repl.debugger.cycles >= 16 * 1024 * 1024);
+
+        let finish = repl.command("fin", &mut core, &mut bus).unwrap();
+        assert!(finish.starts_with(&still_running), "{}", finish);
+        assert_eq!(core.reg.gp[15], symbols["func"]);
+    }
+
+    #[test]
+    fn state() {
+        let (mut core, mut bus, symbols) = program();
+        let mut repl: Repl<FlatMemory> = Repl::new();
+        core.reg.gp[13] = 0x1111;
+        core.reg.svc[0] = 0x0F00;
+
+        let registers = repl.command("regs", &mut core, &mut bus).unwrap();
+        assert!(registers.contains(" sp 00000F00"), "{}", registers);
+        assert!(registers.contains(" pc 00000100"));
+        assert!(registers.contains("cpsr 000000D3  nzcv IFt  Supervisor\n"));
+        assert!(registers.contains("spsr 00000000"));
+
+        let banked = repl.command("banked", &mut core, &mut bus).unwrap();
+        assert!(banked.contains("usr r8=00000000 r9=00000000 r10=00000000 r11=00000000 r12=00000000 r13=00001111 r14=00000000\n"), "{}", banked);
+        assert!(banked.contains("svc r13=00000F00 r14=00000000  spsr 00000000"));
+
+        let data = symbols["data"];
+        assert_eq!(repl.command(&format!("poke 0x{:x} 0x41424344", data), &mut core, &mut bus).unwrap().lines().count(), 1);
+        repl.command(&format!("poke 0x{:x} 0x7E b", data + 3), &mut core, &mut bus);
+        assert_eq!(bus.mem_read_32(data as usize), 0x7E424344);
+        let dump = repl.command(&format!("x 0x{:x} 4", data), &mut core, &mut bus).unwrap();
+        assert!(dump.contains("44 43 42 7E"), "{}", dump);
+        assert!(dump.trim_end().ends_with("DCB~"));
+        assert!(repl.command("x 0xFF8 16", &mut core, &mut bus).unwrap().contains("?? ?? ??"));
+        assert!(repl.command("poke 0x2000 1", &mut core, &mut bus).unwrap().starts_with("Nothing is mapped"));
+
+        /* Around the PC, and from an address */
+        core.reg.gp[15] = 0x108;
+        let around = repl.command("l", &mut core, &mut bus).unwrap();
+        assert_eq!(around.lines().count(), 10);
+        assert_eq!(around.lines().nth(4).unwrap(), "=> 00000108  E3A02003  mov       r2,0x3");
+        let thumb = repl.command(&format!("disasm 0x{:x} 2", symbols["thumb"] + 1), &mut core, &mut bus).unwrap();
+        assert_eq!(thumb.lines().count(), 2);
+        assert!(thumb.starts_with(&format!("   {:08X}  2606", symbols["thumb"])), "{}", thumb);
+        assert!(repl.command("x pc 4", &mut core, &mut bus).unwrap().contains("03 20 A0 E3"));
+
+        assert!(repl.command("frobnicate", &mut core, &mut bus).unwrap().starts_with("Unknown command"));
+        assert_eq!(repl.command("q", &mut core, &mut bus), None);
+    }
+
+    #[test]
+    fn watchpoints() {
+        let (mut core, mut bus, symbols) = program();
+        let mut repl: Repl<FlatMemory> = Repl::new();
+        let data = symbols["data"];
+
+        assert_eq!(repl.command("watch", &mut core, &mut bus).unwrap(), "No watchpoints\n");
+        let spec = format!("write:0x{:x}=0x12345678", data);
+        assert_eq!(repl.command(&format!("watch {}", spec), &mut core, &mut bus).unwrap(), format!("Watching write:0x{:08X}=0x12345678\n", data));
+        assert_eq!(repl.command("watch", &mut core, &mut bus).unwrap(), format!("write:0x{:08X}=0x12345678\n", data));
+        assert!(repl.command("watch write:nowhere", &mut core, &mut bus).unwrap().ends_with("should be KIND:START[-END][=VALUE]\n"));
+
+        let stopped = repl.command("c", &mut core, &mut bus).unwrap();
+        let expected = format!("Write watchpoint: 0x12345678 written to 0x{:08X} by the instruction at 0x{:08X}\n=> {:08X}", data, symbols["store"], symbols["store"] + 4);
+        assert!(stopped.starts_with(&expected), "{}", stopped);
+
+        assert!(repl.command(&format!("unwatch {}", spec), &mut core, &mut bus).unwrap().starts_with("Stopped watching"));
+        assert!(repl.command(&format!("unwatch {}", spec), &mut core, &mut bus).unwrap().starts_with("There's no watchpoint"));
+        repl.command(&format!("watch exec:0x{:x}", symbols["spin"]), &mut core, &mut bus);
+        assert!(repl.command("c", &mut core, &mut bus).unwrap().starts_with(&format!("Execute watchpoint at 0x{:08X}\n", symbols["spin"])));
+        repl.command("unwatch", &mut core, &mut bus);
+        assert!(bus.watchpoints.is_empty());
+    }
+
+    #[test]
+    fn terminal() {
+        let (mut core, mut bus, symbols) = program();
+        let mut out = Vec::new();
+        Repl::new().run(&mut core, &mut bus, &mut "s\n\nquit\nregs\n".as_bytes(), &mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert_eq!(out.matches("(gba) ").count(), 3);
+        assert!(out.starts_with("=> 00000100"));
+        assert!(!out.contains("cpsr"));
+        assert_eq!(core.reg.gp[15], symbols["func"]);
+    }
+}
@@ -4,96 +4,40 @@ mod arm7tdmi;
 mod decode;
 #[path = "../src/exec.rs"]
 mod exec;
+#[path = "../src/bios.rs"]
+mod bios;
+#[path = "../src/memory.rs"]
+mod memory;
 
-mod bus {
-    use std::io;
-    use std::io::Read;
-    use std::io::BufReader;
-    use std::fs::File;
+use std::io;
+use std::io::Read;
+use std::io::BufReader;
+use std::fs::File;
 
-    pub struct Bus {
-        pub mem: [u8; 8816]
-    }
-
-    impl Bus {
-        pub fn new() -> Self {
-            Bus {
-                mem: [0; 8816]
-            }
-        }
+use memory::Memory;
 
-        pub fn mem_read(&self, addr: usize) -> u8 {
-            self.mem[addr]
-        }
+pub fn init_mem(mem: &mut memory::FlatMemory) -> io::Result<()> {
+    let f = File::open("../../gba-tests/arm/arm.gba")?;
+    let mut reader = BufReader::new(f);
+    let mut buffer = Vec::new();
 
-        pub fn mem_write(&mut self, addr: usize, data: u8) {
-            self.mem[addr] = data;
-        }
+    // Read file into vector.
+    reader.read_to_end(&mut buffer)?;
 
-        pub fn mem_read_16(&mut self, addr: usize) -> u16 {
-            let lo = self.mem_read(addr) as u16;
-            let hi = self.mem_read(addr+1) as u16;
-            lo | (hi << 8)
-        }
-    
-        pub fn mem_read_32(&mut self, addr: usize) -> u32 {
-            let lo = self.mem_read(addr) as u32;
-            let lo1 = self.mem_read(addr+1) as u32;
-            let hi = self.mem_read(addr+2) as u32;
-            let hi1 = self.mem_read(addr+3) as u32;
-            lo | (lo1 << 8) | (hi << 16) | (hi1 << 24)
-        }
-    
-        pub fn mem_write_16(&mut self, addr: usize, data: u16) {
-            let lo = (data & 0xFF) as u8;
-            let hi = (data >> 8) as u8;
-            self.mem_write(addr, lo);
-            self.mem_write(addr+1, hi);
-        }
-    
-        pub fn mem_write_32(&mut self, addr: usize, data: u32) {
-            let lo = (data & 0xFF) as u8;
-            let lo1 = ((data >> 8) & 0xFF) as u8;
-            let hi = ((data >> 16) & 0xFF) as u8;
-            let hi1 = ((data >> 24) & 0xFF) as u8;
-            self.mem_write(addr, lo);
-            self.mem_write(addr+1, lo1);
-            self.mem_write(addr+2, hi);
-            self.mem_write(addr+3, hi1);
-        }
+    println!("{}", buffer.len());
 
-        pub fn init_mem(&mut self) -> io::Result<()> {
-            let f = File::open("../../gba-tests/arm/arm.gba")?;
-            let mut reader = BufReader::new(f);
-            let mut buffer = Vec::new();
-            
-            // Read file into vector.
-            reader.read_to_end(&mut buffer)?;
-            
-            println!("{}", buffer.len());
-
-            for i in 0..buffer.len() {
-                self.mem[i] = buffer[i];
-            }
-            Ok(())
-        }
+    for i in 0..buffer.len() {
+        mem.mem[i] = buffer[i];
     }
+    Ok(())
 }
 
-pub fn test_inst(core: &mut arm7tdmi::Core, bus: &mut bus::Bus, inst: u32) {
-    core.cycle = 0;
-
-    println!("Inst: 0x{:x}, Type: {}, Dissassembly: {}, PC: 0x{:x}", inst, decode::decode_arm(inst), decode::disassemble_arm(inst), core.reg.gp[15]);
-
-    let mut state = None;
-
-    while state == None {
-        println!("{}", core.reg);
-        state = exec::step_arm(core, bus, inst);
-        core.cycle += 1;
-    }
+pub fn test_inst(core: &mut arm7tdmi::Core, bus: &mut memory::FlatMemory, inst: u32) {
+    println!("Inst: 0x{:x}, Type: {}, Dissassembly: {}, PC: 0x{:x}", inst, decode::decode_arm(inst), decode::disassemble_arm(inst, core.reg.gp[15]), core.reg.gp[15]);
 
-    core.reg.gp[15] += 4;
+    /* The core fetches through its own pipeline and moves R15 on by itself */
+    exec::run_arm(core, bus);
+    println!("{}", core.reg);
 }
 
 #[cfg(test)]
@@ -103,9 +47,9 @@ mod tests {
     #[test]
     fn test_arm() {
         let mut core = arm7tdmi::Core::new();
-        let mut bus = bus::Bus::new();
+        let mut bus = memory::FlatMemory::new(8816);
 
-        println!("{:?}", bus.init_mem());
+        println!("{:?}", init_mem(&mut bus));
         
         core.reg.write(13, 0x007f0003);
 
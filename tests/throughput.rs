@@ -0,0 +1,121 @@
+#[path = "../src/arm7tdmi.rs"]
+mod arm7tdmi;
+#[path = "../src/decode.rs"]
+mod decode;
+#[path = "../src/exec.rs"]
+mod exec;
+#[path = "../src/bios.rs"]
+mod bios;
+#[path = "../src/memory.rs"]
+mod memory;
+#[path = "../src/interp.rs"]
+mod interp;
+#[path = "../src/recomp.rs"]
+mod recomp;
+#[path = "../src/bus.rs"]
+mod bus;
+#[path = "../src/cache.rs"]
+mod cache;
+#[path = "../src/asm.rs"]
+mod asm;
+
+/*
+How fast each engine gets through the same loop, on the GBA bus and on flat memory. The times only mean anything in a release build,
+so these are left out of a normal run:
+
+    cargo test --release --test throughput -- --ignored --nocapture
+*/
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use memory::Memory;
+    use interp::{ Engine, Interpreter };
+    use recomp::Recompiler;
+    use std::time::{ Duration, Instant };
+
+    /* A bit of ALU work, a load and a store per go round */
+    const LOOP: &str = "
+                mov r4,0x4000
+        loop:   adds r0,r0,1
+                eor r1,r1,r0,lsl 3
+                ldr r2,[r3,4]
+                str r2,[r3,8]
+                add r5,r5,r2,lsr r0
+                subs r4,r4,1
+                bne loop
+        end:    b end
+    ";
+
+    /* Runs a step of the loop with one of the engines */
+    type Step<M> = Box<dyn FnMut(&mut arm7tdmi::Core, &mut M) -> u32>;
+
+    fn stepper<M: Memory + 'static>(engine: Engine) -> Step<M> {
+        match engine {
+            Engine::Block => {
+                let mut recompiler: Recompiler<M> = Recompiler::new();
+                Box::new(move |core, bus| recompiler.step(core, bus))
+            },
+            _ => {
+                let interpreter: Interpreter<M> = Interpreter::new(engine);
+                Box::new(move |core, bus| interpreter.step(core, bus))
+            },
+        }
+    }
+
+    /*
+    How many times faster the second engine gets through the loop at base than the first. They take turns, and the best of a few
+    goes each is taken, as anything else running gets in the way
+    */
+    fn speedup<M: Memory + 'static>(memory: impl Fn() -> M, base: u32, data: u32, engines: [Engine; 2], place: &str) -> f64 {
+        let program = asm::assemble(LOOP, base).unwrap();
+        let mut runs: Vec<(Step<M>, M, Duration)> = engines.iter().map(|&engine| {
+            let mut bus = memory();
+            for (i, byte) in program.bytes.iter().enumerate() {
+                bus.mem_write(base as usize + i, *byte);
+            }
+            (stepper(engine), bus, Duration::MAX)
+        }).collect();
+
+        for _ in 0..15 {
+            for (step, bus, best) in runs.iter_mut() {
+                let mut core = arm7tdmi::Core::new();
+                core.reg.gp[3] = data;
+                core.reg.gp[15] = base;
+
+                let start = Instant::now();
+                while core.reg.gp[15] != program.symbols["end"] {
+                    step(&mut core, bus);
+                }
+                *best = (*best).min(start.elapsed());
+            }
+        }
+
+        let speedup = runs[0].2.as_secs_f64() / runs[1].2.as_secs_f64();
+        println!("{:>6} {:?} {:>10.2?} {:?} {:>10.2?} {:.1}x", place, engines[0], runs[0].2, engines[1], runs[1].2, speedup);
+        speedup
+    }
+
+    #[test]
+    #[ignore]
+    fn fast_interpreter() {
+        for (place, base) in [("IWRAM", 0x03000000), ("BIOS", 0x100), ("ROM", 0x08000000)] {
+            let speedup = speedup(bus::Bus::new, base, 0x03001000, [Engine::Stepped, Engine::Fast], place);
+            assert!(speedup > 1.3, "the fast interpreter should be at least 1.3x as fast in {}", place);
+        }
+        let speedup = speedup(|| memory::FlatMemory::new(0x2000), 0x100, 0x1000, [Engine::Stepped, Engine::Fast], "flat");
+        assert!(speedup > 1.3, "the fast interpreter should be at least 1.3x as fast on flat memory");
+    }
+
+    /* Blocks are only worth it where they can be kept, anywhere else it falls back to the fast interpreter so it's only a little slower */
+    #[test]
+    #[ignore]
+    fn block_recompiler() {
+        for (place, base, least) in [("IWRAM", 0x03000000, 1.3), ("ROM", 0x08000000, 1.3), ("BIOS", 0x100, 0.8)] {
+            let speedup = speedup(bus::Bus::new, base, 0x03001000, [Engine::Fast, Engine::Block], place);
+            assert!(speedup > least, "the block recompiler should be at least {}x as fast in {}", least, place);
+        }
+        let speedup = speedup(|| memory::FlatMemory::new(0x2000), 0x100, 0x1000, [Engine::Fast, Engine::Block], "flat");
+        assert!(speedup > 1.3, "the block recompiler should be at least 1.3x as fast on flat memory");
+    }
+}
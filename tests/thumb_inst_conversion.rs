@@ -232,4 +232,31 @@ mod test {
         let converted: Option<u32>  = decode::translate_thumb(thumbinst);
         assert_eq!(arminst, get_result(converted));
     }
-}
\ No newline at end of file
+
+    /* The instructions the engines run, which are the same as translate_thumb except where that doesn't do the same thing */
+    #[test]
+    fn exact_equivalents() {
+        let cases: [(u16, Option<u32>); 13] = [
+            (0x408B, Some(0xE1B03113)),     //lsls r3,r1 = movs r3,r3,lsl r1
+            (0x41EA, Some(0xE1B02572)),     //rors r2,r5 = movs r2,r2,ror r5
+            (0x525C, Some(0xE18340B1)),     //strh r4,[r3,r1]
+            (0x6891, Some(0xE5921008)),     //ldr r1,[r2,#0x8]
+            (0x7091, Some(0xE5C21002)),     //strb r1,[r2,#0x2]
+            (0x9004, Some(0xE58D0010)),     //str r0,[sp,#0x10]
+            (0xAA08, Some(0xE28D2F08)),     //add r2,sp,#0x20
+            (0xB007, Some(0xE28DDF07)),     //add sp,#0x1C
+            (0xB087, Some(0xE24DDF07)),     //sub sp,#0x1C
+            (0x4587, Some(0xE15F0000)),     //cmp pc,r0, without CMPP's Rd
+            (0xA101, None),                 //add r1,pc,#0x4
+            (0xE7FE, None),                 //b
+            (0xD0FE, None),                 //beq
+        ];
+        for (thumbinst, arminst) in cases {
+            assert_eq!(decode::thumb_equivalent(thumbinst), arminst, "{:04X}", thumbinst);
+        }
+        /* Everything else is left as it was */
+        for thumbinst in [0x1888u16, 0x4048, 0x4358, 0x4497, 0x5A9A, 0xBC03, 0xC107, 0xDF05] {
+            assert_eq!(decode::thumb_equivalent(thumbinst), decode::translate_thumb(thumbinst), "{:04X}", thumbinst);
+        }
+    }
+}
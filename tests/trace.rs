@@ -0,0 +1,137 @@
+#[path = "../src/arm7tdmi.rs"]
+mod arm7tdmi;
+#[path = "../src/decode.rs"]
+mod decode;
+#[path = "../src/exec.rs"]
+mod exec;
+#[path = "../src/bios.rs"]
+mod bios;
+#[path = "../src/memory.rs"]
+mod memory;
+#[path = "../src/interp.rs"]
+mod interp;
+#[path = "../src/recomp.rs"]
+mod recomp;
+#[path = "../src/cache.rs"]
+mod cache;
+#[path = "../src/asm.rs"]
+mod asm;
+#[path = "../src/trace.rs"]
+mod trace;
+
+/*
+Execution traces, the formats they're written in and which instructions end up in them
+*/
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use memory::{ FlatMemory, Memory };
+    use interp::{ Engine, Interpreter };
+    use recomp::Recompiler;
+    use trace::{ TraceFormat, Tracer };
+
+    fn load(source: &str, origin: u32) -> (arm7tdmi::Core, FlatMemory, asm::Program) {
+        let program = asm::assemble(source, origin).unwrap();
+        let mut bus = FlatMemory::new(0x1000);
+        for (i, byte) in program.bytes.iter().enumerate() {
+            bus.mem_write(origin as usize + i, *byte);
+        }
+        let mut core = arm7tdmi::Core::new();
+        core.reg.gp[15] = origin;
+        (core, bus, program)
+    }
+
+    #[test]
+    fn formats() {
+        let mut reg = arm7tdmi::Reg { cpsr: arm7tdmi::Status::from(0x600000D3), ..Default::default() };
+        reg.gp[0] = 0x11D;
+        reg.gp[13] = 0x03000000;    //the User/System one, SVC has its own
+        reg.write(13, 0x03007F00);
+        reg.gp[15] = 0x114;
+
+        assert_eq!(TraceFormat::Mgba.line(&reg, 0xE28F0001, "add r0,pc,#0x1"),
+            "0000011D 00000000 00000000 00000000 00000000 00000000 00000000 00000000 00000000 00000000 00000000 00000000 00000000 03007F00 00000000 0000011C cpsr: 600000D3 | E28F0001: add r0,pc,#0x1");
+        assert_eq!(TraceFormat::Plain.line(&reg, 0xE28F0001, "add       r0,=0x11D"),
+            "00000114  E28F0001  add       r0,=0x11D                      r0=0000011D r1=00000000 r2=00000000 r3=00000000 r4=00000000 r5=00000000 r6=00000000 r7=00000000 r8=00000000 r9=00000000 r10=00000000 r11=00000000 r12=00000000 r13=03007F00 r14=00000000 r15=00000114 cpsr=600000D3");
+
+        /* Thumb opcodes line up with the ARM ones, and R15 is only 4 ahead */
+        reg.cpsr.state = true;
+        reg.gp[15] = 0x11C;
+        assert!(TraceFormat::Mgba.line(&reg, 0x2000, "movs r0,#0x0").ends_with(" 00000120 cpsr: 600000F3 |     2000: movs r0,#0x0"));
+        assert!(TraceFormat::Plain.line(&reg, 0x2000, "movs      r0,0x0").starts_with("0000011C      2000  movs      r0,0x0  "));
+
+        assert_eq!(TraceFormat::from_name("nba"), Some(TraceFormat::Mgba));
+        assert_eq!(TraceFormat::from_name("json"), None);
+    }
+
+    #[test]
+    fn ring_and_range() {
+        let (mut core, mut bus, program) = load("
+                    mov r0,0
+                    mov r1,3
+            sum:    add r0,r0,r1
+                    subs r1,r1,1
+                    bne sum
+            done:   b done
+        ", 0x100);
+        let interpreter: Interpreter<FlatMemory> = Interpreter::new(Engine::Fast);
+
+        /* Only the loop, and only the last 4 instructions of it */
+        let mut tracer = Tracer::ring(TraceFormat::Plain, 4);
+        tracer.range = Some(program.symbols["sum"]..program.symbols["done"]);
+        while core.reg.gp[15] != program.symbols["done"] {
+            tracer.record(&core.reg, &mut bus);
+            interpreter.step(&mut core, &mut bus);
+        }
+
+        assert_eq!(tracer.count, 9);
+        let lines = tracer.lines();
+        assert_eq!(lines.len(), 4);
+        assert!(lines[0].starts_with("00000110  1AFFFFFC  bne       0x108"));
+        assert!(lines[1].starts_with("00000108  E0800001  add       r0,r0,r1"));
+        assert!(lines[2].starts_with("0000010C  E2511001  subs      r1,r1,0x1"));
+        assert!(lines[2].contains("r0=00000006 r1=00000001"));
+        assert!(lines[3].contains("r0=00000006 r1=00000000") && lines[3].ends_with("cpsr=600000D3"));
+
+        let mut out = Vec::new();
+        tracer.dump(&mut out).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap().lines().count(), 4);
+        assert!(tracer.lines().is_empty());
+    }
+
+    /* The block engine has to run an instruction at a time for the trace to see each of them */
+    #[test]
+    fn thumb_blocks() {
+        let (mut core, mut bus, program) = load("
+                    .thumb
+                    movs r0,2
+                    ldr r1,=0x4000000
+            loop:   subs r0,r0,1
+                    bne loop
+            done:   b done
+                    .pool
+        ", 0x200);
+        core.reg.cpsr.state = true;
+
+        let mut recompiler: Recompiler<FlatMemory> = Recompiler::with_max_block(1);
+        let mut tracer = Tracer::ring(TraceFormat::Mgba, 16);
+        while core.reg.gp[15] != program.symbols["done"] {
+            tracer.record(&core.reg, &mut bus);
+            recompiler.step(&mut core, &mut bus);
+        }
+
+        let lines = tracer.lines();
+        let ends: Vec<&str> = lines.iter().map(|line| line.split(" | ").nth(1).unwrap()).collect();
+        assert_eq!(ends, vec![
+            "    2002: movs r0,#0x2",
+            "    4902: ldr r1,[0x20C] ;=#0x4000000",
+            "    1E40: subs r0,r0,#0x1",
+            "    D1FD: bne 0x204",
+            "    1E40: subs r0,r0,#0x1",
+            "    D1FD: bne 0x204",
+        ]);
+        assert!(lines[2].starts_with("00000002 04000000 "));
+        assert!(lines[5].contains(" 0000020A cpsr: 600000F3 "));
+    }
+}